@@ -0,0 +1,156 @@
+use criterion::{criterion_group, criterion_main, BatchSize, BenchmarkId, Criterion};
+use tempfile::TempDir;
+
+use kvs::KvStore;
+
+const KEY_COUNT: usize = 1000;
+const VALUE_LEN: usize = 100;
+/// Keys of the store opened by the open benchmarks, enough to make loading
+/// the index show.
+const LARGE_KEY_COUNT: usize = 100_000;
+
+/// Deterministic xorshift, so every run reads the same keys.
+struct Rng(u64);
+
+impl Rng {
+    fn next_index(&mut self, bound: usize) -> usize {
+        self.0 ^= self.0 << 13;
+        self.0 ^= self.0 >> 7;
+        self.0 ^= self.0 << 17;
+        (self.0 % bound as u64) as usize
+    }
+}
+
+fn key(i: usize) -> String {
+    format!("key{:08}", i)
+}
+
+/// A store in a fresh temporary directory holding `KEY_COUNT` keys.
+fn populated() -> (TempDir, KvStore) {
+    let temp_dir = TempDir::new().expect("unable to create temporary working directory");
+    let mut store = KvStore::open(temp_dir.path()).unwrap();
+    for i in 0..KEY_COUNT {
+        store.set(key(i), "v".repeat(VALUE_LEN)).unwrap();
+    }
+    store.flush().unwrap();
+    (temp_dir, store)
+}
+
+fn sequential_put(c: &mut Criterion) {
+    c.bench_function("sequential_put", |b| {
+        b.iter_batched(
+            || TempDir::new().expect("unable to create temporary working directory"),
+            |temp_dir| {
+                let mut store = KvStore::open(temp_dir.path()).unwrap();
+                for i in 0..KEY_COUNT {
+                    store.set(key(i), "v".repeat(VALUE_LEN)).unwrap();
+                }
+                store.flush().unwrap();
+            },
+            BatchSize::PerIteration,
+        )
+    });
+}
+
+fn random_get(c: &mut Criterion) {
+    let (_temp_dir, mut store) = populated();
+    let mut rng = Rng(0x2545_f491_4f6c_dd1d);
+    c.bench_function("random_get", |b| {
+        b.iter(|| store.get(key(rng.next_index(KEY_COUNT))).unwrap())
+    });
+}
+
+/// Rewrites a handful of keys over and over, so compaction keeps kicking in.
+fn overwrite_heavy(c: &mut Criterion) {
+    let (_temp_dir, mut store) = populated();
+    let mut rng = Rng(0x9e37_79b9_7f4a_7c15);
+    c.bench_function("overwrite_heavy", |b| {
+        b.iter(|| {
+            store
+                .set(key(rng.next_index(10)), "v".repeat(VALUE_LEN))
+                .unwrap()
+        })
+    });
+}
+
+/// A closed store in a fresh temporary directory holding `LARGE_KEY_COUNT`
+/// keys, with an index checkpoint next to it.
+fn populated_large() -> TempDir {
+    let temp_dir = TempDir::new().expect("unable to create temporary working directory");
+    let mut store = KvStore::builder()
+        .index_checkpoint(true)
+        .build(temp_dir.path())
+        .unwrap();
+    for i in 0..LARGE_KEY_COUNT {
+        store.set(key(i), "v".repeat(VALUE_LEN)).unwrap();
+    }
+    drop(store);
+    temp_dir
+}
+
+/// Opens a large store with its index checkpoint, which closing it writes
+/// again for the next iteration, and without, so the whole file is read.
+fn cold_open(c: &mut Criterion) {
+    let temp_dir = populated_large();
+    let checkpoint_path = temp_dir.path().join("miniDB.index");
+    c.bench_function("cold_open", |b| {
+        b.iter_batched(
+            || (),
+            |()| {
+                KvStore::builder()
+                    .index_checkpoint(true)
+                    .build(temp_dir.path())
+                    .unwrap()
+            },
+            BatchSize::PerIteration,
+        )
+    });
+    c.bench_function("cold_open_no_checkpoint", |b| {
+        b.iter_batched(
+            || {
+                let _ = std::fs::remove_file(&checkpoint_path);
+            },
+            |()| KvStore::open(temp_dir.path()).unwrap(),
+            BatchSize::PerIteration,
+        )
+    });
+}
+
+/// Opens a large store without its checkpoint, so the whole file is read
+/// through a buffer of each size.
+fn open_read_buffer(c: &mut Criterion) {
+    let temp_dir = populated_large();
+    let checkpoint_path = temp_dir.path().join("miniDB.index");
+    let mut group = c.benchmark_group("open_read_buffer");
+    for capacity in [8 * 1024, 256 * 1024] {
+        group.bench_with_input(
+            BenchmarkId::from_parameter(capacity),
+            &capacity,
+            |b, &capacity| {
+                b.iter_batched(
+                    || {
+                        let _ = std::fs::remove_file(&checkpoint_path);
+                    },
+                    |()| {
+                        KvStore::builder()
+                            .read_buffer_capacity(capacity)
+                            .build(temp_dir.path())
+                            .unwrap()
+                    },
+                    BatchSize::PerIteration,
+                )
+            },
+        );
+    }
+    group.finish();
+}
+
+criterion_group!(
+    benches,
+    sequential_put,
+    random_get,
+    overwrite_heavy,
+    cold_open,
+    open_read_buffer
+);
+criterion_main!(benches);
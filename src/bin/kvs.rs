@@ -1,56 +1,186 @@
 extern crate kvs;
 
 use std::env::current_dir;
+use std::fs::File;
+use std::io::{self, BufReader, BufWriter, Read, Write};
+use std::path::PathBuf;
 use std::process::exit;
 
 use clap::{Parser, Subcommand};
-use kvs::{KvStore, KvsError};
+use kvs::{KvStore, KvsError, Result};
 
 #[derive(Debug, Parser)]
 #[clap(version = env!("CARGO_PKG_VERSION"))]
 struct Cli {
+    /// Directory of the store, the current directory if not given
+    #[clap(long, alias = "dir", global = true, env = "MINIDB_PATH")]
+    path: Option<PathBuf>,
+
     #[clap(subcommand)]
     command: Command,
 }
 
 #[derive(Debug, Subcommand)]
 enum Command {
-    /// set <KEY> <VALUE>
+    /// set <KEY> <VALUE>, with a VALUE of - read from stdin
     #[clap(arg_required_else_help = true)]
     Set { key: String, val: String },
 
     /// get <KEY>
     #[clap(arg_required_else_help = true)]
-    Get { key: String },
+    Get {
+        key: String,
+
+        /// Write the value exactly as stored, without a newline, and report a
+        /// missing key on stderr with a non-zero exit code
+        #[clap(long)]
+        raw: bool,
+    },
 
     /// rm <KEY>
     #[clap(arg_required_else_help = true)]
     #[clap(name = "rm")]
     Remove { key: String },
+
+    /// export <FILE>
+    #[clap(arg_required_else_help = true)]
+    Export { file: PathBuf },
+
+    /// import <FILE>
+    #[clap(arg_required_else_help = true)]
+    Import {
+        file: PathBuf,
+
+        /// Remove every existing key before importing
+        #[clap(long)]
+        clear_first: bool,
+    },
+
+    /// Print every live key, sorted
+    #[clap(alias = "keys")]
+    List {
+        /// Only print keys starting with this
+        #[clap(long)]
+        prefix: Option<String>,
+
+        /// Print key=value pairs instead of bare keys
+        #[clap(long)]
+        values: bool,
+    },
+
+    /// Print key count, file size and reclaimable space
+    Stats,
+
+    /// Drop a damaged tail of the data file
+    Repair,
+
+    /// Upgrade a data file written by an older version
+    Migrate,
+
+    /// Check the data file for corruption without changing it
+    Verify,
 }
 
 fn main() {
     let args = Cli::parse();
-    let mut kv_store = KvStore::open(current_dir().unwrap().as_path()).unwrap();
+    if let Err(err) = run(args) {
+        eprintln!("{}", err);
+        exit(1);
+    }
+}
+
+fn run(args: Cli) -> Result<()> {
+    let path = match args.path {
+        Some(path) => path,
+        None => current_dir()?,
+    };
+    let open = || KvStore::open(path.as_path());
 
     match args.command {
-        Command::Get { key } => {
-            if let Some(val) = kv_store.get(key).unwrap() {
+        Command::Get { key, raw: false } => {
+            if let Some(val) = open()?.get(key)? {
                 println!("{}", val);
             } else {
                 println!("Key not found");
             }
         }
-        Command::Set { key, val } => {
-            if let Err(err) = kv_store.set(key, val) {
-                eprintln!("{:?}", err);
+        Command::Get { key, raw: true } => {
+            let mut stdout = io::stdout().lock();
+            if !open()?.get_writer(&key, &mut stdout)? {
+                eprintln!("Key not found");
+                exit(1);
             }
+            stdout.flush()?;
+        }
+        Command::Set { key, val } if val == "-" => {
+            let mut val = String::new();
+            io::stdin().read_to_string(&mut val)?;
+            open()?.set(key, val)?;
+        }
+        Command::Set { key, val } => {
+            open()?.set(key, val)?;
         }
-        Command::Remove { key } => {
-            if let Err(KvsError::KeyNotFound) = kv_store.remove(key) {
+        Command::Remove { key } => match open()?.remove(key) {
+            Err(KvsError::KeyNotFound) => {
                 println!("Key not found");
                 exit(1);
             }
+            result => result?,
+        },
+        Command::Export { file } => {
+            open()?.export(BufWriter::new(File::create(file)?))?;
+        }
+        Command::Import { file, clear_first } => {
+            let mut kv_store = open()?;
+            if clear_first {
+                kv_store.clear()?;
+            }
+            kv_store.import(BufReader::new(File::open(file)?))?;
+        }
+        Command::List { prefix, values } => {
+            let prefix = prefix.unwrap_or_default();
+            for (key, val) in open()?.scan()? {
+                if !key.starts_with(&prefix) {
+                    continue;
+                }
+                if values {
+                    println!("{}={}", key, val);
+                } else {
+                    println!("{}", key);
+                }
+            }
+        }
+        Command::Stats => {
+            let mut kv_store = open()?;
+            let stats = kv_store.stats();
+            let estimate = kv_store.compaction_estimate()?;
+            println!("keys: {}", stats.key_count);
+            println!("file size: {} bytes", stats.file_size);
+            println!("pending compaction: {} bytes", stats.pending_bytes);
+            println!("reclaimable: {} bytes", estimate.reclaimable_bytes);
+        }
+        Command::Repair => {
+            let dropped = KvStore::repair(path.as_path())?;
+            println!("Dropped {} bytes", dropped);
+        }
+        Command::Migrate => {
+            if KvStore::migrate(path.as_path())? {
+                println!("Migrated");
+            } else {
+                println!("Already up to date");
+            }
+        }
+        Command::Verify => {
+            let report = KvStore::verify(path.as_path())?;
+            println!(
+                "{} valid entries, {} bytes scanned",
+                report.valid_entries, report.bytes_scanned
+            );
+            if let Some(offset) = report.first_corruption {
+                println!("Corrupted entry at offset {}", offset);
+                exit(1);
+            }
         }
     }
+    Ok(())
 }
@@ -0,0 +1,73 @@
+use std::path::Path;
+use std::sync::{Arc, Mutex};
+
+use tokio::task;
+
+use super::error::Result;
+use super::kv_store::KvStore;
+use super::options::Options;
+
+/// Async front end for `KvStore`.
+///
+/// Every call runs the blocking store operation on tokio's blocking pool, so
+/// it must be used from within a tokio runtime. Clones share the same store.
+#[derive(Clone)]
+pub struct AsyncKvStore {
+    inner: Arc<Mutex<KvStore>>,
+}
+
+impl AsyncKvStore {
+    pub async fn open(path: &Path) -> Result<AsyncKvStore> {
+        AsyncKvStore::open_with_options(path, Options::default()).await
+    }
+
+    pub async fn open_with_options(path: &Path, options: Options) -> Result<AsyncKvStore> {
+        let path_buf = path.to_path_buf();
+        let store =
+            unblock(move || KvStore::open_with_options(path_buf.as_path(), options)).await?;
+        Ok(AsyncKvStore::new(store))
+    }
+
+    /// Wraps an already opened store.
+    pub fn new(store: KvStore) -> AsyncKvStore {
+        AsyncKvStore {
+            inner: Arc::new(Mutex::new(store)),
+        }
+    }
+
+    pub async fn get(&self, key: String) -> Result<Option<String>> {
+        self.with_store(move |store| store.get(key)).await
+    }
+
+    pub async fn set(&self, key: String, val: String) -> Result<()> {
+        self.with_store(move |store| store.set(key, val)).await
+    }
+
+    pub async fn remove(&self, key: String) -> Result<()> {
+        self.with_store(move |store| store.remove(key)).await
+    }
+
+    pub async fn flush(&self) -> Result<()> {
+        self.with_store(|store| store.flush()).await
+    }
+
+    async fn with_store<T, F>(&self, f: F) -> Result<T>
+    where
+        T: Send + 'static,
+        F: FnOnce(&mut KvStore) -> Result<T> + Send + 'static,
+    {
+        let inner = self.inner.clone();
+        unblock(move || f(&mut inner.lock().unwrap())).await
+    }
+}
+
+async fn unblock<T, F>(f: F) -> Result<T>
+where
+    T: Send + 'static,
+    F: FnOnce() -> Result<T> + Send + 'static,
+{
+    match task::spawn_blocking(f).await {
+        Ok(result) => result,
+        Err(e) => std::panic::resume_unwind(e.into_panic()),
+    }
+}
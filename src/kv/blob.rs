@@ -0,0 +1,166 @@
+use std::fs::{File, OpenOptions};
+use std::io::{self, BufWriter, Read, Seek, SeekFrom, Write};
+use std::path::{Path, PathBuf};
+
+use super::error::Result;
+use super::storage::copy_utf8;
+
+/// Location of a value kept in the blob file.
+#[derive(Clone, Copy, Debug, PartialEq, Eq)]
+pub struct BlobRef {
+    pub offset: u64,
+
+    pub len: u64,
+}
+
+impl BlobRef {
+    /// Text stored as the value of the log entry pointing at the blob.
+    pub fn encode(&self) -> String {
+        format!("{}:{}", self.offset, self.len)
+    }
+
+    pub fn decode(s: &str) -> Option<BlobRef> {
+        let (offset, len) = s.split_once(':')?;
+        Some(BlobRef {
+            offset: offset.parse().ok()?,
+            len: len.parse().ok()?,
+        })
+    }
+}
+
+/// Append-only file holding values too large to keep in the data file.
+pub struct BlobFile {
+    path_buf: PathBuf,
+
+    reader: File,
+
+    writer: BufWriter<File>,
+
+    len: u64,
+}
+
+impl BlobFile {
+    pub fn open(path_buf: PathBuf, capacity: usize) -> Result<BlobFile> {
+        let writer = OpenOptions::new()
+            .create(true)
+            .append(true)
+            .open(path_buf.as_path())?;
+        let len = writer.metadata()?.len();
+        Ok(BlobFile {
+            reader: File::open(path_buf.as_path())?,
+            writer: BufWriter::with_capacity(capacity, writer),
+            path_buf,
+            len,
+        })
+    }
+
+    pub fn path(&self) -> &Path {
+        self.path_buf.as_path()
+    }
+
+    pub fn len(&self) -> u64 {
+        self.len
+    }
+
+    pub fn is_empty(&self) -> bool {
+        self.len == 0
+    }
+
+    pub fn append(&mut self, val: &str) -> Result<BlobRef> {
+        let blob = BlobRef {
+            offset: self.len,
+            len: val.len() as u64,
+        };
+        self.writer.write_all(val.as_bytes())?;
+        // handed to the file right away so a data file flush never gets ahead
+        // of the blobs its entries point at
+        self.writer.flush()?;
+        self.len += blob.len;
+        Ok(blob)
+    }
+
+    /// Like `append`, copying the next `len` bytes of `reader`, which must be
+    /// valid UTF-8. Nothing is kept if they can't all be copied.
+    pub fn append_from(&mut self, reader: &mut dyn Read, len: usize) -> Result<BlobRef> {
+        let blob = BlobRef {
+            offset: self.len,
+            len: len as u64,
+        };
+        if let Err(e) = copy_utf8(reader, &mut self.writer, len) {
+            self.writer.flush()?;
+            self.writer.get_ref().set_len(self.len)?;
+            return Err(e);
+        }
+        self.writer.flush()?;
+        self.len += blob.len;
+        Ok(blob)
+    }
+
+    /// Like `append`, copying the blob at `blob` of `src`, or `None` if it lies
+    /// past the end of `src`. Left in the buffer until the next flush.
+    pub fn append_copy(&mut self, src: &mut BlobFile, blob: BlobRef) -> Result<Option<BlobRef>> {
+        let copied = BlobRef {
+            offset: self.len,
+            len: blob.len,
+        };
+        if !src.copy_to(blob, &mut self.writer)? {
+            return Ok(None);
+        }
+        self.len += copied.len;
+        Ok(Some(copied))
+    }
+
+    /// The blob at `blob`, or `None` if it lies past the end of the file.
+    pub fn read(&mut self, blob: BlobRef) -> Result<Option<String>> {
+        match blob.offset.checked_add(blob.len) {
+            Some(end) if end <= self.len => {}
+            _ => return Ok(None),
+        }
+        self.reader.seek(SeekFrom::Start(blob.offset))?;
+        let mut buf = vec![0; blob.len as usize];
+        self.reader.read_exact(buf.as_mut_slice())?;
+        Ok(Some(String::from_utf8(buf)?))
+    }
+
+    /// Writes the blob at `blob` to `writer`, returning false if it lies past
+    /// the end of the file.
+    pub fn copy_to(&mut self, blob: BlobRef, writer: &mut dyn Write) -> Result<bool> {
+        match blob.offset.checked_add(blob.len) {
+            Some(end) if end <= self.len => {}
+            _ => return Ok(false),
+        }
+        self.reader.seek(SeekFrom::Start(blob.offset))?;
+        io::copy(&mut (&mut self.reader).take(blob.len), writer)?;
+        Ok(true)
+    }
+
+    /// The handle writes go through.
+    pub fn file(&self) -> &File {
+        self.writer.get_ref()
+    }
+
+    pub fn flush(&mut self) -> Result<()> {
+        self.writer.flush()?;
+        Ok(())
+    }
+
+    pub fn sync(&mut self) -> Result<()> {
+        self.writer.flush()?;
+        self.writer.get_ref().sync_data()?;
+        Ok(())
+    }
+
+    /// Like `sync`, also syncing the metadata of the file.
+    pub fn sync_all(&mut self) -> Result<()> {
+        self.writer.flush()?;
+        self.writer.get_ref().sync_all()?;
+        Ok(())
+    }
+
+    pub fn clear(&mut self) -> Result<()> {
+        self.writer.flush()?;
+        self.writer.get_ref().set_len(0)?;
+        self.len = 0;
+        Ok(())
+    }
+}
@@ -0,0 +1,65 @@
+use std::collections::hash_map::DefaultHasher;
+use std::hash::{Hash, Hasher};
+
+/// Sizing of the bloom filter over keys.
+#[derive(Clone, Copy, Debug, PartialEq)]
+pub struct BloomOptions {
+    /// Number of keys the filter is sized for. More keys than this raise the
+    /// false-positive rate above the target.
+    pub expected_keys: usize,
+
+    /// Target rate of absent keys reported as possibly present.
+    pub false_positive_rate: f64,
+}
+
+/// Bloom filter over keys, a definite miss means the key was never inserted.
+pub struct BloomFilter {
+    bits: Vec<u64>,
+    num_bits: u64,
+    num_hashes: u32,
+}
+
+impl BloomFilter {
+    pub fn new(options: BloomOptions) -> BloomFilter {
+        let n = options.expected_keys.max(1) as f64;
+        let p = options.false_positive_rate.clamp(f64::MIN_POSITIVE, 0.5);
+        let ln2 = std::f64::consts::LN_2;
+        let num_bits = (-n * p.ln() / (ln2 * ln2)).ceil().max(64.0) as u64;
+        let num_hashes = ((num_bits as f64 / n) * ln2).round().max(1.0) as u32;
+        BloomFilter {
+            bits: vec![0; num_bits.div_ceil(64) as usize],
+            num_bits,
+            num_hashes,
+        }
+    }
+
+    pub fn insert(&mut self, key: &str) {
+        for bit in self.bit_positions(key) {
+            self.bits[(bit / 64) as usize] |= 1 << (bit % 64);
+        }
+    }
+
+    pub fn may_contain(&self, key: &str) -> bool {
+        self.bit_positions(key)
+            .all(|bit| self.bits[(bit / 64) as usize] & (1 << (bit % 64)) != 0)
+    }
+
+    pub fn clear(&mut self) {
+        self.bits.iter_mut().for_each(|word| *word = 0);
+    }
+
+    /// Double hashing: the i-th position is `h1 + i * h2`.
+    fn bit_positions(&self, key: &str) -> impl Iterator<Item = u64> {
+        let h1 = hash_with_seed(key, 0);
+        let h2 = hash_with_seed(key, 1) | 1;
+        let num_bits = self.num_bits;
+        (0..self.num_hashes as u64).map(move |i| h1.wrapping_add(i.wrapping_mul(h2)) % num_bits)
+    }
+}
+
+fn hash_with_seed(key: &str, seed: u64) -> u64 {
+    let mut hasher = DefaultHasher::new();
+    seed.hash(&mut hasher);
+    key.hash(&mut hasher);
+    hasher.finish()
+}
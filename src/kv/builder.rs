@@ -0,0 +1,204 @@
+use std::path::Path;
+use std::sync::Arc;
+use std::time::Duration;
+
+use super::bloom::BloomOptions;
+use super::clock::Clock;
+use super::error::Result;
+#[cfg(feature = "testing")]
+use super::fault::FaultInjector;
+use super::index::IndexHasher;
+use super::kv_store::KvStore;
+use super::options::{CancellationToken, CommitInterval, CompactionCallback, IndexKind, Options};
+
+/// Chainable way to fill in `Options` before opening a store, see
+/// `KvStore::builder`. Every setter overrides the matching field of
+/// `Options::default()`.
+#[derive(Clone, Debug, Default)]
+pub struct KvStoreBuilder {
+    options: Options,
+}
+
+impl KvStoreBuilder {
+    pub fn new() -> KvStoreBuilder {
+        KvStoreBuilder::default()
+    }
+
+    /// Starts from `options` instead of the defaults.
+    pub fn from_options(options: Options) -> KvStoreBuilder {
+        KvStoreBuilder { options }
+    }
+
+    pub fn name(mut self, name: &str) -> KvStoreBuilder {
+        self.options.name = name.to_owned();
+        self
+    }
+
+    pub fn create(mut self, create: bool) -> KvStoreBuilder {
+        self.options.create = create;
+        self
+    }
+
+    pub fn create_new(mut self, create_new: bool) -> KvStoreBuilder {
+        self.options.create_new = create_new;
+        self
+    }
+
+    pub fn index_kind(mut self, index_kind: IndexKind) -> KvStoreBuilder {
+        self.options.index_kind = index_kind;
+        self
+    }
+
+    pub fn index_hasher(mut self, hasher: IndexHasher) -> KvStoreBuilder {
+        self.options.index_hasher = Some(hasher);
+        self
+    }
+
+    pub fn max_key_size(mut self, max_key_size: usize) -> KvStoreBuilder {
+        self.options.max_key_size = max_key_size;
+        self
+    }
+
+    pub fn max_value_size(mut self, max_value_size: usize) -> KvStoreBuilder {
+        self.options.max_value_size = max_value_size;
+        self
+    }
+
+    pub fn read_buffer_capacity(mut self, capacity: usize) -> KvStoreBuilder {
+        self.options.read_buffer_capacity = capacity;
+        self
+    }
+
+    pub fn write_buffer_capacity(mut self, capacity: usize) -> KvStoreBuilder {
+        self.options.write_buffer_capacity = capacity;
+        self
+    }
+
+    pub fn bloom_filter(mut self, bloom_filter: BloomOptions) -> KvStoreBuilder {
+        self.options.bloom_filter = Some(bloom_filter);
+        self
+    }
+
+    pub fn blob_threshold(mut self, threshold: usize) -> KvStoreBuilder {
+        self.options.blob_threshold = Some(threshold);
+        self
+    }
+
+    pub fn auto_compaction(mut self, auto_compaction: bool) -> KvStoreBuilder {
+        self.options.auto_compaction = auto_compaction;
+        self
+    }
+
+    pub fn commit_interval(mut self, interval: CommitInterval) -> KvStoreBuilder {
+        self.options.commit_interval = Some(interval);
+        self
+    }
+
+    pub fn no_sync(mut self, no_sync: bool) -> KvStoreBuilder {
+        self.options.no_sync = no_sync;
+        self
+    }
+
+    /// Keeps every value in the blob file and only keys with references to
+    /// their values in the data file, so opening the store and compacting it
+    /// read little more than the keys. Short for `blob_threshold(0)`.
+    pub fn separate_values(self) -> KvStoreBuilder {
+        self.blob_threshold(0)
+    }
+
+    pub fn compaction_threshold(mut self, threshold: u64) -> KvStoreBuilder {
+        self.options.compaction_threshold = threshold;
+        self
+    }
+
+    pub fn compaction_ratio(mut self, ratio: f64) -> KvStoreBuilder {
+        self.options.compaction_ratio = Some(ratio);
+        self
+    }
+
+    pub fn compaction_idle(mut self, quiet: Duration) -> KvStoreBuilder {
+        self.options.compaction_idle = Some(quiet);
+        self
+    }
+
+    pub fn compaction_ceiling(mut self, ceiling: u64) -> KvStoreBuilder {
+        self.options.compaction_ceiling = Some(ceiling);
+        self
+    }
+
+    pub fn max_data_size(mut self, max_data_size: u64) -> KvStoreBuilder {
+        self.options.max_data_size = Some(max_data_size);
+        self
+    }
+
+    pub fn preallocate(mut self, len: u64) -> KvStoreBuilder {
+        self.options.preallocate = Some(len);
+        self
+    }
+
+    pub fn tombstone_grace(mut self, grace: Duration) -> KvStoreBuilder {
+        self.options.tombstone_grace = grace;
+        self
+    }
+
+    pub fn keep_versions(mut self, keep_versions: usize) -> KvStoreBuilder {
+        self.options.keep_versions = keep_versions;
+        self
+    }
+
+    pub fn cache_capacity(mut self, capacity: usize) -> KvStoreBuilder {
+        self.options.cache_capacity = capacity;
+        self
+    }
+
+    pub fn inline_threshold(mut self, threshold: usize) -> KvStoreBuilder {
+        self.options.inline_threshold = Some(threshold);
+        self
+    }
+
+    pub fn index_checkpoint(mut self, index_checkpoint: bool) -> KvStoreBuilder {
+        self.options.index_checkpoint = index_checkpoint;
+        self
+    }
+
+    pub fn on_compaction(mut self, callback: CompactionCallback) -> KvStoreBuilder {
+        self.options.on_compaction = Some(callback);
+        self
+    }
+
+    pub fn compaction_cancel(mut self, token: CancellationToken) -> KvStoreBuilder {
+        self.options.compaction_cancel = Some(token);
+        self
+    }
+
+    pub fn clock<C: Clock + 'static>(mut self, clock: C) -> KvStoreBuilder {
+        self.options.clock = Some(Arc::new(clock));
+        self
+    }
+
+    #[cfg(feature = "testing")]
+    pub fn faults(mut self, faults: FaultInjector) -> KvStoreBuilder {
+        self.options.faults = Some(faults);
+        self
+    }
+
+    /// The options built so far.
+    pub fn options(&self) -> &Options {
+        &self.options
+    }
+
+    /// Opens the store in the directory `path`.
+    pub fn build(self, path: &Path) -> Result<KvStore> {
+        KvStore::open_with_options(path, self.options)
+    }
+
+    /// Opens a store in a new temporary directory, see `KvStore::open_temp`.
+    pub fn build_temp(self) -> Result<KvStore> {
+        KvStore::open_temp_with_options(self.options)
+    }
+
+    /// Opens the data file at `file_path`, see `KvStore::open_file`.
+    pub fn build_file(self, file_path: &Path) -> Result<KvStore> {
+        KvStore::open_file_with_options(file_path, self.options)
+    }
+}
@@ -0,0 +1,63 @@
+use std::collections::{BTreeMap, HashMap};
+use std::sync::Arc;
+
+/// Least recently used cache of values by key. Values are shared, so a hit
+/// costs a reference count rather than a copy.
+pub struct LruCache {
+    capacity: usize,
+
+    /// Value and last use of each cached key.
+    entries: HashMap<String, (Arc<str>, u64)>,
+
+    /// Cached keys by last use, oldest first.
+    order: BTreeMap<u64, String>,
+
+    tick: u64,
+}
+
+impl LruCache {
+    pub fn new(capacity: usize) -> LruCache {
+        LruCache {
+            capacity,
+            entries: HashMap::new(),
+            order: BTreeMap::new(),
+            tick: 0,
+        }
+    }
+
+    pub fn get(&mut self, key: &str) -> Option<Arc<str>> {
+        self.tick += 1;
+        let (val, used) = self.entries.get_mut(key)?;
+        let key = self.order.remove(used).unwrap_or_else(|| key.to_owned());
+        *used = self.tick;
+        let val = val.clone();
+        self.order.insert(self.tick, key);
+        Some(val)
+    }
+
+    pub fn insert(&mut self, key: String, val: Arc<str>) {
+        if self.capacity == 0 {
+            return;
+        }
+        self.remove(&key);
+        if self.entries.len() >= self.capacity {
+            if let Some((_, oldest)) = self.order.pop_first() {
+                self.entries.remove(&oldest);
+            }
+        }
+        self.tick += 1;
+        self.order.insert(self.tick, key.clone());
+        self.entries.insert(key, (val, self.tick));
+    }
+
+    pub fn remove(&mut self, key: &str) {
+        if let Some((_, used)) = self.entries.remove(key) {
+            self.order.remove(&used);
+        }
+    }
+
+    pub fn clear(&mut self) {
+        self.entries.clear();
+        self.order.clear();
+    }
+}
@@ -0,0 +1,67 @@
+use std::io::{ErrorKind, Read};
+
+use super::dump;
+use super::error::Result;
+use super::storage::{self, Entry, EntryHeader, ENTRY_HEAD_LEN};
+
+/// Byte layout of a single entry written to or read from a stream.
+///
+/// The data file always uses `Manual`: loading, compaction and positional
+/// reads rely on its fixed-size header to find the key and value without
+/// decoding the whole entry.
+#[derive(Clone, Copy, Debug, Default, PartialEq, Eq)]
+pub enum EntryCodec {
+    /// The data file layout, a fixed-size header followed by the key and the
+    /// value.
+    #[default]
+    Manual,
+
+    /// The whole entry serialized with bincode, prefixed by its length as a
+    /// big-endian `u64`. A few bytes larger, with no layout code of its own.
+    Bincode,
+}
+
+impl EntryCodec {
+    pub fn encode(&self, e: &Entry) -> Result<Vec<u8>> {
+        match self {
+            EntryCodec::Manual => Ok(e.encode()),
+            EntryCodec::Bincode => {
+                let body = bincode::serialize(e)?;
+                let mut buf = Vec::with_capacity(8 + body.len());
+                buf.extend_from_slice(&(body.len() as u64).to_be_bytes());
+                buf.extend_from_slice(&body);
+                Ok(buf)
+            }
+        }
+    }
+
+    /// Reads the next entry, `None` on a clean end of input.
+    pub fn decode<R: Read>(&self, reader: &mut R) -> Result<Option<Entry>> {
+        match self {
+            EntryCodec::Manual => {
+                let mut buf = [0; ENTRY_HEAD_LEN];
+                match storage::read_full(reader, &mut buf)? {
+                    0 => return Ok(None),
+                    ENTRY_HEAD_LEN => {}
+                    _ => return Err(std::io::Error::from(ErrorKind::UnexpectedEof).into()),
+                }
+                let head = EntryHeader::decode(&buf)?;
+                let key = read_string(reader, head.key_len as u64)?;
+                let value = read_string(reader, head.value_len as u64)?;
+                Ok(Some(Entry::from_header(head, key, value)))
+            }
+            EntryCodec::Bincode => {
+                let len = match dump::read_u64(reader)? {
+                    Some(len) => len,
+                    None => return Ok(None),
+                };
+                let body = dump::read_bytes(reader, len)?;
+                Ok(Some(bincode::deserialize(&body)?))
+            }
+        }
+    }
+}
+
+fn read_string<R: Read>(reader: &mut R, len: u64) -> Result<String> {
+    Ok(String::from_utf8(dump::read_bytes(reader, len)?)?)
+}
@@ -0,0 +1,122 @@
+use std::fs::File;
+use std::io;
+use std::sync::atomic::{AtomicU64, Ordering};
+use std::sync::mpsc::{self, RecvTimeoutError, Sender};
+use std::sync::{Arc, Mutex};
+use std::thread::{self, JoinHandle};
+
+use log::error;
+
+use super::options::CommitInterval;
+
+/// Handles on the files of a store, synced by its `Committer`.
+#[derive(Default)]
+struct Files {
+    data: Option<File>,
+
+    blobs: Option<File>,
+}
+
+#[derive(Default)]
+struct Shared {
+    files: Mutex<Files>,
+
+    /// Bytes handed to the files since the last sync started. Kept outside
+    /// the lock so writes don't wait for a sync to report theirs.
+    unsynced: AtomicU64,
+}
+
+impl Shared {
+    /// Syncs the blobs first, so a synced entry never points past the
+    /// synced blobs.
+    fn sync(&self) -> io::Result<()> {
+        let files = self.files.lock().unwrap();
+        self.unsynced.store(0, Ordering::Relaxed);
+        if let Some(blobs) = &files.blobs {
+            blobs.sync_data()?;
+        }
+        if let Some(data) = &files.data {
+            data.sync_data()?;
+        }
+        Ok(())
+    }
+}
+
+/// Background thread syncing the files of a store every
+/// `CommitInterval::every`, see `Options::commit_interval`. The store hands
+/// every write to the OS itself and reports it through `wrote`.
+pub(crate) struct Committer {
+    shared: Arc<Shared>,
+
+    max_bytes: u64,
+
+    /// Combined length of the files as of the last `wrote`.
+    end: u64,
+
+    /// Dropped to stop the thread.
+    stop: Option<Sender<()>>,
+
+    thread: Option<JoinHandle<()>>,
+}
+
+impl Committer {
+    pub(crate) fn start(interval: CommitInterval) -> io::Result<Committer> {
+        let shared = Arc::new(Shared::default());
+        let (stop, stopped) = mpsc::channel::<()>();
+        let background = shared.clone();
+        let thread = thread::Builder::new()
+            .name("kvs-commit".to_owned())
+            .spawn(move || {
+                while let Err(RecvTimeoutError::Timeout) = stopped.recv_timeout(interval.every) {
+                    if background.unsynced.load(Ordering::Relaxed) == 0 {
+                        continue;
+                    }
+                    if let Err(e) = background.sync() {
+                        error!("failed to sync in the background: {}", e);
+                    }
+                }
+            })?;
+        Ok(Committer {
+            shared,
+            max_bytes: interval.max_bytes,
+            end: 0,
+            stop: Some(stop),
+            thread: Some(thread),
+        })
+    }
+
+    /// Replaces the handles the thread syncs, after the files were reopened.
+    pub(crate) fn set_files(&self, data: File, blobs: Option<File>) {
+        let mut files = self.shared.files.lock().unwrap();
+        files.data = Some(data);
+        files.blobs = blobs;
+    }
+
+    /// Reports that the files, `end` bytes long together, have been handed
+    /// to the OS, syncing right away once `max_bytes` are unsynced. Files
+    /// that shrank, e.g. by compaction, add nothing.
+    pub(crate) fn wrote(&mut self, end: u64) -> io::Result<()> {
+        let len = end.saturating_sub(self.end);
+        self.end = end;
+        let unsynced = self.shared.unsynced.fetch_add(len, Ordering::Relaxed) + len;
+        if unsynced >= self.max_bytes {
+            self.shared.sync()?;
+        }
+        Ok(())
+    }
+
+    pub(crate) fn sync(&self) -> io::Result<()> {
+        self.shared.sync()
+    }
+}
+
+impl Drop for Committer {
+    fn drop(&mut self) {
+        drop(self.stop.take());
+        if let Some(thread) = self.thread.take() {
+            if thread.join().is_err() {
+                error!("the background sync thread panicked");
+            }
+        }
+    }
+}
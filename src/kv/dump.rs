@@ -0,0 +1,68 @@
+use std::io::{ErrorKind, Read, Write};
+
+use super::error::Result;
+use super::storage;
+
+const LEN_SIZE: usize = std::mem::size_of::<u64>();
+
+/// Writes each pair as a big-endian `u64` key length, the key, a big-endian
+/// `u64` value length and the value.
+pub fn write_pairs<W: Write>(writer: &mut W, pairs: &[(String, String)]) -> Result<()> {
+    for (key, val) in pairs {
+        write_field(writer, key)?;
+        write_field(writer, val)?;
+    }
+    writer.flush()?;
+    Ok(())
+}
+
+/// Reads pairs written by `write_pairs` until the end of the input.
+pub fn read_pairs<R: Read>(reader: &mut R) -> Result<Vec<(String, String)>> {
+    let mut pairs = Vec::new();
+    while let Some(key) = read_field(reader)? {
+        let val =
+            read_field(reader)?.ok_or_else(|| std::io::Error::from(ErrorKind::UnexpectedEof))?;
+        pairs.push((key, val));
+    }
+    Ok(pairs)
+}
+
+pub fn write_field<W: Write>(writer: &mut W, field: &str) -> Result<()> {
+    writer.write_all(&(field.len() as u64).to_be_bytes())?;
+    writer.write_all(field.as_bytes())?;
+    Ok(())
+}
+
+/// Returns `None` on a clean end of input before the length prefix.
+pub fn read_field<R: Read>(reader: &mut R) -> Result<Option<String>> {
+    let len = match read_u64(reader)? {
+        Some(len) => len,
+        None => return Ok(None),
+    };
+    Ok(Some(String::from_utf8(read_bytes(reader, len)?)?))
+}
+
+/// Reads exactly `len` bytes.
+pub fn read_bytes<R: Read>(reader: &mut R, len: u64) -> Result<Vec<u8>> {
+    // read through `take` so a bogus length can't force a huge allocation up front
+    let mut buf = Vec::new();
+    reader.by_ref().take(len).read_to_end(&mut buf)?;
+    if (buf.len() as u64) < len {
+        return Err(std::io::Error::from(ErrorKind::UnexpectedEof).into());
+    }
+    Ok(buf)
+}
+
+/// Reads a big-endian `u64`, returning `None` on a clean end of input.
+pub fn read_u64<R: Read>(reader: &mut R) -> Result<Option<u64>> {
+    let mut buf = [0; LEN_SIZE];
+    let mut read = 0;
+    while read < LEN_SIZE {
+        match storage::retry_interrupted(|| reader.read(&mut buf[read..]))? {
+            0 if read == 0 => return Ok(None),
+            0 => return Err(std::io::Error::from(ErrorKind::UnexpectedEof).into()),
+            n => read += n,
+        }
+    }
+    Ok(Some(u64::from_be_bytes(buf)))
+}
@@ -1,4 +1,7 @@
+#![allow(non_local_definitions)]
+
 use std::io;
+use std::path::PathBuf;
 use std::string::FromUtf8Error;
 
 use failure::Fail;
@@ -19,14 +22,84 @@ pub enum KvsError {
     #[fail(display = "{}", _0)]
     StringDecode(#[cause] FromUtf8Error),
 
+    /// A typed value couldn't be converted to or from JSON.
+    #[fail(display = "{}", _0)]
+    Serde(#[cause] serde_json::Error),
+
     #[fail(display = "Key not found")]
     KeyNotFound,
 
-    #[fail(display = "Reach the file end")]
-    EOF,
-
     #[fail(display = "invalid data path")]
     InvalidDataPath,
+
+    /// Something other than a regular file, e.g. a directory, is where the
+    /// data file should be.
+    #[fail(display = "{:?} exists but is not a regular file", _0)]
+    NotAFile(PathBuf),
+
+    #[fail(display = "key size {} exceeds the limit of {} bytes", size, limit)]
+    KeyTooLarge { size: usize, limit: usize },
+
+    #[fail(display = "size {} exceeds the limit of {} bytes", size, limit)]
+    ValueTooLarge { size: usize, limit: usize },
+
+    #[fail(display = "data file would grow past its limit of {} bytes", limit)]
+    StorageFull { limit: u64 },
+
+    /// The data file was written in another format, `found` is 0 for a file
+    /// without a format header.
+    #[fail(
+        display = "data file format version {} is not supported, expected {}",
+        found, expected
+    )]
+    IncompatibleFormat { found: u32, expected: u32 },
+
+    #[fail(display = "corrupted entry at offset {}", _0)]
+    CorruptedEntry(u64),
+
+    #[fail(display = "no entry starts at offset {}", _0)]
+    InvalidOffset(u64),
+
+    #[fail(display = "operation not supported by this storage backend")]
+    Unsupported,
+
+    /// A conditional write found the key at another version, 0 meaning it
+    /// doesn't exist.
+    #[fail(display = "expected version {} of the key, found {}", expected, found)]
+    VersionMismatch { expected: u64, found: u64 },
+
+    /// A batch given a key more than once under `DuplicatePolicy::Error`.
+    #[fail(display = "key {} appears more than once in the batch", _0)]
+    DuplicateKey(String),
+
+    /// A compaction was stopped through `Options::compaction_cancel`.
+    #[fail(display = "compaction was cancelled")]
+    CompactionCancelled,
+
+    #[fail(display = "value is not an integer")]
+    NotAnInteger,
+
+    #[fail(display = "integer overflow")]
+    IntegerOverflow,
+
+    /// A stored key a `TypedKvStore` can't decode to its key type.
+    #[fail(display = "key {} is not a key of this typed store", _0)]
+    InvalidKey(String),
+}
+
+impl KvsError {
+    /// Whether the error comes from data on disk that can't be decoded, as
+    /// opposed to a failure of the underlying IO.
+    pub fn is_corruption(&self) -> bool {
+        match self {
+            KvsError::IO(e) => e.kind() == io::ErrorKind::UnexpectedEof,
+            KvsError::SliceDecode(_)
+            | KvsError::ReprDecode(_)
+            | KvsError::StringDecode(_)
+            | KvsError::CorruptedEntry(_) => true,
+            _ => false,
+        }
+    }
 }
 
 impl From<io::Error> for KvsError {
@@ -47,6 +120,12 @@ impl From<Box<bincode::ErrorKind>> for KvsError {
     }
 }
 
+impl From<serde_json::Error> for KvsError {
+    fn from(err: serde_json::Error) -> KvsError {
+        KvsError::Serde(err)
+    }
+}
+
 impl From<FromUtf8Error> for KvsError {
     fn from(err: FromUtf8Error) -> KvsError {
         KvsError::StringDecode(err)
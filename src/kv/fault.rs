@@ -0,0 +1,123 @@
+use std::borrow::Borrow;
+use std::fs::File;
+use std::io::{self, Read, Seek, SeekFrom, Write};
+use std::ops::Deref;
+use std::sync::{Arc, Mutex};
+
+/// File operation a `FaultInjector` can make fail.
+#[derive(Clone, Copy, Debug, PartialEq, Eq)]
+pub enum FaultKind {
+    Read,
+    Write,
+    Seek,
+}
+
+#[derive(Default)]
+struct Plan {
+    /// Calls made so far, indexed by `FaultKind`.
+    calls: [u64; 3],
+
+    /// Call numbers that fail.
+    armed: Vec<(FaultKind, u64)>,
+}
+
+/// Makes chosen reads, writes or seeks on the data file of a store fail, to
+/// exercise error paths. Set it as `Options::faults` and keep a clone to arm
+/// faults while the store is in use.
+///
+/// Only the data file is affected, and only calls that reach it: buffered
+/// writes get there on flush, and reads served by the `mmap` feature never do.
+#[derive(Clone, Default)]
+pub struct FaultInjector {
+    plan: Arc<Mutex<Plan>>,
+}
+
+impl FaultInjector {
+    pub fn new() -> FaultInjector {
+        FaultInjector::default()
+    }
+
+    /// Makes the `n`th call of `kind` from now on fail, counting from 1.
+    pub fn fail_nth(&self, kind: FaultKind, n: u64) {
+        let mut plan = self.plan.lock().unwrap();
+        let at = plan.calls[kind as usize] + n;
+        plan.armed.push((kind, at));
+    }
+
+    /// Calls of `kind` made so far.
+    pub fn calls(&self, kind: FaultKind) -> u64 {
+        self.plan.lock().unwrap().calls[kind as usize]
+    }
+
+    fn check(&self, kind: FaultKind) -> io::Result<()> {
+        let mut plan = self.plan.lock().unwrap();
+        plan.calls[kind as usize] += 1;
+        let call = (kind, plan.calls[kind as usize]);
+        match plan.armed.iter().position(|armed| *armed == call) {
+            Some(i) => {
+                plan.armed.swap_remove(i);
+                Err(io::Error::other(format!("injected {:?} fault", kind)))
+            }
+            None => Ok(()),
+        }
+    }
+}
+
+/// A data file whose operations go through a `FaultInjector`.
+pub(crate) struct FaultyFile {
+    file: File,
+
+    faults: Option<FaultInjector>,
+}
+
+impl FaultyFile {
+    pub(crate) fn new(file: File, faults: Option<FaultInjector>) -> FaultyFile {
+        FaultyFile { file, faults }
+    }
+
+    fn check(&self, kind: FaultKind) -> io::Result<()> {
+        match &self.faults {
+            Some(faults) => faults.check(kind),
+            None => Ok(()),
+        }
+    }
+}
+
+impl Deref for FaultyFile {
+    type Target = File;
+
+    fn deref(&self) -> &File {
+        &self.file
+    }
+}
+
+impl Borrow<File> for FaultyFile {
+    fn borrow(&self) -> &File {
+        &self.file
+    }
+}
+
+impl Read for FaultyFile {
+    fn read(&mut self, buf: &mut [u8]) -> io::Result<usize> {
+        self.check(FaultKind::Read)?;
+        self.file.read(buf)
+    }
+}
+
+impl Write for FaultyFile {
+    fn write(&mut self, buf: &[u8]) -> io::Result<usize> {
+        self.check(FaultKind::Write)?;
+        self.file.write(buf)
+    }
+
+    fn flush(&mut self) -> io::Result<()> {
+        self.file.flush()
+    }
+}
+
+impl Seek for FaultyFile {
+    fn seek(&mut self, pos: SeekFrom) -> io::Result<u64> {
+        self.check(FaultKind::Seek)?;
+        self.file.seek(pos)
+    }
+}
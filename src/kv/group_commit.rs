@@ -0,0 +1,148 @@
+use std::collections::HashMap;
+use std::io;
+use std::path::Path;
+use std::sync::{Condvar, Mutex};
+use std::time::{Duration, Instant};
+
+use super::error::{KvsError, Result};
+use super::kv_store::KvStore;
+
+/// Batching limits of a `GroupCommitKvStore`.
+#[derive(Clone, Copy, Debug)]
+pub struct GroupCommitOptions {
+    /// Most writes committed by a single sync.
+    pub max_batch: usize,
+
+    /// How long the writer doing the sync waits for others to join its batch.
+    pub max_wait: Duration,
+}
+
+impl Default for GroupCommitOptions {
+    fn default() -> GroupCommitOptions {
+        GroupCommitOptions {
+            max_batch: 128,
+            max_wait: Duration::from_millis(1),
+        }
+    }
+}
+
+enum Op {
+    Put(String, String),
+    Remove(String),
+}
+
+#[derive(Default)]
+struct Queue {
+    pending: Vec<(u64, Op)>,
+    next_ticket: u64,
+    committing: bool,
+    results: HashMap<u64, Result<()>>,
+}
+
+/// A store shared between threads whose writes only return once they are on
+/// stable storage.
+///
+/// Concurrent writers queue their writes and one of them applies the whole
+/// batch and syncs the data file once for all of them, instead of one sync
+/// per write.
+pub struct GroupCommitKvStore {
+    store: Mutex<KvStore>,
+    queue: Mutex<Queue>,
+    committed: Condvar,
+    options: GroupCommitOptions,
+}
+
+impl GroupCommitKvStore {
+    pub fn open(path: &Path, options: GroupCommitOptions) -> Result<GroupCommitKvStore> {
+        Ok(GroupCommitKvStore::new(KvStore::open(path)?, options))
+    }
+
+    pub fn new(store: KvStore, options: GroupCommitOptions) -> GroupCommitKvStore {
+        GroupCommitKvStore {
+            store: Mutex::new(store),
+            queue: Mutex::new(Queue::default()),
+            committed: Condvar::new(),
+            options,
+        }
+    }
+
+    pub fn get(&self, key: String) -> Result<Option<String>> {
+        self.store.lock().unwrap().get(key)
+    }
+
+    /// Sets `key` and waits until the write is synced.
+    pub fn set(&self, key: String, val: String) -> Result<()> {
+        self.submit(Op::Put(key, val))
+    }
+
+    /// Removes `key` and waits until the removal is synced.
+    pub fn remove(&self, key: String) -> Result<()> {
+        self.submit(Op::Remove(key))
+    }
+
+    fn submit(&self, op: Op) -> Result<()> {
+        let mut queue = self.queue.lock().unwrap();
+        let ticket = queue.next_ticket;
+        queue.next_ticket += 1;
+        queue.pending.push((ticket, op));
+        self.committed.notify_all();
+
+        loop {
+            if let Some(result) = queue.results.remove(&ticket) {
+                return result;
+            }
+            if queue.committing {
+                queue = self.committed.wait(queue).unwrap();
+                continue;
+            }
+
+            // nobody is committing, so this writer gathers and commits a batch
+            queue.committing = true;
+            let deadline = Instant::now() + self.options.max_wait;
+            while queue.pending.len() < self.options.max_batch {
+                let now = Instant::now();
+                if now >= deadline {
+                    break;
+                }
+                queue = self
+                    .committed
+                    .wait_timeout(queue, deadline - now)
+                    .unwrap()
+                    .0;
+            }
+            let len = queue.pending.len().min(self.options.max_batch);
+            let batch: Vec<(u64, Op)> = queue.pending.drain(..len).collect();
+            drop(queue);
+
+            let results = self.commit(batch);
+
+            queue = self.queue.lock().unwrap();
+            queue.results.extend(results);
+            queue.committing = false;
+            self.committed.notify_all();
+        }
+    }
+
+    /// Applies a batch with a single sync at the end.
+    fn commit(&self, batch: Vec<(u64, Op)>) -> Vec<(u64, Result<()>)> {
+        let mut store = self.store.lock().unwrap();
+        let mut results: Vec<(u64, Result<()>)> = batch
+            .into_iter()
+            .map(|(ticket, op)| {
+                let result = match op {
+                    Op::Put(key, val) => store.set(key, val),
+                    Op::Remove(key) => store.remove(key),
+                };
+                (ticket, result)
+            })
+            .collect();
+
+        if let Err(e) = store.sync() {
+            // every write of the batch shares the failed sync
+            for (_, result) in results.iter_mut().filter(|(_, result)| result.is_ok()) {
+                *result = Err(KvsError::IO(io::Error::other(e.to_string())));
+            }
+        }
+        results
+    }
+}
@@ -0,0 +1,451 @@
+use std::collections::hash_map::{DefaultHasher, Entry, RandomState};
+use std::collections::{BTreeMap, HashMap};
+use std::fmt;
+use std::hash::{BuildHasher, Hasher};
+use std::ops::Bound;
+use std::sync::Arc;
+
+use super::error::Result;
+use super::options::IndexKind;
+
+/// Reads back the key of the entry at an offset, for the hashed index,
+/// which doesn't keep keys in memory. The other kinds never call it.
+pub type KeyAt<'a> = &'a dyn Fn(u64) -> Result<String>;
+
+/// Hash function of the hash indexes, e.g. a faster one than the default
+/// SipHash or one seeded differently, see `Options::index_hasher`.
+#[derive(Clone)]
+pub struct IndexHasher(Arc<dyn Fn() -> Box<dyn Hasher> + Send + Sync>);
+
+impl IndexHasher {
+    pub fn new<B>(build: B) -> IndexHasher
+    where
+        B: BuildHasher + Send + Sync + 'static,
+        B::Hasher: 'static,
+    {
+        IndexHasher(Arc::new(move || Box::new(build.build_hasher())))
+    }
+}
+
+impl fmt::Debug for IndexHasher {
+    fn fmt(&self, f: &mut fmt::Formatter<'_>) -> fmt::Result {
+        f.write_str("IndexHasher")
+    }
+}
+
+/// Hashes keys for the index, with randomly seeded SipHash unless given an
+/// `IndexHasher`, which goes through a boxed hasher per key.
+#[derive(Clone)]
+pub enum KeyHasher {
+    Default(RandomState),
+    Custom(IndexHasher),
+}
+
+impl KeyHasher {
+    fn new(hasher: Option<&IndexHasher>) -> KeyHasher {
+        match hasher {
+            Some(hasher) => KeyHasher::Custom(hasher.clone()),
+            None => KeyHasher::Default(RandomState::new()),
+        }
+    }
+}
+
+impl BuildHasher for KeyHasher {
+    type Hasher = KeyHasherState;
+
+    fn build_hasher(&self) -> KeyHasherState {
+        match self {
+            KeyHasher::Default(state) => KeyHasherState::Default(state.build_hasher()),
+            KeyHasher::Custom(hasher) => KeyHasherState::Custom((hasher.0)()),
+        }
+    }
+}
+
+pub enum KeyHasherState {
+    Default(DefaultHasher),
+    Custom(Box<dyn Hasher>),
+}
+
+impl Hasher for KeyHasherState {
+    fn finish(&self) -> u64 {
+        match self {
+            KeyHasherState::Default(h) => h.finish(),
+            KeyHasherState::Custom(h) => h.finish(),
+        }
+    }
+
+    fn write(&mut self, bytes: &[u8]) {
+        match self {
+            KeyHasherState::Default(h) => h.write(bytes),
+            KeyHasherState::Custom(h) => h.write(bytes),
+        }
+    }
+}
+
+/// In-memory index mapping keys to their entry offset in the data file.
+pub enum Index {
+    Hash(HashMap<String, u64, KeyHasher>),
+    Ordered(BTreeMap<String, u64>),
+    Hashed(HashedIndex),
+}
+
+impl Index {
+    pub fn new(kind: IndexKind, hasher: Option<&IndexHasher>) -> Index {
+        let hasher = KeyHasher::new(hasher);
+        match kind {
+            IndexKind::Hash => Index::Hash(HashMap::with_hasher(hasher)),
+            IndexKind::Ordered => Index::Ordered(BTreeMap::new()),
+            IndexKind::Hashed => Index::Hashed(HashedIndex::new(hasher)),
+        }
+    }
+
+    pub fn get(&self, key: &str, keys: KeyAt) -> Result<Option<u64>> {
+        match self {
+            Index::Hash(m) => Ok(m.get(key).copied()),
+            Index::Ordered(m) => Ok(m.get(key).copied()),
+            Index::Hashed(h) => h.find(key, keys),
+        }
+    }
+
+    /// Whether `key` maps to `offset`, which must hold an entry of `key`.
+    /// Never reads keys back.
+    pub fn points_at(&self, key: &str, offset: u64) -> bool {
+        match self {
+            Index::Hash(m) => m.get(key) == Some(&offset),
+            Index::Ordered(m) => m.get(key) == Some(&offset),
+            Index::Hashed(h) => h.candidates(h.key_hash(key)).any(|found| found == offset),
+        }
+    }
+
+    pub fn insert(&mut self, key: String, offset: u64, keys: KeyAt) -> Result<Option<u64>> {
+        match self {
+            Index::Hash(m) => Ok(m.insert(key, offset)),
+            Index::Ordered(m) => Ok(m.insert(key, offset)),
+            Index::Hashed(h) => {
+                let old = h.find(&key, keys)?;
+                match old {
+                    Some(old) => {
+                        h.replace(h.key_hash(&key), old, offset);
+                    }
+                    None => h.push(h.key_hash(&key), offset),
+                }
+                Ok(old)
+            }
+        }
+    }
+
+    pub fn remove(&mut self, key: &str, keys: KeyAt) -> Result<Option<u64>> {
+        match self {
+            Index::Hash(m) => Ok(m.remove(key)),
+            Index::Ordered(m) => Ok(m.remove(key)),
+            Index::Hashed(h) => {
+                let old = h.find(key, keys)?;
+                if let Some(old) = old {
+                    h.remove(h.key_hash(key), old);
+                }
+                Ok(old)
+            }
+        }
+    }
+
+    /// Drops `key` if it maps to `offset`, returning whether it did. Never
+    /// reads keys back.
+    pub fn remove_at(&mut self, key: &str, offset: u64) -> bool {
+        match self {
+            Index::Hash(m) if m.get(key) == Some(&offset) => m.remove(key).is_some(),
+            Index::Ordered(m) if m.get(key) == Some(&offset) => m.remove(key).is_some(),
+            Index::Hashed(h) => h.remove(h.key_hash(key), offset),
+            _ => false,
+        }
+    }
+
+    /// Points `key` at `new` if it maps to `old`, returning whether it did.
+    /// Never reads keys back.
+    pub fn relocate(&mut self, key: &str, old: u64, new: u64) -> bool {
+        let offset = match self {
+            Index::Hash(m) => m.get_mut(key),
+            Index::Ordered(m) => m.get_mut(key),
+            Index::Hashed(h) => return h.replace(h.key_hash(key), old, new),
+        };
+        match offset {
+            Some(offset) if *offset == old => {
+                *offset = new;
+                true
+            }
+            _ => false,
+        }
+    }
+
+    /// Moves every offset to `f(offset)`.
+    pub fn remap(&mut self, f: impl Fn(u64) -> u64) {
+        match self {
+            Index::Hash(m) => m.values_mut().for_each(|offset| *offset = f(*offset)),
+            Index::Ordered(m) => m.values_mut().for_each(|offset| *offset = f(*offset)),
+            Index::Hashed(h) => h.offsets_mut().for_each(|offset| *offset = f(*offset)),
+        }
+    }
+
+    pub fn contains_key(&self, key: &str, keys: KeyAt) -> Result<bool> {
+        Ok(self.get(key, keys)?.is_some())
+    }
+
+    pub fn len(&self) -> usize {
+        match self {
+            Index::Hash(m) => m.len(),
+            Index::Ordered(m) => m.len(),
+            Index::Hashed(h) => h.len(),
+        }
+    }
+
+    pub fn clear(&mut self) {
+        match self {
+            Index::Hash(m) => m.clear(),
+            Index::Ordered(m) => m.clear(),
+            Index::Hashed(h) => *h = HashedIndex::new(h.hasher.clone()),
+        }
+    }
+
+    pub fn is_empty(&self) -> bool {
+        self.len() == 0
+    }
+
+    /// Entries the index has room for without allocating. A `BTreeMap`
+    /// allocates per node, so the ordered index reports its length.
+    pub fn capacity(&self) -> usize {
+        match self {
+            Index::Hash(m) => m.capacity(),
+            Index::Ordered(m) => m.len(),
+            Index::Hashed(h) => h.first.capacity() + h.colliding.capacity(),
+        }
+    }
+
+    /// Gives back memory held for removed keys, which the hash maps keep
+    /// until told otherwise.
+    pub fn shrink_to_fit(&mut self) {
+        match self {
+            Index::Hash(m) => m.shrink_to_fit(),
+            Index::Ordered(_) => {}
+            Index::Hashed(h) => {
+                h.first.shrink_to_fit();
+                h.colliding.shrink_to_fit();
+            }
+        }
+    }
+
+    /// Every offset, in no particular order. Never reads keys back.
+    pub fn offsets(&self) -> Vec<u64> {
+        match self {
+            Index::Hash(m) => m.values().copied().collect(),
+            Index::Ordered(m) => m.values().copied().collect(),
+            Index::Hashed(h) => h.offsets().collect(),
+        }
+    }
+
+    /// Smallest key, the hash indexes have to look at every key to find it.
+    pub fn first_key(&self, keys: KeyAt) -> Result<Option<String>> {
+        match self {
+            Index::Hash(m) => Ok(m.keys().min().cloned()),
+            Index::Ordered(m) => Ok(m.first_key_value().map(|(k, _)| k.clone())),
+            Index::Hashed(h) => Ok(h.keys(keys)?.into_iter().map(|(k, _)| k).min()),
+        }
+    }
+
+    /// Largest key, the hash indexes have to look at every key to find it.
+    pub fn last_key(&self, keys: KeyAt) -> Result<Option<String>> {
+        match self {
+            Index::Hash(m) => Ok(m.keys().max().cloned()),
+            Index::Ordered(m) => Ok(m.last_key_value().map(|(k, _)| k.clone())),
+            Index::Hashed(h) => Ok(h.keys(keys)?.into_iter().map(|(k, _)| k).max()),
+        }
+    }
+
+    /// Every key and offset, sorted by key.
+    pub fn entries(&self, keys: KeyAt) -> Result<Vec<(String, u64)>> {
+        let mut found: Vec<(String, u64)> = match self {
+            Index::Hash(m) => m.iter().map(|(k, v)| (k.clone(), *v)).collect(),
+            Index::Ordered(m) => return Ok(m.iter().map(|(k, v)| (k.clone(), *v)).collect()),
+            Index::Hashed(h) => h.keys(keys)?,
+        };
+        found.sort_unstable_by(|a, b| a.0.cmp(&b.0));
+        Ok(found)
+    }
+
+    /// Up to `limit` keys and offsets following `cursor`, or from the start
+    /// without one, sorted by key.
+    ///
+    /// The hash indexes have no order, so they filter and sort every key.
+    pub fn page(
+        &self,
+        cursor: Option<&str>,
+        limit: usize,
+        keys: KeyAt,
+    ) -> Result<Vec<(String, u64)>> {
+        let lower = match cursor {
+            Some(cursor) => Bound::Excluded(cursor),
+            None => Bound::Unbounded,
+        };
+        let mut found: Vec<(String, u64)> = match self {
+            Index::Hash(m) => m
+                .iter()
+                .filter(|(k, _)| cursor.is_none_or(|cursor| k.as_str() > cursor))
+                .map(|(k, v)| (k.clone(), *v))
+                .collect(),
+            Index::Ordered(m) => {
+                return Ok(m
+                    .range::<str, _>((lower, Bound::Unbounded))
+                    .take(limit)
+                    .map(|(k, v)| (k.clone(), *v))
+                    .collect())
+            }
+            Index::Hashed(h) => h
+                .keys(keys)?
+                .into_iter()
+                .filter(|(k, _)| cursor.is_none_or(|cursor| k.as_str() > cursor))
+                .collect(),
+        };
+        found.sort_unstable_by(|a, b| a.0.cmp(&b.0));
+        found.truncate(limit);
+        Ok(found)
+    }
+
+    /// Keys and offsets in `[start, end)`, sorted by key.
+    ///
+    /// The hash indexes have no order, so they filter and sort every key.
+    pub fn range(&self, start: &str, end: &str, keys: KeyAt) -> Result<Vec<(String, u64)>> {
+        let mut found: Vec<(String, u64)> = match self {
+            Index::Hash(m) => m
+                .iter()
+                .filter(|(k, _)| k.as_str() >= start && k.as_str() < end)
+                .map(|(k, v)| (k.clone(), *v))
+                .collect(),
+            Index::Ordered(m) => {
+                if start >= end {
+                    return Ok(Vec::new());
+                }
+                return Ok(m
+                    .range::<str, _>((Bound::Included(start), Bound::Excluded(end)))
+                    .map(|(k, v)| (k.clone(), *v))
+                    .collect());
+            }
+            Index::Hashed(h) => h
+                .keys(keys)?
+                .into_iter()
+                .filter(|(k, _)| k.as_str() >= start && k.as_str() < end)
+                .collect(),
+        };
+        found.sort_unstable_by(|a, b| a.0.cmp(&b.0));
+        Ok(found)
+    }
+}
+
+/// Offsets grouped by a 64-bit hash of their key, see `IndexKind::Hashed`.
+pub struct HashedIndex {
+    hasher: KeyHasher,
+
+    /// Offset of one live key per hash.
+    first: HashMap<u64, u64>,
+
+    /// Offsets of the other live keys with a hash already in `first`, which
+    /// stays empty unless two keys collide.
+    colliding: HashMap<u64, Vec<u64>>,
+}
+
+impl HashedIndex {
+    fn new(hasher: KeyHasher) -> HashedIndex {
+        HashedIndex {
+            hasher,
+            first: HashMap::new(),
+            colliding: HashMap::new(),
+        }
+    }
+
+    fn key_hash(&self, key: &str) -> u64 {
+        // the hashes only have to agree within one process
+        self.hasher.hash_one(key)
+    }
+
+    fn candidates(&self, hash: u64) -> impl Iterator<Item = u64> + '_ {
+        let colliding = self.colliding.get(&hash).into_iter().flatten();
+        self.first.get(&hash).into_iter().chain(colliding).copied()
+    }
+
+    /// Offset of `key`, telling it apart from keys with the same hash by
+    /// reading them back.
+    fn find(&self, key: &str, keys: KeyAt) -> Result<Option<u64>> {
+        for offset in self.candidates(self.key_hash(key)) {
+            if keys(offset)? == key {
+                return Ok(Some(offset));
+            }
+        }
+        Ok(None)
+    }
+
+    fn push(&mut self, hash: u64, offset: u64) {
+        match self.first.entry(hash) {
+            Entry::Vacant(first) => {
+                first.insert(offset);
+            }
+            Entry::Occupied(_) => self.colliding.entry(hash).or_default().push(offset),
+        }
+    }
+
+    fn replace(&mut self, hash: u64, old: u64, new: u64) -> bool {
+        let colliding = self.colliding.get_mut(&hash).into_iter().flatten();
+        match self
+            .first
+            .get_mut(&hash)
+            .into_iter()
+            .chain(colliding)
+            .find(|o| **o == old)
+        {
+            Some(offset) => {
+                *offset = new;
+                true
+            }
+            None => false,
+        }
+    }
+
+    fn remove(&mut self, hash: u64, offset: u64) -> bool {
+        if self.first.get(&hash) == Some(&offset) {
+            match self.colliding.get_mut(&hash).and_then(Vec::pop) {
+                Some(next) => self.first.insert(hash, next),
+                None => self.first.remove(&hash),
+            };
+        } else {
+            let colliding = match self.colliding.get_mut(&hash) {
+                Some(colliding) => colliding,
+                None => return false,
+            };
+            match colliding.iter().position(|o| *o == offset) {
+                Some(i) => colliding.swap_remove(i),
+                None => return false,
+            };
+        }
+        if self.colliding.get(&hash).is_some_and(Vec::is_empty) {
+            self.colliding.remove(&hash);
+        }
+        true
+    }
+
+    fn offsets(&self) -> impl Iterator<Item = u64> + '_ {
+        let colliding = self.colliding.values().flatten();
+        self.first.values().chain(colliding).copied()
+    }
+
+    fn offsets_mut(&mut self) -> impl Iterator<Item = &mut u64> {
+        let colliding = self.colliding.values_mut().flatten();
+        self.first.values_mut().chain(colliding)
+    }
+
+    /// Every key, read back, with its offset.
+    fn keys(&self, keys: KeyAt) -> Result<Vec<(String, u64)>> {
+        self.offsets()
+            .map(|offset| Ok((keys(offset)?, offset)))
+            .collect()
+    }
+
+    fn len(&self) -> usize {
+        self.first.len() + self.colliding.values().map(Vec::len).sum::<usize>()
+    }
+}
@@ -1,29 +1,929 @@
+use std::collections::HashMap;
+use std::fs::File;
+use std::io::{Read, Seek, Write};
 use std::path::Path;
+use std::sync::mpsc::{self, Receiver, SyncSender, TrySendError};
+use std::sync::Arc;
+#[cfg(feature = "latency")]
+use std::time::Instant;
 
-use super::error::Result;
-use super::storage::{SimplifiedBitcask, Storage};
+use serde::de::DeserializeOwned;
+use serde::Serialize;
+use tempfile::TempDir;
 
-pub struct KvStore {
-    storage: Box<dyn Storage>,
+use super::builder::KvStoreBuilder;
+use super::dump;
+use super::error::{KvsError, Result};
+#[cfg(feature = "latency")]
+use super::latency::{LatencyReport, LatencyTracker};
+use super::memory::MemoryStorage;
+use super::metrics::MetricsSnapshot;
+use super::namespace::Namespace;
+use super::options::Options;
+use super::reader::ReaderStorage;
+use super::replication;
+use super::storage::{
+    CmdKind, CompactionEstimate, CompactionProgress, KeyState, Page, ReadSource, ScanIter,
+    SimplifiedBitcask, Storage, StoreStats, VerifyReport, WriteOp,
+};
+
+/// Events a subscriber can fall behind by before new ones are dropped.
+const SUBSCRIPTION_CAPACITY: usize = 1024;
+
+/// A write reported to subscribers.
+#[derive(Clone, Debug, PartialEq, Eq)]
+pub struct ChangeEvent {
+    pub key: String,
+
+    pub kind: CmdKind,
+
+    /// The new value, empty for `CmdKind::DEL`.
+    pub value: String,
+}
+
+/// What `put_batch` does with a key given more than once.
+#[derive(Clone, Copy, Debug, PartialEq, Eq, Default)]
+pub enum DuplicatePolicy {
+    /// The last value given for the key is stored, the others are skipped.
+    #[default]
+    LastWins,
+
+    /// The batch fails with `KvsError::DuplicateKey` before anything is set.
+    Error,
+}
+
+/// A store on top of a `Storage` backend.
+///
+/// By default the backend is a `dyn Storage` chosen when opening, which
+/// costs a dynamic call per operation. A store built with `from_storage`
+/// names its backend type instead, e.g. `BitcaskStore`, so those calls can
+/// be inlined.
+pub struct KvStore<S: Storage + ?Sized = dyn Storage> {
+    storage: Box<S>,
+
+    subscribers: Vec<SyncSender<ChangeEvent>>,
+
+    #[cfg(feature = "latency")]
+    latency: LatencyTracker,
+
+    /// Directory of a store from `open_temp`, removed once the store is
+    /// dropped. Declared last so the files are closed before.
+    temp_dir: Option<TempDir>,
 }
 
 impl KvStore {
     pub fn open(path: &Path) -> Result<KvStore> {
-        let storage = SimplifiedBitcask::open(path.to_path_buf())?;
-        Ok(KvStore {
+        KvStore::open_with_options(path, Options::default())
+    }
+
+    /// Starts configuring a store to open, as an alternative to filling in
+    /// `Options` for `open_with_options`.
+    pub fn builder() -> KvStoreBuilder {
+        KvStoreBuilder::new()
+    }
+
+    /// Opens the store called `name` in `path`, kept in `<name>.data`.
+    pub fn open_named(path: &Path, name: &str) -> Result<KvStore> {
+        let options = Options {
+            name: name.to_owned(),
+            ..Options::default()
+        };
+        KvStore::open_with_options(path, options)
+    }
+
+    pub fn open_with_options(path: &Path, options: Options) -> Result<KvStore> {
+        let storage = SimplifiedBitcask::open(path.to_path_buf(), options)?;
+        Ok(KvStore::with_storage(Box::new(storage)))
+    }
+
+    /// Opens the data file at `file_path` directly, e.g. a backup that isn't
+    /// named `<name>.data`. Files the store needs besides it, such as the one
+    /// used by compaction, are kept in the same directory and named after its
+    /// file stem.
+    pub fn open_file(file_path: &Path) -> Result<KvStore> {
+        KvStore::open_file_with_options(file_path, Options::default())
+    }
+
+    /// Like `open_file`. `options.name` is ignored in favour of the file stem.
+    pub fn open_file_with_options(file_path: &Path, options: Options) -> Result<KvStore> {
+        let storage = SimplifiedBitcask::open_file(file_path.to_path_buf(), options)?;
+        Ok(KvStore::with_storage(Box::new(storage)))
+    }
+
+    /// Opens the data file at `file_path` through `reader` and `writer`,
+    /// handles to it opened by the caller, see `SimplifiedBitcask::from_files`.
+    pub fn open_files(file_path: &Path, reader: File, writer: File) -> Result<KvStore> {
+        KvStore::open_files_with_options(file_path, reader, writer, Options::default())
+    }
+
+    /// Like `open_files`. `options.name` is ignored in favour of the file stem.
+    pub fn open_files_with_options(
+        file_path: &Path,
+        reader: File,
+        writer: File,
+        options: Options,
+    ) -> Result<KvStore> {
+        let storage =
+            SimplifiedBitcask::from_files(file_path.to_path_buf(), reader, writer, options)?;
+        Ok(KvStore::with_storage(Box::new(storage)))
+    }
+
+    /// Opens a read-only store over the contents of a data file read through
+    /// `reader`, e.g. a decompressing wrapper or a `Cursor`, see
+    /// `ReaderStorage`. Every entry is read once to build the index.
+    pub fn open_reader<R: Read + Seek + Send + 'static>(reader: R) -> Result<KvStore> {
+        KvStore::open_reader_with_options(reader, Options::default())
+    }
+
+    /// Like `open_reader`, the size limits and read buffer capacity of
+    /// `options` apply.
+    pub fn open_reader_with_options<R: Read + Seek + Send + 'static>(
+        reader: R,
+        options: Options,
+    ) -> Result<KvStore> {
+        let storage = ReaderStorage::new(reader, options)?;
+        Ok(KvStore::with_storage(Box::new(storage)))
+    }
+
+    /// Opens a store in a new temporary directory, which is removed with
+    /// everything in it when the store is dropped.
+    pub fn open_temp() -> Result<KvStore> {
+        KvStore::open_temp_with_options(Options::default())
+    }
+
+    pub fn open_temp_with_options(options: Options) -> Result<KvStore> {
+        let temp_dir = TempDir::new()?;
+        let mut store = KvStore::open_with_options(temp_dir.path(), options)?;
+        store.temp_dir = Some(temp_dir);
+        Ok(store)
+    }
+
+    /// Opens a store that lives only in memory and is lost when dropped.
+    pub fn in_memory() -> KvStore {
+        KvStore::with_storage(Box::new(MemoryStorage::new()))
+    }
+
+    /// Builds a store on top of any `Storage` backend.
+    pub fn with_storage(storage: Box<dyn Storage>) -> KvStore {
+        KvStore {
+            storage,
+            subscribers: Vec::new(),
+            #[cfg(feature = "latency")]
+            latency: LatencyTracker::default(),
+            temp_dir: None,
+        }
+    }
+
+    /// Drops whatever follows the last readable entry of the store in `path`,
+    /// e.g. an entry cut short by a crash, so it can be opened again.
+    /// Returns the number of bytes dropped.
+    pub fn repair(path: &Path) -> Result<u64> {
+        KvStore::repair_with_options(path, Options::default())
+    }
+
+    pub fn repair_with_options(path: &Path, options: Options) -> Result<u64> {
+        SimplifiedBitcask::repair(path.to_path_buf(), options)
+    }
+
+    /// Reads through the data file of the store in `path`, reporting how much
+    /// of it is intact. Unlike `repair` and `open`, never writes to it.
+    pub fn verify(path: &Path) -> Result<VerifyReport> {
+        KvStore::verify_with_options(path, Options::default())
+    }
+
+    pub fn verify_with_options(path: &Path, options: Options) -> Result<VerifyReport> {
+        SimplifiedBitcask::verify(path.to_path_buf(), options)
+    }
+
+    /// Rewrites the store in `path` if it was written in an older format,
+    /// leaving it in place if anything goes wrong. Returns
+    /// whether the store needed migrating.
+    pub fn migrate(path: &Path) -> Result<bool> {
+        KvStore::migrate_with_options(path, Options::default())
+    }
+
+    pub fn migrate_with_options(path: &Path, options: Options) -> Result<bool> {
+        SimplifiedBitcask::migrate(path.to_path_buf(), options)
+    }
+}
+
+/// A store whose backend is known to be a `SimplifiedBitcask`, see
+/// `KvStore::from_storage`.
+pub type BitcaskStore = KvStore<SimplifiedBitcask>;
+
+impl<S: Storage> KvStore<S> {
+    /// Builds a store on top of `storage`, keeping its type so calls to it
+    /// are dispatched statically.
+    pub fn from_storage(storage: S) -> KvStore<S> {
+        KvStore {
             storage: Box::new(storage),
-        })
+            subscribers: Vec::new(),
+            #[cfg(feature = "latency")]
+            latency: LatencyTracker::default(),
+            temp_dir: None,
+        }
     }
+}
 
+impl<S: Storage + ?Sized> KvStore<S> {
     pub fn get(&mut self, key: String) -> Result<Option<String>> {
-        self.storage.get(key)
+        #[cfg(feature = "latency")]
+        let start = Instant::now();
+        let val = self.storage.get(key);
+        #[cfg(feature = "latency")]
+        self.latency.record_get(start.elapsed());
+        val
+    }
+
+    /// Like `get`, also returning when the value was written, in milliseconds
+    /// since the Unix epoch. Values migrated from a data file without
+    /// timestamps report 0.
+    pub fn get_with_meta(&mut self, key: String) -> Result<Option<(String, u64)>> {
+        self.storage.get_with_meta(key)
+    }
+
+    /// Like `get`, also returning the version of the key: 1 after its first
+    /// write, going up by one with every put or remove. Versions carry on
+    /// after a remove while its tombstone is in the log, so once compaction
+    /// has dropped it, a rewritten key starts again from 1.
+    pub fn get_versioned(&mut self, key: String) -> Result<Option<(String, u64)>> {
+        self.storage.get_versioned(key)
+    }
+
+    /// Sets `key` to `val` only if it's at version `expected`, see
+    /// `get_versioned`, with 0 meaning the key must not exist. Returns the new
+    /// version, or fails with `KvsError::VersionMismatch` without writing.
+    pub fn put_if_version(&mut self, key: String, val: String, expected: u64) -> Result<u64> {
+        let found = self.storage.key_version(&key)?;
+        if found != expected {
+            return Err(KvsError::VersionMismatch { expected, found });
+        }
+        self.put(key.clone(), val)?;
+        self.storage.key_version(&key)
+    }
+
+    /// Copies the value of `key` into `buf`, replacing its contents, and
+    /// returns whether the key exists. `buf` is left alone if it doesn't.
+    /// Reusing `buf` across calls avoids allocating for every read the way
+    /// `get` does.
+    pub fn get_into(&mut self, key: &str, buf: &mut Vec<u8>) -> Result<bool> {
+        self.storage.get_into(key, buf)
+    }
+
+    /// Like `get`, replacing whatever in the value isn't valid UTF-8 with
+    /// U+FFFD rather than failing with `KvsError::StringDecode`, so a value
+    /// damaged on disk can still be read. Other corruption is still an error.
+    pub fn get_lossy(&mut self, key: &str) -> Result<Option<String>> {
+        let mut buf = Vec::new();
+        match self.storage.get_into(key, &mut buf)? {
+            true => Ok(Some(String::from_utf8_lossy(&buf).into_owned())),
+            false => Ok(None),
+        }
+    }
+
+    /// Like `get`, returning a value held by the read cache without copying
+    /// it, which saves the copy for large values read again and again. A
+    /// value that isn't cached, or a store without a cache, costs one copy
+    /// like `get`.
+    pub fn get_shared(&mut self, key: &str) -> Result<Option<Arc<str>>> {
+        #[cfg(feature = "latency")]
+        let start = Instant::now();
+        let val = self.storage.get_shared(key);
+        #[cfg(feature = "latency")]
+        self.latency.record_get(start.elapsed());
+        val
+    }
+
+    /// Looks up several keys at once. The values come back in the order of
+    /// `keys`, whatever order they were read from disk in.
+    pub fn get_many(&mut self, keys: &[String]) -> Result<Vec<Option<String>>> {
+        self.storage.get_many(keys)
+    }
+
+    /// Like `get`, also telling whether the value was read from storage or
+    /// the read cache, or the key wasn't found, e.g. to measure cache
+    /// effectiveness per call.
+    pub fn get_with_source(&mut self, key: String) -> Result<(Option<String>, ReadSource)> {
+        self.storage.get_with_source(key)
+    }
+
+    /// Like `get`, but tells a removed key apart from one that was never written.
+    ///
+    /// Tombstones are dropped by compaction, after which a removed key is
+    /// reported as `KeyState::Absent`.
+    pub fn get_state(&mut self, key: String) -> Result<KeyState> {
+        self.storage.get_state(key)
     }
 
     pub fn set(&mut self, key: String, val: String) -> Result<()> {
-        self.storage.put(key, val)
+        self.put(key, val)
+    }
+
+    /// Stores the next `len` bytes of `reader` as the value of `key`, copying
+    /// them a piece at a time instead of holding the whole value in memory.
+    /// The bytes must be valid UTF-8. Nothing is stored if they aren't or if
+    /// `reader` runs out before `len` bytes.
+    ///
+    /// Subscribers get the value in their `ChangeEvent`, so with any around it
+    /// is read back in full.
+    pub fn put_reader(&mut self, key: String, reader: &mut dyn Read, len: usize) -> Result<()> {
+        self.storage.put_reader(key.clone(), reader, len)?;
+        if !self.subscribers.is_empty() {
+            let val = self.storage.get(key.clone())?.unwrap_or_default();
+            self.notify_put(key, val);
+        }
+        Ok(())
+    }
+
+    /// Writes the value of `key` to `writer` a piece at a time, the streaming
+    /// counterpart of `get`. Returns whether the key exists.
+    pub fn get_writer(&mut self, key: &str, writer: &mut dyn Write) -> Result<bool> {
+        self.storage.get_writer(key, writer)
+    }
+
+    /// Stores `val` at `key` as JSON.
+    pub fn put_typed<T: Serialize + ?Sized>(&mut self, key: String, val: &T) -> Result<()> {
+        let val = serde_json::to_string(val)?;
+        self.put(key, val)
+    }
+
+    /// Reads back a value stored with `put_typed`. Fails with
+    /// `KvsError::Serde` if the value isn't JSON for a `T`.
+    pub fn get_typed<T: DeserializeOwned>(&mut self, key: String) -> Result<Option<T>> {
+        match self.storage.get(key)? {
+            Some(val) => Ok(Some(serde_json::from_str(&val)?)),
+            None => Ok(None),
+        }
+    }
+
+    /// Like `set`, returning the offset of the new entry in the data file for
+    /// later use with `get_at`.
+    pub fn put_and_locate(&mut self, key: String, val: String) -> Result<u64> {
+        if self.subscribers.is_empty() {
+            return self.storage.put_and_locate(key, val);
+        }
+        let offset = self.storage.put_and_locate(key.clone(), val.clone())?;
+        self.notify_put(key, val);
+        Ok(offset)
+    }
+
+    /// Reads the value written at `offset`, as returned by `put_and_locate`,
+    /// without looking up the key. Returns the value even if the key has been
+    /// overwritten since, and `None` for a removal or the end of the file.
+    ///
+    /// Offsets only stay valid until the next compaction. One that doesn't
+    /// point at the start of an entry gives `KvsError::InvalidOffset`, though
+    /// that can't be detected in every case.
+    pub fn get_at(&mut self, offset: u64) -> Result<Option<String>> {
+        self.storage.get_at(offset)
+    }
+
+    /// Where the current value of `key` lives: the offset of its entry in the
+    /// data file and the bytes the entry takes up there, `None` if the key
+    /// doesn't exist. Only the entry header is read. Like those of
+    /// `put_and_locate`, the offset stays valid until the next compaction.
+    pub fn locate(&mut self, key: &str) -> Result<Option<(u64, usize)>> {
+        self.storage.locate(key)
+    }
+
+    /// Sets every pair in order, stopping at the first error. A key given
+    /// twice is written twice, see `put_batch` to avoid that.
+    pub fn put_many(&mut self, pairs: Vec<(String, String)>) -> Result<()> {
+        for (key, val) in pairs {
+            self.put(key, val)?;
+        }
+        Ok(())
+    }
+
+    /// Like `put_many`, with a key given more than once handled by `policy`.
+    /// Only one value per key is written, so the skipped ones take no space
+    /// in the data file, and subscribers only hear about the stored one.
+    pub fn put_batch(
+        &mut self,
+        pairs: Vec<(String, String)>,
+        policy: DuplicatePolicy,
+    ) -> Result<()> {
+        let mut last = HashMap::with_capacity(pairs.len());
+        for (i, (key, _)) in pairs.iter().enumerate() {
+            if last.insert(key.as_str(), i).is_some() && policy == DuplicatePolicy::Error {
+                return Err(KvsError::DuplicateKey(key.clone()));
+            }
+        }
+        let keep: Vec<bool> = pairs
+            .iter()
+            .enumerate()
+            .map(|(i, (key, _))| last[key.as_str()] == i)
+            .collect();
+        for ((key, val), keep) in pairs.into_iter().zip(keep) {
+            if keep {
+                self.put(key, val)?;
+            }
+        }
+        Ok(())
+    }
+
+    /// Sets `key` to `new` only if its current value is `expected`, with
+    /// `None` meaning the key must not exist. Returns whether it was set.
+    pub fn compare_and_swap(
+        &mut self,
+        key: String,
+        expected: Option<String>,
+        new: String,
+    ) -> Result<bool> {
+        if self.storage.get(key.clone())? != expected {
+            return Ok(false);
+        }
+        self.put(key, new)?;
+        Ok(true)
+    }
+
+    /// Returns the value at `key`, first storing the one `f` computes if the
+    /// key doesn't exist. `f` isn't called when it does. Nothing else can
+    /// write in between, as both steps happen under the same `&mut self`.
+    pub fn get_or_insert_with<F: FnOnce() -> String>(
+        &mut self,
+        key: String,
+        f: F,
+    ) -> Result<String> {
+        if let Some(val) = self.storage.get(key.clone())? {
+            return Ok(val);
+        }
+        let val = f();
+        self.put(key, val.clone())?;
+        Ok(val)
+    }
+
+    /// Path of the data file, e.g. for backups or reporting disk usage.
+    /// `None` for a store that lives only in memory.
+    pub fn data_path(&self) -> Option<&Path> {
+        self.storage.data_path()
+    }
+
+    /// Directory holding the data file and the other files of the store.
+    pub fn data_dir(&self) -> Option<&Path> {
+        self.data_path().and_then(Path::parent)
+    }
+
+    /// Adds `delta` to the integer stored at `key`, starting from 0 if the key
+    /// doesn't exist, and returns the new value. Use a negative `delta` to
+    /// decrement.
+    pub fn incr(&mut self, key: String, delta: i64) -> Result<i64> {
+        let current = match self.storage.get(key.clone())? {
+            Some(val) => val.parse::<i64>().map_err(|_| KvsError::NotAnInteger)?,
+            None => 0,
+        };
+        let new = current
+            .checked_add(delta)
+            .ok_or(KvsError::IntegerOverflow)?;
+        self.put(key, new.to_string())?;
+        Ok(new)
+    }
+
+    /// Appends `suffix` to the value at `key`, creating it if needed, and
+    /// returns the new length in bytes.
+    pub fn append(&mut self, key: String, suffix: &str) -> Result<usize> {
+        let mut val = self.storage.get(key.clone())?.unwrap_or_default();
+        val.push_str(suffix);
+        let len = val.len();
+        self.put(key, val)?;
+        Ok(len)
     }
 
     pub fn remove(&mut self, key: String) -> Result<()> {
-        self.storage.remove(key)
+        self.delete(key)
+    }
+
+    /// Moves the value of `old` to `new`, replacing any value `new` had, and
+    /// removes `old`, failing with `KvsError::KeyNotFound` if it doesn't
+    /// exist. The put and the removal go through `apply_batch`, so the rename
+    /// is atomic: a crash leaves the value under `old` or under `new`, never
+    /// both or neither.
+    pub fn rename(&mut self, old: &str, new: &str) -> Result<()> {
+        if self.subscribers.is_empty() || old == new {
+            return self.storage.rename(old, new);
+        }
+        let val = self.storage.get(old.to_owned())?;
+        self.storage.rename(old, new)?;
+        self.notify_put(new.to_owned(), val.unwrap_or_default());
+        self.notify(ChangeEvent {
+            key: old.to_owned(),
+            kind: CmdKind::DEL,
+            value: String::new(),
+        });
+        Ok(())
+    }
+
+    /// Swaps the values of `a` and `b`, failing with `KvsError::KeyNotFound`
+    /// if either doesn't exist. Both puts go through `apply_batch`, so a crash
+    /// leaves both keys with their old values or both with the new ones.
+    pub fn swap(&mut self, a: &str, b: &str) -> Result<()> {
+        let val_a = self.storage.get(a.to_owned())?;
+        let val_b = self.storage.get(b.to_owned())?;
+        let (val_a, val_b) = match (val_a, val_b) {
+            (Some(val_a), Some(val_b)) => (val_a, val_b),
+            _ => return Err(KvsError::KeyNotFound),
+        };
+        if a == b {
+            return Ok(());
+        }
+        self.apply_batch(vec![
+            WriteOp::Put {
+                key: a.to_owned(),
+                val: val_b,
+            },
+            WriteOp::Put {
+                key: b.to_owned(),
+                val: val_a,
+            },
+        ])
+    }
+
+    /// Removes every key of `keys` that exists, in one go: the data file is
+    /// flushed and checked for compaction once rather than per key. Keys
+    /// that don't exist are skipped and returned.
+    pub fn remove_batch(&mut self, keys: &[String]) -> Result<Vec<String>> {
+        let skipped = self.storage.remove_batch(keys)?;
+        if !self.subscribers.is_empty() {
+            // `skipped` follows the order of `keys`, so whatever doesn't line
+            // up with its next key was removed
+            let mut next_skipped = skipped.iter().peekable();
+            for key in keys {
+                if next_skipped.peek() == Some(&key) {
+                    next_skipped.next();
+                } else {
+                    self.notify(ChangeEvent {
+                        key: key.clone(),
+                        kind: CmdKind::DEL,
+                        value: String::new(),
+                    });
+                }
+            }
+        }
+        Ok(skipped)
+    }
+
+    /// Applies `ops` in order as one write that a crash can't leave half
+    /// done, see `Storage::apply_batch`. A delete of a key that doesn't exist
+    /// by then fails the whole batch with `KvsError::KeyNotFound`.
+    pub fn apply_batch(&mut self, ops: Vec<WriteOp>) -> Result<()> {
+        if self.subscribers.is_empty() {
+            return self.storage.apply_batch(ops);
+        }
+        self.storage.apply_batch(ops.clone())?;
+        for op in ops {
+            match op {
+                WriteOp::Put { key, val } => self.notify_put(key, val),
+                WriteOp::Delete { key } => self.notify(ChangeEvent {
+                    key,
+                    kind: CmdKind::DEL,
+                    value: String::new(),
+                }),
+            }
+        }
+        Ok(())
+    }
+
+    /// Removes every key for which `f` returns false and returns how many
+    /// were removed. Their space is reclaimed by the next compaction.
+    pub fn retain<F: FnMut(&str, &str) -> bool>(&mut self, mut f: F) -> Result<usize> {
+        let mut removed = 0;
+        for (key, val) in self.storage.scan()? {
+            if !f(&key, &val) {
+                self.delete(key)?;
+                removed += 1;
+            }
+        }
+        Ok(removed)
+    }
+
+    /// A handle on the keys of the namespace `name`, kept apart from those of
+    /// other namespaces in the same store.
+    ///
+    /// # Panics
+    ///
+    /// If `name` contains a NUL character.
+    pub fn namespace(&mut self, name: &str) -> Namespace<'_, S> {
+        Namespace::new(self, name)
+    }
+
+    /// Returns the key/value pairs with keys in `[start, end)`, sorted by key.
+    ///
+    /// Works with any index kind, but only `IndexKind::Ordered` avoids
+    /// filtering and sorting every key.
+    pub fn range(&mut self, start: String, end: String) -> Result<Vec<(String, String)>> {
+        self.storage.range(start, end)
+    }
+
+    /// Returns every live key/value pair, sorted by key.
+    pub fn scan(&mut self) -> Result<Vec<(String, String)>> {
+        self.storage.scan()
+    }
+
+    /// Like `scan`, but reads each value only when the iterator gets to it.
+    ///
+    /// The iterator is a point-in-time view: it sees the pairs live when it
+    /// was created, whatever is written afterwards, and doesn't borrow the
+    /// store, so writes can go on meanwhile. It keeps the data file of that
+    /// moment open, so a compaction in between doesn't disturb it on Unix,
+    /// where a replaced file stays readable through handles opened before.
+    /// `clear` makes it fail.
+    pub fn scan_iter(&mut self) -> Result<ScanIter> {
+        self.storage.scan_iter()
+    }
+
+    /// Like `scan_iter`, but yields the live pairs in the order they sit in
+    /// the data file, which is the order each key was last written, rather
+    /// than sorted by key. Handy for looking into write history.
+    pub fn iter_in_write_order(&mut self) -> Result<ScanIter> {
+        self.storage.iter_in_write_order()
+    }
+
+    /// Returns up to `limit` pairs with keys after `cursor`, sorted by key,
+    /// and the cursor to pass for the next page: the last key returned, or
+    /// `None` once the end is reached. Start with a `None` cursor. A `limit`
+    /// of 0 counts as 1, so every page but the last moves the cursor.
+    ///
+    /// No state is kept between calls, so writes made in between show up in
+    /// later pages if their key comes after the cursor. Only
+    /// `IndexKind::Ordered` avoids filtering and sorting every key per page.
+    pub fn scan_page(&mut self, cursor: Option<String>, limit: usize) -> Result<Page> {
+        self.storage.scan_page(cursor, limit)
+    }
+
+    /// Smallest key in the store, e.g. to start paging through it.
+    ///
+    /// Cheap with `IndexKind::Ordered`, other backends look at every key,
+    /// and `IndexKind::Hashed` reads them all from disk.
+    pub fn first_key(&self) -> Result<Option<String>> {
+        self.storage.first_key()
+    }
+
+    /// Largest key in the store, see `first_key`.
+    pub fn last_key(&self) -> Result<Option<String>> {
+        self.storage.last_key()
+    }
+
+    /// Writes every live pair to `writer` in the dump format, independent of
+    /// the on-disk log layout. Returns the number of pairs written.
+    pub fn export<W: Write>(&mut self, mut writer: W) -> Result<usize> {
+        let pairs = self.storage.scan()?;
+        dump::write_pairs(&mut writer, &pairs)?;
+        Ok(pairs.len())
+    }
+
+    /// Loads pairs written by `export`, overwriting existing keys, and flushes
+    /// once at the end. Returns the number of pairs read.
+    pub fn import<R: Read>(&mut self, mut reader: R) -> Result<usize> {
+        let pairs = dump::read_pairs(&mut reader)?;
+        let count = pairs.len();
+        for (key, val) in pairs {
+            self.put(key, val)?;
+        }
+        self.storage.flush()?;
+        Ok(count)
+    }
+
+    /// Copies the current state of the store into the directory `dest`, which
+    /// can then be opened as a store of its own. Writes made afterwards are not
+    /// part of the snapshot.
+    pub fn snapshot(&mut self, dest: &Path) -> Result<()> {
+        self.storage.snapshot(dest)
+    }
+
+    /// Like `snapshot`, copying the files at no more than `bytes_per_sec`, so
+    /// a backup doesn't take all the disk bandwidth other work relies on. The
+    /// store is borrowed until the copy is done, which takes correspondingly
+    /// longer. Backends without files write their snapshot unthrottled.
+    pub fn snapshot_throttled(&mut self, dest: &Path, bytes_per_sec: u64) -> Result<()> {
+        self.storage.snapshot_throttled(dest, bytes_per_sec)
+    }
+
+    /// Like `snapshot`, returning only once the copy is on stable storage,
+    /// even for a store opened with `Options::no_sync`. Each file of the
+    /// copy replaces the one in `dest` in a single rename, so a crash
+    /// leaves the last checkpoint or the new one, never a mix within a file.
+    ///
+    /// Keeping the store itself on tmpfs under `no_sync` and checkpointing it
+    /// to a real disk now and then loses at most the writes since the last
+    /// checkpoint.
+    pub fn checkpoint(&mut self, dest: &Path) -> Result<()> {
+        self.storage.checkpoint(dest)
+    }
+
+    /// Reloads the index from the data file, for when it was changed by
+    /// something other than this store, e.g. `repair` or another process.
+    /// Writes not yet flushed are flushed first.
+    pub fn reopen(&mut self) -> Result<()> {
+        self.storage.reopen()
+    }
+
+    /// Removes every key by truncating the data file.
+    pub fn clear(&mut self) -> Result<()> {
+        self.storage.clear()
+    }
+
+    /// Writes buffered entries to the data file.
+    ///
+    /// Writes are buffered, so other handles on the same directory only see
+    /// them after a flush. Reads through this store always see them, and the
+    /// buffer is also flushed when the store is dropped.
+    pub fn flush(&mut self) -> Result<()> {
+        self.storage.flush()
+    }
+
+    /// Flushes and waits until the written data is on stable storage.
+    pub fn sync(&mut self) -> Result<()> {
+        self.storage.sync()
+    }
+
+    /// A durability barrier, e.g. before acknowledging a transaction: returns
+    /// once everything written so far is on stable storage, along with the
+    /// file metadata and directory entries `sync` leaves out, such as a data
+    /// file swapped in by compaction. Independent of any per-write syncing.
+    ///
+    /// This waits on the disk with several syscalls, which can take anywhere
+    /// from microseconds to hundreds of milliseconds.
+    pub fn flush_and_sync(&mut self) -> Result<()> {
+        self.storage.flush_and_sync()
+    }
+
+    /// Operation counters since the store was opened.
+    pub fn metrics(&self) -> MetricsSnapshot {
+        self.storage.metrics()
+    }
+
+    /// Percentiles of how long `get`, `set` and `remove` calls took since the
+    /// store was opened, including any compaction a write ran inline. Other
+    /// writes count as puts when they set single keys, e.g. `incr` or
+    /// `put_many`, while batch removes and reads other than `get` aren't
+    /// timed.
+    #[cfg(feature = "latency")]
+    pub fn latency_report(&self) -> LatencyReport {
+        self.latency.report()
+    }
+
+    /// Bytes the store takes on disk, blob file included, for any backend.
+    /// A store kept in memory reports what a snapshot of it would take.
+    pub fn size_on_disk(&self) -> Result<u64> {
+        self.storage.size_on_disk()
+    }
+
+    /// Live key count and data file size, see `StoreStats`. Cheap, unlike
+    /// `compaction_estimate`.
+    ///
+    /// The log is a single data file, so these and `compaction_estimate`'s
+    /// live and dead bytes are the whole picture, there are no segments to
+    /// list one by one.
+    pub fn stats(&self) -> StoreStats {
+        self.storage.stats()
+    }
+
+    /// Compacts the data file a slice at a time, scanning about `budget` bytes
+    /// per call, so the work can be spread out instead of stalling a `set`.
+    /// Call it until it returns `CompactionProgress::Finished`; writes in
+    /// between are fine.
+    pub fn compact_step(&mut self, budget: u64) -> Result<CompactionProgress> {
+        self.storage.compact_step(budget)
+    }
+
+    /// Works out how much space a compaction would reclaim right now without
+    /// rewriting anything, e.g. to decide whether it's worth running one. Looks
+    /// at the header of every live entry.
+    pub fn compaction_estimate(&mut self) -> Result<CompactionEstimate> {
+        self.storage.compaction_estimate()
+    }
+
+    /// Compacts now to clean up after removals: every tombstone older than
+    /// `Options::tombstone_grace` is dropped, along with whatever else a
+    /// compaction reclaims. Returns how many tombstones were dropped.
+    pub fn purge_tombstones(&mut self) -> Result<u64> {
+        self.storage.purge_tombstones()
+    }
+
+    /// Compacts if `Options::compaction_threshold` and `compaction_ratio` say
+    /// there's enough to reclaim, returning whether it did. Meant to be polled
+    /// by a maintenance task with `Options::auto_compaction` off, so writes
+    /// never compact, or with `Options::compaction_idle`, in which case it
+    /// also waits for the store to have been quiet that long.
+    pub fn compact_if_needed(&mut self) -> Result<bool> {
+        self.storage.compact_if_needed()
+    }
+
+    /// Compacts now like `purge_tombstones`, writing the surviving entries in
+    /// key order. This reads the old file out of order, so it is slower than
+    /// a regular compaction, but afterwards `scan`, `range` and the like read
+    /// the data file front to back, which pays off most with
+    /// `IndexKind::Ordered`. Writes made later are appended as usual, and the
+    /// next regular compaction keeps the order of what it copies.
+    pub fn compact_sorted(&mut self) -> Result<u64> {
+        self.storage.compact_sorted()
+    }
+
+    /// Compacts only what `keys` left behind: their overwritten values and
+    /// tombstones past `Options::tombstone_grace` are dropped, everything
+    /// else is copied as it is. Meant for a few hot keys that churn while
+    /// the rest of the store stays put. The whole data file is still
+    /// rewritten, as it is the only way to give back space in the middle of
+    /// it, but other keys' entries are copied without deciding anything
+    /// about them. Returns the number of bytes reclaimed.
+    pub fn compact_keys(&mut self, keys: &[String]) -> Result<u64> {
+        self.storage.compact_keys(keys)
+    }
+
+    /// Frees the memory the index kept after keys were removed, which a hash
+    /// map holds on to otherwise, e.g. after a mass deletion in a long-lived
+    /// process. Returns how many entries the index had room for before and
+    /// after.
+    pub fn shrink_index(&mut self) -> (usize, usize) {
+        self.storage.shrink_index()
+    }
+
+    /// Every value `key` has held that is still in the data file, oldest
+    /// first with the offset of its entry, and `None` where the key was
+    /// removed, e.g. to see what it held before a bad write. Reads the whole
+    /// file. Compaction drops all but the current value, or as many as
+    /// `Options::keep_versions` says, and tombstones past
+    /// `Options::tombstone_grace`, so how far back this goes depends on
+    /// when the store last compacted. A store in memory has no history.
+    pub fn get_history(&mut self, key: &str) -> Result<Vec<(u64, Option<String>)>> {
+        self.storage.get_history(key)
+    }
+
+    /// Ships the log entries written from `offset` on to `writer`, for a
+    /// `Replica` to apply. Start from 0 for a fresh replica. Returns the offset
+    /// to ship from next time.
+    pub fn ship_log<W: Write>(&mut self, offset: u64, mut writer: W) -> Result<u64> {
+        let entries = self.storage.log_from(offset)?;
+        replication::write_log(&mut writer, offset, &entries)
+    }
+
+    /// Returns a channel receiving every later put and remove made through
+    /// this store, including those done by `incr`, `append` and the like.
+    /// `clear` is not reported.
+    ///
+    /// Writes never wait for subscribers: events are dropped for a subscriber
+    /// that is more than a fixed number of events behind.
+    pub fn subscribe(&mut self) -> Receiver<ChangeEvent> {
+        let (sender, receiver) = mpsc::sync_channel(SUBSCRIPTION_CAPACITY);
+        self.subscribers.push(sender);
+        receiver
+    }
+
+    fn put(&mut self, key: String, val: String) -> Result<()> {
+        if self.subscribers.is_empty() {
+            return self.store(key, val);
+        }
+        self.store(key.clone(), val.clone())?;
+        self.notify_put(key, val);
+        Ok(())
+    }
+
+    /// `Storage::put`, timed with the `latency` feature.
+    fn store(&mut self, key: String, val: String) -> Result<()> {
+        #[cfg(feature = "latency")]
+        let start = Instant::now();
+        let stored = self.storage.put(key, val);
+        #[cfg(feature = "latency")]
+        self.latency.record_put(start.elapsed());
+        stored
+    }
+
+    fn notify_put(&mut self, key: String, val: String) {
+        self.notify(ChangeEvent {
+            key,
+            kind: CmdKind::PUT,
+            value: val,
+        });
+    }
+
+    fn delete(&mut self, key: String) -> Result<()> {
+        if self.subscribers.is_empty() {
+            return self.unstore(key);
+        }
+        self.unstore(key.clone())?;
+        self.notify(ChangeEvent {
+            key,
+            kind: CmdKind::DEL,
+            value: String::new(),
+        });
+        Ok(())
+    }
+
+    /// `Storage::remove`, timed with the `latency` feature.
+    fn unstore(&mut self, key: String) -> Result<()> {
+        #[cfg(feature = "latency")]
+        let start = Instant::now();
+        let removed = self.storage.remove(key);
+        #[cfg(feature = "latency")]
+        self.latency.record_remove(start.elapsed());
+        removed
+    }
+
+    fn notify(&mut self, event: ChangeEvent) {
+        self.subscribers
+            .retain(|sender| match sender.try_send(event.clone()) {
+                Ok(()) | Err(TrySendError::Full(_)) => true,
+                Err(TrySendError::Disconnected(_)) => false,
+            });
     }
 }
@@ -0,0 +1,114 @@
+use std::time::Duration;
+
+/// Significant decimal digits the histograms keep, so a recorded duration is
+/// off by at most a hundredth in the report.
+const SIGNIFICANT_DIGITS: u8 = 2;
+
+/// Durations of `get`, `put` and `remove` calls, kept by a store built with
+/// the `latency` feature.
+#[derive(Default)]
+pub struct LatencyTracker {
+    get: Histogram,
+    put: Histogram,
+    remove: Histogram,
+}
+
+impl LatencyTracker {
+    pub fn record_get(&mut self, elapsed: Duration) {
+        self.get.record(elapsed);
+    }
+
+    pub fn record_put(&mut self, elapsed: Duration) {
+        self.put.record(elapsed);
+    }
+
+    pub fn record_remove(&mut self, elapsed: Duration) {
+        self.remove.record(elapsed);
+    }
+
+    pub fn report(&self) -> LatencyReport {
+        LatencyReport {
+            get: self.get.stats(),
+            put: self.put.stats(),
+            remove: self.remove.stats(),
+        }
+    }
+}
+
+/// Latencies since the store was opened, see `KvStore::latency_report`.
+#[derive(Clone, Copy, Debug, Default, PartialEq, Eq)]
+pub struct LatencyReport {
+    pub get: LatencyStats,
+
+    pub put: LatencyStats,
+
+    pub remove: LatencyStats,
+}
+
+/// Percentiles of the calls of one operation. They are read off an HDR
+/// histogram keeping two significant digits, rounded up to the end of their
+/// bucket, so they overstate by at most a hundredth. All zero before the
+/// first call.
+#[derive(Clone, Copy, Debug, Default, PartialEq, Eq)]
+pub struct LatencyStats {
+    pub count: u64,
+
+    pub p50: Duration,
+
+    pub p90: Duration,
+
+    pub p99: Duration,
+
+    pub p999: Duration,
+
+    /// The slowest call, exact.
+    pub max: Duration,
+}
+
+/// Durations in nanoseconds, with the exact slowest one next to the
+/// histogram, which only keeps the bucket it fell in.
+struct Histogram {
+    counts: hdrhistogram::Histogram<u64>,
+    max: u64,
+}
+
+impl Default for Histogram {
+    fn default() -> Histogram {
+        Histogram {
+            // grows to whatever durations get recorded
+            counts: hdrhistogram::Histogram::new(SIGNIFICANT_DIGITS)
+                .expect("valid significant digits"),
+            max: 0,
+        }
+    }
+}
+
+impl Histogram {
+    fn record(&mut self, elapsed: Duration) {
+        let nanos = u64::try_from(elapsed.as_nanos()).unwrap_or(u64::MAX);
+        // past what the histogram can grow to, it counts as its largest value
+        if self.counts.record(nanos).is_err() {
+            self.counts.saturating_record(nanos);
+        }
+        self.max = self.max.max(nanos);
+    }
+
+    /// Upper end of the bucket holding the `quantile` share of durations.
+    fn percentile(&self, quantile: f64) -> Duration {
+        if self.counts.is_empty() {
+            return Duration::ZERO;
+        }
+        Duration::from_nanos(self.counts.value_at_quantile(quantile).min(self.max))
+    }
+
+    fn stats(&self) -> LatencyStats {
+        LatencyStats {
+            count: self.counts.len(),
+            p50: self.percentile(0.5),
+            p90: self.percentile(0.9),
+            p99: self.percentile(0.99),
+            p999: self.percentile(0.999),
+            max: Duration::from_nanos(self.max),
+        }
+    }
+}
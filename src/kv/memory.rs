@@ -0,0 +1,389 @@
+use std::collections::{HashMap, HashSet};
+use std::io::{Read, Write};
+use std::path::Path;
+use std::sync::Arc;
+
+use super::clock::Clock;
+use super::error::{KvsError, Result};
+use super::metrics::{Metrics, MetricsSnapshot};
+use super::options::Options;
+use super::storage::{
+    self, CompactionEstimate, CompactionProgress, Entry, KeyState, Page, ReadSource, ScanIter,
+    SimplifiedBitcask, Storage, StoreStats, WriteOp,
+};
+
+/// Storage kept entirely in memory, nothing is written to disk.
+#[derive(Default)]
+pub struct MemoryStorage {
+    map: HashMap<String, String>,
+
+    /// When each key in `map` was last written.
+    written_at: HashMap<String, u64>,
+
+    /// Version of each key in `map` or `removed`.
+    versions: HashMap<String, u64>,
+
+    removed: HashSet<String>,
+
+    /// Tells the time for `written_at`, the system clock if not set.
+    clock: Option<Arc<dyn Clock>>,
+
+    metrics: Metrics,
+}
+
+impl MemoryStorage {
+    pub fn new() -> MemoryStorage {
+        MemoryStorage::default()
+    }
+
+    /// Like `new`, taking the time writes happen at from `clock`.
+    pub fn with_clock(clock: Arc<dyn Clock>) -> MemoryStorage {
+        MemoryStorage {
+            clock: Some(clock),
+            ..MemoryStorage::default()
+        }
+    }
+
+    /// Writes the entries into a store in `dest`, replacing what it held.
+    fn write_snapshot(&self, dest: &Path) -> Result<SimplifiedBitcask> {
+        let mut bitcask = SimplifiedBitcask::open(dest.to_path_buf(), Options::default())?;
+        bitcask.clear()?;
+        for (key, val) in &self.map {
+            bitcask.put(key.clone(), val.clone())?;
+        }
+        Ok(bitcask)
+    }
+}
+
+impl Storage for MemoryStorage {
+    fn get(&mut self, key: String) -> Result<Option<String>> {
+        let val = self.map.get(&key).cloned();
+        self.metrics.record_get(val.is_some());
+        Ok(val)
+    }
+
+    fn get_state(&mut self, key: String) -> Result<KeyState> {
+        let val = self.map.get(&key).cloned();
+        self.metrics.record_get(val.is_some());
+        if let Some(val) = val {
+            return Ok(KeyState::Present(val));
+        }
+        if self.removed.contains(&key) {
+            return Ok(KeyState::Tombstoned);
+        }
+        Ok(KeyState::Absent)
+    }
+
+    fn get_with_source(&mut self, key: String) -> Result<(Option<String>, ReadSource)> {
+        // the map is the storage, so a hit counts as a read from it
+        let val = self.get(key)?;
+        let source = match val {
+            Some(_) => ReadSource::IndexHitDisk,
+            None => ReadSource::Miss,
+        };
+        Ok((val, source))
+    }
+
+    fn get_with_meta(&mut self, key: String) -> Result<Option<(String, u64)>> {
+        let val = self.map.get(&key).cloned();
+        self.metrics.record_get(val.is_some());
+        Ok(val.map(|val| (val, self.written_at[&key])))
+    }
+
+    fn get_versioned(&mut self, key: String) -> Result<Option<(String, u64)>> {
+        let val = self.map.get(&key).cloned();
+        self.metrics.record_get(val.is_some());
+        Ok(val.map(|val| (val, self.versions[&key])))
+    }
+
+    fn key_version(&mut self, key: &str) -> Result<u64> {
+        match self.map.contains_key(key) {
+            true => Ok(self.versions[key]),
+            false => Ok(0),
+        }
+    }
+
+    fn get_into(&mut self, key: &str, buf: &mut Vec<u8>) -> Result<bool> {
+        let val = self.map.get(key);
+        self.metrics.record_get(val.is_some());
+        if let Some(val) = val {
+            buf.clear();
+            buf.extend_from_slice(val.as_bytes());
+        }
+        Ok(val.is_some())
+    }
+
+    fn put_reader(&mut self, key: String, reader: &mut dyn Read, len: usize) -> Result<()> {
+        // the value ends up in memory either way
+        let mut buf = vec![0; len];
+        reader.read_exact(buf.as_mut_slice())?;
+        self.put(key, String::from_utf8(buf)?)
+    }
+
+    fn get_writer(&mut self, key: &str, writer: &mut dyn Write) -> Result<bool> {
+        let val = self.map.get(key);
+        self.metrics.record_get(val.is_some());
+        if let Some(val) = val {
+            writer.write_all(val.as_bytes())?;
+        }
+        Ok(val.is_some())
+    }
+
+    fn get_shared(&mut self, key: &str) -> Result<Option<Arc<str>>> {
+        let val = self.map.get(key).map(|val| Arc::from(val.as_str()));
+        self.metrics.record_get(val.is_some());
+        Ok(val)
+    }
+
+    fn get_many(&mut self, keys: &[String]) -> Result<Vec<Option<String>>> {
+        keys.iter().map(|key| self.get(key.clone())).collect()
+    }
+
+    fn put(&mut self, key: String, val: String) -> Result<()> {
+        self.removed.remove(&key);
+        *self.versions.entry(key.clone()).or_default() += 1;
+        let now = match &self.clock {
+            Some(clock) => clock.now_millis(),
+            None => storage::unix_millis(),
+        };
+        self.written_at.insert(key.clone(), now);
+        self.map.insert(key, val);
+        self.metrics.record_put();
+        Ok(())
+    }
+
+    fn put_and_locate(&mut self, _key: String, _val: String) -> Result<u64> {
+        Err(KvsError::Unsupported)
+    }
+
+    fn locate(&mut self, _key: &str) -> Result<Option<(u64, usize)>> {
+        Err(KvsError::Unsupported)
+    }
+
+    fn get_at(&mut self, _offset: u64) -> Result<Option<String>> {
+        Err(KvsError::Unsupported)
+    }
+
+    fn remove(&mut self, key: String) -> Result<()> {
+        self.written_at.remove(&key);
+        match self.map.remove(&key) {
+            Some(_) => {
+                *self.versions.entry(key.clone()).or_default() += 1;
+                self.removed.insert(key);
+                self.metrics.record_remove();
+                Ok(())
+            }
+            None => Err(KvsError::KeyNotFound),
+        }
+    }
+
+    fn rename(&mut self, old: &str, new: &str) -> Result<()> {
+        let val = self.map.get(old).cloned().ok_or(KvsError::KeyNotFound)?;
+        if old == new {
+            return Ok(());
+        }
+        self.apply_batch(vec![
+            WriteOp::Put {
+                key: new.to_owned(),
+                val,
+            },
+            WriteOp::Delete {
+                key: old.to_owned(),
+            },
+        ])
+    }
+
+    fn remove_batch(&mut self, keys: &[String]) -> Result<Vec<String>> {
+        let mut skipped = Vec::new();
+        for key in keys {
+            if let Err(KvsError::KeyNotFound) = self.remove(key.clone()) {
+                skipped.push(key.clone());
+            }
+        }
+        Ok(skipped)
+    }
+
+    fn apply_batch(&mut self, ops: Vec<WriteOp>) -> Result<()> {
+        // nothing can fail once every delete is known to find its key
+        let mut exists = HashMap::new();
+        for op in &ops {
+            if let WriteOp::Delete { key } = op {
+                let found = exists.get(key.as_str()).copied();
+                if !found.unwrap_or_else(|| self.map.contains_key(key)) {
+                    return Err(KvsError::KeyNotFound);
+                }
+            }
+            exists.insert(op.key(), matches!(op, WriteOp::Put { .. }));
+        }
+        for op in ops {
+            match op {
+                WriteOp::Put { key, val } => self.put(key, val)?,
+                WriteOp::Delete { key } => self.remove(key)?,
+            }
+        }
+        Ok(())
+    }
+
+    fn range(&mut self, start: String, end: String) -> Result<Vec<(String, String)>> {
+        let mut pairs: Vec<(String, String)> = self
+            .map
+            .iter()
+            .filter(|(k, _)| **k >= start && **k < end)
+            .map(|(k, v)| (k.clone(), v.clone()))
+            .collect();
+        pairs.sort_unstable_by(|a, b| a.0.cmp(&b.0));
+        Ok(pairs)
+    }
+
+    fn scan(&mut self) -> Result<Vec<(String, String)>> {
+        let mut pairs: Vec<(String, String)> = self
+            .map
+            .iter()
+            .map(|(k, v)| (k.clone(), v.clone()))
+            .collect();
+        pairs.sort_unstable_by(|a, b| a.0.cmp(&b.0));
+        Ok(pairs)
+    }
+
+    fn scan_iter(&mut self) -> Result<ScanIter> {
+        Ok(Box::new(self.scan()?.into_iter().map(Ok)))
+    }
+
+    fn iter_in_write_order(&mut self) -> Result<ScanIter> {
+        Err(KvsError::Unsupported)
+    }
+
+    fn scan_page(&mut self, cursor: Option<String>, limit: usize) -> Result<Page> {
+        // an empty page would read as the end, so a page holds at least one pair
+        let limit = limit.max(1);
+        let mut pairs: Vec<(String, String)> = self
+            .map
+            .iter()
+            .filter(|(k, _)| cursor.as_ref().is_none_or(|cursor| *k > cursor))
+            .map(|(k, v)| (k.clone(), v.clone()))
+            .collect();
+        pairs.sort_unstable_by(|a, b| a.0.cmp(&b.0));
+        let mut next = None;
+        if pairs.len() > limit {
+            pairs.truncate(limit);
+            next = pairs.last().map(|(key, _)| key.clone());
+        }
+        Ok((pairs, next))
+    }
+
+    fn first_key(&self) -> Result<Option<String>> {
+        Ok(self.map.keys().min().cloned())
+    }
+
+    fn last_key(&self) -> Result<Option<String>> {
+        Ok(self.map.keys().max().cloned())
+    }
+
+    fn clear(&mut self) -> Result<()> {
+        self.map.clear();
+        self.written_at.clear();
+        self.versions.clear();
+        self.removed.clear();
+        Ok(())
+    }
+
+    fn flush(&mut self) -> Result<()> {
+        Ok(())
+    }
+
+    fn sync(&mut self) -> Result<()> {
+        Ok(())
+    }
+
+    fn flush_and_sync(&mut self) -> Result<()> {
+        Ok(())
+    }
+
+    fn snapshot(&mut self, dest: &Path) -> Result<()> {
+        self.write_snapshot(dest)?.flush()
+    }
+
+    fn snapshot_throttled(&mut self, dest: &Path, _bytes_per_sec: u64) -> Result<()> {
+        // there's no file to copy, the entries are written out one by one
+        self.snapshot(dest)
+    }
+
+    fn checkpoint(&mut self, dest: &Path) -> Result<()> {
+        self.write_snapshot(dest)?.flush_and_sync()
+    }
+
+    fn reopen(&mut self) -> Result<()> {
+        // nothing outside the store can change it
+        Ok(())
+    }
+
+    fn metrics(&self) -> MetricsSnapshot {
+        self.metrics.snapshot()
+    }
+
+    fn stats(&self) -> StoreStats {
+        StoreStats {
+            key_count: self.map.len() as u64,
+            ..StoreStats::default()
+        }
+    }
+
+    fn data_path(&self) -> Option<&Path> {
+        None
+    }
+
+    fn size_on_disk(&self) -> Result<u64> {
+        let entries: usize = self
+            .map
+            .iter()
+            .map(|(key, val)| storage::ENTRY_HEAD_LEN + key.len() + val.len())
+            .sum();
+        Ok(storage::HEADER_LEN + entries as u64)
+    }
+
+    fn compact_step(&mut self, _budget: u64) -> Result<CompactionProgress> {
+        Ok(CompactionProgress::Finished)
+    }
+
+    fn compaction_estimate(&mut self) -> Result<CompactionEstimate> {
+        Ok(CompactionEstimate::default())
+    }
+
+    fn purge_tombstones(&mut self) -> Result<u64> {
+        let purged = self.removed.len() as u64;
+        for key in self.removed.drain() {
+            self.versions.remove(&key);
+        }
+        Ok(purged)
+    }
+
+    fn compact_if_needed(&mut self) -> Result<bool> {
+        Ok(false)
+    }
+
+    fn compact_sorted(&mut self) -> Result<u64> {
+        // nothing is laid out on disk, only tombstones can go
+        self.purge_tombstones()
+    }
+
+    fn compact_keys(&mut self, _keys: &[String]) -> Result<u64> {
+        Ok(0)
+    }
+
+    fn shrink_index(&mut self) -> (usize, usize) {
+        let before = self.map.capacity();
+        self.map.shrink_to_fit();
+        self.written_at.shrink_to_fit();
+        self.versions.shrink_to_fit();
+        self.removed.shrink_to_fit();
+        (before, self.map.capacity())
+    }
+
+    fn log_from(&mut self, _offset: u64) -> Result<Vec<(u64, Entry)>> {
+        Ok(Vec::new())
+    }
+
+    fn get_history(&mut self, _key: &str) -> Result<Vec<(u64, Option<String>)>> {
+        Ok(Vec::new())
+    }
+}
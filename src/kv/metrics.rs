@@ -0,0 +1,85 @@
+/// Operation counters kept by a storage backend.
+#[derive(Default)]
+pub struct Metrics {
+    get_count: u64,
+    put_count: u64,
+    remove_count: u64,
+    hit_count: u64,
+    miss_count: u64,
+    compaction_count: u64,
+    cache_hit_count: u64,
+    cache_miss_count: u64,
+}
+
+impl Metrics {
+    pub fn record_get(&mut self, hit: bool) {
+        self.get_count += 1;
+        if hit {
+            self.hit_count += 1;
+        } else {
+            self.miss_count += 1;
+        }
+    }
+
+    pub fn record_cache_lookup(&mut self, hit: bool) {
+        if hit {
+            self.cache_hit_count += 1;
+        } else {
+            self.cache_miss_count += 1;
+        }
+    }
+
+    pub fn record_put(&mut self) {
+        self.put_count += 1;
+    }
+
+    pub fn record_remove(&mut self) {
+        self.remove_count += 1;
+    }
+
+    pub fn record_compaction(&mut self) {
+        self.compaction_count += 1;
+    }
+
+    pub fn snapshot(&self) -> MetricsSnapshot {
+        MetricsSnapshot {
+            get_count: self.get_count,
+            put_count: self.put_count,
+            remove_count: self.remove_count,
+            hit_count: self.hit_count,
+            miss_count: self.miss_count,
+            compaction_count: self.compaction_count,
+            cache_hit_count: self.cache_hit_count,
+            cache_miss_count: self.cache_miss_count,
+        }
+    }
+}
+
+/// Counters since the store was opened.
+#[derive(Clone, Copy, Debug, Default, PartialEq, Eq)]
+pub struct MetricsSnapshot {
+    /// Lookups through `get` or `get_state`.
+    pub get_count: u64,
+
+    /// Successful `set` calls.
+    pub put_count: u64,
+
+    /// Successful `remove` calls.
+    pub remove_count: u64,
+
+    /// Lookups that found a value.
+    pub hit_count: u64,
+
+    /// Lookups that found nothing.
+    pub miss_count: u64,
+
+    /// Compactions of the data file.
+    pub compaction_count: u64,
+
+    /// Lookups of present keys served by the value cache.
+    pub cache_hit_count: u64,
+
+    /// Lookups of present keys that had to read the data file while the
+    /// value cache is enabled.
+    pub cache_miss_count: u64,
+}
@@ -1,3 +1,27 @@
+#[cfg(feature = "async")]
+pub mod async_kv_store;
+pub mod blob;
+pub mod bloom;
+pub mod builder;
+pub mod cache;
+pub mod clock;
+pub mod codec;
+pub mod commit;
+pub mod dump;
 pub mod error;
+#[cfg(feature = "testing")]
+pub mod fault;
+pub mod group_commit;
+pub mod index;
 pub mod kv_store;
+#[cfg(feature = "latency")]
+pub mod latency;
+pub mod memory;
+pub mod metrics;
+pub mod namespace;
+pub mod options;
+pub mod reader;
+pub mod replication;
+pub mod sharded;
 pub mod storage;
+pub mod typed;
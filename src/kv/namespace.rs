@@ -0,0 +1,70 @@
+use super::error::Result;
+use super::kv_store::KvStore;
+use super::storage::Storage;
+
+/// Separates the namespace from the key in the stored key.
+const SEPARATOR: char = '\0';
+
+/// A keyspace of its own within a `KvStore`, returned by
+/// `KvStore::namespace`.
+///
+/// Keys are stored as `<name>\0<key>`, so namespaces never see each other's
+/// keys, while plain keys of the store stay visible to `KvStore::scan`.
+pub struct Namespace<'a, S: Storage + ?Sized = dyn Storage> {
+    store: &'a mut KvStore<S>,
+
+    prefix: String,
+}
+
+impl<'a, S: Storage + ?Sized> Namespace<'a, S> {
+    pub(crate) fn new(store: &'a mut KvStore<S>, name: &str) -> Namespace<'a, S> {
+        assert!(
+            !name.contains(SEPARATOR),
+            "a namespace name can't contain a NUL character"
+        );
+        Namespace {
+            store,
+            prefix: format!("{}{}", name, SEPARATOR),
+        }
+    }
+
+    /// Name of the namespace, as passed to `KvStore::namespace`.
+    pub fn name(&self) -> &str {
+        &self.prefix[..self.prefix.len() - SEPARATOR.len_utf8()]
+    }
+
+    pub fn get(&mut self, key: String) -> Result<Option<String>> {
+        self.store.get(self.prefixed(&key))
+    }
+
+    pub fn set(&mut self, key: String, val: String) -> Result<()> {
+        let key = self.prefixed(&key);
+        self.store.set(key, val)
+    }
+
+    pub fn remove(&mut self, key: String) -> Result<()> {
+        let key = self.prefixed(&key);
+        self.store.remove(key)
+    }
+
+    /// Every key of the namespace, sorted.
+    pub fn keys(&mut self) -> Result<Vec<String>> {
+        Ok(self.scan()?.into_iter().map(|(key, _)| key).collect())
+    }
+
+    /// Every key/value pair of the namespace, sorted by key.
+    pub fn scan(&mut self) -> Result<Vec<(String, String)>> {
+        // the separator is the smallest char, so the next one bounds the range
+        let end = format!("{}\u{1}", self.name());
+        let pairs = self.store.range(self.prefix.clone(), end)?;
+        let prefix_len = self.prefix.len();
+        Ok(pairs
+            .into_iter()
+            .map(|(key, val)| (key[prefix_len..].to_owned(), val))
+            .collect())
+    }
+
+    fn prefixed(&self, key: &str) -> String {
+        format!("{}{}", self.prefix, key)
+    }
+}
@@ -0,0 +1,350 @@
+use std::fmt;
+use std::sync::atomic::{AtomicBool, Ordering};
+use std::sync::Arc;
+use std::time::Duration;
+
+use super::bloom::BloomOptions;
+use super::clock::Clock;
+#[cfg(feature = "testing")]
+use super::fault::FaultInjector;
+use super::index::IndexHasher;
+use super::storage;
+
+/// Kind of the in-memory index.
+#[derive(Clone, Copy, Debug, PartialEq, Eq, Default)]
+pub enum IndexKind {
+    /// `HashMap` backed index, best for point lookups.
+    #[default]
+    Hash,
+
+    /// `BTreeMap` backed index, keeps keys sorted for range queries.
+    Ordered,
+
+    /// Keeps a 64-bit hash of each key instead of the key itself, so index
+    /// memory no longer grows with key length. The price is disk reads: a
+    /// lookup whose hash is present reads the key back from the data file to
+    /// rule out a collision, every overwrite or removal does the same while
+    /// loading, and anything ordered by key (`scan`, `range`, `first_key`, ...)
+    /// reads every key.
+    Hashed,
+}
+
+/// Compaction event passed to `Options::on_compaction`.
+#[derive(Clone, Copy, Debug, PartialEq, Eq)]
+pub enum CompactionInfo {
+    /// A compaction is about to rewrite the data file.
+    Started {
+        /// Size of the data file.
+        bytes_before: u64,
+
+        /// Bytes taken by overwritten entries.
+        pending_bytes: u64,
+    },
+
+    /// A compaction has scanned `scanned` of the `total` bytes of the data
+    /// file, reported about every megabyte.
+    Progress { scanned: u64, total: u64 },
+
+    /// A compaction has finished.
+    Finished {
+        bytes_before: u64,
+
+        bytes_after: u64,
+
+        duration: Duration,
+    },
+}
+
+pub type CompactionCallback = Arc<dyn Fn(CompactionInfo) + Send + Sync>;
+
+/// Flag shared with a store to stop its compactions, see
+/// `Options::compaction_cancel`. Clones share the flag, and once cancelled
+/// it stays cancelled.
+#[derive(Clone, Debug, Default)]
+pub struct CancellationToken(Arc<AtomicBool>);
+
+impl CancellationToken {
+    pub fn new() -> CancellationToken {
+        CancellationToken::default()
+    }
+
+    pub fn cancel(&self) {
+        self.0.store(true, Ordering::Relaxed);
+    }
+
+    pub fn is_cancelled(&self) -> bool {
+        self.0.load(Ordering::Relaxed)
+    }
+}
+
+/// How often a store syncs in the background, see
+/// `Options::commit_interval`.
+#[derive(Clone, Copy, Debug, PartialEq, Eq)]
+pub struct CommitInterval {
+    /// Longest a write waits to be synced.
+    pub every: Duration,
+
+    /// Unsynced bytes at which the write bringing them there syncs at once.
+    pub max_bytes: u64,
+}
+
+const DEFAULT_NAME: &str = "miniDB";
+const DEFAULT_BUFFER_CAPACITY: usize = 8 * 1024;
+const DEFAULT_MAX_KEY_SIZE: usize = 1 << 16;
+const DEFAULT_MAX_VALUE_SIZE: usize = 1 << 26;
+const DEFAULT_COMPACTION_THRESHOLD: u64 = 1 << 16;
+
+/// Options used when opening a store.
+#[derive(Clone)]
+pub struct Options {
+    /// Name of the store, used as the prefix of its files so several stores
+    /// can share a directory.
+    pub name: String,
+
+    /// Whether opening a store that doesn't exist yet creates it. When false,
+    /// opening fails unless the data file is already there.
+    pub create: bool,
+
+    /// Whether opening fails if the store already exists, so a fresh one is
+    /// always created. Takes precedence over `create`.
+    pub create_new: bool,
+
+    pub index_kind: IndexKind,
+
+    /// Hash function of `IndexKind::Hash` and `IndexKind::Hashed`, e.g. a
+    /// faster one where keys can be trusted. `None` uses SipHash with a random
+    /// seed, which keys chosen to collide can't defeat.
+    pub index_hasher: Option<IndexHasher>,
+
+    /// Largest key accepted by `put`, in bytes, a longer one failing with
+    /// `KvsError::KeyTooLarge`. Entries already on disk are read back
+    /// whatever their size, so the limit can be lowered for a store.
+    ///
+    /// Besides being written with every entry of the key, each live key is
+    /// held in full by the index, unless it is `IndexKind::Hashed`. The index
+    /// can thus take up to this many bytes per key on top of its own
+    /// overhead, the figure to plan memory with when keys come from untrusted
+    /// input.
+    pub max_key_size: usize,
+
+    /// Largest value accepted by `put`, in bytes. Like `max_key_size`, it
+    /// doesn't apply to entries already on disk.
+    pub max_value_size: usize,
+
+    /// Buffer size of the data file reader. Larger buffers mean fewer reads
+    /// while scanning the whole file on open and during compaction.
+    pub read_buffer_capacity: usize,
+
+    /// Buffer size of the data file writer.
+    pub write_buffer_capacity: usize,
+
+    /// Bloom filter consulted before the index, so most lookups of absent keys
+    /// are answered without touching it. Rebuilt when the store is opened.
+    pub bloom_filter: Option<BloomOptions>,
+
+    /// Values longer than this many bytes are kept in a separate `<name>.blob`
+    /// file, the data file only holding a reference to them, so loading the
+    /// index doesn't have to go through them. `None` keeps every value in the
+    /// data file. `Some(0)` moves every non-empty value out, leaving the data
+    /// file a log of keys and value references in the way of WiscKey, see
+    /// `KvStoreBuilder::separate_values`.
+    ///
+    /// A full compaction copies the live blobs to a new blob file, giving
+    /// back the space of overwritten ones. `KvStore::compact_keys` and
+    /// `KvStore::compact_step` leave the blob file as it is, and only the
+    /// references of overwritten blobs count toward `compaction_threshold`.
+    pub blob_threshold: Option<usize>,
+
+    /// Whether writes compact once `compaction_threshold` is crossed. With it
+    /// off, the data file is only compacted when asked to, e.g. through
+    /// `KvStore::purge_tombstones` or `KvStore::compact_step`, or to make
+    /// room under `max_data_size`.
+    pub auto_compaction: bool,
+
+    /// Hands every write to the OS right away and syncs the files from a
+    /// background thread every `CommitInterval::every`, or as soon as
+    /// `CommitInterval::max_bytes` are unsynced, so a crash of the machine
+    /// loses at most that much. `None` leaves syncing to `KvStore::sync`.
+    /// Only the on-disk store has files to sync.
+    pub commit_interval: Option<CommitInterval>,
+
+    /// Never syncs the files of the store, for one kept on tmpfs or a RAM
+    /// disk where syncing buys nothing: `KvStore::sync`,
+    /// `KvStore::flush_and_sync` and `commit_interval` only hand the writes
+    /// to the OS. Durable copies are made with `KvStore::checkpoint`.
+    pub no_sync: bool,
+
+    /// Dead bytes, taken by overwritten and removed entries, that make the
+    /// next `put` or `remove` compact before returning, as long as
+    /// `auto_compaction` is on and `compaction_idle` isn't set. Under
+    /// `compaction_idle`, writes only compact past `compaction_ceiling`,
+    /// which bounds how far compaction can fall behind, and without
+    /// `auto_compaction` they never do.
+    pub compaction_threshold: u64,
+
+    /// Share of the data file that must be dead, on top of
+    /// `compaction_threshold`, before compacting, e.g. 0.3. The threshold
+    /// then only keeps small stores from compacting all the time, while large
+    /// ones compact at the same dead share rather than the same dead bytes.
+    /// `None` compacts at the threshold alone.
+    pub compaction_ratio: Option<f64>,
+
+    /// Holds compaction back until the store has seen no writes for this
+    /// long, so it runs in a lull rather than in the middle of a burst.
+    /// Writes then don't compact at `compaction_threshold`, that's left to
+    /// `KvStore::compact_if_needed`, which only compacts once the store has
+    /// been quiet long enough and is meant to be polled, e.g. every second.
+    /// `None` compacts as soon as the threshold is crossed.
+    pub compaction_idle: Option<Duration>,
+
+    /// Dead bytes past which writes compact anyway under `compaction_idle`,
+    /// so a store that is never quiet doesn't grow without bound. `None`
+    /// leaves compaction entirely to quiet periods.
+    pub compaction_ceiling: Option<u64>,
+
+    /// Largest size the data file and the blob file may reach together, in
+    /// bytes. A `put` that wouldn't fit compacts first, and fails with
+    /// `KvsError::StorageFull` if that doesn't free enough. Removes are never
+    /// refused, so space can always be freed. `None` sets no limit.
+    pub max_data_size: Option<u64>,
+
+    /// Bytes of disk space to reserve for the data file whenever it's opened
+    /// or replaced by compaction, so appends within them don't have the
+    /// filesystem allocate blocks as they go. Its length doesn't change, the
+    /// space is only set aside. Best effort: only done on Linux, through
+    /// `fallocate`, and a filesystem that doesn't support it is just logged.
+    /// `None` reserves nothing.
+    pub preallocate: Option<u64>,
+
+    /// How long compaction keeps the tombstone of a removed key, e.g. so a
+    /// lagging replica still gets to see the removal. Zero drops tombstones
+    /// at the first compaction.
+    pub tombstone_grace: Duration,
+
+    /// Values of each live key a compaction keeps, the current one included,
+    /// so the older ones stay in the log for `iter_entries` and `log_from`.
+    /// The values of a removed key go with it. 0 counts as 1, keeping only
+    /// the current value. Keeping more makes every compaction read the log
+    /// once more to count them, and `compaction_estimate` still counts them
+    /// as reclaimable.
+    pub keep_versions: usize,
+
+    /// Number of values kept in an LRU cache in front of the data file,
+    /// 0 disables the cache.
+    pub cache_capacity: usize,
+
+    /// Values of at most this many bytes are also kept in memory next to the
+    /// index, so reading them never goes to the data file. Unlike the cache,
+    /// this holds every such value, loaded when the store is opened. Values
+    /// in the blob file are never kept.
+    pub inline_threshold: Option<usize>,
+
+    /// Write the whole index next to the data file on a clean close, so the
+    /// next open reads it back in one go instead of scanning every entry.
+    /// It is only used if the data file is still as it was closed.
+    pub index_checkpoint: bool,
+
+    /// Called before and after each compaction, and as it progresses.
+    pub on_compaction: Option<CompactionCallback>,
+
+    /// Once cancelled, a compaction in flight stops, deleting what it wrote
+    /// and leaving the data file as it was, and fails with
+    /// `KvsError::CompactionCancelled`. Later ones don't start, e.g. so a
+    /// process can shut down without waiting for one. Writes that would
+    /// have compacted go through without it.
+    pub compaction_cancel: Option<CancellationToken>,
+
+    /// Time source for entry timestamps, `tombstone_grace` and
+    /// `compaction_idle`, e.g. a `MockClock` in tests. `None` uses the system
+    /// clock.
+    pub clock: Option<Arc<dyn Clock>>,
+
+    /// Makes chosen operations on the data file fail.
+    #[cfg(feature = "testing")]
+    pub faults: Option<FaultInjector>,
+}
+
+impl Default for Options {
+    fn default() -> Options {
+        Options {
+            name: DEFAULT_NAME.to_owned(),
+            create: true,
+            create_new: false,
+            index_kind: IndexKind::default(),
+            index_hasher: None,
+            max_key_size: DEFAULT_MAX_KEY_SIZE,
+            max_value_size: DEFAULT_MAX_VALUE_SIZE,
+            read_buffer_capacity: DEFAULT_BUFFER_CAPACITY,
+            write_buffer_capacity: DEFAULT_BUFFER_CAPACITY,
+            bloom_filter: None,
+            blob_threshold: None,
+            auto_compaction: true,
+            commit_interval: None,
+            no_sync: false,
+            compaction_threshold: DEFAULT_COMPACTION_THRESHOLD,
+            compaction_ratio: None,
+            compaction_idle: None,
+            compaction_ceiling: None,
+            max_data_size: None,
+            preallocate: None,
+            tombstone_grace: Duration::ZERO,
+            keep_versions: 1,
+            cache_capacity: 0,
+            inline_threshold: None,
+            index_checkpoint: false,
+            on_compaction: None,
+            compaction_cancel: None,
+            clock: None,
+            #[cfg(feature = "testing")]
+            faults: None,
+        }
+    }
+}
+
+impl Options {
+    /// Milliseconds since the Unix epoch, as told by `clock`.
+    pub(crate) fn now_millis(&self) -> u64 {
+        match &self.clock {
+            Some(clock) => clock.now_millis(),
+            None => storage::unix_millis(),
+        }
+    }
+}
+
+impl fmt::Debug for Options {
+    fn fmt(&self, f: &mut fmt::Formatter<'_>) -> fmt::Result {
+        let mut debug = f.debug_struct("Options");
+        debug
+            .field("name", &self.name)
+            .field("create", &self.create)
+            .field("create_new", &self.create_new)
+            .field("index_kind", &self.index_kind)
+            .field("index_hasher", &self.index_hasher.is_some())
+            .field("max_key_size", &self.max_key_size)
+            .field("max_value_size", &self.max_value_size)
+            .field("read_buffer_capacity", &self.read_buffer_capacity)
+            .field("write_buffer_capacity", &self.write_buffer_capacity)
+            .field("bloom_filter", &self.bloom_filter)
+            .field("blob_threshold", &self.blob_threshold)
+            .field("auto_compaction", &self.auto_compaction)
+            .field("commit_interval", &self.commit_interval)
+            .field("no_sync", &self.no_sync)
+            .field("compaction_threshold", &self.compaction_threshold)
+            .field("compaction_ratio", &self.compaction_ratio)
+            .field("compaction_idle", &self.compaction_idle)
+            .field("compaction_ceiling", &self.compaction_ceiling)
+            .field("max_data_size", &self.max_data_size)
+            .field("preallocate", &self.preallocate)
+            .field("tombstone_grace", &self.tombstone_grace)
+            .field("keep_versions", &self.keep_versions)
+            .field("cache_capacity", &self.cache_capacity)
+            .field("inline_threshold", &self.inline_threshold)
+            .field("index_checkpoint", &self.index_checkpoint)
+            .field("on_compaction", &self.on_compaction.is_some())
+            .field("compaction_cancel", &self.compaction_cancel)
+            .field("clock", &self.clock.is_some());
+        #[cfg(feature = "testing")]
+        debug.field("faults", &self.faults.is_some());
+        debug.finish()
+    }
+}
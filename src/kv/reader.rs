@@ -0,0 +1,471 @@
+use std::collections::{BTreeMap, HashSet};
+use std::io::{BufReader, Read, Seek, SeekFrom, Write};
+use std::ops::Bound;
+use std::path::Path;
+use std::sync::Arc;
+
+use super::error::{KvsError, Result};
+use super::metrics::{Metrics, MetricsSnapshot};
+use super::options::Options;
+use super::storage::{
+    self, CmdKind, CompactionEstimate, CompactionProgress, Entry, KeyState, Page, ReadSource,
+    ScanIter, SimplifiedBitcask, Storage, StoreStats, WriteOp, HEADER_LEN,
+};
+
+/// Read-only storage over the contents of a data file behind any seekable
+/// reader, e.g. a `Cursor` over bytes already in memory or a decompressing
+/// wrapper, so nothing has to be written to disk first.
+///
+/// Writes fail with `KvsError::Unsupported`, and so do values kept in a blob
+/// file, which the reader doesn't cover. A damaged tail is an error rather
+/// than dropped, since it can't be truncated.
+pub struct ReaderStorage<R: Read + Seek> {
+    reader: BufReader<R>,
+
+    /// Length of the log, read once when opened or reopened.
+    len: u64,
+
+    index: BTreeMap<String, u64>,
+
+    tombstones: HashSet<String>,
+
+    metrics: Metrics,
+}
+
+impl<R: Read + Seek> ReaderStorage<R> {
+    pub fn new(reader: R, options: Options) -> Result<ReaderStorage<R>> {
+        let mut storage = ReaderStorage {
+            reader: BufReader::with_capacity(options.read_buffer_capacity, reader),
+            len: 0,
+            index: BTreeMap::new(),
+            tombstones: HashSet::new(),
+            metrics: Metrics::default(),
+        };
+        storage.load()?;
+        Ok(storage)
+    }
+
+    fn load(&mut self) -> Result<()> {
+        self.len = self.reader.seek(SeekFrom::End(0))?;
+        self.reader.seek(SeekFrom::Start(0))?;
+        let mut header = [0; HEADER_LEN as usize];
+        let read = storage::read_full(&mut self.reader, &mut header)?;
+        storage::check_format(&header[..read])?;
+
+        self.index.clear();
+        self.tombstones.clear();
+        // the entries of a batch are held back until all of them are read,
+        // a crash part way through writing it loses the whole batch
+        let mut batch = Vec::new();
+        let mut batch_left = 0;
+        let mut offset = HEADER_LEN;
+        while let Some(e) = self.read_next(offset)? {
+            let size = e.size() as u64;
+            if *e.kind() == CmdKind::BATCH {
+                batch_left = e.version();
+            } else if batch_left > 0 {
+                batch.push((offset, e));
+                batch_left -= 1;
+                if batch_left == 0 {
+                    for (offset, e) in std::mem::take(&mut batch) {
+                        self.apply_loaded(offset, e);
+                    }
+                }
+            } else {
+                self.apply_loaded(offset, e);
+            }
+            offset += size;
+        }
+        Ok(())
+    }
+
+    fn apply_loaded(&mut self, offset: u64, e: Entry) {
+        let key = e.key().to_owned();
+        match e.kind() {
+            CmdKind::DEL => {
+                self.index.remove(&key);
+                self.tombstones.insert(key);
+            }
+            CmdKind::PUT | CmdKind::BLOB => {
+                self.tombstones.remove(&key);
+                self.index.insert(key, offset);
+            }
+            CmdKind::BATCH => {}
+        }
+    }
+
+    /// Writes the live pairs into a store in `dest`, replacing what it held.
+    fn write_snapshot(&mut self, dest: &Path) -> Result<SimplifiedBitcask> {
+        let mut bitcask = SimplifiedBitcask::open(dest.to_path_buf(), Options::default())?;
+        bitcask.clear()?;
+        let found = self.index.iter().map(|(k, v)| (k.clone(), *v)).collect();
+        for (key, val) in self.read_pairs(found)? {
+            bitcask.put(key, val)?;
+        }
+        Ok(bitcask)
+    }
+
+    fn read_next(&mut self, offset: u64) -> Result<Option<Entry>> {
+        storage::read_entry_from(&mut self.reader, offset, self.len)
+    }
+
+    /// Reads the entry an index offset points to, which must hold a value
+    /// kept in the log itself.
+    fn read_at(&mut self, offset: u64) -> Result<Entry> {
+        let e = self
+            .read_next(offset)?
+            .ok_or(KvsError::CorruptedEntry(offset))?;
+        match e.kind() {
+            CmdKind::BLOB => Err(KvsError::Unsupported),
+            _ => Ok(e),
+        }
+    }
+
+    fn read_value(&mut self, offset: u64) -> Result<String> {
+        Ok(self.read_at(offset)?.value().to_owned())
+    }
+
+    fn lookup(&mut self, key: &str) -> Option<u64> {
+        let offset = self.index.get(key).copied();
+        self.metrics.record_get(offset.is_some());
+        offset
+    }
+
+    fn read_pairs(&mut self, found: Vec<(String, u64)>) -> Result<Vec<(String, String)>> {
+        let mut pairs = Vec::with_capacity(found.len());
+        for (key, offset) in found {
+            pairs.push((key, self.read_value(offset)?));
+        }
+        Ok(pairs)
+    }
+}
+
+impl<R: Read + Seek + Send> Storage for ReaderStorage<R> {
+    fn get(&mut self, key: String) -> Result<Option<String>> {
+        match self.lookup(&key) {
+            Some(offset) => Ok(Some(self.read_value(offset)?)),
+            None => Ok(None),
+        }
+    }
+
+    fn get_state(&mut self, key: String) -> Result<KeyState> {
+        if let Some(val) = self.get(key.clone())? {
+            return Ok(KeyState::Present(val));
+        }
+        if self.tombstones.contains(&key) {
+            return Ok(KeyState::Tombstoned);
+        }
+        Ok(KeyState::Absent)
+    }
+
+    fn get_with_source(&mut self, key: String) -> Result<(Option<String>, ReadSource)> {
+        let val = self.get(key)?;
+        let source = match val {
+            Some(_) => ReadSource::IndexHitDisk,
+            None => ReadSource::Miss,
+        };
+        Ok((val, source))
+    }
+
+    fn get_with_meta(&mut self, key: String) -> Result<Option<(String, u64)>> {
+        match self.lookup(&key) {
+            Some(offset) => {
+                let e = self.read_at(offset)?;
+                Ok(Some((e.value().to_owned(), e.timestamp())))
+            }
+            None => Ok(None),
+        }
+    }
+
+    fn get_versioned(&mut self, key: String) -> Result<Option<(String, u64)>> {
+        match self.lookup(&key) {
+            Some(offset) => {
+                let e = self.read_at(offset)?;
+                Ok(Some((e.value().to_owned(), e.version())))
+            }
+            None => Ok(None),
+        }
+    }
+
+    fn key_version(&mut self, key: &str) -> Result<u64> {
+        match self.index.get(key) {
+            Some(offset) => Ok(self.read_at(*offset)?.version()),
+            None => Ok(0),
+        }
+    }
+
+    fn get_into(&mut self, key: &str, buf: &mut Vec<u8>) -> Result<bool> {
+        match self.lookup(key) {
+            Some(offset) => {
+                let val = self.read_value(offset)?;
+                buf.clear();
+                buf.extend_from_slice(val.as_bytes());
+                Ok(true)
+            }
+            None => Ok(false),
+        }
+    }
+
+    fn get_shared(&mut self, key: &str) -> Result<Option<Arc<str>>> {
+        Ok(self.get(key.to_owned())?.map(Arc::from))
+    }
+
+    fn get_many(&mut self, keys: &[String]) -> Result<Vec<Option<String>>> {
+        keys.iter().map(|key| self.get(key.clone())).collect()
+    }
+
+    fn put(&mut self, _key: String, _val: String) -> Result<()> {
+        Err(KvsError::Unsupported)
+    }
+
+    fn put_reader(&mut self, _key: String, _reader: &mut dyn Read, _len: usize) -> Result<()> {
+        Err(KvsError::Unsupported)
+    }
+
+    fn get_writer(&mut self, key: &str, writer: &mut dyn Write) -> Result<bool> {
+        match self.lookup(key) {
+            Some(offset) => {
+                writer.write_all(self.read_value(offset)?.as_bytes())?;
+                Ok(true)
+            }
+            None => Ok(false),
+        }
+    }
+
+    fn put_and_locate(&mut self, _key: String, _val: String) -> Result<u64> {
+        Err(KvsError::Unsupported)
+    }
+
+    fn locate(&mut self, key: &str) -> Result<Option<(u64, usize)>> {
+        let offset = match self.index.get(key) {
+            Some(offset) => *offset,
+            None => return Ok(None),
+        };
+        let e = self
+            .read_next(offset)?
+            .ok_or(KvsError::CorruptedEntry(offset))?;
+        Ok(Some((offset, e.size())))
+    }
+
+    fn get_at(&mut self, offset: u64) -> Result<Option<String>> {
+        if offset < HEADER_LEN {
+            return Err(KvsError::InvalidOffset(offset));
+        }
+        match self.read_next(offset) {
+            Ok(Some(e)) if matches!(e.kind(), CmdKind::DEL | CmdKind::BATCH) => Ok(None),
+            Ok(Some(e)) if *e.kind() == CmdKind::BLOB => Err(KvsError::Unsupported),
+            Ok(Some(e)) => Ok(Some(e.value().to_owned())),
+            Ok(None) => Ok(None),
+            Err(e) if e.is_corruption() => Err(KvsError::InvalidOffset(offset)),
+            Err(e) => Err(e),
+        }
+    }
+
+    fn remove(&mut self, _key: String) -> Result<()> {
+        Err(KvsError::Unsupported)
+    }
+
+    fn rename(&mut self, _old: &str, _new: &str) -> Result<()> {
+        Err(KvsError::Unsupported)
+    }
+
+    fn remove_batch(&mut self, _keys: &[String]) -> Result<Vec<String>> {
+        Err(KvsError::Unsupported)
+    }
+
+    fn apply_batch(&mut self, _ops: Vec<WriteOp>) -> Result<()> {
+        Err(KvsError::Unsupported)
+    }
+
+    fn range(&mut self, start: String, end: String) -> Result<Vec<(String, String)>> {
+        if start >= end {
+            return Ok(Vec::new());
+        }
+        let found = self
+            .index
+            .range(start..end)
+            .map(|(k, v)| (k.clone(), *v))
+            .collect();
+        self.read_pairs(found)
+    }
+
+    fn scan(&mut self) -> Result<Vec<(String, String)>> {
+        let found = self.index.iter().map(|(k, v)| (k.clone(), *v)).collect();
+        self.read_pairs(found)
+    }
+
+    fn scan_iter(&mut self) -> Result<ScanIter> {
+        // nothing can change the log under it, so reading up front is as good
+        Ok(Box::new(self.scan()?.into_iter().map(Ok)))
+    }
+
+    fn iter_in_write_order(&mut self) -> Result<ScanIter> {
+        let mut found: Vec<(String, u64)> =
+            self.index.iter().map(|(k, v)| (k.clone(), *v)).collect();
+        found.sort_unstable_by_key(|(_, offset)| *offset);
+        Ok(Box::new(self.read_pairs(found)?.into_iter().map(Ok)))
+    }
+
+    fn scan_page(&mut self, cursor: Option<String>, limit: usize) -> Result<Page> {
+        // an empty page would read as the end, so a page holds at least one pair
+        let limit = limit.max(1);
+        let lower = match &cursor {
+            Some(cursor) => Bound::Excluded(cursor.as_str()),
+            None => Bound::Unbounded,
+        };
+        let mut found: Vec<(String, u64)> = self
+            .index
+            .range::<str, _>((lower, Bound::Unbounded))
+            .take(limit.saturating_add(1))
+            .map(|(k, v)| (k.clone(), *v))
+            .collect();
+        let mut next = None;
+        if found.len() > limit {
+            found.truncate(limit);
+            next = found.last().map(|(key, _)| key.clone());
+        }
+        Ok((self.read_pairs(found)?, next))
+    }
+
+    fn first_key(&self) -> Result<Option<String>> {
+        Ok(self.index.keys().next().cloned())
+    }
+
+    fn last_key(&self) -> Result<Option<String>> {
+        Ok(self.index.keys().next_back().cloned())
+    }
+
+    fn clear(&mut self) -> Result<()> {
+        Err(KvsError::Unsupported)
+    }
+
+    fn flush(&mut self) -> Result<()> {
+        Ok(())
+    }
+
+    fn sync(&mut self) -> Result<()> {
+        Ok(())
+    }
+
+    fn flush_and_sync(&mut self) -> Result<()> {
+        Ok(())
+    }
+
+    fn snapshot(&mut self, dest: &Path) -> Result<()> {
+        self.write_snapshot(dest)?.flush()
+    }
+
+    fn snapshot_throttled(&mut self, dest: &Path, _bytes_per_sec: u64) -> Result<()> {
+        // there's no file to copy, the entries are written out one by one
+        self.snapshot(dest)
+    }
+
+    fn checkpoint(&mut self, dest: &Path) -> Result<()> {
+        self.write_snapshot(dest)?.flush_and_sync()
+    }
+
+    fn reopen(&mut self) -> Result<()> {
+        self.load()
+    }
+
+    fn metrics(&self) -> MetricsSnapshot {
+        self.metrics.snapshot()
+    }
+
+    fn stats(&self) -> StoreStats {
+        StoreStats {
+            key_count: self.index.len() as u64,
+            file_size: self.len,
+            pending_bytes: 0,
+            clean_shutdown: false,
+            index_from_checkpoint: false,
+        }
+    }
+
+    fn data_path(&self) -> Option<&Path> {
+        None
+    }
+
+    fn size_on_disk(&self) -> Result<u64> {
+        // whatever the reader reads from, this is how much it holds
+        Ok(self.len)
+    }
+
+    fn compact_step(&mut self, _budget: u64) -> Result<CompactionProgress> {
+        Err(KvsError::Unsupported)
+    }
+
+    fn compaction_estimate(&mut self) -> Result<CompactionEstimate> {
+        let mut live_bytes = 0;
+        let offsets: Vec<u64> = self.index.values().copied().collect();
+        for offset in offsets {
+            let e = self
+                .read_next(offset)?
+                .ok_or(KvsError::CorruptedEntry(offset))?;
+            live_bytes += e.size() as u64;
+        }
+        let dead_bytes = self.len - HEADER_LEN - live_bytes;
+        Ok(CompactionEstimate {
+            reclaimable_bytes: dead_bytes,
+            live_bytes,
+            dead_bytes,
+        })
+    }
+
+    fn purge_tombstones(&mut self) -> Result<u64> {
+        Err(KvsError::Unsupported)
+    }
+
+    fn compact_if_needed(&mut self) -> Result<bool> {
+        Ok(false)
+    }
+
+    fn compact_sorted(&mut self) -> Result<u64> {
+        Err(KvsError::Unsupported)
+    }
+
+    fn compact_keys(&mut self, _keys: &[String]) -> Result<u64> {
+        Err(KvsError::Unsupported)
+    }
+
+    fn shrink_index(&mut self) -> (usize, usize) {
+        // a `BTreeMap` frees nodes as keys go
+        (self.index.len(), self.index.len())
+    }
+
+    fn log_from(&mut self, offset: u64) -> Result<Vec<(u64, Entry)>> {
+        let mut entries = Vec::new();
+        let mut offset = offset.max(HEADER_LEN);
+        while let Some(e) = self.read_next(offset)? {
+            if *e.kind() == CmdKind::BLOB {
+                return Err(KvsError::Unsupported);
+            }
+            let size = e.size() as u64;
+            entries.push((offset, e));
+            offset += size;
+        }
+        Ok(entries)
+    }
+
+    fn get_history(&mut self, key: &str) -> Result<Vec<(u64, Option<String>)>> {
+        let mut history = Vec::new();
+        let mut offset = HEADER_LEN;
+        while let Some(e) = self.read_next(offset)? {
+            let size = e.size() as u64;
+            if e.key() == key {
+                let val = match e.kind() {
+                    CmdKind::DEL => None,
+                    CmdKind::BLOB => return Err(KvsError::Unsupported),
+                    CmdKind::PUT => Some(e.value().to_owned()),
+                    CmdKind::BATCH => {
+                        offset += size;
+                        continue;
+                    }
+                };
+                history.push((offset, val));
+            }
+            offset += size;
+        }
+        Ok(history)
+    }
+}
@@ -0,0 +1,87 @@
+use std::io::{Read, Write};
+
+use super::dump;
+use super::error::{KvsError, Result};
+use super::kv_store::KvStore;
+use super::storage::{CmdKind, Entry};
+
+/// Writes the log entries starting at `offset` to `writer`, each as the
+/// big-endian `u64` offset right after it, a kind byte and the key and value
+/// in the dump format. Returns the offset right after the last entry shipped,
+/// to pass as `offset` next time.
+pub fn write_log<W: Write>(writer: &mut W, offset: u64, entries: &[(u64, Entry)]) -> Result<u64> {
+    let mut next = offset;
+    for (pos, e) in entries {
+        // the end is shipped since a blob value doesn't tell how big its entry is
+        next = pos + e.size() as u64;
+        writer.write_all(&next.to_be_bytes())?;
+        writer.write_all(&[*e.kind() as u8])?;
+        dump::write_field(writer, e.key())?;
+        dump::write_field(writer, e.value())?;
+    }
+    writer.flush()?;
+    Ok(next)
+}
+
+/// A store kept in sync by applying the log shipped from a primary with
+/// `KvStore::ship_log`.
+///
+/// Offsets refer to the primary's data file, so they are no longer valid once
+/// the primary compacts: the replica then has to be rebuilt, e.g. with
+/// `export`/`import`, and restarted from the primary's current end.
+pub struct Replica {
+    store: KvStore,
+
+    /// Primary log offset of the next entry to apply.
+    offset: u64,
+}
+
+impl Replica {
+    /// Wraps `store`, which already holds the primary's data up to `offset`.
+    pub fn new(store: KvStore, offset: u64) -> Replica {
+        Replica { store, offset }
+    }
+
+    /// Primary log offset to resume shipping from, e.g. after a reconnect.
+    pub fn offset(&self) -> u64 {
+        self.offset
+    }
+
+    pub fn store(&mut self) -> &mut KvStore {
+        &mut self.store
+    }
+
+    /// Applies shipped entries until the end of `reader` and returns how many
+    /// were applied. Entries before `offset` were already applied and are
+    /// skipped, so shipping the same range twice is harmless.
+    pub fn apply<R: Read>(&mut self, reader: &mut R) -> Result<usize> {
+        let mut applied = 0;
+        while let Some(next) = dump::read_u64(reader)? {
+            let mut kind = [0; 1];
+            reader.read_exact(&mut kind)?;
+            let key = dump::read_field(reader)?.ok_or(KvsError::CorruptedEntry(next))?;
+            let val = dump::read_field(reader)?.ok_or(KvsError::CorruptedEntry(next))?;
+            if next <= self.offset {
+                continue;
+            }
+
+            match kind[0] {
+                k if k == CmdKind::PUT as u8 => self.store.set(key, val)?,
+                k if k == CmdKind::DEL as u8 => match self.store.remove(key) {
+                    Ok(()) | Err(KvsError::KeyNotFound) => {}
+                    Err(err) => return Err(err),
+                },
+                // the entries of the batch follow, applied one by one
+                k if k == CmdKind::BATCH as u8 => {
+                    self.offset = next;
+                    continue;
+                }
+                _ => return Err(KvsError::CorruptedEntry(next)),
+            }
+            self.offset = next;
+            applied += 1;
+        }
+        self.store.flush()?;
+        Ok(applied)
+    }
+}
@@ -0,0 +1,71 @@
+use std::path::Path;
+use std::sync::Mutex;
+
+use super::error::Result;
+use super::kv_store::KvStore;
+use super::options::Options;
+use super::storage;
+
+/// Keys spread over several independent stores in one directory, so writes
+/// to different shards can run in parallel.
+///
+/// Shard `i` lives in `<name>.<i>.data`. A key's shard depends on the shard
+/// count, so a directory must always be opened with the same count.
+///
+/// Shards spawn no threads of their own: each compacts inline on the thread
+/// whose write crosses its `compaction_threshold`, so any number of shards
+/// compact on the callers' threads alone.
+pub struct ShardedKvStore {
+    shards: Vec<Mutex<KvStore>>,
+}
+
+impl ShardedKvStore {
+    pub fn open(path: &Path, shard_count: usize) -> Result<ShardedKvStore> {
+        ShardedKvStore::open_with_options(path, shard_count, Options::default())
+    }
+
+    /// Opens `shard_count` stores, all with `options` apart from their name.
+    pub fn open_with_options(
+        path: &Path,
+        shard_count: usize,
+        options: Options,
+    ) -> Result<ShardedKvStore> {
+        assert!(shard_count > 0, "a sharded store needs at least one shard");
+        let mut shards = Vec::with_capacity(shard_count);
+        for i in 0..shard_count {
+            let shard_options = Options {
+                name: format!("{}.{}", options.name, i),
+                ..options.clone()
+            };
+            shards.push(Mutex::new(KvStore::open_with_options(path, shard_options)?));
+        }
+        Ok(ShardedKvStore { shards })
+    }
+
+    pub fn shard_count(&self) -> usize {
+        self.shards.len()
+    }
+
+    pub fn get(&self, key: String) -> Result<Option<String>> {
+        self.shard(&key).lock().unwrap().get(key)
+    }
+
+    pub fn set(&self, key: String, val: String) -> Result<()> {
+        self.shard(&key).lock().unwrap().set(key, val)
+    }
+
+    pub fn remove(&self, key: String) -> Result<()> {
+        self.shard(&key).lock().unwrap().remove(key)
+    }
+
+    pub fn flush(&self) -> Result<()> {
+        for shard in &self.shards {
+            shard.lock().unwrap().flush()?;
+        }
+        Ok(())
+    }
+
+    fn shard(&self, key: &str) -> &Mutex<KvStore> {
+        &self.shards[(storage::fnv1a(key.as_bytes()) % self.shards.len() as u64) as usize]
+    }
+}
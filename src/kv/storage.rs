@@ -1,28 +1,91 @@
-use std::collections::HashMap;
+#[cfg(feature = "mmap")]
+use std::borrow::Borrow;
+use std::collections::{HashMap, HashSet};
 use std::convert::TryInto;
 use std::fs::{File, OpenOptions};
 use std::io;
-use std::io::{BufReader, BufWriter, Read, Seek, SeekFrom, Write};
-use std::path::PathBuf;
+use std::io::{BufRead, BufReader, BufWriter, Read, Seek, SeekFrom, Write};
+use std::path::{Path, PathBuf};
+use std::sync::Arc;
+use std::time::{Duration, Instant, SystemTime, UNIX_EPOCH};
 
+use log::{debug, error, info, warn};
 use serde::{Deserialize, Serialize};
 
 use serde_repr::*;
 
+use super::blob::{BlobFile, BlobRef};
+use super::bloom::BloomFilter;
+use super::cache::LruCache;
+use super::commit::Committer;
 use super::error::{KvsError, Result};
+#[cfg(feature = "testing")]
+use super::fault::FaultyFile;
+use super::index::Index;
+use super::metrics::{Metrics, MetricsSnapshot};
+use super::options::{CancellationToken, CompactionInfo, Options};
 
-const STORAGE_FILE_PREFIX: &str = "miniDB";
-const COMPACTION_THRESHOLD: u64 = 1 << 16;
-const USIZE_LEN: usize = std::mem::size_of::<usize>();
-const ENTRY_HEAD_LEN: usize = USIZE_LEN * 2 + 1;
+/// Key and value lengths are stored as big-endian `u64`s whatever the width
+/// of `usize`, so data files written on 64-bit hosts read on 32-bit ones.
+const LEN_FIELD_LEN: usize = std::mem::size_of::<u64>();
+const TIMESTAMP_LEN: usize = std::mem::size_of::<u64>();
+const VERSION_LEN: usize = std::mem::size_of::<u64>();
+pub(crate) const ENTRY_HEAD_LEN: usize = V2_HEAD_LEN + VERSION_LEN;
+/// Header length of format versions 0 and 1, which had no timestamps.
+const LEGACY_HEAD_LEN: usize = LEN_FIELD_LEN * 2 + 1;
+/// Header length of format version 2, which had no key versions.
+const V2_HEAD_LEN: usize = LEGACY_HEAD_LEN + TIMESTAMP_LEN;
+/// Start of every data file, followed by the format version.
+const FORMAT_MAGIC: &[u8; 4] = b"mnDB";
+/// Bump whenever the entry encoding changes.
+const FORMAT_VERSION: u32 = 3;
+/// Length of the magic and version written at the start of the data file,
+/// the first entry follows it.
+pub(crate) const HEADER_LEN: u64 = 8;
+/// Handle the data file is read and written through, which can be made to
+/// fail on demand with the `testing` feature.
+#[cfg(not(feature = "testing"))]
+type LogFile = File;
+#[cfg(feature = "testing")]
+type LogFile = FaultyFile;
+/// Size of the pieces `put_reader` copies a value in.
+const COPY_CHUNK_LEN: usize = 8 * 1024;
+/// Bytes a compaction scans between two `CompactionInfo::Progress` reports.
+const PROGRESS_INTERVAL: u64 = 1 << 20;
+/// Smallest data file worth loading in parallel.
+#[cfg(feature = "rayon")]
+const PARALLEL_LOAD_MIN_LEN: u64 = 1 << 20;
+/// Magic starting the footer written next to the data file on a clean close.
+const FOOTER_MAGIC: &[u8; 4] = b"mnDF";
+/// Magic, end of the log and checksum.
+const FOOTER_LEN: usize = FOOTER_MAGIC.len() + 2 * std::mem::size_of::<u64>();
+/// Bytes at the end of the log covered by the footer checksum.
+const FOOTER_TAIL_LEN: u64 = 4096;
+/// Longest key and value a torn entry may claim, see `is_torn_tail`. Far past
+/// any size limit in use, while a flipped high bit in a length goes over it.
+const MAX_TORN_BODY_LEN: u64 = u32::MAX as u64;
+/// Magic starting the index checkpoint, see `Options::index_checkpoint`.
+const CHECKPOINT_MAGIC: &[u8; 4] = b"mnIX";
 
-#[derive(Serialize_repr, Deserialize_repr, PartialEq, Debug)]
+/// What a log entry does to its key.
+#[derive(Serialize_repr, Deserialize_repr, Clone, Copy, PartialEq, Eq, Hash, Debug)]
 #[repr(u8)]
 pub enum CmdKind {
+    /// A put with the value in the entry.
     PUT = 1,
+    /// A removal, the entry has no value.
     DEL = 2,
+    /// A put whose value is kept in the blob file, the entry only holds a
+    /// reference to it.
+    BLOB = 3,
+    /// Starts the entries written by one `Storage::apply_batch`, which are
+    /// only loaded if all of them made it to disk. It has no key or value,
+    /// and holds the number of entries following it in place of a version.
+    BATCH = 4,
 }
 
+/// One write in the log, as yielded by `SimplifiedBitcask::iter_entries`
+/// or shipped by `KvStore::ship_log`.
 #[derive(Serialize, Deserialize, Debug)]
 pub struct Entry {
     key_len: usize,
@@ -34,261 +97,3866 @@ pub struct Entry {
     value: String,
 
     kind: CmdKind,
+
+    /// Milliseconds since the Unix epoch when the entry was written.
+    timestamp: u64,
+
+    /// Number of writes to the key so far, this one included.
+    version: u64,
+}
+
+impl Entry {
+    /// Creates an entry stamped with the current time. Its version is
+    /// assigned when it's written.
+    pub fn new(key: String, value: String, kind: CmdKind) -> Entry {
+        Entry {
+            key_len: key.len(),
+            value_len: value.len(),
+            key,
+            value,
+            kind,
+            timestamp: unix_millis(),
+            version: 0,
+        }
+    }
+
+    pub fn key(&self) -> &str {
+        &self.key
+    }
+
+    /// The value of a put, empty for a removal. Entries read straight from
+    /// the log hold the reference to the blob for `CmdKind::BLOB`.
+    pub fn value(&self) -> &str {
+        &self.value
+    }
+
+    pub fn kind(&self) -> &CmdKind {
+        &self.kind
+    }
+
+    /// Milliseconds since the Unix epoch when the entry was written, 0 for
+    /// entries migrated from a format without timestamps.
+    pub fn timestamp(&self) -> u64 {
+        self.timestamp
+    }
+
+    /// Version of the key this entry wrote, see `KvStore::get_versioned`.
+    pub fn version(&self) -> u64 {
+        self.version
+    }
+
+    /// Bytes the entry takes up in the data file. For an entry read back with
+    /// its blob filled in, that's the size of the reference, not the value.
+    pub fn size(&self) -> usize {
+        ENTRY_HEAD_LEN + self.key_len + self.value_len
+    }
+
+    pub fn encode(&self) -> Vec<u8> {
+        let key_len = self.key.len();
+        let value_len = self.value.len();
+        let head = EntryHeader {
+            key_len,
+            value_len,
+            kind: self.kind,
+            timestamp: self.timestamp,
+            version: self.version,
+        };
+        let mut buf = vec![0; ENTRY_HEAD_LEN + key_len + value_len];
+        buf[..ENTRY_HEAD_LEN].copy_from_slice(&head.encode());
+
+        // encode key
+        buf[ENTRY_HEAD_LEN..ENTRY_HEAD_LEN + key_len].copy_from_slice(self.key.as_bytes());
+
+        // encode value
+        buf[ENTRY_HEAD_LEN + key_len..].copy_from_slice(self.value.as_bytes());
+
+        buf
+    }
+
+    /// Completes the entry `head` starts with from the key and value that
+    /// follow it.
+    pub fn from_header(head: EntryHeader, key: String, value: String) -> Entry {
+        Entry {
+            key_len: head.key_len,
+            value_len: head.value_len,
+            key,
+            value,
+            kind: head.kind,
+            timestamp: head.timestamp,
+            version: head.version,
+        }
+    }
+}
+
+/// Fixed size start of an encoded entry, telling how long the key and value
+/// following it are.
+#[derive(Clone, Copy, Debug, PartialEq, Eq)]
+pub struct EntryHeader {
+    pub key_len: usize,
+
+    pub value_len: usize,
+
+    pub kind: CmdKind,
+
+    pub timestamp: u64,
+
+    pub version: u64,
+}
+
+impl EntryHeader {
+    pub fn encode(&self) -> [u8; ENTRY_HEAD_LEN] {
+        let mut buf = [0; ENTRY_HEAD_LEN];
+        // encode key len
+        buf[0..LEN_FIELD_LEN].copy_from_slice(&(self.key_len as u64).to_be_bytes());
+
+        // encode value length
+        buf[LEN_FIELD_LEN..LEN_FIELD_LEN * 2]
+            .copy_from_slice(&(self.value_len as u64).to_be_bytes());
+
+        // encode kind
+        buf[LEN_FIELD_LEN * 2..LEGACY_HEAD_LEN]
+            .copy_from_slice(bincode::serialize(&self.kind).unwrap().as_slice());
+
+        // encode timestamp
+        buf[LEGACY_HEAD_LEN..V2_HEAD_LEN].copy_from_slice(&self.timestamp.to_be_bytes());
+
+        // encode version
+        buf[V2_HEAD_LEN..ENTRY_HEAD_LEN].copy_from_slice(&self.version.to_be_bytes());
+
+        buf
+    }
+
+    pub fn decode(b: &[u8; ENTRY_HEAD_LEN]) -> Result<EntryHeader> {
+        let key_len = decode_len(&b[0..LEN_FIELD_LEN])?;
+        let value_len = decode_len(&b[LEN_FIELD_LEN..LEN_FIELD_LEN * 2])?;
+        let kind: CmdKind = bincode::deserialize(&b[LEN_FIELD_LEN * 2..LEGACY_HEAD_LEN])?;
+        let timestamp = u64::from_be_bytes(b[LEGACY_HEAD_LEN..V2_HEAD_LEN].try_into()?);
+        let version = u64::from_be_bytes(b[V2_HEAD_LEN..ENTRY_HEAD_LEN].try_into()?);
+        Ok(EntryHeader {
+            key_len,
+            value_len,
+            kind,
+            timestamp,
+            version,
+        })
+    }
+
+    /// Size of the whole entry, header included.
+    pub fn entry_size(&self) -> usize {
+        ENTRY_HEAD_LEN + self.key_len + self.value_len
+    }
+}
+
+/// Reads a stored length. One that doesn't fit a `usize` saturates, so adding
+/// it to another overflows and `check_entry` rejects it like any other length
+/// running past the end of the file.
+fn decode_len(b: &[u8]) -> Result<usize> {
+    let len = u64::from_be_bytes(b.try_into()?);
+    Ok(usize::try_from(len).unwrap_or(usize::MAX))
+}
+
+/// Pairs returned by a `scan_page` call and the cursor for the next one.
+pub type Page = (Vec<(String, String)>, Option<String>);
+
+/// Pairs returned by a `scan_iter` call, read as the iterator advances.
+pub type ScanIter = Box<dyn Iterator<Item = Result<(String, String)>> + Send>;
+
+/// Where a `get_with_source` call found its answer.
+#[derive(Clone, Copy, Debug, PartialEq, Eq)]
+pub enum ReadSource {
+    /// The key was in the index and its value was read from storage.
+    IndexHitDisk,
+
+    /// The value came from the read cache, see `Options::cache_capacity`.
+    CacheHit,
+
+    /// The value is kept in memory next to the index, see
+    /// `Options::inline_threshold`.
+    InlineHit,
+
+    /// The key wasn't found, possibly without consulting the index at all
+    /// when a bloom filter ruled it out.
+    Miss,
+}
+
+/// What the store knows about a key.
+#[derive(Clone, Debug, PartialEq, Eq)]
+pub enum KeyState {
+    /// The key holds this value.
+    Present(String),
+
+    /// The key was removed and its tombstone is still in the log.
+    Tombstoned,
+
+    /// The key was never written, or its tombstone was compacted away.
+    Absent,
+}
+
+/// One write of a batch given to `Storage::apply_batch`.
+#[derive(Clone, Debug, PartialEq, Eq)]
+pub enum WriteOp {
+    Put {
+        key: String,
+        val: String,
+    },
+
+    /// Removes `key`, which has to exist once the ops before it are applied.
+    Delete {
+        key: String,
+    },
+}
+
+impl WriteOp {
+    pub fn key(&self) -> &str {
+        match self {
+            WriteOp::Put { key, .. } | WriteOp::Delete { key } => key,
+        }
+    }
+}
+
+pub trait Storage: Send {
+    fn get(&mut self, key: String) -> Result<Option<String>>;
+
+    fn get_state(&mut self, key: String) -> Result<KeyState>;
+
+    /// Like `get`, also telling where the value came from.
+    fn get_with_source(&mut self, key: String) -> Result<(Option<String>, ReadSource)>;
+
+    /// Value of `key` with the time it was written, in milliseconds since the
+    /// Unix epoch.
+    fn get_with_meta(&mut self, key: String) -> Result<Option<(String, u64)>>;
+
+    /// Value of `key` with its version, the number of writes to it so far.
+    fn get_versioned(&mut self, key: String) -> Result<Option<(String, u64)>>;
+
+    /// Version of `key` while it's live, 0 otherwise.
+    fn key_version(&mut self, key: &str) -> Result<u64>;
+
+    /// Replaces the contents of `buf` with the value of `key`, returning
+    /// false and leaving `buf` alone if there is none.
+    fn get_into(&mut self, key: &str, buf: &mut Vec<u8>) -> Result<bool>;
+
+    /// Like `get`, handing out the value the read cache holds rather than a
+    /// copy of it. Values that aren't cached are copied once.
+    fn get_shared(&mut self, key: &str) -> Result<Option<Arc<str>>>;
+
+    /// Values of `keys`, in the same order as `keys`.
+    fn get_many(&mut self, keys: &[String]) -> Result<Vec<Option<String>>>;
+
+    fn put(&mut self, key: String, val: String) -> Result<()>;
+
+    /// Stores the next `len` bytes of `reader` as the value of `key` without
+    /// holding them in memory all at once. Nothing is stored if `reader` runs
+    /// out early or the bytes aren't valid UTF-8.
+    fn put_reader(&mut self, key: String, reader: &mut dyn Read, len: usize) -> Result<()>;
+
+    /// Writes the value of `key` to `writer` without holding it in memory
+    /// all at once, returning whether the key exists.
+    fn get_writer(&mut self, key: &str, writer: &mut dyn Write) -> Result<bool>;
+
+    /// Like `put`, returning the offset the entry ended up at.
+    fn put_and_locate(&mut self, key: String, val: String) -> Result<u64>;
+
+    /// Offset and size in the data file of the live entry of `key`, read off
+    /// the index and the entry header.
+    fn locate(&mut self, key: &str) -> Result<Option<(u64, usize)>>;
+
+    /// Value of the entry starting at `offset`, `None` for a tombstone or the
+    /// end of the log.
+    fn get_at(&mut self, offset: u64) -> Result<Option<String>>;
+
+    fn remove(&mut self, key: String) -> Result<()>;
+
+    /// Moves the value of `old` to `new`, overwriting any value of `new`, and
+    /// removes `old`. Fails with `KvsError::KeyNotFound` if `old` doesn't
+    /// exist.
+    fn rename(&mut self, old: &str, new: &str) -> Result<()>;
+
+    /// Removes every key of `keys` that exists, checking whether to compact
+    /// once at the end. Returns the keys that didn't exist.
+    fn remove_batch(&mut self, keys: &[String]) -> Result<Vec<String>>;
+
+    /// Applies `ops` in order, all of them or none: a crash part way through
+    /// writing them loses the whole batch, and a `WriteOp::Delete` of a key
+    /// that doesn't exist fails it with `KvsError::KeyNotFound` before
+    /// anything is written. The batch is synced unless `Options::no_sync`.
+    fn apply_batch(&mut self, ops: Vec<WriteOp>) -> Result<()>;
+
+    fn range(&mut self, start: String, end: String) -> Result<Vec<(String, String)>>;
+
+    /// All live key/value pairs, sorted by key.
+    fn scan(&mut self) -> Result<Vec<(String, String)>>;
+
+    /// Like `scan`, reading values only as the iterator advances. Later
+    /// writes don't show up in it.
+    fn scan_iter(&mut self) -> Result<ScanIter>;
+
+    /// Like `scan_iter`, but in the order the pairs were last written rather
+    /// than by key. Backends without a log return `KvsError::Unsupported`.
+    fn iter_in_write_order(&mut self) -> Result<ScanIter>;
+
+    /// Up to `limit` pairs with keys after `cursor`, sorted by key, and the
+    /// cursor for the next page, `None` once there is nothing left. A `limit`
+    /// of 0 counts as 1.
+    fn scan_page(&mut self, cursor: Option<String>, limit: usize) -> Result<Page>;
+
+    /// Smallest live key.
+    fn first_key(&self) -> Result<Option<String>>;
+
+    /// Largest live key.
+    fn last_key(&self) -> Result<Option<String>>;
+
+    fn clear(&mut self) -> Result<()>;
+
+    fn flush(&mut self) -> Result<()>;
+
+    /// Flushes and waits until the data file is on stable storage.
+    fn sync(&mut self) -> Result<()>;
+
+    /// Like `sync`, also syncing the metadata of the files and the directory
+    /// holding them, so a file created or renamed in since is found again
+    /// after a crash.
+    fn flush_and_sync(&mut self) -> Result<()>;
+
+    /// Writes a store holding the current live data into the directory `dest`.
+    fn snapshot(&mut self, dest: &Path) -> Result<()>;
+
+    /// Like `snapshot`, copying files at no more than `bytes_per_sec`.
+    fn snapshot_throttled(&mut self, dest: &Path, bytes_per_sec: u64) -> Result<()>;
+
+    /// Like `snapshot`, returning only once the copy in `dest` is on stable
+    /// storage, directory entries included, whether or not the store syncs
+    /// its own files.
+    fn checkpoint(&mut self, dest: &Path) -> Result<()>;
+
+    /// Rebuilds the in-memory state from the data file, picking up changes
+    /// made to it behind the store's back, e.g. by `repair` or another tool.
+    fn reopen(&mut self) -> Result<()>;
+
+    fn metrics(&self) -> MetricsSnapshot;
+
+    /// Size of the stored data right now.
+    fn stats(&self) -> StoreStats;
+
+    /// Path of the data file, `None` for backends that don't have one.
+    fn data_path(&self) -> Option<&Path>;
+
+    /// Bytes the store takes in its files, as the file system reports them.
+    /// Backends without files estimate what a snapshot of them would take.
+    fn size_on_disk(&self) -> Result<u64>;
+
+    /// Does a bounded slice of compaction work, scanning about `budget` bytes
+    /// of the log per call.
+    fn compact_step(&mut self, budget: u64) -> Result<CompactionProgress>;
+
+    /// What a compaction would do right now, without doing it.
+    fn compaction_estimate(&mut self) -> Result<CompactionEstimate>;
+
+    /// Compacts right away to drop every tombstone past its grace period,
+    /// returning how many were dropped.
+    fn purge_tombstones(&mut self) -> Result<u64>;
+
+    /// Rewrites the data file without the overwritten entries and expired
+    /// tombstones of `keys`, leaving those of other keys for a later
+    /// compaction. Returns the number of bytes reclaimed.
+    fn compact_keys(&mut self, keys: &[String]) -> Result<u64>;
+
+    /// Releases memory the index holds for keys that are gone, returning how
+    /// many entries it had room for before and after.
+    fn shrink_index(&mut self) -> (usize, usize);
+
+    /// Compacts if as much is dead as would make a write compact, whether or
+    /// not writes do, returning whether it did.
+    fn compact_if_needed(&mut self) -> Result<bool>;
+
+    /// Compacts right away, writing the live entries in key order so scans
+    /// and ranges afterwards read the data file sequentially. Returns how many
+    /// tombstones were dropped, like `purge_tombstones`.
+    fn compact_sorted(&mut self) -> Result<u64>;
+
+    /// Log entries from `offset` on, in write order, with their offsets and
+    /// any blob values filled in. Backends without a log return nothing.
+    fn log_from(&mut self, offset: u64) -> Result<Vec<(u64, Entry)>>;
+
+    /// Every value of `key` still in the log, in write order with their
+    /// offsets, and `None` where it was removed. Backends without a log
+    /// return nothing.
+    fn get_history(&mut self, key: &str) -> Result<Vec<(u64, Option<String>)>>;
+}
+
+/// Key, old offset, new offset and kind of each entry copied by a merge.
+type MovedEntries = Vec<(String, u64, u64, CmdKind)>;
+
+/// Older values of each live key a merge drops before it gets to the ones
+/// `keep_versions` keeps, see `SimplifiedBitcask::old_versions`.
+type OldVersions = HashMap<String, usize>;
+
+/// Order a merge writes the entries it keeps in.
+#[derive(Clone, Copy)]
+enum MergeOrder {
+    /// As they appear in the log, read in one pass.
+    Log,
+
+    /// Sorted by key, so reading keys in order later reads the file front to
+    /// back.
+    Key,
+}
+
+/// Result of a `compact_step` call.
+#[derive(Clone, Copy, Debug, PartialEq, Eq)]
+pub enum CompactionProgress {
+    /// More calls are needed, `scanned` of `total` bytes have been processed.
+    Pending { scanned: u64, total: u64 },
+
+    /// The compacted file has replaced the data file.
+    Finished,
+}
+
+/// Result of a `compaction_estimate` call.
+#[derive(Clone, Copy, Debug, Default, PartialEq, Eq)]
+pub struct CompactionEstimate {
+    /// Bytes a compaction would free, the dead bytes less those of tombstones
+    /// kept for `Options::tombstone_grace`.
+    pub reclaimable_bytes: u64,
+
+    /// Bytes taken by the entries holding the current values.
+    pub live_bytes: u64,
+
+    /// Bytes taken by overwritten entries and tombstones.
+    pub dead_bytes: u64,
+}
+
+/// Result of a `stats` call.
+#[derive(Clone, Copy, Debug, Default, PartialEq, Eq)]
+pub struct StoreStats {
+    /// Live keys.
+    pub key_count: u64,
+
+    /// Bytes in the data file, buffered writes included. Values kept in the
+    /// blob file don't count.
+    pub file_size: u64,
+
+    /// Bytes overwritten or removed since the store was opened or last
+    /// compacted, which count toward the next compaction. Dead bytes found
+    /// while loading don't, see `compaction_estimate` for those.
+    pub pending_bytes: u64,
+
+    /// Whether the store was opened from a data file closed cleanly and left
+    /// alone since, as told by the footer written next to it on close. Always
+    /// false for backends without files.
+    pub clean_shutdown: bool,
+
+    /// Whether the index was read from the checkpoint written on the last
+    /// close rather than rebuilt from the data file, see
+    /// `Options::index_checkpoint`.
+    pub index_from_checkpoint: bool,
+}
+
+/// The index as written on a clean close under `Options::index_checkpoint`,
+/// along with the end and `tail_checksum` of the data file it was taken from.
+#[derive(Serialize, Deserialize)]
+struct IndexCheckpoint {
+    end: u64,
+    checksum: u64,
+    keys: Vec<(String, u64)>,
+    tombstones: Vec<(String, u64)>,
+}
+
+/// Result of a `verify` call.
+#[derive(Clone, Copy, Debug, Default, PartialEq, Eq)]
+pub struct VerifyReport {
+    /// Entries that decoded cleanly, up to the first corrupted one.
+    pub valid_entries: u64,
+
+    /// Offset of the first entry that can't be read back, `None` if the whole
+    /// file is intact.
+    pub first_corruption: Option<u64>,
+
+    /// Bytes checked, the file header included. Stops short of the file
+    /// length when there is a corruption.
+    pub bytes_scanned: u64,
+}
+
+/// State of a compaction spread over several `compact_step` calls.
+struct IncrementalMerge {
+    path_buf: PathBuf,
+
+    writer: BufWriterWithPos<File>,
+
+    /// Next entry to look at in the data file.
+    scan_offset: u64,
+
+    /// End of the data file when the compaction started, later writes are
+    /// copied over as they are once the scan reaches this point.
+    end: u64,
+
+    pending_at_start: u64,
+
+    /// Keys copied to the merge file with their old and new offsets.
+    relocated: Vec<(String, u64, u64)>,
+
+    /// Like `relocated`, for tombstones still within their grace period.
+    relocated_tombstones: Vec<(String, u64, u64)>,
+
+    /// Counted over the log up to `end` when the compaction started.
+    old_versions: OldVersions,
+}
+
+impl IncrementalMerge {
+    fn discard(self) {
+        drop(self.writer);
+        let _ = std::fs::remove_file(self.path_buf.as_path());
+    }
+}
+
+/// The backend `KvStore::open` uses: an append-only data file with an
+/// in-memory index of where each live key was last written.
+pub struct SimplifiedBitcask {
+    data_path_buf: PathBuf,
+
+    reader: BufReaderWithPos<LogFile>,
+
+    writer: BufWriterWithPos<LogFile>,
+
+    index: Index,
+
+    /// Every key ever put since the store was opened, if enabled.
+    bloom: Option<BloomFilter>,
+
+    /// Values over `blob_threshold`, opened on first use.
+    blobs: Option<BlobFile>,
+
+    /// Recently read values, only present when `cache_capacity` is set.
+    cache: Option<LruCache>,
+
+    /// Every live value of at most `inline_threshold` bytes.
+    inline: HashMap<String, Arc<str>>,
+
+    /// Removed keys with the offset of their tombstone in the data file.
+    tombstones: HashMap<String, u64>,
+
+    pending_compact: u64,
+
+    /// When the last write went through, in milliseconds since the Unix
+    /// epoch by `Options::clock`, for `Options::compaction_idle`.
+    last_write: Option<u64>,
+
+    options: Options,
+
+    metrics: Metrics,
+
+    incremental: Option<IncrementalMerge>,
+
+    /// Started by the first write when `commit_interval` is set.
+    committer: Option<Committer>,
+
+    /// Whether the footer found when opening vouched for the data file, see
+    /// `take_footer`.
+    clean_shutdown: bool,
+
+    /// Whether the index was read from the checkpoint, see `load_checkpoint`.
+    index_from_checkpoint: bool,
+
+    /// Whether the index was loaded from the whole data file. Until it is,
+    /// e.g. when loading failed part way, closing must not vouch for the
+    /// file with a footer.
+    loaded: bool,
+
+    #[cfg(feature = "mmap")]
+    map: Option<memmap2::Mmap>,
 }
 
-impl Entry {
-    pub fn new(key: String, value: String, kind: CmdKind) -> Entry {
-        Entry {
-            key_len: key.as_bytes().len(),
-            value_len: value.as_bytes().len(),
-            key,
-            value,
-            kind,
+impl Storage for SimplifiedBitcask {
+    fn get(&mut self, key: String) -> Result<Option<String>> {
+        match self.read(&key) {
+            Ok(val) => Ok(Some(val)),
+            Err(KvsError::KeyNotFound) => Ok(None),
+            Err(e) => Err(e),
+        }
+    }
+
+    fn get_state(&mut self, key: String) -> Result<KeyState> {
+        match self.read(&key) {
+            Ok(val) => Ok(KeyState::Present(val)),
+            Err(KvsError::KeyNotFound) if self.tombstones.contains_key(&key) => {
+                Ok(KeyState::Tombstoned)
+            }
+            Err(KvsError::KeyNotFound) => Ok(KeyState::Absent),
+            Err(e) => Err(e),
+        }
+    }
+
+    fn get_with_source(&mut self, key: String) -> Result<(Option<String>, ReadSource)> {
+        match self.read_with_source(&key)? {
+            Some((val, source)) => Ok((Some(val), source)),
+            None => Ok((None, ReadSource::Miss)),
+        }
+    }
+
+    fn get_with_meta(&mut self, key: String) -> Result<Option<(String, u64)>> {
+        let offset = match self.index_get(&key)? {
+            Some(offset) => offset,
+            None => {
+                self.metrics.record_get(false);
+                return Ok(None);
+            }
+        };
+        self.metrics.record_get(true);
+        let e = self.read_at(offset)?;
+        let e = self.resolve(e, offset)?;
+        Ok(Some((e.value, e.timestamp)))
+    }
+
+    fn get_versioned(&mut self, key: String) -> Result<Option<(String, u64)>> {
+        let offset = match self.index_get(&key)? {
+            Some(offset) => offset,
+            None => {
+                self.metrics.record_get(false);
+                return Ok(None);
+            }
+        };
+        self.metrics.record_get(true);
+        let e = self.read_at(offset)?;
+        let e = self.resolve(e, offset)?;
+        Ok(Some((e.value, e.version)))
+    }
+
+    fn key_version(&mut self, key: &str) -> Result<u64> {
+        match self.index_get(key)? {
+            Some(offset) => Ok(self.read_header(offset)?.version),
+            None => Ok(0),
+        }
+    }
+
+    fn get_into(&mut self, key: &str, buf: &mut Vec<u8>) -> Result<bool> {
+        if let Some(val) = self.get_inline(key) {
+            buf.clear();
+            buf.extend_from_slice(val.as_bytes());
+            return Ok(true);
+        }
+        if let Some(bloom) = &self.bloom {
+            if !bloom.may_contain(key) {
+                self.metrics.record_get(false);
+                return Ok(false);
+            }
+        }
+        let offset = match self.index_get(key)? {
+            Some(offset) => offset,
+            None => {
+                self.metrics.record_get(false);
+                return Ok(false);
+            }
+        };
+        self.metrics.record_get(true);
+        buf.clear();
+        if let Some(cache) = &mut self.cache {
+            let cached = cache.get(key);
+            self.metrics.record_cache_lookup(cached.is_some());
+            if let Some(val) = cached {
+                buf.extend_from_slice(val.as_bytes());
+                return Ok(true);
+            }
+        }
+        self.read_value_into(offset, buf)?;
+        Ok(true)
+    }
+
+    fn get_shared(&mut self, key: &str) -> Result<Option<Arc<str>>> {
+        self.read_shared(key)
+    }
+
+    fn get_many(&mut self, keys: &[String]) -> Result<Vec<Option<String>>> {
+        let mut values = vec![None; keys.len()];
+        let mut pending = Vec::with_capacity(keys.len());
+        for (i, key) in keys.iter().enumerate() {
+            if let Some(val) = self.get_inline(key) {
+                values[i] = Some(val.to_string());
+                continue;
+            }
+            let offset = match self.index_get(key)? {
+                Some(offset) => offset,
+                None => {
+                    self.metrics.record_get(false);
+                    continue;
+                }
+            };
+            self.metrics.record_get(true);
+            if let Some(val) = self.cache.as_mut().and_then(|cache| cache.get(key)) {
+                self.metrics.record_cache_lookup(true);
+                values[i] = Some(val.to_string());
+                continue;
+            }
+            pending.push((offset, i));
+        }
+
+        // read in file order so the reader mostly moves forward
+        pending.sort_unstable();
+        for (offset, i) in pending {
+            let val = self.read_value(offset)?;
+            if let Some(cache) = &mut self.cache {
+                self.metrics.record_cache_lookup(false);
+                cache.insert(keys[i].clone(), Arc::from(val.as_str()));
+            }
+            values[i] = Some(val);
+        }
+        Ok(values)
+    }
+
+    fn put(&mut self, key: String, val: String) -> Result<()> {
+        check_key_size(key.len(), self.options.max_key_size)?;
+        check_size(val.len(), self.options.max_value_size)?;
+        let to_blob = self.to_blob(val.len());
+        self.make_room_for(&key, val.len(), to_blob)?;
+        let e = if to_blob {
+            let blob = self.blobs()?.append(&val)?;
+            Entry::new(key, blob.encode(), CmdKind::BLOB)
+        } else {
+            Entry::new(key, val, CmdKind::PUT)
+        };
+        self.write(e)?;
+        self.metrics.record_put();
+        self.after_write()?;
+        Ok(())
+    }
+
+    fn put_reader(&mut self, key: String, reader: &mut dyn Read, len: usize) -> Result<()> {
+        check_key_size(key.len(), self.options.max_key_size)?;
+        check_size(len, self.options.max_value_size)?;
+        let to_blob = self.to_blob(len);
+        self.make_room_for(&key, len, to_blob)?;
+        if to_blob {
+            let blob = self.blobs()?.append_from(reader, len)?;
+            self.write(Entry::new(key, blob.encode(), CmdKind::BLOB))?;
+        } else {
+            let pos = self.writer.pos;
+            let head = EntryHeader {
+                key_len: key.len(),
+                value_len: len,
+                kind: CmdKind::PUT,
+                timestamp: self.options.now_millis(),
+                version: self.next_version(&key)?,
+            };
+            let mut streamed = self.writer.write_all(&head.encode());
+            if streamed.is_ok() {
+                streamed = self.writer.write_all(key.as_bytes());
+            }
+            // flushed in full: reads only flush when an entry starts within
+            // the write buffer, and this one may start before it
+            let streamed = streamed
+                .map_err(KvsError::from)
+                .and_then(|()| copy_utf8(reader, &mut self.writer, len))
+                .and_then(|()| Ok(self.writer.flush()?));
+            if let Err(e) = streamed {
+                self.discard_from(pos)?;
+                return Err(e);
+            }
+            self.tombstones.remove(&key);
+            self.index_put(key.clone(), pos)?;
+            if self
+                .options
+                .inline_threshold
+                .is_some_and(|threshold| len <= threshold)
+            {
+                let val = self.read_value(pos)?;
+                self.inline.insert(key, Arc::from(val));
+            }
+        }
+        self.metrics.record_put();
+        self.after_write()?;
+        Ok(())
+    }
+
+    fn get_writer(&mut self, key: &str, writer: &mut dyn Write) -> Result<bool> {
+        if let Some(val) = self.get_inline(key) {
+            writer.write_all(val.as_bytes())?;
+            return Ok(true);
+        }
+        if let Some(bloom) = &self.bloom {
+            if !bloom.may_contain(key) {
+                self.metrics.record_get(false);
+                return Ok(false);
+            }
+        }
+        let offset = match self.index_get(key)? {
+            Some(offset) => offset,
+            None => {
+                self.metrics.record_get(false);
+                return Ok(false);
+            }
+        };
+        self.metrics.record_get(true);
+        if let Some(cache) = &mut self.cache {
+            let cached = cache.get(key);
+            self.metrics.record_cache_lookup(cached.is_some());
+            if let Some(val) = cached {
+                writer.write_all(val.as_bytes())?;
+                return Ok(true);
+            }
+        }
+        self.copy_value(offset, writer)?;
+        Ok(true)
+    }
+
+    fn put_and_locate(&mut self, key: String, val: String) -> Result<u64> {
+        self.put(key.clone(), val)?;
+        // looked up afterwards since the put may have triggered a compaction
+        self.index_get(&key)?.ok_or(KvsError::KeyNotFound)
+    }
+
+    fn locate(&mut self, key: &str) -> Result<Option<(u64, usize)>> {
+        match self.index_get(key)? {
+            Some(offset) => Ok(Some((offset, self.read_header(offset)?.entry_size()))),
+            None => Ok(None),
+        }
+    }
+
+    fn get_at(&mut self, offset: u64) -> Result<Option<String>> {
+        if offset < HEADER_LEN {
+            return Err(KvsError::InvalidOffset(offset));
+        }
+        match self.read_next(offset) {
+            Ok(Some(e)) if matches!(e.kind, CmdKind::DEL | CmdKind::BATCH) => Ok(None),
+            Ok(Some(e)) => self.resolve(e, offset).map(|e| Some(e.value)),
+            Ok(None) => Ok(None),
+            Err(e) if e.is_corruption() => Err(KvsError::InvalidOffset(offset)),
+            Err(e) => Err(e),
+        }
+    }
+
+    fn remove(&mut self, key: String) -> Result<()> {
+        if !self.write_tombstone(key)? {
+            return Err(KvsError::KeyNotFound);
+        }
+        self.after_write()?;
+        Ok(())
+    }
+
+    fn rename(&mut self, old: &str, new: &str) -> Result<()> {
+        let val = self.read(old)?;
+        if old == new {
+            return Ok(());
+        }
+        // one batch, so a crash leaves the value under exactly one of the keys
+        self.apply_batch(vec![
+            WriteOp::Put {
+                key: new.to_owned(),
+                val,
+            },
+            WriteOp::Delete {
+                key: old.to_owned(),
+            },
+        ])
+    }
+
+    fn remove_batch(&mut self, keys: &[String]) -> Result<Vec<String>> {
+        let mut skipped = Vec::new();
+        for key in keys {
+            if !self.write_tombstone(key.clone())? {
+                skipped.push(key.clone());
+            }
+        }
+        self.writer.flush()?;
+        self.after_write()?;
+        Ok(skipped)
+    }
+
+    fn apply_batch(&mut self, ops: Vec<WriteOp>) -> Result<()> {
+        if ops.is_empty() {
+            return Ok(());
+        }
+        // everything that can fail the batch is checked before writing
+        let mut exists: HashMap<&str, bool> = HashMap::new();
+        let mut size = ENTRY_HEAD_LEN as u64;
+        for op in &ops {
+            size += (ENTRY_HEAD_LEN + op.key().len()) as u64;
+            check_key_size(op.key().len(), self.options.max_key_size)?;
+            match op {
+                WriteOp::Put { key, val } => {
+                    check_size(val.len(), self.options.max_value_size)?;
+                    size += match self.to_blob(val.len()) {
+                        true => (self.stored_len(val.len())? + val.len()) as u64,
+                        false => val.len() as u64,
+                    };
+                    exists.insert(key, true);
+                }
+                WriteOp::Delete { key } => {
+                    let found = match exists.get(key.as_str()) {
+                        Some(found) => *found,
+                        None => self.index_get(key)?.is_some(),
+                    };
+                    if !found {
+                        return Err(KvsError::KeyNotFound);
+                    }
+                    exists.insert(key, false);
+                }
+            }
+        }
+        self.make_room(size)?;
+
+        // the marker and the entries go out in one write, and are only put in
+        // the index once they're all in the file
+        let start = self.writer.pos;
+        let now = self.options.now_millis();
+        let mut marker = Entry::new(String::new(), String::new(), CmdKind::BATCH);
+        marker.timestamp = now;
+        marker.version = ops.len() as u64;
+        let mut buf = marker.encode();
+        let mut versions: HashMap<String, u64> = HashMap::new();
+        let mut written = Vec::with_capacity(ops.len());
+        for op in ops {
+            let mut e = match op {
+                WriteOp::Put { key, val } if self.to_blob(val.len()) => {
+                    let blob = self.blobs()?.append(&val)?;
+                    Entry::new(key, blob.encode(), CmdKind::BLOB)
+                }
+                WriteOp::Put { key, val } => Entry::new(key, val, CmdKind::PUT),
+                WriteOp::Delete { key } => Entry::new(key, String::new(), CmdKind::DEL),
+            };
+            e.timestamp = now;
+            e.version = match versions.get(&e.key) {
+                Some(version) => version + 1,
+                None => self.next_version(&e.key)?,
+            };
+            versions.insert(e.key.clone(), e.version);
+            let inline = self.inline_copy(&e);
+            written.push((e.key.clone(), start + buf.len() as u64, e.kind, inline));
+            buf.extend(e.encode());
+        }
+        let flushed = self
+            .writer
+            .write_all(&buf)
+            .and_then(|()| self.writer.flush());
+        if let Err(e) = flushed {
+            self.discard_from(start)?;
+            return Err(e.into());
+        }
+        self.sync()?;
+
+        // the marker is dead weight as soon as the batch is written
+        self.pending_compact += ENTRY_HEAD_LEN as u64;
+        for (key, pos, kind, inline) in written {
+            match kind {
+                CmdKind::DEL => {
+                    if let Some(old_pos) = self.index_get(&key)? {
+                        self.pending_compact += self.read_size(old_pos)?;
+                        self.index.remove_at(&key, old_pos);
+                    }
+                    if let Some(cache) = &mut self.cache {
+                        cache.remove(&key);
+                    }
+                    self.inline.remove(&key);
+                    self.tombstones.insert(key, pos);
+                    self.metrics.record_remove();
+                }
+                _ => {
+                    self.tombstones.remove(&key);
+                    self.index_put(key.clone(), pos)?;
+                    if let Some(val) = inline {
+                        self.inline.insert(key, val);
+                    }
+                    self.metrics.record_put();
+                }
+            }
+        }
+        self.after_write()
+    }
+
+    fn range(&mut self, start: String, end: String) -> Result<Vec<(String, String)>> {
+        let found = self.index.range(&start, &end, &self.key_reader())?;
+        let mut pairs = Vec::with_capacity(found.len());
+        for (key, offset) in found {
+            pairs.push((key, self.read_value(offset)?));
+        }
+        Ok(pairs)
+    }
+
+    fn scan(&mut self) -> Result<Vec<(String, String)>> {
+        let found = self.index.entries(&self.key_reader())?;
+        let mut pairs = Vec::with_capacity(found.len());
+        for (key, offset) in found {
+            pairs.push((key, self.read_value(offset)?));
+        }
+        Ok(pairs)
+    }
+
+    fn scan_iter(&mut self) -> Result<ScanIter> {
+        let entries = self.index.entries(&self.key_reader())?;
+        self.pinned_scan(entries)
+    }
+
+    fn iter_in_write_order(&mut self) -> Result<ScanIter> {
+        let mut entries = self.index.entries(&self.key_reader())?;
+        entries.sort_unstable_by_key(|(_, offset)| *offset);
+        self.pinned_scan(entries)
+    }
+
+    fn scan_page(&mut self, cursor: Option<String>, limit: usize) -> Result<Page> {
+        // an empty page would read as the end, so a page holds at least one pair
+        let limit = limit.max(1);
+        // one extra key tells whether another page follows
+        let mut found = self.index.page(
+            cursor.as_deref(),
+            limit.saturating_add(1),
+            &self.key_reader(),
+        )?;
+        let mut next = None;
+        if found.len() > limit {
+            found.truncate(limit);
+            next = found.last().map(|(key, _)| key.clone());
+        }
+        let mut pairs = Vec::with_capacity(found.len());
+        for (key, offset) in found {
+            pairs.push((key, self.read_value(offset)?));
+        }
+        Ok((pairs, next))
+    }
+
+    fn first_key(&self) -> Result<Option<String>> {
+        self.index.first_key(&self.key_reader())
+    }
+
+    fn last_key(&self) -> Result<Option<String>> {
+        self.index.last_key(&self.key_reader())
+    }
+
+    fn clear(&mut self) -> Result<()> {
+        self.abort_incremental();
+        self.writer.flush()?;
+        self.unmap();
+        self.writer.writer.get_ref().set_len(HEADER_LEN)?;
+        self.writer.pos = HEADER_LEN;
+        self.reserve_space();
+        self.reader.invalidate();
+        self.reader.seek(SeekFrom::Start(0))?;
+        if self.blobs.is_some() || self.blob_path_buf()?.exists() {
+            self.blobs()?.clear()?;
+        }
+        self.index.clear();
+        if let Some(bloom) = &mut self.bloom {
+            bloom.clear();
+        }
+        if let Some(cache) = &mut self.cache {
+            cache.clear();
+        }
+        self.inline.clear();
+        self.tombstones.clear();
+        self.pending_compact = 0;
+        Ok(())
+    }
+
+    fn reopen(&mut self) -> Result<()> {
+        self.abort_incremental();
+        self.writer.flush()?;
+        self.unmap();
+        // the file may have been renamed over since it was opened, so the old
+        // handles could still point at the replaced one
+        let (writer, reader) = open_log(
+            self.data_path_buf.as_path(),
+            OpenOptions::new().append(true),
+            &self.options,
+        )?;
+        self.writer = writer;
+        self.reader = reader;
+        self.blobs = None;
+        self.share_commit_files()?;
+        self.index.clear();
+        if let Some(bloom) = &mut self.bloom {
+            bloom.clear();
+        }
+        if let Some(cache) = &mut self.cache {
+            cache.clear();
+        }
+        self.inline.clear();
+        self.tombstones.clear();
+        self.pending_compact = 0;
+        // the footer vouched for the file as it was when first opened
+        self.clean_shutdown = false;
+        self.index_from_checkpoint = false;
+        self.loaded = false;
+        self.check_header()?;
+        self.load_index()
+    }
+
+    fn flush(&mut self) -> Result<()> {
+        if let Some(blobs) = &mut self.blobs {
+            blobs.flush()?;
+        }
+        self.writer.flush()?;
+        Ok(())
+    }
+
+    fn sync(&mut self) -> Result<()> {
+        if self.options.no_sync {
+            return self.flush();
+        }
+        // blobs first, so a synced entry never points past the synced blobs
+        if let Some(blobs) = &mut self.blobs {
+            blobs.sync()?;
+        }
+        self.writer.flush()?;
+        self.writer.writer.get_ref().sync_data()?;
+        Ok(())
+    }
+
+    fn flush_and_sync(&mut self) -> Result<()> {
+        if self.options.no_sync {
+            return self.flush();
+        }
+        if let Some(blobs) = &mut self.blobs {
+            blobs.sync_all()?;
+        }
+        self.writer.flush()?;
+        self.writer.writer.get_ref().sync_all()?;
+        let dir = self
+            .data_path_buf
+            .parent()
+            .ok_or(KvsError::InvalidDataPath)?;
+        sync_dir(dir)
+    }
+
+    fn snapshot(&mut self, dest: &Path) -> Result<()> {
+        self.copy_snapshot(dest, None)
+    }
+
+    fn snapshot_throttled(&mut self, dest: &Path, bytes_per_sec: u64) -> Result<()> {
+        self.copy_snapshot(dest, Some(Pacer::new(bytes_per_sec)))
+    }
+
+    fn checkpoint(&mut self, dest: &Path) -> Result<()> {
+        // every file is synced before it's renamed in, which leaves the renames
+        self.copy_snapshot(dest, None)?;
+        sync_dir(dest)
+    }
+
+    fn metrics(&self) -> MetricsSnapshot {
+        self.metrics.snapshot()
+    }
+
+    fn stats(&self) -> StoreStats {
+        StoreStats {
+            key_count: self.index.len() as u64,
+            file_size: self.writer.pos,
+            pending_bytes: self.pending_compact,
+            clean_shutdown: self.clean_shutdown,
+            index_from_checkpoint: self.index_from_checkpoint,
+        }
+    }
+
+    fn data_path(&self) -> Option<&Path> {
+        Some(self.data_path_buf.as_path())
+    }
+
+    fn size_on_disk(&self) -> Result<u64> {
+        // buffered writes are already part of the store, if not of the file
+        let buffered = self.writer.pos - self.writer.flushed_pos();
+        let mut size = std::fs::metadata(self.data_path_buf.as_path())?.len() + buffered;
+        if let Some(blobs) = &self.blobs {
+            size += blobs.len();
+        } else if let Ok(metadata) = std::fs::metadata(self.blob_path_buf()?) {
+            size += metadata.len();
+        }
+        Ok(size)
+    }
+
+    fn compact_step(&mut self, budget: u64) -> Result<CompactionProgress> {
+        let mut state = match self.incremental.take() {
+            Some(state) => state,
+            None => {
+                let path_buf = self.merge_path_buf()?;
+                let mut writer = BufWriterWithPos::new(
+                    File::create(path_buf.as_path())?,
+                    self.options.write_buffer_capacity,
+                )?;
+                writer.write_all(&file_header())?;
+                info!(
+                    "starting incremental compaction of {}: {} bytes, {} of them overwritten",
+                    self.data_path_buf.display(),
+                    self.writer.pos,
+                    self.pending_compact
+                );
+                let old_versions = match self.old_versions() {
+                    Ok(old_versions) => old_versions,
+                    Err(e) => {
+                        drop(writer);
+                        let _ = std::fs::remove_file(path_buf.as_path());
+                        return Err(e);
+                    }
+                };
+                IncrementalMerge {
+                    writer,
+                    path_buf,
+                    scan_offset: HEADER_LEN,
+                    end: self.writer.pos,
+                    pending_at_start: self.pending_compact,
+                    relocated: Vec::new(),
+                    relocated_tombstones: Vec::new(),
+                    old_versions,
+                }
+            }
+        };
+
+        if let Err(e) = self.advance_incremental(&mut state, budget) {
+            state.discard();
+            return Err(e);
+        }
+        if state.scan_offset < state.end {
+            let progress = CompactionProgress::Pending {
+                scanned: state.scan_offset,
+                total: state.end,
+            };
+            self.incremental = Some(state);
+            return Ok(progress);
+        }
+
+        let path_buf = state.path_buf.clone();
+        if let Err(e) = self.finish_incremental(state) {
+            let _ = std::fs::remove_file(path_buf.as_path());
+            return Err(e);
+        }
+        Ok(CompactionProgress::Finished)
+    }
+
+    fn purge_tombstones(&mut self) -> Result<u64> {
+        self.compact()
+    }
+
+    fn compact_if_needed(&mut self) -> Result<bool> {
+        if !self.needs_compaction() || !(self.is_idle() || self.past_ceiling()) {
+            return Ok(false);
+        }
+        self.compact()?;
+        Ok(true)
+    }
+
+    fn compact_sorted(&mut self) -> Result<u64> {
+        self.compact_in(MergeOrder::Key)
+    }
+
+    fn compact_keys(&mut self, keys: &[String]) -> Result<u64> {
+        let keys = keys.iter().map(String::as_str).collect();
+        self.merge_keys(&keys)
+    }
+
+    fn shrink_index(&mut self) -> (usize, usize) {
+        let before = self.index.capacity();
+        self.index.shrink_to_fit();
+        self.tombstones.shrink_to_fit();
+        (before, self.index.capacity())
+    }
+
+    fn compaction_estimate(&mut self) -> Result<CompactionEstimate> {
+        let mut live_bytes = 0;
+        for offset in self.index.offsets() {
+            live_bytes += self.read_size(offset)?;
+        }
+        let mut kept_bytes = 0;
+        let tombstones: Vec<u64> = self.tombstones.values().copied().collect();
+        for offset in tombstones {
+            let e = self.read_at(offset)?;
+            if self.keeps_tombstone(&e, offset) {
+                kept_bytes += e.size() as u64;
+            }
+        }
+        let dead_bytes = self.writer.pos - HEADER_LEN - live_bytes;
+        Ok(CompactionEstimate {
+            reclaimable_bytes: dead_bytes - kept_bytes,
+            live_bytes,
+            dead_bytes,
+        })
+    }
+
+    fn log_from(&mut self, offset: u64) -> Result<Vec<(u64, Entry)>> {
+        let entries: Vec<(u64, Entry)> = self.iter_entries_from(offset).collect::<Result<_>>()?;
+        entries
+            .into_iter()
+            .map(|(offset, e)| Ok((offset, self.resolve(e, offset)?)))
+            .collect()
+    }
+
+    fn get_history(&mut self, key: &str) -> Result<Vec<(u64, Option<String>)>> {
+        let mut history = Vec::new();
+        let mut offset = HEADER_LEN;
+        while let Some(e) = self.read_next(offset)? {
+            let size = e.size() as u64;
+            if e.key == key && e.kind != CmdKind::BATCH {
+                let val = match e.kind {
+                    CmdKind::DEL => None,
+                    _ => Some(self.resolve(e, offset)?.value),
+                };
+                history.push((offset, val));
+            }
+            offset += size;
+        }
+        Ok(history)
+    }
+}
+
+impl SimplifiedBitcask {
+    pub fn open(path_buf: PathBuf, options: Options) -> Result<SimplifiedBitcask> {
+        let mut instance = SimplifiedBitcask::new(path_buf, options)?;
+        instance.check_header()?;
+        instance.load_index()?;
+        Ok(instance)
+    }
+
+    /// Opens the data file at `data_path_buf`, whatever it's called. The
+    /// merge and blob files go next to it, named after its file stem.
+    pub fn open_file(data_path_buf: PathBuf, options: Options) -> Result<SimplifiedBitcask> {
+        let name = data_path_buf
+            .file_stem()
+            .and_then(|stem| stem.to_str())
+            .ok_or(KvsError::InvalidDataPath)?
+            .to_owned();
+        let options = Options { name, ..options };
+        let mut instance = SimplifiedBitcask::with_data_path(data_path_buf, options)?;
+        instance.check_header()?;
+        instance.load_index()?;
+        Ok(instance)
+    }
+
+    /// Opens the data file at `data_path_buf` through handles opened by the
+    /// caller, e.g. passed in by a supervisor that does the opening for a
+    /// sandboxed process. `reader` has to be readable and `writer` opened for
+    /// appending, both to the same file.
+    ///
+    /// Only gets and writes go through the handles. Compaction, `reopen`,
+    /// `scan_iter` and blob files still open files next to `data_path_buf`,
+    /// named after its file stem as with `open_file`.
+    pub fn from_files(
+        data_path_buf: PathBuf,
+        reader: File,
+        writer: File,
+        options: Options,
+    ) -> Result<SimplifiedBitcask> {
+        let name = data_path_buf
+            .file_stem()
+            .and_then(|stem| stem.to_str())
+            .ok_or(KvsError::InvalidDataPath)?
+            .to_owned();
+        let options = Options { name, ..options };
+        let clean_shutdown = take_footer(
+            data_path_buf.as_path(),
+            footer_path_of(data_path_buf.as_path(), &options.name)?.as_path(),
+        )?;
+        // loading the index moves `pos` to the end, wherever the handle is
+        let writer =
+            BufWriterWithPos::new(log_file(writer, &options), options.write_buffer_capacity)?;
+        let reader =
+            BufReaderWithPos::new(log_file(reader, &options), options.read_buffer_capacity)?;
+        let mut instance = SimplifiedBitcask::with_log(data_path_buf, writer, reader, options);
+        instance.clean_shutdown = clean_shutdown;
+        instance.check_header()?;
+        instance.load_index()?;
+        Ok(instance)
+    }
+
+    /// Truncates the data file after the last entry that can be read back.
+    /// Returns the number of bytes dropped.
+    pub fn repair(path_buf: PathBuf, options: Options) -> Result<u64> {
+        let mut instance = SimplifiedBitcask::new(path_buf, options)?;
+        let file_len = instance.writer.writer.get_ref().metadata()?.len();
+        if file_len < HEADER_LEN {
+            // not even the header made it, start over
+            if file_len > 0 {
+                warn!(
+                    "dropping {} bytes of a torn header in {}",
+                    file_len,
+                    instance.data_path_buf.display()
+                );
+            }
+            instance.write_header()?;
+            return Ok(file_len);
+        }
+        instance.check_header()?;
+
+        let mut offset = HEADER_LEN;
+        // marker offset and entries left to read of a batch
+        let mut batch: Option<(u64, u64)> = None;
+        loop {
+            match instance.read_next(offset) {
+                Ok(Some(e)) => {
+                    batch = match e.kind {
+                        CmdKind::BATCH => Some((offset, e.version)),
+                        _ => batch.map(|(start, left)| (start, left - 1)),
+                    }
+                    .filter(|&(_, left)| left > 0);
+                    offset += e.size() as u64;
+                }
+                Ok(None) => break,
+                Err(e) if e.is_corruption() => break,
+                Err(e) => return Err(e),
+            }
+        }
+        // a batch is kept whole or not at all
+        if let Some((start, _)) = batch {
+            offset = start;
+        }
+
+        instance.unmap();
+        let file = instance.writer.writer.get_ref();
+        let file_len = file.metadata()?.len();
+        if offset == file_len {
+            return Ok(0);
+        }
+        warn!(
+            "dropping {} bytes after the last readable entry at offset {} in {}",
+            file_len - offset,
+            offset,
+            instance.data_path_buf.display()
+        );
+        file.set_len(offset)?;
+        instance.writer.pos = offset;
+        Ok(file_len - offset)
+    }
+
+    /// Checks that every entry of the data file decodes, without changing
+    /// anything on disk.
+    pub fn verify(path_buf: PathBuf, options: Options) -> Result<VerifyReport> {
+        let data_path_buf = path_buf.join(options.name.clone() + ".data");
+        check_regular_file(data_path_buf.as_path())?;
+        let mut reader = BufReader::with_capacity(
+            options.read_buffer_capacity,
+            File::open(data_path_buf.as_path())?,
+        );
+        let file_len = reader.get_ref().metadata()?.len();
+        let mut report = VerifyReport::default();
+        if file_len < HEADER_LEN {
+            report.first_corruption = Some(0);
+            return Ok(report);
+        }
+        let mut header = [0; HEADER_LEN as usize];
+        reader.read_exact(&mut header)?;
+        check_format(&header)?;
+
+        let mut offset = HEADER_LEN;
+        while offset < file_len {
+            match verify_entry(&mut reader, offset, file_len) {
+                Ok(size) => {
+                    report.valid_entries += 1;
+                    offset += size;
+                }
+                Err(e) if e.is_corruption() => {
+                    warn!(
+                        "corrupted entry at offset {} in {}: {}",
+                        offset,
+                        data_path_buf.display(),
+                        e
+                    );
+                    report.first_corruption = Some(offset);
+                    break;
+                }
+                Err(e) => return Err(e),
+            }
+        }
+        report.bytes_scanned = offset;
+        Ok(report)
+    }
+
+    /// Upgrades a data file written before the format header existed, i.e.
+    /// one that doesn't start with the magic, or before entries had
+    /// timestamps or versions. The entries are converted into a temporary file, which is
+    /// compacted and then renamed over the data file. Returns whether the file needed migrating.
+    pub fn migrate(path_buf: PathBuf, options: Options) -> Result<bool> {
+        let mut instance = SimplifiedBitcask::new(path_buf.clone(), options.clone())?;
+        let (start, head_len) = match instance.check_header() {
+            Ok(()) => return Ok(false),
+            Err(KvsError::IncompatibleFormat { found: 0, .. }) => (0, LEGACY_HEAD_LEN),
+            Err(KvsError::IncompatibleFormat { found: 1, .. }) => (HEADER_LEN, LEGACY_HEAD_LEN),
+            Err(KvsError::IncompatibleFormat { found: 2, .. }) => (HEADER_LEN, V2_HEAD_LEN),
+            Err(e) => return Err(e),
+        };
+        let data_path_buf = instance.data_path_buf.clone();
+        drop(instance);
+
+        let migrate_options = Options {
+            name: options.name.clone() + ".migrate",
+            create_new: false,
+            ..options
+        };
+        let migrate_path_buf = path_buf.join(migrate_options.name.clone() + ".data");
+        let migrate_footer_path =
+            footer_path_of(migrate_path_buf.as_path(), &migrate_options.name)?;
+        let migrate_checkpoint_path =
+            checkpoint_path_of(migrate_path_buf.as_path(), &migrate_options.name)?;
+        let mut dest = BufWriter::with_capacity(
+            migrate_options.write_buffer_capacity,
+            File::create(migrate_path_buf.as_path())?,
+        );
+        dest.write_all(&file_header())?;
+        let converted = convert_legacy(
+            data_path_buf.as_path(),
+            start,
+            head_len,
+            &mut dest,
+            &migrate_options,
+        );
+        if let Err(e) = converted.and_then(|()| Ok(dest.flush()?)) {
+            drop(dest);
+            let _ = std::fs::remove_file(migrate_path_buf.as_path());
+            return Err(e);
+        }
+        drop(dest);
+
+        let migrated =
+            SimplifiedBitcask::open(path_buf, migrate_options).and_then(|mut bitcask| {
+                bitcask.merge(MergeOrder::Log)?;
+                bitcask.sync()
+            });
+        if let Err(e) = migrated {
+            let _ = std::fs::remove_file(migrate_path_buf.as_path());
+            return Err(e);
+        }
+        std::fs::rename(migrate_path_buf.as_path(), data_path_buf.as_path())?;
+        // left by closing the migrated copy under its temporary name
+        let _ = std::fs::remove_file(migrate_footer_path.as_path());
+        let _ = std::fs::remove_file(migrate_checkpoint_path.as_path());
+        info!(
+            "migrated {} to format version {}",
+            data_path_buf.display(),
+            FORMAT_VERSION
+        );
+        Ok(true)
+    }
+
+    /// Walks the raw log in write order, tombstones included, yielding each
+    /// entry with its offset.
+    pub fn iter_entries(&mut self) -> EntryIter<'_> {
+        self.iter_entries_from(HEADER_LEN)
+    }
+
+    /// Like `iter_entries`, starting at the entry at `offset`, or the first
+    /// one if `offset` lies within the file header.
+    pub fn iter_entries_from(&mut self, offset: u64) -> EntryIter<'_> {
+        EntryIter {
+            bitcask: self,
+            offset: offset.max(HEADER_LEN),
+            done: false,
+        }
+    }
+
+    /// Times the reader threw its buffer away because the data file changed,
+    /// so a test can tell it read a write back from the file.
+    pub fn reader_generation(&self) -> u64 {
+        self.reader.generation
+    }
+
+    fn new(path_buf: PathBuf, options: Options) -> Result<SimplifiedBitcask> {
+        let data_path_buf = path_buf.join(options.name.clone() + ".data");
+        SimplifiedBitcask::with_data_path(data_path_buf, options)
+    }
+
+    fn with_data_path(data_path_buf: PathBuf, options: Options) -> Result<SimplifiedBitcask> {
+        check_regular_file(data_path_buf.as_path())?;
+        // before the merge file it goes by is dealt with
+        recover_blob_merge(
+            data_path_buf.as_path(),
+            merge_path_of(data_path_buf.as_path(), &options.name)?.as_path(),
+            blob_merge_path_of(data_path_buf.as_path(), &options.name)?.as_path(),
+        )?;
+        recover_merge(
+            data_path_buf.as_path(),
+            merge_path_of(data_path_buf.as_path(), &options.name)?.as_path(),
+        )?;
+        let clean_shutdown = take_footer(
+            data_path_buf.as_path(),
+            footer_path_of(data_path_buf.as_path(), &options.name)?.as_path(),
+        )?;
+        let mut open_options = OpenOptions::new();
+        open_options.append(true);
+        if options.create_new {
+            open_options.create_new(true);
+        } else {
+            open_options.create(options.create);
+        }
+        let (writer, reader) = open_log(data_path_buf.as_path(), &open_options, &options)?;
+        let mut instance = SimplifiedBitcask::with_log(data_path_buf, writer, reader, options);
+        instance.clean_shutdown = clean_shutdown;
+        Ok(instance)
+    }
+
+    fn with_log(
+        data_path_buf: PathBuf,
+        writer: BufWriterWithPos<LogFile>,
+        reader: BufReaderWithPos<LogFile>,
+        options: Options,
+    ) -> SimplifiedBitcask {
+        SimplifiedBitcask {
+            data_path_buf,
+            reader,
+            writer,
+            index: Index::new(options.index_kind, options.index_hasher.as_ref()),
+            bloom: options.bloom_filter.map(BloomFilter::new),
+            blobs: None,
+            cache: match options.cache_capacity {
+                0 => None,
+                capacity => Some(LruCache::new(capacity)),
+            },
+            inline: HashMap::new(),
+            tombstones: HashMap::new(),
+            pending_compact: 0,
+            last_write: None,
+            options,
+            metrics: Metrics::default(),
+            incremental: None,
+            committer: None,
+            clean_shutdown: false,
+            index_from_checkpoint: false,
+            loaded: false,
+            #[cfg(feature = "mmap")]
+            map: None,
+        }
+    }
+
+    /// Checks the format header of the data file, writing it if the file is
+    /// new. A header cut short by a crash right after creating the file is
+    /// written again.
+    fn check_header(&mut self) -> Result<()> {
+        let file_len = self.reader.reader.get_ref().metadata()?.len();
+        let mut buf = [0; HEADER_LEN as usize];
+        let header = &mut buf[..file_len.min(HEADER_LEN) as usize];
+        self.reader.seek(SeekFrom::Start(0))?;
+        self.reader.read_exact(header)?;
+        if file_len < HEADER_LEN && file_header().starts_with(header) {
+            if file_len > 0 {
+                warn!(
+                    "rewriting the torn header of {}",
+                    self.data_path_buf.display()
+                );
+            }
+            return self.write_header();
+        }
+        check_format(header)
+    }
+
+    /// Replaces the whole data file with a bare header.
+    fn write_header(&mut self) -> Result<()> {
+        self.unmap();
+        self.writer.writer.get_ref().set_len(0)?;
+        self.reader.invalidate();
+        self.writer.write_all(&file_header())?;
+        self.writer.flush()?;
+        self.writer.pos = HEADER_LEN;
+        Ok(())
+    }
+
+    /// Appends a tombstone for `key` and drops it from the index, returning
+    /// false without writing anything if the key doesn't exist.
+    fn write_tombstone(&mut self, key: String) -> Result<bool> {
+        let old_pos = match self.index_get(&key)? {
+            Some(offset) => offset,
+            None => return Ok(false),
+        };
+        // the removed entry is as dead as an overwritten one
+        let removed = self.read_header(old_pos)?;
+        let pos = self.writer.pos;
+        let mut tombstone = Entry::new(key.clone(), String::new(), CmdKind::DEL);
+        tombstone.timestamp = self.options.now_millis();
+        tombstone.version = removed.version + 1;
+        self.append(tombstone)?;
+        self.index.remove_at(&key, old_pos);
+        self.inline.remove(&key);
+        self.pending_compact += removed.entry_size() as u64;
+        self.tombstones.insert(key, pos);
+        self.metrics.record_remove();
+        Ok(true)
+    }
+
+    /// Offset of the live entry of `key`.
+    fn index_get(&self, key: &str) -> Result<Option<u64>> {
+        self.index.get(key, &self.key_reader())
+    }
+
+    fn index_insert(&mut self, key: String, offset: u64) -> Result<Option<u64>> {
+        let (file, writer): (&File, _) = (self.reader.reader.get_ref(), &self.writer);
+        self.index
+            .insert(key, offset, &|offset| key_at(file, writer, offset))
+    }
+
+    fn index_remove(&mut self, key: &str) -> Result<Option<u64>> {
+        let (file, writer): (&File, _) = (self.reader.reader.get_ref(), &self.writer);
+        self.index
+            .remove(key, &|offset| key_at(file, writer, offset))
+    }
+
+    /// Reads keys back for the index, see `key_at`.
+    fn key_reader(&self) -> impl Fn(u64) -> Result<String> + '_ {
+        let file: &File = self.reader.reader.get_ref();
+        move |offset| key_at(file, &self.writer, offset)
+    }
+
+    /// Whether writes should compact now, see `needs_compaction`. Under
+    /// `compaction_idle` they only do past `compaction_ceiling`.
+    fn should_compact(&self) -> bool {
+        let now = self.options.compaction_idle.is_none() || self.past_ceiling();
+        self.options.auto_compaction && now && self.needs_compaction()
+    }
+
+    /// Whether there have been no writes for `compaction_idle`, always true
+    /// without it.
+    fn is_idle(&self) -> bool {
+        match (self.options.compaction_idle, self.last_write) {
+            (Some(quiet), Some(last_write)) => {
+                let elapsed = self.options.now_millis().saturating_sub(last_write);
+                u128::from(elapsed) >= quiet.as_millis()
+            }
+            _ => true,
+        }
+    }
+
+    fn past_ceiling(&self) -> bool {
+        self.options
+            .compaction_ceiling
+            .is_some_and(|ceiling| self.pending_compact >= ceiling)
+    }
+
+    /// Whether enough of the data file is dead to compact it, per
+    /// `compaction_threshold` and `compaction_ratio`.
+    fn needs_compaction(&self) -> bool {
+        if self.pending_compact < self.options.compaction_threshold {
+            return false;
+        }
+        match self.options.compaction_ratio {
+            Some(ratio) => self.pending_compact as f64 >= ratio * self.writer.pos as f64,
+            None => true,
+        }
+    }
+
+    /// Commits a write that has gone through, see `commit`, and compacts if
+    /// it's time to.
+    fn after_write(&mut self) -> Result<()> {
+        self.last_write = Some(self.options.now_millis());
+        self.commit()?;
+        self.maybe_compact()
+    }
+
+    /// Hands the writes so far to the OS and reports them to the committer,
+    /// starting it on the first write, if `commit_interval` is set. Under
+    /// `no_sync` there's nothing to report them to.
+    fn commit(&mut self) -> Result<()> {
+        let interval = match self.options.commit_interval {
+            Some(interval) => interval,
+            None => return Ok(()),
+        };
+        if let Some(blobs) = &mut self.blobs {
+            blobs.flush()?;
+        }
+        self.writer.flush()?;
+        if self.options.no_sync {
+            return Ok(());
+        }
+        if self.committer.is_none() {
+            self.committer = Some(Committer::start(interval)?);
+            self.share_commit_files()?;
+        }
+        let end = self.writer.pos + self.blobs.as_ref().map_or(0, BlobFile::len);
+        if let Some(committer) = &mut self.committer {
+            committer.wrote(end)?;
+        }
+        Ok(())
+    }
+
+    /// Points the committer, if any, at the current files after they were
+    /// opened anew.
+    fn share_commit_files(&mut self) -> Result<()> {
+        let committer = match &self.committer {
+            Some(committer) => committer,
+            None => return Ok(()),
+        };
+        let data = self.writer.writer.get_ref().try_clone()?;
+        let blobs = match &self.blobs {
+            Some(blobs) => Some(blobs.file().try_clone()?),
+            None => None,
+        };
+        committer.set_files(data, blobs);
+        Ok(())
+    }
+
+    /// Compacts if `should_compact` says so. A cancelled compaction isn't an
+    /// error here, the write that triggered it has already gone through.
+    fn maybe_compact(&mut self) -> Result<()> {
+        if !self.should_compact() {
+            return Ok(());
+        }
+        match self.compact() {
+            Ok(_) | Err(KvsError::CompactionCancelled) => Ok(()),
+            Err(e) => Err(e),
+        }
+    }
+
+    /// Whether `Options::compaction_cancel` has been cancelled.
+    fn compaction_cancelled(&self) -> bool {
+        self.options
+            .compaction_cancel
+            .as_ref()
+            .is_some_and(CancellationToken::is_cancelled)
+    }
+
+    /// Makes sure `size` more bytes fit in the data and blob files under
+    /// `max_data_size`, compacting first if they don't.
+    fn make_room(&mut self, size: u64) -> Result<()> {
+        let limit = match self.options.max_data_size {
+            Some(limit) => limit,
+            None => return Ok(()),
+        };
+        if self.used_size()? + size <= limit {
+            return Ok(());
+        }
+        if self.pending_compact > 0 || !self.tombstones.is_empty() {
+            self.compact()?;
+        }
+        if self.used_size()? + size > limit {
+            return Err(KvsError::StorageFull { limit });
+        }
+        Ok(())
+    }
+
+    /// Bytes of the data and blob files, as counted by `max_data_size`.
+    fn used_size(&mut self) -> Result<u64> {
+        let blobs_len = match self.blobs.is_some() || self.blob_path_buf()?.exists() {
+            true => self.blobs()?.len(),
+            false => 0,
+        };
+        Ok(self.writer.pos + blobs_len)
+    }
+
+    fn write(&mut self, mut entry: Entry) -> Result<()> {
+        entry.timestamp = self.options.now_millis();
+        entry.version = self.next_version(&entry.key)?;
+        self.tombstones.remove(&entry.key);
+        let pos = self.writer.pos;
+        let key = entry.key.clone();
+        let inline = self.inline_copy(&entry);
+        self.append(entry)?;
+        self.index_put(key.clone(), pos)?;
+        if let Some(val) = inline {
+            self.inline.insert(key, val);
+        }
+        Ok(())
+    }
+
+    /// The value of `e` to keep in memory once it's written, if it's small
+    /// enough for `inline_threshold`.
+    fn inline_copy(&self, e: &Entry) -> Option<Arc<str>> {
+        match self.options.inline_threshold {
+            Some(threshold) if e.kind == CmdKind::PUT && e.value.len() <= threshold => {
+                Some(Arc::from(e.value.as_str()))
+            }
+            _ => None,
+        }
+    }
+
+    /// The value of `key` kept in memory, counted as a hit.
+    fn get_inline(&mut self, key: &str) -> Option<Arc<str>> {
+        let val = self.inline.get(key).cloned();
+        if val.is_some() {
+            self.metrics.record_get(true);
+        }
+        val
+    }
+
+    /// Points `key` at the entry just written at `pos`.
+    fn index_put(&mut self, key: String, pos: u64) -> Result<()> {
+        if let Some(bloom) = &mut self.bloom {
+            bloom.insert(&key);
+        }
+        if let Some(cache) = &mut self.cache {
+            cache.remove(&key);
+        }
+        self.inline.remove(&key);
+        if let Some(old_pos) = self.index_insert(key, pos)? {
+            self.pending_compact += self.read_size(old_pos)?;
+        }
+        Ok(())
+    }
+
+    /// Drops whatever was written from `offset` on, e.g. an entry that could
+    /// only be partly written.
+    fn discard_from(&mut self, offset: u64) -> Result<()> {
+        self.writer.flush()?;
+        self.unmap();
+        self.writer.writer.get_ref().set_len(offset)?;
+        self.reader.invalidate();
+        self.writer.pos = offset;
+        self.reserve_space();
+        Ok(())
+    }
+
+    /// Reserves disk space for the data file per `Options::preallocate`,
+    /// again after anything truncating it, which gives the space back.
+    fn reserve_space(&self) {
+        preallocate(
+            self.writer.writer.get_ref(),
+            self.data_path_buf.as_path(),
+            &self.options,
+        );
+    }
+
+    /// Whether a value of `len` bytes goes to the blob file.
+    fn to_blob(&self, len: usize) -> bool {
+        matches!(self.options.blob_threshold, Some(threshold) if len > threshold)
+    }
+
+    /// `make_room` for the entry putting a value of `len` bytes at `key`, and
+    /// its blob if `to_blob`.
+    fn make_room_for(&mut self, key: &str, len: usize, to_blob: bool) -> Result<()> {
+        let stored_len = match to_blob {
+            true => self.stored_len(len)? + len,
+            false => len,
+        };
+        self.make_room((ENTRY_HEAD_LEN + key.len() + stored_len) as u64)
+    }
+
+    /// Size in the log of the reference to a blob of `len` bytes appended
+    /// next.
+    fn stored_len(&mut self, len: usize) -> Result<usize> {
+        let blob = BlobRef {
+            offset: self.blobs()?.len(),
+            len: len as u64,
+        };
+        Ok(blob.encode().len())
+    }
+
+    /// Writes `entry` at the end of the log without touching the index.
+    fn append(&mut self, entry: Entry) -> Result<()> {
+        if let Some(cache) = &mut self.cache {
+            cache.remove(&entry.key);
+        }
+        let buf = entry.encode();
+        self.writer.write_all(&buf)?;
+        Ok(())
+    }
+
+    /// Iterates over the values of `entries`, in their order, through
+    /// handles opened now, see `PinnedScan`.
+    fn pinned_scan(&mut self, entries: Vec<(String, u64)>) -> Result<ScanIter> {
+        self.flush()?;
+        let blob_path_buf = self.blob_path_buf()?;
+        let blobs = match blob_path_buf.exists() {
+            true => {
+                let file = File::open(blob_path_buf.as_path())?;
+                let len = file.metadata()?.len();
+                Some((file, len))
+            }
+            false => None,
+        };
+        Ok(Box::new(PinnedScan {
+            file: File::open(self.data_path_buf.as_path())?,
+            end: self.writer.pos,
+            blobs,
+            entries: entries.into_iter(),
+        }))
+    }
+
+    fn read(&mut self, key: &str) -> Result<String> {
+        match self.read_with_source(key)? {
+            Some((val, _)) => Ok(val),
+            None => Err(KvsError::KeyNotFound),
+        }
+    }
+
+    fn read_with_source(&mut self, key: &str) -> Result<Option<(String, ReadSource)>> {
+        if let Some(val) = self.get_inline(key) {
+            return Ok(Some((val.to_string(), ReadSource::InlineHit)));
+        }
+        if let Some(bloom) = &self.bloom {
+            if !bloom.may_contain(key) {
+                self.metrics.record_get(false);
+                return Ok(None);
+            }
+        }
+        if let Some(pos) = self.index_get(key)? {
+            self.metrics.record_get(true);
+            if let Some(val) = self.cache.as_mut().and_then(|cache| cache.get(key)) {
+                self.metrics.record_cache_lookup(true);
+                return Ok(Some((val.to_string(), ReadSource::CacheHit)));
+            }
+            let val = self.read_value(pos)?;
+            if let Some(cache) = &mut self.cache {
+                self.metrics.record_cache_lookup(false);
+                cache.insert(key.to_owned(), Arc::from(val.as_str()));
+            }
+            return Ok(Some((val, ReadSource::IndexHitDisk)));
+        };
+
+        self.metrics.record_get(false);
+        Ok(None)
+    }
+
+    /// Like `read_with_source`, sharing the value with the cache.
+    fn read_shared(&mut self, key: &str) -> Result<Option<Arc<str>>> {
+        if let Some(val) = self.get_inline(key) {
+            return Ok(Some(val));
+        }
+        if let Some(bloom) = &self.bloom {
+            if !bloom.may_contain(key) {
+                self.metrics.record_get(false);
+                return Ok(None);
+            }
+        }
+        let pos = match self.index_get(key)? {
+            Some(pos) => pos,
+            None => {
+                self.metrics.record_get(false);
+                return Ok(None);
+            }
+        };
+        self.metrics.record_get(true);
+        if let Some(val) = self.cache.as_mut().and_then(|cache| cache.get(key)) {
+            self.metrics.record_cache_lookup(true);
+            return Ok(Some(val));
+        }
+        let val: Arc<str> = Arc::from(self.read_value(pos)?);
+        if let Some(cache) = &mut self.cache {
+            self.metrics.record_cache_lookup(false);
+            cache.insert(key.to_owned(), val.clone());
+        }
+        Ok(Some(val))
+    }
+
+    /// Reads the entry an index offset points to, which must exist.
+    fn read_at(&mut self, offset: u64) -> Result<Entry> {
+        self.read_next(offset)?
+            .ok_or(KvsError::CorruptedEntry(offset))
+    }
+
+    /// Reads the value of the entry an index offset points to, fetching it
+    /// from the blob file if needed.
+    fn read_value(&mut self, offset: u64) -> Result<String> {
+        let e = self.read_at(offset)?;
+        Ok(self.resolve(e, offset)?.value)
+    }
+
+    /// Reads the value bytes of the entry at `offset` into `buf`, skipping
+    /// over the key without allocating for it. The bytes aren't checked to
+    /// be valid UTF-8.
+    fn read_value_into(&mut self, offset: u64, buf: &mut Vec<u8>) -> Result<()> {
+        self.prepare_read(offset)?;
+        self.reader.seek(SeekFrom::Start(offset))?;
+        let mut head_buf: [u8; ENTRY_HEAD_LEN] = [0; ENTRY_HEAD_LEN];
+        self.reader.read_exact(&mut head_buf)?;
+        let head = EntryHeader::decode(&head_buf)?;
+        let file_len = self.reader.reader.get_ref().metadata()?.len();
+        check_entry(&head, offset, file_len)?;
+        if head.kind == CmdKind::BLOB {
+            let e = self.read_at(offset)?;
+            let blob = BlobRef::decode(&e.value).ok_or(KvsError::CorruptedEntry(offset))?;
+            if !self.blobs()?.copy_to(blob, buf)? {
+                return Err(KvsError::CorruptedEntry(offset));
+            }
+            return Ok(());
+        }
+
+        io::copy(
+            &mut (&mut self.reader).take(head.key_len as u64),
+            &mut io::sink(),
+        )?;
+        buf.resize(head.value_len, 0);
+        self.reader.read_exact(buf.as_mut_slice())?;
+        Ok(())
+    }
+
+    /// Writes the value of the entry at `offset` to `writer`, piece by piece.
+    fn copy_value(&mut self, offset: u64, writer: &mut dyn Write) -> Result<()> {
+        self.prepare_read(offset)?;
+        self.reader.seek(SeekFrom::Start(offset))?;
+        let mut head_buf: [u8; ENTRY_HEAD_LEN] = [0; ENTRY_HEAD_LEN];
+        self.reader.read_exact(&mut head_buf)?;
+        let head = EntryHeader::decode(&head_buf)?;
+        let file_len = self.reader.reader.get_ref().metadata()?.len();
+        check_entry(&head, offset, file_len)?;
+        if head.kind == CmdKind::BLOB {
+            let e = self.read_at(offset)?;
+            let blob = BlobRef::decode(&e.value).ok_or(KvsError::CorruptedEntry(offset))?;
+            if !self.blobs()?.copy_to(blob, writer)? {
+                return Err(KvsError::CorruptedEntry(offset));
+            }
+            return Ok(());
+        }
+
+        io::copy(
+            &mut (&mut self.reader).take(head.key_len as u64),
+            &mut io::sink(),
+        )?;
+        let copied = io::copy(&mut (&mut self.reader).take(head.value_len as u64), writer)?;
+        if copied < head.value_len as u64 {
+            return Err(KvsError::CorruptedEntry(offset));
+        }
+        Ok(())
+    }
+
+    /// Turns a blob reference read at `offset` into a put of the blob itself,
+    /// keeping the entry size it has in the data file.
+    fn resolve(&mut self, mut e: Entry, offset: u64) -> Result<Entry> {
+        if e.kind != CmdKind::BLOB {
+            return Ok(e);
+        }
+        let blob = BlobRef::decode(&e.value).ok_or(KvsError::CorruptedEntry(offset))?;
+        e.value = self
+            .blobs()?
+            .read(blob)?
+            .ok_or(KvsError::CorruptedEntry(offset))?;
+        e.kind = CmdKind::PUT;
+        Ok(e)
+    }
+
+    fn blobs(&mut self) -> Result<&mut BlobFile> {
+        if self.blobs.is_none() {
+            let path_buf = self.blob_path_buf()?;
+            self.blobs = Some(BlobFile::open(
+                path_buf,
+                self.options.write_buffer_capacity,
+            )?);
+            self.share_commit_files()?;
+        }
+        Ok(self.blobs.as_mut().unwrap())
+    }
+
+    /// Copies the files of the store into `dest`, paced by `pacer` if given.
+    fn copy_snapshot(&mut self, dest: &Path, mut pacer: Option<Pacer>) -> Result<()> {
+        self.writer.flush()?;
+        // the log is append-only, so everything up to the current end is a consistent state
+        let end = self.writer.pos;
+        // blobs go first, the data file's arrival is what makes the snapshot usable
+        if self.blobs.is_some() || self.blob_path_buf()?.exists() {
+            let blobs = self.blobs()?;
+            blobs.flush()?;
+            let (src, len) = (blobs.path().to_path_buf(), blobs.len());
+            self.copy_to(src.as_path(), len, dest, ".blob", pacer.as_mut())?;
+        }
+        let src = self.data_path_buf.clone();
+        self.copy_to(src.as_path(), end, dest, ".data", pacer.as_mut())
+    }
+
+    /// Copies the first `len` bytes of `src` into `dest` as the file of this
+    /// store with the given extension, through a temporary file.
+    fn copy_to(
+        &self,
+        src: &Path,
+        len: u64,
+        dest: &Path,
+        extension: &str,
+        pacer: Option<&mut Pacer>,
+    ) -> Result<()> {
+        let tmp_path_buf = dest.join(self.options.name.clone() + ".snapshot");
+        let mut src = File::open(src)?.take(len);
+        let mut tmp = File::create(tmp_path_buf.as_path())?;
+        match pacer {
+            Some(pacer) => pacer.copy(&mut src, &mut tmp)?,
+            None => {
+                io::copy(&mut src, &mut tmp)?;
+            }
+        }
+        tmp.sync_all()?;
+        std::fs::rename(
+            tmp_path_buf.as_path(),
+            dest.join(self.options.name.clone() + extension),
+        )?;
+        Ok(())
+    }
+
+    /// Writes the footer vouching for the data file as it is now, see
+    /// `take_footer`.
+    fn write_footer(&self) -> Result<()> {
+        let end = self.writer.pos;
+        let mut footer = FOOTER_MAGIC.to_vec();
+        footer.extend_from_slice(&end.to_be_bytes());
+        footer.extend_from_slice(&tail_checksum(self.data_path_buf.as_path(), end)?.to_be_bytes());
+        std::fs::write(
+            footer_path_of(self.data_path_buf.as_path(), &self.options.name)?,
+            footer,
+        )?;
+        Ok(())
+    }
+
+    /// Writes the index checkpoint for the data file as it is now, see
+    /// `load_checkpoint`.
+    fn write_checkpoint(&self) -> Result<()> {
+        let end = self.writer.pos;
+        let checkpoint = IndexCheckpoint {
+            end,
+            checksum: tail_checksum(self.data_path_buf.as_path(), end)?,
+            keys: self.index.entries(&self.key_reader())?,
+            tombstones: self
+                .tombstones
+                .iter()
+                .map(|(key, offset)| (key.clone(), *offset))
+                .collect(),
+        };
+        let mut buf = CHECKPOINT_MAGIC.to_vec();
+        bincode::serialize_into(&mut buf, &checkpoint)?;
+        std::fs::write(
+            checkpoint_path_of(self.data_path_buf.as_path(), &self.options.name)?,
+            buf,
+        )?;
+        Ok(())
+    }
+
+    /// Reads and removes the index checkpoint, loading the index from it if
+    /// the footer vouched for the data file and the checkpoint was taken from
+    /// the file as it is now. Returns false, loading nothing, otherwise.
+    fn load_checkpoint(&mut self) -> Result<bool> {
+        let path_buf = checkpoint_path_of(self.data_path_buf.as_path(), &self.options.name)?;
+        let buf = match std::fs::read(path_buf.as_path()) {
+            Ok(buf) => buf,
+            Err(e) if e.kind() == io::ErrorKind::NotFound => return Ok(false),
+            Err(e) => return Err(e.into()),
+        };
+        std::fs::remove_file(path_buf.as_path())?;
+        if !self.clean_shutdown {
+            return Ok(false);
+        }
+        let checkpoint: IndexCheckpoint = match buf
+            .strip_prefix(CHECKPOINT_MAGIC.as_slice())
+            .map(bincode::deserialize)
+        {
+            Some(Ok(checkpoint)) => checkpoint,
+            _ => {
+                warn!(
+                    "ignoring the malformed index checkpoint {}",
+                    path_buf.display()
+                );
+                return Ok(false);
+            }
+        };
+        // a close that wrote the footer but failed to write the checkpoint
+        // leaves an older one behind
+        if checkpoint.end != self.writer.pos
+            || checkpoint.checksum != tail_checksum(self.data_path_buf.as_path(), checkpoint.end)?
+        {
+            return Ok(false);
+        }
+        for (key, offset) in checkpoint.keys {
+            if let Some(bloom) = &mut self.bloom {
+                bloom.insert(&key);
+            }
+            self.index_insert(key, offset)?;
+        }
+        self.tombstones.extend(checkpoint.tombstones);
+        self.index_from_checkpoint = true;
+        Ok(true)
+    }
+
+    fn blob_path_buf(&self) -> Result<PathBuf> {
+        Ok(self
+            .data_path_buf
+            .parent()
+            .ok_or(KvsError::InvalidDataPath)?
+            .join(self.options.name.clone() + ".blob"))
+    }
+
+    /// Reads the entry at `offset`, or `None` at the end of the file.
+    fn read_next(&mut self, offset: u64) -> Result<Option<Entry>> {
+        self.prepare_read(offset)?;
+        self.read_entry(offset)
+    }
+
+    /// Gets ready to read at `offset`. The reader uses its own file handle, so
+    /// it can't see entries still sitting in the write buffer, and whatever it
+    /// buffered before the last write may end short of them.
+    fn prepare_read(&mut self, offset: u64) -> Result<()> {
+        if offset >= self.writer.flushed_pos() {
+            self.writer.flush()?;
+        }
+        self.reader.file_len_changed(self.writer.flushed_pos());
+        Ok(())
+    }
+
+    /// Size of the entry at `offset`, reading only its header.
+    fn read_size(&mut self, offset: u64) -> Result<u64> {
+        Ok(self.read_header(offset)?.entry_size() as u64)
+    }
+
+    fn read_header(&mut self, offset: u64) -> Result<EntryHeader> {
+        self.prepare_read(offset)?;
+        self.reader.seek(SeekFrom::Start(offset))?;
+        let mut buf: [u8; ENTRY_HEAD_LEN] = [0; ENTRY_HEAD_LEN];
+        self.reader.read_exact(&mut buf)?;
+        EntryHeader::decode(&buf)
+    }
+
+    /// Version the next write to `key` gets. It carries on from a tombstone
+    /// still in the log, so a removed and rewritten key doesn't repeat
+    /// versions until compaction drops the tombstone.
+    fn next_version(&mut self, key: &str) -> Result<u64> {
+        let offset = match self.index_get(key)? {
+            Some(offset) => offset,
+            None => match self.tombstones.get(key) {
+                Some(offset) => *offset,
+                None => return Ok(1),
+            },
+        };
+        Ok(self.read_header(offset)?.version + 1)
+    }
+
+    #[cfg(not(feature = "mmap"))]
+    fn read_entry(&mut self, offset: u64) -> Result<Option<Entry>> {
+        let file_len = self.reader.reader.get_ref().metadata()?.len();
+        read_entry_from(&mut self.reader, offset, file_len)
+    }
+
+    /// Decodes straight from the mapped file, only remapping when the entry
+    /// lies past the end of the current mapping.
+    #[cfg(feature = "mmap")]
+    fn read_entry(&mut self, offset: u64) -> Result<Option<Entry>> {
+        let head_end = offset + ENTRY_HEAD_LEN as u64;
+        if self.mapped_len() < head_end {
+            self.remap()?;
+        }
+        let mapped_len = self.mapped_len();
+        if offset >= mapped_len {
+            return Ok(None);
+        }
+        if head_end > mapped_len {
+            return Err(KvsError::CorruptedEntry(offset));
+        }
+
+        let start = offset as usize;
+        let head = EntryHeader::decode(self.mapped(start, ENTRY_HEAD_LEN).try_into()?)?;
+        let entry_end = head_end
+            .saturating_add(head.key_len as u64)
+            .saturating_add(head.value_len as u64);
+        if self.mapped_len() < entry_end {
+            self.remap()?;
+        }
+        check_entry(&head, offset, self.mapped_len())?;
+
+        let key_start = start + ENTRY_HEAD_LEN;
+        let key = String::from_utf8(self.mapped(key_start, head.key_len).to_vec())?;
+        let value_start = key_start + head.key_len;
+        let value = String::from_utf8(self.mapped(value_start, head.value_len).to_vec())?;
+
+        Ok(Some(Entry::from_header(head, key, value)))
+    }
+
+    #[cfg(feature = "mmap")]
+    fn mapped_len(&self) -> u64 {
+        self.map.as_ref().map_or(0, |map| map.len() as u64)
+    }
+
+    #[cfg(feature = "mmap")]
+    fn mapped(&self, start: usize, len: usize) -> &[u8] {
+        match &self.map {
+            Some(map) => &map[start..start + len],
+            None => &[],
+        }
+    }
+
+    #[cfg(feature = "mmap")]
+    fn remap(&mut self) -> Result<()> {
+        // Safety: the data file is only ever appended to while it is mapped,
+        // every truncation or replacement drops the map first through `unmap`.
+        let file: &File = self.reader.reader.get_ref().borrow();
+        self.map = Some(unsafe { memmap2::Mmap::map(file)? });
+        Ok(())
+    }
+
+    /// Drops the memory map, must be called before the data file is truncated
+    /// or replaced.
+    fn unmap(&mut self) {
+        #[cfg(feature = "mmap")]
+        {
+            self.map = None;
+        }
+    }
+
+    fn load_index(&mut self) -> Result<()> {
+        let start = Instant::now();
+        self.load_entries()?;
+        self.load_inline()?;
+        self.loaded = true;
+        self.reserve_space();
+        debug!(
+            "loaded {} keys and {} tombstones from {} ({} bytes) in {:?}",
+            self.index.len(),
+            self.tombstones.len(),
+            self.data_path_buf.display(),
+            self.writer.pos,
+            start.elapsed()
+        );
+        Ok(())
+    }
+
+    /// Reads every live value small enough for `inline_threshold` into
+    /// memory, in file order.
+    fn load_inline(&mut self) -> Result<()> {
+        let threshold = match self.options.inline_threshold {
+            Some(threshold) => threshold,
+            None => return Ok(()),
+        };
+        let mut entries = self.index.entries(&self.key_reader())?;
+        entries.sort_unstable_by_key(|(_, offset)| *offset);
+        for (key, offset) in entries {
+            let head = self.read_header(offset)?;
+            if head.kind == CmdKind::PUT && head.value_len <= threshold {
+                let val = self.read_value(offset)?;
+                self.inline.insert(key, Arc::from(val));
+            }
+        }
+        Ok(())
+    }
+
+    fn load_entries(&mut self) -> Result<()> {
+        // the hashed index reads keys back below `pos` while loading
+        self.writer.pos = self.reader.reader.get_ref().metadata()?.len();
+        if self.load_checkpoint()? {
+            return Ok(());
+        }
+        #[cfg(feature = "rayon")]
+        {
+            if self.load_index_parallel()? {
+                return Ok(());
+            }
+        }
+
+        let mut offset = HEADER_LEN;
+        loop {
+            match self.read_next(offset) {
+                Ok(Some(e)) if e.kind == CmdKind::BATCH => {
+                    let size = e.size() as u64;
+                    if !self.batch_is_complete(offset + size, e.version)? {
+                        if self.clean_shutdown {
+                            return Err(KvsError::CorruptedEntry(offset));
+                        }
+                        // a crash while writing the batch, it goes as a whole
+                        warn!(
+                            "dropping an incomplete batch at offset {} in {}",
+                            offset,
+                            self.data_path_buf.display()
+                        );
+                        self.unmap();
+                        self.writer.writer.get_ref().set_len(offset)?;
+                        self.reader.invalidate();
+                        self.writer.pos = offset;
+                        return Ok(());
+                    }
+                    offset += size;
+                }
+                Ok(Some(e)) => {
+                    let size = e.size() as u64;
+                    self.apply_loaded(e.key, offset, e.kind)?;
+                    offset += size;
+                }
+                Ok(None) => {
+                    self.writer.pos = offset;
+                    return Ok(());
+                }
+                Err(KvsError::StringDecode(_)) => {
+                    offset += self.load_undecodable(offset)?;
+                }
+                // a file closed cleanly can't end in a torn write, so anything
+                // wrong with it is corruption
+                Err(e)
+                    if e.is_corruption()
+                        && !self.clean_shutdown
+                        && self.is_torn_tail(offset)? =>
+                {
+                    // an entry cut short by a crash, drop it like `repair` would
+                    warn!(
+                        "dropping a torn entry at offset {} in {}: {}",
+                        offset,
+                        self.data_path_buf.display(),
+                        e
+                    );
+                    self.unmap();
+                    self.writer.writer.get_ref().set_len(offset)?;
+                    self.reader.invalidate();
+                    self.writer.pos = offset;
+                    return Ok(());
+                }
+                Err(e) => {
+                    return Err(e);
+                }
+            }
+        }
+    }
+
+    /// Whether the `count` entries of a batch starting at `offset` all fit in
+    /// the file, going by their headers alone.
+    fn batch_is_complete(&mut self, mut offset: u64, count: u64) -> Result<bool> {
+        let file_len = self.reader.reader.get_ref().metadata()?.len();
+        for _ in 0..count {
+            if file_len.saturating_sub(offset) < ENTRY_HEAD_LEN as u64 {
+                return Ok(false);
+            }
+            offset += self.read_header(offset)?.entry_size() as u64;
+            if offset > file_len {
+                return Ok(false);
+            }
+        }
+        Ok(true)
+    }
+
+    /// Replays the entry at `offset`, whose key or value isn't valid UTF-8,
+    /// returning its size. A bad value is loaded all the same, for
+    /// `KvStore::get_lossy` to read, while an entry with a bad key can't be
+    /// looked up and is skipped.
+    fn load_undecodable(&mut self, offset: u64) -> Result<u64> {
+        let head = self.read_header(offset)?;
+        let mut key_buf = vec![0; head.key_len];
+        self.reader.read_exact(key_buf.as_mut_slice())?;
+        match String::from_utf8(key_buf) {
+            Ok(key) => self.apply_loaded(key, offset, head.kind)?,
+            Err(_) => warn!(
+                "skipping the entry at offset {} in {}: its key isn't valid UTF-8",
+                offset,
+                self.data_path_buf.display()
+            ),
+        }
+        Ok(head.entry_size() as u64)
+    }
+
+    /// Replays one log entry into the in-memory state while loading.
+    fn apply_loaded(&mut self, key: String, offset: u64, kind: CmdKind) -> Result<()> {
+        match kind {
+            CmdKind::DEL => {
+                self.index_remove(&key)?;
+                self.tombstones.insert(key, offset);
+            }
+            CmdKind::PUT | CmdKind::BLOB => {
+                if let Some(bloom) = &mut self.bloom {
+                    bloom.insert(&key);
+                }
+                self.tombstones.remove(&key);
+                self.index_insert(key, offset)?;
+            }
+            CmdKind::BATCH => {}
+        }
+        Ok(())
+    }
+
+    /// Builds the index by scanning chunks of the file on the rayon pool.
+    /// Returns false without loading anything when the file is small, or its
+    /// tail looks damaged, leaving it to the sequential scan.
+    #[cfg(feature = "rayon")]
+    fn load_index_parallel(&mut self) -> Result<bool> {
+        use rayon::prelude::*;
+
+        let file_len = self.reader.reader.get_ref().metadata()?.len();
+        if file_len < PARALLEL_LOAD_MIN_LEN {
+            return Ok(false);
+        }
+
+        // walk the headers alone to find where each chunk can start
+        let chunk_len = file_len / rayon::current_num_threads() as u64 + 1;
+        let mut starts = vec![HEADER_LEN];
+        let mut offset = HEADER_LEN;
+        let mut batch_left = 0;
+        while offset < file_len {
+            if offset >= starts[starts.len() - 1] + chunk_len {
+                starts.push(offset);
+            }
+            if file_len - offset < ENTRY_HEAD_LEN as u64 {
+                return Ok(false);
+            }
+            self.reader.seek(SeekFrom::Start(offset))?;
+            let mut buf: [u8; ENTRY_HEAD_LEN] = [0; ENTRY_HEAD_LEN];
+            self.reader.read_exact(&mut buf)?;
+            let head = match EntryHeader::decode(&buf) {
+                Ok(head) => head,
+                Err(_) => return Ok(false),
+            };
+            if check_entry(&head, offset, file_len).is_err() {
+                return Ok(false);
+            }
+            batch_left = match head.kind {
+                CmdKind::BATCH => head.version,
+                _ => batch_left.saturating_sub(1),
+            };
+            offset += head.entry_size() as u64;
+        }
+        if batch_left > 0 {
+            return Ok(false);
+        }
+
+        debug!(
+            "loading {} in {} chunks",
+            self.data_path_buf.display(),
+            starts.len()
+        );
+        let mut ranges: Vec<(u64, u64)> = starts.windows(2).map(|w| (w[0], w[1])).collect();
+        ranges.push((starts[starts.len() - 1], file_len));
+        let path_buf = self.data_path_buf.clone();
+        let capacity = self.options.read_buffer_capacity;
+        let partials: Vec<Result<HashMap<String, (u64, CmdKind)>>> = ranges
+            .par_iter()
+            .map(|&(start, end)| scan_range(&path_buf, start, end, capacity))
+            .collect();
+
+        // chunks are in file order, so replaying them in turn keeps the last write
+        for partial in partials {
+            let mut latest: Vec<(String, (u64, CmdKind))> = partial?.into_iter().collect();
+            latest.sort_unstable_by_key(|(_, (offset, _))| *offset);
+            for (key, (offset, kind)) in latest {
+                self.apply_loaded(key, offset, kind)?;
+            }
+        }
+        self.writer.pos = file_len;
+        Ok(true)
+    }
+
+    /// Whether the entry at `offset` looks like the last write was cut short:
+    /// either the header itself is incomplete, or it decodes and describes an
+    /// entry running past the end of the file. Anything else, lengths over
+    /// `MAX_TORN_BODY_LEN` included, is treated as corruption in the middle of
+    /// the log. The size limits in `Options` play no part, the entry may have
+    /// been written under other ones.
+    fn is_torn_tail(&mut self, offset: u64) -> Result<bool> {
+        let file_len = self.reader.reader.get_ref().metadata()?.len();
+        let remaining = file_len.saturating_sub(offset);
+        if remaining < ENTRY_HEAD_LEN as u64 {
+            return Ok(true);
+        }
+
+        self.reader.seek(SeekFrom::Start(offset))?;
+        let mut buf: [u8; ENTRY_HEAD_LEN] = [0; ENTRY_HEAD_LEN];
+        self.reader.read_exact(&mut buf)?;
+        let head = match EntryHeader::decode(&buf) {
+            Ok(head) => head,
+            Err(_) => return Ok(false),
+        };
+        let body_len = match head.key_len.checked_add(head.value_len) {
+            Some(body_len) if body_len as u64 <= MAX_TORN_BODY_LEN => body_len as u64,
+            _ => return Ok(false),
+        };
+        Ok(body_len > remaining - ENTRY_HEAD_LEN as u64)
+    }
+
+    /// Runs `merge`, reporting it to the `on_compaction` callback. Returns
+    /// how many tombstones were dropped.
+    fn compact(&mut self) -> Result<u64> {
+        self.compact_in(MergeOrder::Log)
+    }
+
+    fn compact_in(&mut self, order: MergeOrder) -> Result<u64> {
+        if self.compaction_cancelled() {
+            return Err(KvsError::CompactionCancelled);
+        }
+        let callback = self.options.on_compaction.clone();
+        let bytes_before = self.writer.pos;
+        info!(
+            "compacting {}: {} bytes, {} of them overwritten",
+            self.data_path_buf.display(),
+            bytes_before,
+            self.pending_compact
+        );
+        if let Some(callback) = &callback {
+            callback(CompactionInfo::Started {
+                bytes_before,
+                pending_bytes: self.pending_compact,
+            });
+        }
+        let start = Instant::now();
+        let dropped = self.merge(order)?;
+        let duration = start.elapsed();
+        info!(
+            "compacted {}: reclaimed {} bytes in {:?}",
+            self.data_path_buf.display(),
+            bytes_before.saturating_sub(self.writer.pos),
+            duration
+        );
+        if let Some(callback) = callback {
+            callback(CompactionInfo::Finished {
+                bytes_before,
+                bytes_after: self.writer.pos,
+                duration,
+            });
+        }
+        Ok(dropped)
+    }
+
+    fn merge_path_buf(&self) -> Result<PathBuf> {
+        merge_path_of(self.data_path_buf.as_path(), &self.options.name)
+    }
+
+    fn blob_merge_path_buf(&self) -> Result<PathBuf> {
+        blob_merge_path_of(self.data_path_buf.as_path(), &self.options.name)
+    }
+
+    /// Copies the live entries among the next `budget` bytes of the scan.
+    fn advance_incremental(&mut self, state: &mut IncrementalMerge, budget: u64) -> Result<()> {
+        let stop = state.scan_offset.saturating_add(budget);
+        while state.scan_offset < state.end && state.scan_offset < stop {
+            let e = self.read_at(state.scan_offset)?;
+            let size = e.size() as u64;
+            if self.keeps_version(&e, state.scan_offset, &mut state.old_versions) {
+                let new_pos = state.writer.pos;
+                state.writer.write_all(&e.encode())?;
+                state.relocated.push((e.key, state.scan_offset, new_pos));
+            } else if self.keeps_tombstone(&e, state.scan_offset) {
+                let new_pos = state.writer.pos;
+                state.writer.write_all(&e.encode())?;
+                state
+                    .relocated_tombstones
+                    .push((e.key, state.scan_offset, new_pos));
+            }
+            state.scan_offset += size;
+        }
+        Ok(())
+    }
+
+    /// Copies what was written since the compaction started and swaps the
+    /// merge file in for the data file.
+    fn finish_incremental(&mut self, mut state: IncrementalMerge) -> Result<()> {
+        let tail_start = state.end;
+        let tail_end = self.writer.pos;
+        let shift_base = state.writer.pos;
+        let mut tombstones = HashMap::new();
+        for (key, old_pos, new_pos) in state.relocated_tombstones {
+            if self.tombstones.get(&key) == Some(&old_pos) {
+                tombstones.insert(key, new_pos);
+            }
+        }
+        let mut offset = tail_start;
+        while offset < tail_end {
+            let e = self.read_at(offset)?;
+            if e.kind == CmdKind::DEL && self.tombstones.get(&e.key) == Some(&offset) {
+                tombstones.insert(e.key.clone(), shift_base + offset - tail_start);
+            }
+            state.writer.write_all(&e.encode())?;
+            offset += e.size() as u64;
+        }
+        state.writer.flush()?;
+
+        // moved entries land below `tail_start`, so they can't be mistaken
+        // for tail entries when shifting those afterwards
+        for (key, old_pos, new_pos) in state.relocated {
+            self.index.relocate(&key, old_pos, new_pos);
+        }
+        self.index.remap(|pos| match pos >= tail_start {
+            true => shift_base + pos - tail_start,
+            false => pos,
+        });
+
+        self.install_merged(state.writer, state.path_buf.as_path())?;
+        self.tombstones = tombstones;
+        self.pending_compact = self.pending_compact.saturating_sub(state.pending_at_start);
+        self.metrics.record_compaction();
+        info!(
+            "incremental compaction of {} finished: reclaimed {} bytes",
+            self.data_path_buf.display(),
+            tail_end.saturating_sub(self.writer.pos)
+        );
+        Ok(())
+    }
+
+    /// Swaps the merge file at `path`, written through `merged`, in for the
+    /// data file. The merge file was created for plain writing, so it is
+    /// reopened in append mode like the data file always is, otherwise a later
+    /// truncation would leave the writer past the end of the file.
+    ///
+    /// Every read borrows the store mutably, so none can be in progress while
+    /// the handles are replaced. The one read that outlives a call, the
+    /// iterator of `scan_iter`, has its own handle on the old file, which
+    /// stays readable after being unlinked until that handle is dropped.
+    fn install_merged(&mut self, mut merged: BufWriterWithPos<File>, path: &Path) -> Result<()> {
+        merged.flush()?;
+        let pos = merged.pos;
+        drop(merged);
+        self.unmap();
+        let (writer, reader) = open_log(path, OpenOptions::new().append(true), &self.options)?;
+        self.writer = writer;
+        self.writer.pos = pos;
+        self.reader = reader;
+        self.share_commit_files()?;
+        std::fs::remove_file(self.data_path_buf.as_path())?;
+        std::fs::rename(path, self.data_path_buf.as_path())?;
+        self.reserve_space();
+        Ok(())
+    }
+
+    /// Whether compaction has to copy `e`, read at `offset`, because it is the
+    /// latest tombstone of its key and still within `tombstone_grace`.
+    fn keeps_tombstone(&self, e: &Entry, offset: u64) -> bool {
+        if e.kind != CmdKind::DEL || self.tombstones.get(&e.key) != Some(&offset) {
+            return false;
+        }
+        let age = self.options.now_millis().saturating_sub(e.timestamp);
+        u128::from(age) < self.options.tombstone_grace.as_millis()
+    }
+
+    /// Whether compaction has to copy `e`, read at `offset`, as one of the
+    /// values `keep_versions` keeps of its key: the current one, or an older
+    /// one once `old` says the ones before it have been dropped.
+    fn keeps_version(&self, e: &Entry, offset: u64, old: &mut OldVersions) -> bool {
+        if !matches!(e.kind, CmdKind::PUT | CmdKind::BLOB) {
+            return false;
+        }
+        if self.index.points_at(&e.key, offset) {
+            return true;
+        }
+        match old.get_mut(&e.key) {
+            Some(0) => true,
+            Some(to_drop) => {
+                *to_drop -= 1;
+                false
+            }
+            None => false,
+        }
+    }
+
+    /// How many of the older values of each live key a compaction drops,
+    /// counted by reading the log, so that `keep_versions` of them remain
+    /// with the current one. Empty without reading anything when only the
+    /// current values are kept.
+    fn old_versions(&mut self) -> Result<OldVersions> {
+        let keep = self.options.keep_versions.max(1);
+        let mut counts: OldVersions = HashMap::new();
+        if keep == 1 {
+            return Ok(counts);
+        }
+        let mut offset = HEADER_LEN;
+        while let Some(e) = self.read_next(offset)? {
+            offset += e.size() as u64;
+            if matches!(e.kind, CmdKind::PUT | CmdKind::BLOB) {
+                *counts.entry(e.key).or_default() += 1;
+            }
+        }
+        let mut old_versions = HashMap::with_capacity(counts.len());
+        for (key, count) in counts {
+            if self.index_get(&key)?.is_some() {
+                old_versions.insert(key, count.saturating_sub(keep));
+            }
+        }
+        Ok(old_versions)
+    }
+
+    /// Throws away a compaction started by `compact_step`.
+    fn abort_incremental(&mut self) {
+        if let Some(state) = self.incremental.take() {
+            state.discard();
+        }
+    }
+
+    fn merge(&mut self, order: MergeOrder) -> Result<u64> {
+        // both write the same merge file
+        self.abort_incremental();
+        let merge_path_buf = self.merge_path_buf()?;
+        let blob_merge_path_buf = self.blob_merge_path_buf()?;
+        let merge_file = File::create(merge_path_buf.as_path())?;
+        let mut write_buf = BufWriterWithPos::new(merge_file, self.options.write_buffer_capacity)?;
+        let copied = self.create_merged_blobs().and_then(|mut blobs| {
+            let copied = match order {
+                MergeOrder::Log => self.copy_live(&mut write_buf, blobs.as_mut()),
+                MergeOrder::Key => self.copy_sorted(&mut write_buf, blobs.as_mut()),
+            };
+            copied.map(|copied| (copied, blobs))
+        });
+        let ((moved, dropped), mut merged_blobs) = match copied {
+            Ok(copied) => copied,
+            Err(e) => {
+                drop(write_buf);
+                // the blob merge file first, it's only ever kept next to a merge file
+                let _ = std::fs::remove_file(blob_merge_path_buf.as_path());
+                let _ = std::fs::remove_file(merge_path_buf.as_path());
+                return Err(e);
+            }
+        };
+
+        // the merged file only keeps live entries and recent tombstones
+        self.tombstones.clear();
+        for (key, old_pos, pos, kind) in moved {
+            match kind {
+                CmdKind::DEL => {
+                    self.tombstones.insert(key, pos);
+                }
+                _ => {
+                    self.index.relocate(&key, old_pos, pos);
+                }
+            }
+        }
+
+        // the merged data file points into the merged blobs, which have to be
+        // whole before it takes the place of the data file
+        if let Some(blobs) = &mut merged_blobs {
+            blobs.flush()?;
+        }
+        self.install_merged(write_buf, merge_path_buf.as_path())?;
+        if let Some(blobs) = merged_blobs {
+            self.install_merged_blobs(blobs)?;
         }
+
+        self.pending_compact = 0;
+        self.metrics.record_compaction();
+        Ok(dropped)
     }
 
-    pub fn size(&self) -> usize {
-        ENTRY_HEAD_LEN + self.key_len + self.value_len
+    /// A new blob file for `merge` to copy the live blobs into, so the dead
+    /// ones are left behind with the old file. `None` if there is no blob
+    /// file.
+    fn create_merged_blobs(&mut self) -> Result<Option<BlobFile>> {
+        if self.blobs.is_none() && !self.blob_path_buf()?.exists() {
+            return Ok(None);
+        }
+        let path_buf = self.blob_merge_path_buf()?;
+        // `BlobFile::open` appends, so whatever an earlier merge left goes
+        File::create(path_buf.as_path())?;
+        Ok(Some(BlobFile::open(
+            path_buf,
+            self.options.write_buffer_capacity,
+        )?))
     }
 
-    pub fn encode(&self) -> Vec<u8> {
-        let mut buf = vec![0; self.size()];
-        // encode key len
-        buf[0..USIZE_LEN].copy_from_slice(&self.key_len.to_be_bytes());
+    /// Swaps the blob file `merged`, written by `merge`, in for the blob file,
+    /// once the merged data file pointing into it is in place. The iterator
+    /// of `scan_iter` keeps reading the old blobs through its own handle.
+    fn install_merged_blobs(&mut self, merged: BlobFile) -> Result<()> {
+        std::fs::rename(merged.path(), self.blob_path_buf()?)?;
+        drop(merged);
+        self.blobs = None;
+        self.blobs()?;
+        Ok(())
+    }
 
-        // encode value length
-        buf[USIZE_LEN..USIZE_LEN * 2].copy_from_slice(&self.value_len.to_be_bytes());
+    /// Encodes `e`, read at `offset`, for a compaction to write. Given
+    /// `blobs`, the blob of a reference is copied there and the reference
+    /// pointed at the copy.
+    fn encode_kept(
+        &mut self,
+        mut e: Entry,
+        offset: u64,
+        blobs: Option<&mut BlobFile>,
+    ) -> Result<Vec<u8>> {
+        if let Some(blobs) = blobs.filter(|_| e.kind == CmdKind::BLOB) {
+            let blob = BlobRef::decode(&e.value).ok_or(KvsError::CorruptedEntry(offset))?;
+            let copied = blobs
+                .append_copy(self.blobs()?, blob)?
+                .ok_or(KvsError::CorruptedEntry(offset))?;
+            e.value = copied.encode();
+            e.value_len = e.value.len();
+        }
+        Ok(e.encode())
+    }
 
-        // encode kind
-        buf[USIZE_LEN * 2..ENTRY_HEAD_LEN]
-            .copy_from_slice(bincode::serialize(&self.kind).unwrap().as_slice());
+    /// Writes the live entries and kept tombstones to `dest` as they are read,
+    /// so only their keys and new offsets are held in memory, never values.
+    /// The blobs they point at are copied to `blobs` if given. Also returns
+    /// how many tombstones were left behind.
+    fn copy_live(
+        &mut self,
+        dest: &mut BufWriterWithPos<File>,
+        mut blobs: Option<&mut BlobFile>,
+    ) -> Result<(MovedEntries, u64)> {
+        dest.write_all(&file_header())?;
+        let callback = self.options.on_compaction.clone();
+        let total = self.writer.pos;
+        let mut next_report = PROGRESS_INTERVAL;
+        let mut old_versions = self.old_versions()?;
+        let mut moved = Vec::new();
+        let mut dropped = 0;
+        let mut offset = HEADER_LEN;
+        while let Some(e) = self.read_next(offset)? {
+            if self.compaction_cancelled() {
+                return Err(KvsError::CompactionCancelled);
+            }
+            if let Some(callback) = callback.as_ref().filter(|_| offset >= next_report) {
+                callback(CompactionInfo::Progress {
+                    scanned: offset,
+                    total,
+                });
+                next_report = offset + PROGRESS_INTERVAL;
+            }
+            let size = e.size() as u64;
+            if self.keeps_version(&e, offset, &mut old_versions) || self.keeps_tombstone(&e, offset)
+            {
+                moved.push((e.key.clone(), offset, dest.pos, e.kind));
+                let encoded = self.encode_kept(e, offset, blobs.as_deref_mut())?;
+                dest.write_all(&encoded)?;
+            } else if e.kind == CmdKind::DEL {
+                dropped += 1;
+            }
+            offset += size;
+        }
+        Ok((moved, dropped))
+    }
 
-        // encode key
-        buf[ENTRY_HEAD_LEN..ENTRY_HEAD_LEN + self.key_len].copy_from_slice(self.key.as_bytes());
+    /// Like `merge`, dropping only the dead entries of `keys`, see
+    /// `Storage::compact_keys`. Returns the number of bytes reclaimed.
+    fn merge_keys(&mut self, keys: &HashSet<&str>) -> Result<u64> {
+        self.abort_incremental();
+        let merge_path_buf = self.merge_path_buf()?;
+        let merge_file = File::create(merge_path_buf.as_path())?;
+        let mut write_buf = BufWriterWithPos::new(merge_file, self.options.write_buffer_capacity)?;
+        let (moved, overwritten) = match self.copy_except_dead(keys, &mut write_buf) {
+            Ok(copied) => copied,
+            Err(e) => {
+                drop(write_buf);
+                let _ = std::fs::remove_file(merge_path_buf.as_path());
+                return Err(e);
+            }
+        };
 
-        // encode value
-        buf[ENTRY_HEAD_LEN + self.key_len..].copy_from_slice(self.value.as_bytes());
+        // dead entries of other keys are copied too, so a tombstone only
+        // counts if it was the latest one of its key
+        let mut tombstones = HashMap::new();
+        for (key, old_pos, pos, kind) in moved {
+            match kind {
+                CmdKind::DEL => {
+                    if self.tombstones.get(&key) == Some(&old_pos) {
+                        tombstones.insert(key, pos);
+                    }
+                }
+                _ => {
+                    self.index.relocate(&key, old_pos, pos);
+                }
+            }
+        }
 
-        buf
+        let bytes_before = self.writer.pos;
+        self.install_merged(write_buf, merge_path_buf.as_path())?;
+        self.tombstones = tombstones;
+        self.pending_compact = self.pending_compact.saturating_sub(overwritten);
+        self.metrics.record_compaction();
+        Ok(bytes_before - self.writer.pos)
     }
 
-    pub fn decode(b: &[u8; ENTRY_HEAD_LEN]) -> Result<Entry> {
-        let key_len = usize::from_be_bytes(b[0..USIZE_LEN].try_into()?);
-        let value_len = usize::from_be_bytes(b[USIZE_LEN..USIZE_LEN * 2].try_into()?);
-        let kind: CmdKind = bincode::deserialize(&b[USIZE_LEN * 2..ENTRY_HEAD_LEN])?;
-        Ok(Entry {
-            key_len,
-            value_len,
-            kind,
-            key: String::new(),
-            value: String::new(),
-        })
+    /// Writes every entry to `dest` except the dead ones of `keys`, which
+    /// `copy_live` would drop. Also returns the bytes of the overwritten or
+    /// removed values dropped, as counted by `pending_compact`.
+    fn copy_except_dead(
+        &mut self,
+        keys: &HashSet<&str>,
+        dest: &mut BufWriterWithPos<File>,
+    ) -> Result<(MovedEntries, u64)> {
+        dest.write_all(&file_header())?;
+        let mut old_versions = self.old_versions()?;
+        old_versions.retain(|key, _| keys.contains(key.as_str()));
+        let mut moved = Vec::new();
+        let mut overwritten = 0;
+        let mut offset = HEADER_LEN;
+        while let Some(e) = self.read_next(offset)? {
+            if self.compaction_cancelled() {
+                return Err(KvsError::CompactionCancelled);
+            }
+            let size = e.size() as u64;
+            // a batch marker goes since the entries it counts may not all stay
+            let keep = e.kind != CmdKind::BATCH
+                && (!keys.contains(e.key.as_str())
+                    || self.keeps_version(&e, offset, &mut old_versions)
+                    || self.keeps_tombstone(&e, offset));
+            if keep {
+                moved.push((e.key.clone(), offset, dest.pos, e.kind));
+                dest.write_all(&e.encode())?;
+            } else if e.kind != CmdKind::DEL {
+                overwritten += size;
+            }
+            offset += size;
+        }
+        Ok((moved, overwritten))
     }
-}
 
-pub trait Storage {
-    fn get(&mut self, key: String) -> Result<Option<String>>;
+    /// Like `copy_live`, writing the entries in key order, with a kept
+    /// tombstone where its key would be. Reading them in that order jumps
+    /// around the old file, and progress counts the bytes copied.
+    fn copy_sorted(
+        &mut self,
+        dest: &mut BufWriterWithPos<File>,
+        mut blobs: Option<&mut BlobFile>,
+    ) -> Result<(MovedEntries, u64)> {
+        dest.write_all(&file_header())?;
+        let mut entries = self.index.entries(&self.key_reader())?;
+        let mut dropped = 0;
+        let tombstones: Vec<(String, u64)> = self
+            .tombstones
+            .iter()
+            .map(|(key, offset)| (key.clone(), *offset))
+            .collect();
+        for (key, offset) in tombstones {
+            let e = self.read_at(offset)?;
+            match self.keeps_tombstone(&e, offset) {
+                true => entries.push((key, offset)),
+                false => dropped += 1,
+            }
+        }
+        let mut old_versions = self.old_versions()?;
+        if !old_versions.is_empty() {
+            let mut offset = HEADER_LEN;
+            while let Some(e) = self.read_next(offset)? {
+                let size = e.size() as u64;
+                let live = self.index.points_at(&e.key, offset);
+                if !live && self.keeps_version(&e, offset, &mut old_versions) {
+                    entries.push((e.key, offset));
+                }
+                offset += size;
+            }
+        }
+        // the values of a key stay in the order they were written
+        entries.sort_unstable();
 
-    fn put(&mut self, key: String, val: String) -> Result<()>;
+        let callback = self.options.on_compaction.clone();
+        let total = self.writer.pos;
+        let mut copied = HEADER_LEN;
+        let mut next_report = PROGRESS_INTERVAL;
+        let mut moved = Vec::with_capacity(entries.len());
+        for (key, offset) in entries {
+            if self.compaction_cancelled() {
+                return Err(KvsError::CompactionCancelled);
+            }
+            if let Some(callback) = callback.as_ref().filter(|_| copied >= next_report) {
+                callback(CompactionInfo::Progress {
+                    scanned: copied,
+                    total,
+                });
+                next_report = copied + PROGRESS_INTERVAL;
+            }
+            let e = self.read_at(offset)?;
+            moved.push((key, offset, dest.pos, e.kind));
+            copied += e.size() as u64;
+            let encoded = self.encode_kept(e, offset, blobs.as_deref_mut())?;
+            dest.write_all(&encoded)?;
+        }
+        Ok((moved, dropped))
+    }
+}
 
-    fn remove(&mut self, key: String) -> Result<()>;
+impl Drop for SimplifiedBitcask {
+    fn drop(&mut self) {
+        // `BufWriter` swallows flush errors on drop, so flush here to at least report them
+        if let Some(blobs) = &mut self.blobs {
+            if let Err(e) = blobs.flush() {
+                error!("failed to flush {}: {}", blobs.path().display(), e);
+            }
+        }
+        let flushed = self.writer.flush();
+        if let Err(e) = &flushed {
+            error!("failed to flush {}: {}", self.data_path_buf.display(), e);
+        }
+        if let Some(committer) = &self.committer {
+            if let Err(e) = committer.sync() {
+                error!("failed to sync {}: {}", self.data_path_buf.display(), e);
+            }
+        }
+        self.abort_incremental();
+        // a store whose index never finished loading, or was never loaded as
+        // by `repair`, can't tell whether the file is whole, and holds only
+        // part of the index
+        if flushed.is_ok() && self.loaded {
+            // written first, so that it is only ever trusted along with a footer
+            if self.options.index_checkpoint {
+                if let Err(e) = self.write_checkpoint() {
+                    error!(
+                        "failed to write the index checkpoint of {}: {}",
+                        self.data_path_buf.display(),
+                        e
+                    );
+                }
+            }
+            if let Err(e) = self.write_footer() {
+                error!(
+                    "failed to write the footer of {}: {}",
+                    self.data_path_buf.display(),
+                    e
+                );
+            }
+        }
+    }
 }
 
-pub struct SimplifiedBitcask {
-    data_path_buf: PathBuf,
+/// Iterator returned by `SimplifiedBitcask::iter_entries`, stops after the
+/// first error.
+pub struct EntryIter<'a> {
+    bitcask: &'a mut SimplifiedBitcask,
+    offset: u64,
+    done: bool,
+}
 
-    reader: BufReaderWithPos<File>,
+impl Iterator for EntryIter<'_> {
+    type Item = Result<(u64, Entry)>;
 
-    writer: BufWriterWithPos<File>,
+    fn next(&mut self) -> Option<Self::Item> {
+        if self.done {
+            return None;
+        }
+        match self.bitcask.read_next(self.offset) {
+            Ok(Some(e)) => {
+                let offset = self.offset;
+                self.offset += e.size() as u64;
+                Some(Ok((offset, e)))
+            }
+            Ok(None) => {
+                self.done = true;
+                None
+            }
+            Err(e) => {
+                self.done = true;
+                Some(Err(e))
+            }
+        }
+    }
+}
 
-    index: HashMap<String, u64>,
+/// Iterator behind `SimplifiedBitcask::scan_iter`. It reads through its own
+/// handles, opened when the scan started, so compaction renaming a new data
+/// file over the old one doesn't affect it, and it never reads past `end`.
+/// `clear` truncates the files in place, after which it fails.
+struct PinnedScan {
+    file: File,
 
-    pending_compact: u64,
+    end: u64,
+
+    /// The blob file with its length when the scan started, which no blob
+    /// read may go past.
+    blobs: Option<(File, u64)>,
+
+    /// Keys left to read with their offsets, in the order to yield them.
+    entries: std::vec::IntoIter<(String, u64)>,
 }
 
-impl Storage for SimplifiedBitcask {
-    fn get(&mut self, key: String) -> Result<Option<String>> {
-        match self.read(&key) {
-            Ok(e) => Ok(Some(e.value)),
-            Err(KvsError::KeyNotFound) => Ok(None),
-            Err(e) => Err(e),
+impl PinnedScan {
+    fn read_value(&self, offset: u64) -> Result<String> {
+        let mut buf: [u8; ENTRY_HEAD_LEN] = [0; ENTRY_HEAD_LEN];
+        if offset.saturating_add(ENTRY_HEAD_LEN as u64) > self.end {
+            return Err(KvsError::CorruptedEntry(offset));
+        }
+        file_read_exact_at(&self.file, &mut buf, offset)?;
+        let head = EntryHeader::decode(&buf)?;
+        if offset.saturating_add(head.entry_size() as u64) > self.end {
+            return Err(KvsError::CorruptedEntry(offset));
+        }
+        let mut val_buf = vec![0; head.value_len];
+        let value_offset = offset + (ENTRY_HEAD_LEN + head.key_len) as u64;
+        file_read_exact_at(&self.file, &mut val_buf, value_offset)?;
+        let value = String::from_utf8(val_buf)?;
+        if head.kind != CmdKind::BLOB {
+            return Ok(value);
         }
-    }
 
-    fn put(&mut self, key: String, val: String) -> Result<()> {
-        let e = Entry::new(key, val, CmdKind::PUT);
-        self.write(e)?;
-        if self.pending_compact >= COMPACTION_THRESHOLD {
-            self.merge()?;
+        let blob = BlobRef::decode(&value).ok_or(KvsError::CorruptedEntry(offset))?;
+        let (blobs, blobs_len) = self
+            .blobs
+            .as_ref()
+            .ok_or(KvsError::CorruptedEntry(offset))?;
+        match blob.offset.checked_add(blob.len) {
+            Some(end) if end <= *blobs_len => {}
+            _ => return Err(KvsError::CorruptedEntry(offset)),
         }
-        Ok(())
+        let mut blob_buf = vec![0; blob.len as usize];
+        file_read_exact_at(blobs, &mut blob_buf, blob.offset)?;
+        Ok(String::from_utf8(blob_buf)?)
     }
+}
 
-    fn remove(&mut self, key: String) -> Result<()> {
-        if self.index.contains_key(&key) {
-            let e = Entry::new(key.clone(), String::new(), CmdKind::DEL);
-            self.write(e)?;
-            self.index.remove(&key);
-            return Ok(());
-        }
+impl Iterator for PinnedScan {
+    type Item = Result<(String, String)>;
 
-        Err(KvsError::KeyNotFound)
+    fn next(&mut self) -> Option<Self::Item> {
+        let (key, offset) = self.entries.next()?;
+        Some(self.read_value(offset).map(|val| (key, val)))
     }
 }
 
-impl SimplifiedBitcask {
-    pub fn open(path_buf: PathBuf) -> Result<SimplifiedBitcask> {
-        let data_path_buf = path_buf.join(STORAGE_FILE_PREFIX.to_string() + ".data");
-        let writer = BufWriterWithPos::new(
-            OpenOptions::new()
-                .create(true)
-                .write(true)
-                .append(true)
-                .open(data_path_buf.as_path())?,
-        )?;
-        let reader = BufReaderWithPos::new(File::open(data_path_buf.as_path())?)?;
-        let mut instance = SimplifiedBitcask {
-            data_path_buf,
-            reader,
-            writer,
-            index: HashMap::new(),
-            pending_compact: 0,
-        };
-        instance.load_index()?;
-        Ok(instance)
+/// Checks that `header`, the start of a data file, announces the current
+/// format version.
+pub(crate) fn check_format(header: &[u8]) -> Result<()> {
+    let found = match header.strip_prefix(FORMAT_MAGIC.as_slice()) {
+        Some(version) if version.len() == 4 => u32::from_be_bytes(version.try_into()?),
+        _ => 0,
+    };
+    if found != FORMAT_VERSION {
+        return Err(KvsError::IncompatibleFormat {
+            found,
+            expected: FORMAT_VERSION,
+        });
     }
+    Ok(())
+}
 
-    fn write(&mut self, entry: Entry) -> Result<()> {
-        let key = entry.key.clone();
-        if let Some(old_pos) = self.index.insert(key, self.writer.pos) {
-            self.pending_compact += self.read_at(old_pos).unwrap().size() as u64;
-        }
-        let buf = entry.encode();
-        self.writer.write(&buf)?;
-        self.writer.flush()?;
-        Ok(())
+/// Rejects a decoded header whose lengths don't fit in a file of `file_len`
+/// bytes. The size limits in `Options` only apply to writes, an entry written
+/// under larger ones is still read back.
+fn check_entry(head: &EntryHeader, offset: u64, file_len: u64) -> Result<()> {
+    // a flipped bit in a length field must not turn into a huge allocation
+    let remaining = file_len.saturating_sub(offset + ENTRY_HEAD_LEN as u64);
+    match head.key_len.checked_add(head.value_len) {
+        Some(body_len) if body_len as u64 <= remaining => {}
+        _ => return Err(KvsError::CorruptedEntry(offset)),
+    }
+    Ok(())
+}
+
+/// Reads the entry at `offset` of a log `file_len` bytes long through
+/// `reader`, or `None` at its end.
+pub(crate) fn read_entry_from<R: Read + Seek>(
+    reader: &mut R,
+    offset: u64,
+    file_len: u64,
+) -> Result<Option<Entry>> {
+    reader.seek(SeekFrom::Start(offset))?;
+    let mut buf: [u8; ENTRY_HEAD_LEN] = [0; ENTRY_HEAD_LEN];
+    // a single `read` may stop short, and the zeroes left over in `buf`
+    // could still decode as a valid header
+    match read_full(reader, &mut buf)? {
+        0 => return Ok(None),
+        ENTRY_HEAD_LEN => {}
+        _ => return Err(KvsError::CorruptedEntry(offset)),
     }
+    let head = EntryHeader::decode(&buf)?;
+    check_entry(&head, offset, file_len)?;
 
-    fn read(&mut self, key: &str) -> Result<Entry> {
-        if let Some(offset) = self.index.get(key) {
-            let pos = *offset;
-            return self.read_at(pos);
-        };
+    let mut key_buf = vec![0; head.key_len];
+    reader.read_exact(key_buf.as_mut_slice())?;
+    let key = String::from_utf8(key_buf)?;
+
+    let mut val_buf = vec![0; head.value_len];
+    reader.read_exact(val_buf.as_mut_slice())?;
+    let value = String::from_utf8(val_buf)?;
 
-        Err(KvsError::KeyNotFound)
+    Ok(Some(Entry::from_header(head, key, value)))
+}
+
+/// Reads until `buf` is full or the input ends, returning how much was read.
+pub(crate) fn read_full<R: Read>(reader: &mut R, buf: &mut [u8]) -> io::Result<usize> {
+    let mut read = 0;
+    while read < buf.len() {
+        match reader.read(&mut buf[read..]) {
+            Ok(0) => break,
+            Ok(n) => read += n,
+            Err(e) if e.kind() == io::ErrorKind::Interrupted => {}
+            Err(e) => return Err(e),
+        }
     }
+    Ok(read)
+}
 
-    fn read_at(&mut self, offset: u64) -> Result<Entry> {
-        self.reader.seek(SeekFrom::Start(offset))?;
-        let mut buf: [u8; ENTRY_HEAD_LEN] = [0; ENTRY_HEAD_LEN];
-        let len = self.reader.read(&mut buf)?;
-        if len == 0 {
-            return Err(KvsError::EOF);
+/// Key of the entry at `offset`, read through `file` without moving any
+/// reader, or from the write buffer of `writer` for entries that haven't
+/// reached the file yet.
+fn key_at(file: &File, writer: &BufWriterWithPos<LogFile>, offset: u64) -> Result<String> {
+    let mut buf: [u8; ENTRY_HEAD_LEN] = [0; ENTRY_HEAD_LEN];
+    read_exact_at(file, writer, offset, &mut buf)?;
+    let head = EntryHeader::decode(&buf)?;
+    let key_offset = offset + ENTRY_HEAD_LEN as u64;
+    if key_offset.saturating_add(head.key_len as u64) > writer.pos {
+        return Err(KvsError::CorruptedEntry(offset));
+    }
+    let mut key_buf = vec![0; head.key_len];
+    read_exact_at(file, writer, key_offset, &mut key_buf)?;
+    Ok(String::from_utf8(key_buf)?)
+}
+
+/// Fills `buf` from `offset` of the log, whose tail may still sit in the
+/// write buffer of `writer`.
+fn read_exact_at(
+    file: &File,
+    writer: &BufWriterWithPos<LogFile>,
+    offset: u64,
+    buf: &mut [u8],
+) -> Result<()> {
+    let flushed = writer.flushed_pos();
+    let on_disk_len = flushed.saturating_sub(offset).min(buf.len() as u64) as usize;
+    let (on_disk, buffered) = buf.split_at_mut(on_disk_len);
+    if !on_disk.is_empty() {
+        file_read_exact_at(file, on_disk, offset)?;
+    }
+    if !buffered.is_empty() {
+        let start = (offset + on_disk_len as u64 - flushed) as usize;
+        let pending = writer.writer.buffer();
+        match pending.get(start..start + buffered.len()) {
+            Some(pending) => buffered.copy_from_slice(pending),
+            None => return Err(KvsError::CorruptedEntry(offset)),
         }
-        let mut e = Entry::decode(&buf)?;
+    }
+    Ok(())
+}
 
-        let mut key_buf = vec![0; e.key_len];
-        self.reader.read_exact(key_buf.as_mut_slice())?;
-        e.key = String::from_utf8(key_buf)?;
+#[cfg(unix)]
+fn file_read_exact_at(file: &File, buf: &mut [u8], offset: u64) -> io::Result<()> {
+    use std::os::unix::fs::FileExt;
+    file.read_exact_at(buf, offset)
+}
 
-        let mut val_buf = vec![0; e.value_len];
-        self.reader.read_exact(val_buf.as_mut_slice())?;
-        e.value = String::from_utf8(val_buf)?;
+/// Reserves `Options::preallocate` bytes of disk space for the data file at
+/// `path` without changing its length, which is where the log ends.
+#[cfg(target_os = "linux")]
+fn preallocate(file: &File, path: &Path, options: &Options) {
+    use std::os::unix::io::AsRawFd;
 
-        Ok(e)
+    let len = match options.preallocate {
+        Some(len) => len.min(libc::off_t::MAX as u64) as libc::off_t,
+        None => return,
+    };
+    // SAFETY: `fallocate` only takes the descriptor, which `file` keeps open
+    let ret = unsafe { libc::fallocate(file.as_raw_fd(), libc::FALLOC_FL_KEEP_SIZE, 0, len) };
+    if ret != 0 {
+        warn!(
+            "failed to preallocate {} bytes for {}: {}",
+            len,
+            path.display(),
+            io::Error::last_os_error()
+        );
     }
+}
 
-    fn load_index(&mut self) -> Result<()> {
-        let mut offset = 0;
-        loop {
-            match self.read_at(offset) {
-                Ok(e) => {
-                    let size = e.size() as u64;
-                    match e.kind {
-                        CmdKind::DEL => self.index.remove(&e.key),
-                        CmdKind::PUT => self.index.insert(e.key, offset),
-                    };
-                    offset += size;
-                }
-                Err(KvsError::EOF) => {
-                    self.writer.pos = offset;
-                    return Ok(());
-                }
-                Err(e) => {
-                    return Err(e);
-                }
+/// Preallocating is left out elsewhere, it's only an optimization.
+#[cfg(not(target_os = "linux"))]
+fn preallocate(_file: &File, _path: &Path, _options: &Options) {}
+
+/// Makes the entries of the directory at `path` durable, e.g. a file just
+/// renamed into it.
+#[cfg(unix)]
+fn sync_dir(path: &Path) -> Result<()> {
+    File::open(path)?.sync_all()?;
+    Ok(())
+}
+
+/// Directories can't be opened as files on Windows, where metadata updates
+/// are durable once the file itself is synced.
+#[cfg(windows)]
+fn sync_dir(_path: &Path) -> Result<()> {
+    Ok(())
+}
+
+#[cfg(windows)]
+fn file_read_exact_at(file: &File, mut buf: &mut [u8], mut offset: u64) -> io::Result<()> {
+    use std::os::windows::fs::FileExt;
+    while !buf.is_empty() {
+        match file.seek_read(buf, offset)? {
+            0 => return Err(io::ErrorKind::UnexpectedEof.into()),
+            n => {
+                buf = &mut buf[n..];
+                offset += n as u64;
             }
         }
     }
+    Ok(())
+}
 
-    fn merge(&mut self) -> Result<()> {
-        let mut offset = 0;
-        let mut valid_entry = Vec::new();
-        loop {
-            match self.read_at(offset) {
-                Ok(e) => {
-                    let size = e.size() as u64;
-                    if let Some(valid_pos) = self.index.get(&e.key) {
-                        if e.kind == CmdKind::PUT && *valid_pos == offset {
-                            valid_entry.push(e);
-                        }
-                    }
-                    offset += size;
-                }
-                Err(KvsError::EOF) => {
-                    break;
-                }
-                Err(e) => {
-                    return Err(e);
-                }
+/// Latest offset and kind of each key written in `[start, end)`, which must
+/// begin and end on entry boundaries.
+#[cfg(feature = "rayon")]
+fn scan_range(
+    path: &Path,
+    start: u64,
+    end: u64,
+    capacity: usize,
+) -> Result<HashMap<String, (u64, CmdKind)>> {
+    let mut reader = BufReader::with_capacity(capacity, File::open(path)?);
+    reader.seek(SeekFrom::Start(start))?;
+    let mut latest = HashMap::new();
+    let mut offset = start;
+    while offset < end {
+        let mut buf: [u8; ENTRY_HEAD_LEN] = [0; ENTRY_HEAD_LEN];
+        reader.read_exact(&mut buf)?;
+        let head = EntryHeader::decode(&buf)?;
+        let mut key_buf = vec![0; head.key_len];
+        reader.read_exact(key_buf.as_mut_slice())?;
+        reader.seek_relative(head.value_len as i64)?;
+        match String::from_utf8(key_buf) {
+            Ok(_) if head.kind == CmdKind::BATCH => {}
+            Ok(key) => {
+                latest.insert(key, (offset, head.kind));
             }
+            Err(_) => warn!(
+                "skipping the entry at offset {} in {}: its key isn't valid UTF-8",
+                offset,
+                path.display()
+            ),
+        }
+        offset += head.entry_size() as u64;
+    }
+    Ok(latest)
+}
+
+/// Decodes the entry at `offset`, where `reader` is positioned, returning its
+/// size.
+fn verify_entry<R: Read>(reader: &mut R, offset: u64, file_len: u64) -> Result<u64> {
+    if file_len - offset < ENTRY_HEAD_LEN as u64 {
+        return Err(KvsError::CorruptedEntry(offset));
+    }
+    let mut buf: [u8; ENTRY_HEAD_LEN] = [0; ENTRY_HEAD_LEN];
+    reader.read_exact(&mut buf)?;
+    let head = EntryHeader::decode(&buf)?;
+    let remaining = file_len - offset - ENTRY_HEAD_LEN as u64;
+    match head.key_len.checked_add(head.value_len) {
+        Some(body_len) if body_len as u64 <= remaining => {}
+        _ => return Err(KvsError::CorruptedEntry(offset)),
+    }
+
+    let mut key_buf = vec![0; head.key_len];
+    reader.read_exact(key_buf.as_mut_slice())?;
+    String::from_utf8(key_buf)?;
+    let mut val_buf = vec![0; head.value_len];
+    reader.read_exact(val_buf.as_mut_slice())?;
+    let value = String::from_utf8(val_buf)?;
+    if head.kind == CmdKind::BLOB && BlobRef::decode(&value).is_none() {
+        return Err(KvsError::CorruptedEntry(offset));
+    }
+    Ok(head.entry_size() as u64)
+}
+
+/// Copies the entries of an older data file from `offset` on to `dest` in
+/// the current encoding, given the length of its entry headers, which start
+/// like the current ones. Each key's entries are numbered for its versions.
+/// Entries from formats 0 and 1 get a timestamp of 0, except for tombstones,
+/// which kept their removal time in the value. An entry cut short at the end
+/// is dropped.
+fn convert_legacy<W: Write>(
+    src: &Path,
+    offset: u64,
+    head_len: usize,
+    dest: &mut W,
+    options: &Options,
+) -> Result<()> {
+    let mut reader = BufReader::with_capacity(options.read_buffer_capacity, File::open(src)?);
+    let file_len = reader.get_ref().metadata()?.len();
+    reader.seek(SeekFrom::Start(offset))?;
+    let mut offset = offset;
+    let mut versions: HashMap<String, u64> = HashMap::new();
+    while file_len - offset >= head_len as u64 {
+        let mut buf: [u8; ENTRY_HEAD_LEN] = [0; ENTRY_HEAD_LEN];
+        reader.read_exact(&mut buf[..head_len])?;
+        let mut head = EntryHeader::decode(&buf)?;
+        // a saturated length must not wrap around to a small one
+        let body_len = match head.key_len.checked_add(head.value_len) {
+            Some(body_len) => body_len as u64,
+            None => return Err(KvsError::CorruptedEntry(offset)),
+        };
+        if body_len > file_len - offset - head_len as u64 {
+            break;
         }
 
-        if !valid_entry.is_empty() {
-            let mut data_path_ancestors = self.data_path_buf.ancestors();
-            data_path_ancestors.next();
-            let merge_path_buf = data_path_ancestors
-                .next()
-                .ok_or(KvsError::InvalidDataPath)?
-                .join(STORAGE_FILE_PREFIX.to_string() + ".merge");
-            let merge_file = File::create(merge_path_buf.as_path())?;
-            let mut write_buf = BufWriterWithPos::new(merge_file)?;
+        let mut key_buf = vec![0; head.key_len];
+        reader.read_exact(key_buf.as_mut_slice())?;
+        let key = String::from_utf8(key_buf)?;
+        let mut val_buf = vec![0; head.value_len];
+        reader.read_exact(val_buf.as_mut_slice())?;
+        let mut value = String::from_utf8(val_buf)?;
+        if head.kind == CmdKind::DEL && head_len == LEGACY_HEAD_LEN {
+            head.timestamp = value.parse().unwrap_or(0);
+            value.clear();
+        }
+        let version = versions.entry(key.clone()).or_default();
+        *version += 1;
+        head.version = *version;
+        dest.write_all(&Entry::from_header(head, key, value).encode())?;
+        offset += head_len as u64 + body_len;
+    }
+    Ok(())
+}
 
-            for e in &valid_entry {
-                let key = e.key.clone();
-                self.index.insert(key, write_buf.pos);
-                write_buf.write(&e.encode())?;
+/// Copies exactly `len` bytes from `reader` to `writer`, a chunk at a time,
+/// failing if they aren't valid UTF-8. Some bytes may have been written by
+/// then.
+pub(crate) fn copy_utf8(reader: &mut dyn Read, writer: &mut dyn Write, len: usize) -> Result<()> {
+    let mut buf = vec![0; COPY_CHUNK_LEN];
+    let mut remaining = len;
+    // bytes of a character split across chunks, moved to the start of `buf`
+    let mut carried = 0;
+    while remaining > 0 {
+        let read_len = (buf.len() - carried).min(remaining);
+        reader.read_exact(&mut buf[carried..carried + read_len])?;
+        remaining -= read_len;
+        let filled = carried + read_len;
+        let valid = match std::str::from_utf8(&buf[..filled]) {
+            Ok(_) => filled,
+            Err(e) if e.error_len().is_none() && remaining > 0 => e.valid_up_to(),
+            Err(_) => {
+                let invalid = String::from_utf8(buf[..filled].to_vec()).unwrap_err();
+                return Err(invalid.into());
             }
+        };
+        writer.write_all(&buf[..valid])?;
+        buf.copy_within(valid..filled, 0);
+        carried = filled - valid;
+    }
+    Ok(())
+}
+
+/// Milliseconds since the Unix epoch.
+pub fn unix_millis() -> u64 {
+    SystemTime::now()
+        .duration_since(UNIX_EPOCH)
+        .map_or(0, |elapsed| elapsed.as_millis() as u64)
+}
+
+/// Opens the data file at `path` with `open_options` for appending, and again
+/// for reading.
+fn open_log(
+    path: &Path,
+    open_options: &OpenOptions,
+    options: &Options,
+) -> Result<(BufWriterWithPos<LogFile>, BufReaderWithPos<LogFile>)> {
+    let writer = BufWriterWithPos::new(
+        log_file(open_options.open(path)?, options),
+        options.write_buffer_capacity,
+    )?;
+    let reader = BufReaderWithPos::new(
+        log_file(File::open(path)?, options),
+        options.read_buffer_capacity,
+    )?;
+    Ok((writer, reader))
+}
+
+/// Path of the merge file of the store called `name` with its data file at
+/// `data_path`.
+fn merge_path_of(data_path: &Path, name: &str) -> Result<PathBuf> {
+    let mut data_path_ancestors = data_path.ancestors();
+    data_path_ancestors.next();
+    Ok(data_path_ancestors
+        .next()
+        .ok_or(KvsError::InvalidDataPath)?
+        .join(name.to_owned() + ".merge"))
+}
+
+/// Deals with a merge file left behind by a compaction that didn't finish.
+/// Without a data file the crash came after removing it and before renaming
+/// the merge file, by then complete, in its place, so the rename is done now.
+/// Otherwise the data file is still the one in use and the merge file is
+/// deleted, which would break a compaction running in another process that
+/// has the store open.
+fn recover_merge(data_path: &Path, merge_path: &Path) -> Result<()> {
+    if !merge_path.exists() {
+        return Ok(());
+    }
+    if data_path.exists() {
+        warn!(
+            "removing {} left behind by an interrupted compaction",
+            merge_path.display()
+        );
+        std::fs::remove_file(merge_path)?;
+    } else {
+        warn!(
+            "finishing an interrupted compaction by renaming {} to {}",
+            merge_path.display(),
+            data_path.display()
+        );
+        std::fs::rename(merge_path, data_path)?;
+    }
+    Ok(())
+}
+
+/// Path of the file a compaction copies the live blobs of the store called
+/// `name` into, next to its data file at `data_path`.
+fn blob_merge_path_of(data_path: &Path, name: &str) -> Result<PathBuf> {
+    Ok(data_path
+        .parent()
+        .ok_or(KvsError::InvalidDataPath)?
+        .join(name.to_owned() + ".blob.merge"))
+}
+
+/// Deals with a blob merge file left behind by a compaction that didn't
+/// finish, before `recover_merge` deals with the merge file. The merged data
+/// file points into it, so it replaces the blob file exactly when that one
+/// replaces the data file: the data file is gone, or the merge file already
+/// took its place. Otherwise it's deleted.
+fn recover_blob_merge(data_path: &Path, merge_path: &Path, blob_merge_path: &Path) -> Result<()> {
+    if !blob_merge_path.exists() {
+        return Ok(());
+    }
+    if merge_path.exists() && data_path.exists() {
+        warn!(
+            "removing {} left behind by an interrupted compaction",
+            blob_merge_path.display()
+        );
+        std::fs::remove_file(blob_merge_path)?;
+    } else {
+        let blob_path = blob_merge_path.with_extension("");
+        warn!(
+            "finishing an interrupted compaction by renaming {} to {}",
+            blob_merge_path.display(),
+            blob_path.display()
+        );
+        std::fs::rename(blob_merge_path, blob_path)?;
+    }
+    Ok(())
+}
+
+/// Path of the footer written next to the data file at `data_path` when the
+/// store is closed.
+fn footer_path_of(data_path: &Path, name: &str) -> Result<PathBuf> {
+    Ok(data_path
+        .parent()
+        .ok_or(KvsError::InvalidDataPath)?
+        .join(name.to_owned() + ".footer"))
+}
+
+/// Path of the index checkpoint written next to the data file at `data_path`
+/// when the store is closed, see `Options::index_checkpoint`.
+fn checkpoint_path_of(data_path: &Path, name: &str) -> Result<PathBuf> {
+    Ok(data_path
+        .parent()
+        .ok_or(KvsError::InvalidDataPath)?
+        .join(name.to_owned() + ".index"))
+}
+
+/// Reads and removes the footer of the data file at `data_path`, returning
+/// whether it vouches for the file as it is now: the store was closed cleanly
+/// and the file hasn't been cut or appended to since. The footer goes either
+/// way, the store is about to be written to.
+///
+/// It is kept beside the data file rather than at its end so that everything
+/// reading the log, the CLI and `ReaderStorage` included, never meets it.
+fn take_footer(data_path: &Path, footer_path: &Path) -> Result<bool> {
+    let footer = match std::fs::read(footer_path) {
+        Ok(footer) => footer,
+        Err(e) if e.kind() == io::ErrorKind::NotFound => return Ok(false),
+        Err(e) => return Err(e.into()),
+    };
+    std::fs::remove_file(footer_path)?;
+    if footer.len() != FOOTER_LEN || !footer.starts_with(FOOTER_MAGIC) {
+        warn!("ignoring the malformed footer {}", footer_path.display());
+        return Ok(false);
+    }
+    let end = u64::from_be_bytes(footer[FOOTER_MAGIC.len()..FOOTER_LEN - 8].try_into()?);
+    let checksum = u64::from_be_bytes(footer[FOOTER_LEN - 8..].try_into()?);
+    let file_len = match std::fs::metadata(data_path) {
+        Ok(metadata) => metadata.len(),
+        Err(e) if e.kind() == io::ErrorKind::NotFound => return Ok(false),
+        Err(e) => return Err(e.into()),
+    };
+    Ok(file_len == end && tail_checksum(data_path, end)? == checksum)
+}
+
+/// FNV-1a of `end` and the last `FOOTER_TAIL_LEN` bytes of the log before it,
+/// enough to tell a file that changed since its footer was written without
+/// reading all of it.
+fn tail_checksum(data_path: &Path, end: u64) -> Result<u64> {
+    let start = end.saturating_sub(FOOTER_TAIL_LEN);
+    let mut file = File::open(data_path)?;
+    file.seek(SeekFrom::Start(start))?;
+    let mut tail = end.to_be_bytes().to_vec();
+    file.take(end - start).read_to_end(&mut tail)?;
+    Ok(fnv1a(&tail))
+}
+
+/// FNV-1a, used instead of `DefaultHasher` where a hash must stay the same
+/// across Rust releases, like shard placement and footer checksums.
+pub(crate) fn fnv1a(bytes: &[u8]) -> u64 {
+    let mut hash: u64 = 0xcbf29ce484222325;
+    for byte in bytes {
+        hash ^= *byte as u64;
+        hash = hash.wrapping_mul(0x100000001b3);
+    }
+    hash
+}
+
+/// Fails with `KvsError::NotAFile` if something other than a regular file is
+/// at `path`, which opening it would report with a confusing OS error. Nothing
+/// being there is fine, the file may be about to be created.
+fn check_regular_file(path: &Path) -> Result<()> {
+    match std::fs::metadata(path) {
+        Ok(metadata) if !metadata.is_file() => Err(KvsError::NotAFile(path.to_path_buf())),
+        _ => Ok(()),
+    }
+}
+
+#[cfg(not(feature = "testing"))]
+fn log_file(file: File, _options: &Options) -> LogFile {
+    file
+}
+
+#[cfg(feature = "testing")]
+fn log_file(file: File, options: &Options) -> LogFile {
+    FaultyFile::new(file, options.faults.clone())
+}
+
+fn file_header() -> [u8; HEADER_LEN as usize] {
+    let mut header = [0; HEADER_LEN as usize];
+    header[..4].copy_from_slice(FORMAT_MAGIC);
+    header[4..].copy_from_slice(&FORMAT_VERSION.to_be_bytes());
+    header
+}
+
+fn check_key_size(size: usize, limit: usize) -> Result<()> {
+    if size > limit {
+        return Err(KvsError::KeyTooLarge { size, limit });
+    }
+    Ok(())
+}
+
+fn check_size(size: usize, limit: usize) -> Result<()> {
+    if size > limit {
+        return Err(KvsError::ValueTooLarge { size, limit });
+    }
+    Ok(())
+}
+
+/// Keeps a copy under a rate in bytes per second: it goes a chunk at a time,
+/// sleeping whenever it gets ahead of the rate since the pacer was created.
+struct Pacer {
+    bytes_per_sec: u64,
+
+    start: Instant,
+
+    copied: u64,
+}
 
-            self.writer = write_buf;
-            self.reader = BufReaderWithPos::new(File::open(merge_path_buf.as_path())?)?;
-            std::fs::remove_file(self.data_path_buf.as_path())?;
-            std::fs::rename(merge_path_buf.as_path(), self.data_path_buf.as_path())?;
+impl Pacer {
+    fn new(bytes_per_sec: u64) -> Pacer {
+        Pacer {
+            bytes_per_sec: bytes_per_sec.max(1),
+            start: Instant::now(),
+            copied: 0,
         }
+    }
 
-        self.pending_compact = 0;
-        Ok(())
+    fn copy(&mut self, src: &mut dyn Read, dest: &mut dyn Write) -> Result<()> {
+        // about a tenth of a second's worth per chunk keeps the pace even
+        let chunk_len = (self.bytes_per_sec / 10).clamp(1, COPY_CHUNK_LEN as u64) as usize;
+        let mut buf = vec![0; chunk_len];
+        loop {
+            let read = retry_interrupted(|| src.read(&mut buf))?;
+            if read == 0 {
+                return Ok(());
+            }
+            dest.write_all(&buf[..read])?;
+            self.copied += read as u64;
+            let due = Duration::from_secs_f64(self.copied as f64 / self.bytes_per_sec as f64);
+            if let Some(ahead) = due.checked_sub(self.start.elapsed()) {
+                std::thread::sleep(ahead);
+            }
+        }
     }
 }
 
+/// Buffered reader that keeps its buffer across seeks landing within it, so
+/// reading entries one after the other doesn't refill it each time. Once the
+/// file changes under it, see `invalidate` and `file_len_changed`, the next
+/// seek throws the buffer away.
 struct BufReaderWithPos<R: Read + Seek> {
     reader: BufReader<R>,
     pos: u64,
+
+    /// Length of the file as last reported to `file_len_changed`.
+    known_len: u64,
+
+    /// Whether the buffer may hold bytes the file no longer has.
+    stale: bool,
+
+    /// Number of times a stale buffer was thrown away.
+    generation: u64,
 }
 
 impl<R: Read + Seek> BufReaderWithPos<R> {
-    fn new(mut inner: R) -> Result<Self> {
-        let pos = inner.seek(SeekFrom::Current(0))?;
+    fn new(mut inner: R, capacity: usize) -> Result<Self> {
+        let pos = inner.stream_position()?;
         Ok(BufReaderWithPos {
-            reader: BufReader::new(inner),
+            reader: BufReader::with_capacity(capacity, inner),
             pos,
+            known_len: 0,
+            stale: false,
+            generation: 0,
         })
     }
+
+    /// Makes the next seek refill the buffer, e.g. after the file was
+    /// truncated and may be written again where the buffer came from.
+    fn invalidate(&mut self) {
+        self.stale = true;
+    }
+
+    /// Invalidates the buffer if the file is no longer `len` bytes long since
+    /// the last call, as it is after any write past the end known so far.
+    fn file_len_changed(&mut self, len: u64) {
+        if len != self.known_len {
+            self.known_len = len;
+            self.stale = true;
+        }
+    }
 }
 
 impl<R: Read + Seek> Read for BufReaderWithPos<R> {
     fn read(&mut self, buf: &mut [u8]) -> io::Result<usize> {
-        let len = self.reader.read(buf)?;
+        let len = retry_interrupted(|| self.reader.read(buf))?;
         self.pos += len as u64;
         Ok(len)
     }
@@ -296,35 +3964,64 @@ impl<R: Read + Seek> Read for BufReaderWithPos<R> {
 
 impl<R: Read + Seek> Seek for BufReaderWithPos<R> {
     fn seek(&mut self, pos: SeekFrom) -> io::Result<u64> {
+        if let SeekFrom::Start(target) = pos {
+            let buffered = self.reader.buffer().len() as u64;
+            if !self.stale && target >= self.pos && target - self.pos <= buffered {
+                self.reader.consume((target - self.pos) as usize);
+                self.pos = target;
+                return Ok(target);
+            }
+        }
+        if self.stale {
+            self.stale = false;
+            self.generation += 1;
+        }
+        // seeking the `BufReader` itself always discards its buffer
         self.pos = self.reader.seek(pos)?;
         Ok(self.pos)
     }
 }
 
+/// Runs `op` until it fails with something other than `Interrupted`, which
+/// only means a signal arrived before any data was moved.
+pub(crate) fn retry_interrupted<T>(mut op: impl FnMut() -> io::Result<T>) -> io::Result<T> {
+    loop {
+        match op() {
+            Err(e) if e.kind() == io::ErrorKind::Interrupted => continue,
+            result => return result,
+        }
+    }
+}
+
 struct BufWriterWithPos<W: Write + Seek> {
     writer: BufWriter<W>,
     pos: u64,
 }
 
 impl<W: Write + Seek> BufWriterWithPos<W> {
-    fn new(mut inner: W) -> Result<Self> {
-        let pos = inner.seek(SeekFrom::Current(0))?;
+    fn new(mut inner: W, capacity: usize) -> Result<Self> {
+        let pos = inner.stream_position()?;
         Ok(BufWriterWithPos {
-            writer: BufWriter::new(inner),
+            writer: BufWriter::with_capacity(capacity, inner),
             pos,
         })
     }
+
+    /// Position up to which the data has been handed to the file.
+    fn flushed_pos(&self) -> u64 {
+        self.pos - self.writer.buffer().len() as u64
+    }
 }
 
 impl<W: Write + Seek> Write for BufWriterWithPos<W> {
     fn write(&mut self, buf: &[u8]) -> io::Result<usize> {
-        let len = self.writer.write(buf)?;
+        let len = retry_interrupted(|| self.writer.write(buf))?;
         self.pos += len as u64;
         Ok(len)
     }
 
     fn flush(&mut self) -> io::Result<()> {
-        self.writer.flush()
+        retry_interrupted(|| self.writer.flush())
     }
 }
 
@@ -0,0 +1,190 @@
+use std::marker::PhantomData;
+
+use serde::de::DeserializeOwned;
+use serde::Serialize;
+
+use super::error::{KvsError, Result};
+use super::kv_store::KvStore;
+use super::storage::Storage;
+
+/// A key type of `TypedKvStore`. The store orders keys like their bytes, so
+/// an encoding sorting like the keys themselves, as the provided ones do,
+/// makes `TypedKvStore::range` follow the order of the keys.
+pub trait KvKey: Sized {
+    fn to_key_bytes(&self) -> Vec<u8>;
+
+    /// Decodes what `to_key_bytes` encoded, `None` if `bytes` hold no key.
+    fn from_key_bytes(bytes: &[u8]) -> Option<Self>;
+}
+
+/// A `KvKey` always encoded to `LEN` bytes, so it can come before another key
+/// in a tuple.
+pub trait FixedKvKey: KvKey {
+    const LEN: usize;
+}
+
+macro_rules! unsigned_key {
+    ($($t:ty),*) => {$(
+        impl KvKey for $t {
+            fn to_key_bytes(&self) -> Vec<u8> {
+                self.to_be_bytes().to_vec()
+            }
+
+            fn from_key_bytes(bytes: &[u8]) -> Option<$t> {
+                Some(<$t>::from_be_bytes(bytes.try_into().ok()?))
+            }
+        }
+
+        impl FixedKvKey for $t {
+            const LEN: usize = std::mem::size_of::<$t>();
+        }
+    )*};
+}
+
+unsigned_key!(u8, u16, u32, u64, u128);
+
+macro_rules! signed_key {
+    ($($t:ty => $u:ty),*) => {$(
+        impl KvKey for $t {
+            // with the sign bit flipped negative numbers sort first
+            fn to_key_bytes(&self) -> Vec<u8> {
+                (*self as $u ^ 1 << (<$u>::BITS - 1)).to_be_bytes().to_vec()
+            }
+
+            fn from_key_bytes(bytes: &[u8]) -> Option<$t> {
+                let flipped = <$u>::from_be_bytes(bytes.try_into().ok()?);
+                Some((flipped ^ 1 << (<$u>::BITS - 1)) as $t)
+            }
+        }
+
+        impl FixedKvKey for $t {
+            const LEN: usize = std::mem::size_of::<$t>();
+        }
+    )*};
+}
+
+signed_key!(i8 => u8, i16 => u16, i32 => u32, i64 => u64, i128 => u128);
+
+impl KvKey for String {
+    fn to_key_bytes(&self) -> Vec<u8> {
+        self.as_bytes().to_vec()
+    }
+
+    fn from_key_bytes(bytes: &[u8]) -> Option<String> {
+        String::from_utf8(bytes.to_vec()).ok()
+    }
+}
+
+impl<A: FixedKvKey, B: KvKey> KvKey for (A, B) {
+    fn to_key_bytes(&self) -> Vec<u8> {
+        let mut bytes = self.0.to_key_bytes();
+        bytes.extend(self.1.to_key_bytes());
+        bytes
+    }
+
+    fn from_key_bytes(bytes: &[u8]) -> Option<(A, B)> {
+        if bytes.len() < A::LEN {
+            return None;
+        }
+        let (a, b) = bytes.split_at(A::LEN);
+        Some((A::from_key_bytes(a)?, B::from_key_bytes(b)?))
+    }
+}
+
+impl<A: FixedKvKey, B: FixedKvKey> FixedKvKey for (A, B) {
+    const LEN: usize = A::LEN + B::LEN;
+}
+
+impl<A: FixedKvKey, B: FixedKvKey, C: KvKey> KvKey for (A, B, C) {
+    fn to_key_bytes(&self) -> Vec<u8> {
+        let mut bytes = self.0.to_key_bytes();
+        bytes.extend(self.1.to_key_bytes());
+        bytes.extend(self.2.to_key_bytes());
+        bytes
+    }
+
+    fn from_key_bytes(bytes: &[u8]) -> Option<(A, B, C)> {
+        let ((a, b), c) = <((A, B), C)>::from_key_bytes(bytes)?;
+        Some((a, b, c))
+    }
+}
+
+/// Hex digits keep the byte order and make any bytes a valid stored key.
+fn encode<K: KvKey>(key: &K) -> String {
+    key.to_key_bytes()
+        .iter()
+        .map(|byte| format!("{:02x}", byte))
+        .collect()
+}
+
+fn decode<K: KvKey>(key: &str) -> Result<K> {
+    let invalid = || KvsError::InvalidKey(key.to_owned());
+    if !key.len().is_multiple_of(2) || !key.is_ascii() {
+        return Err(invalid());
+    }
+    let bytes = (0..key.len())
+        .step_by(2)
+        .map(|i| u8::from_str_radix(&key[i..i + 2], 16))
+        .collect::<std::result::Result<Vec<u8>, _>>()
+        .map_err(|_| invalid())?;
+    K::from_key_bytes(&bytes).ok_or_else(invalid)
+}
+
+/// A `KvStore` with keys of type `K` and values of type `V`, stored as JSON
+/// like `KvStore::put_typed` does.
+///
+/// Keys are stored as the hex of `KvKey::to_key_bytes`, so `range` and `scan`
+/// return them in the order of their bytes. Every key of the store has to be
+/// written through the same `TypedKvStore`, `scan` fails with
+/// `KvsError::InvalidKey` on a key it can't decode.
+pub struct TypedKvStore<K, V, S: Storage + ?Sized = dyn Storage> {
+    store: KvStore<S>,
+
+    types: PhantomData<fn() -> (K, V)>,
+}
+
+impl<K: KvKey, V: Serialize + DeserializeOwned, S: Storage + ?Sized> TypedKvStore<K, V, S> {
+    pub fn new(store: KvStore<S>) -> TypedKvStore<K, V, S> {
+        TypedKvStore {
+            store,
+            types: PhantomData,
+        }
+    }
+
+    pub fn get(&mut self, key: &K) -> Result<Option<V>> {
+        self.store.get_typed(encode(key))
+    }
+
+    pub fn set(&mut self, key: &K, val: &V) -> Result<()> {
+        self.store.put_typed(encode(key), val)
+    }
+
+    pub fn remove(&mut self, key: &K) -> Result<()> {
+        self.store.remove(encode(key))
+    }
+
+    /// Returns the pairs with keys in `[start, end)`, sorted by key.
+    pub fn range(&mut self, start: &K, end: &K) -> Result<Vec<(K, V)>> {
+        let pairs = self.store.range(encode(start), encode(end))?;
+        pairs.into_iter().map(decode_pair).collect()
+    }
+
+    /// Returns every pair, sorted by key.
+    pub fn scan(&mut self) -> Result<Vec<(K, V)>> {
+        let pairs = self.store.scan()?;
+        pairs.into_iter().map(decode_pair).collect()
+    }
+
+    /// The underlying store, with the encoded keys.
+    pub fn store(&mut self) -> &mut KvStore<S> {
+        &mut self.store
+    }
+
+    pub fn into_inner(self) -> KvStore<S> {
+        self.store
+    }
+}
+
+fn decode_pair<K: KvKey, V: DeserializeOwned>((key, val): (String, String)) -> Result<(K, V)> {
+    Ok((decode(&key)?, serde_json::from_str(&val)?))
+}
@@ -1,7 +1,33 @@
 extern crate failure;
 extern crate serde;
 
+#[cfg(feature = "async")]
+pub use kv::async_kv_store::AsyncKvStore;
+pub use kv::bloom::BloomOptions;
+pub use kv::builder::KvStoreBuilder;
+pub use kv::clock::{Clock, MockClock, SystemClock};
+pub use kv::codec::EntryCodec;
 pub use kv::error::{KvsError, Result};
-pub use kv::kv_store::KvStore;
+#[cfg(feature = "testing")]
+pub use kv::fault::{FaultInjector, FaultKind};
+pub use kv::group_commit::{GroupCommitKvStore, GroupCommitOptions};
+pub use kv::index::IndexHasher;
+pub use kv::kv_store::{BitcaskStore, ChangeEvent, DuplicatePolicy, KvStore};
+#[cfg(feature = "latency")]
+pub use kv::latency::{LatencyReport, LatencyStats};
+pub use kv::memory::MemoryStorage;
+pub use kv::metrics::MetricsSnapshot;
+pub use kv::namespace::Namespace;
+pub use kv::options::{
+    CancellationToken, CommitInterval, CompactionCallback, CompactionInfo, IndexKind, Options,
+};
+pub use kv::reader::ReaderStorage;
+pub use kv::replication::Replica;
+pub use kv::sharded::ShardedKvStore;
+pub use kv::storage::{
+    CmdKind, CompactionEstimate, CompactionProgress, Entry, EntryIter, KeyState, Page, ReadSource,
+    ScanIter, SimplifiedBitcask, Storage, StoreStats, VerifyReport, WriteOp,
+};
+pub use kv::typed::{FixedKvKey, KvKey, TypedKvStore};
 
 pub mod kv;
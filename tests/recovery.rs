@@ -0,0 +1,425 @@
+extern crate kvs;
+extern crate tempfile;
+
+use std::fs::OpenOptions;
+use std::io::{self, Write};
+use std::path::{Path, PathBuf};
+
+use tempfile::TempDir;
+
+use kvs::{KvStore, KvsError, Result, WriteOp};
+
+/// Length of the file header in front of the first entry.
+const HEADER_LEN: u64 = 8;
+
+/// Length of every entry `Fixture` writes, "keyN" and "valueN" after a 33
+/// byte entry header.
+const ENTRY_LEN: u64 = 43;
+
+/// Keys written by `Fixture::new`, in order.
+const KEYS: usize = 5;
+
+/// Ways a crash or a bad disk can leave the data file, see `corrupt`.
+enum Damage {
+    /// The last `n` bytes never made it to disk.
+    CutTail(u64),
+
+    /// Bytes written after the last entry.
+    Append(&'static [u8]),
+
+    /// The byte at the offset has all its bits flipped.
+    FlipByte(u64),
+}
+
+/// Applies `damage` to the file at `path`.
+fn corrupt(path: &Path, damage: Damage) -> io::Result<()> {
+    match damage {
+        Damage::CutTail(n) => {
+            let file = OpenOptions::new().write(true).open(path)?;
+            let len = file.metadata()?.len();
+            file.set_len(len - n)
+        }
+        Damage::Append(bytes) => OpenOptions::new().append(true).open(path)?.write_all(bytes),
+        Damage::FlipByte(offset) => {
+            let mut data = std::fs::read(path)?;
+            data[offset as usize] ^= 0xff;
+            std::fs::write(path, data)
+        }
+    }
+}
+
+/// A store closed after writing "key1" to "key5", one entry each.
+struct Fixture {
+    dir: TempDir,
+}
+
+impl Fixture {
+    fn new() -> Result<Fixture> {
+        let dir = TempDir::new().expect("unable to create temporary working directory");
+        let mut store = KvStore::open(dir.path())?;
+        for i in 1..=KEYS {
+            store.set(format!("key{}", i), format!("value{}", i))?;
+        }
+        drop(store);
+        let fixture = Fixture { dir };
+        assert_eq!(fixture.data_len()?, HEADER_LEN + KEYS as u64 * ENTRY_LEN);
+        Ok(fixture)
+    }
+
+    fn path(&self) -> &Path {
+        self.dir.path()
+    }
+
+    fn data_path(&self) -> PathBuf {
+        self.path().join("miniDB.data")
+    }
+
+    fn merge_path(&self) -> PathBuf {
+        self.path().join("miniDB.merge")
+    }
+
+    fn data_len(&self) -> io::Result<u64> {
+        Ok(std::fs::metadata(self.data_path())?.len())
+    }
+
+    fn corrupt(&self, damage: Damage) -> io::Result<()> {
+        corrupt(&self.data_path(), damage)
+    }
+
+    /// Checks that the store holds the first `intact` keys and nothing else.
+    fn assert_keys(&self, store: &mut KvStore, intact: usize) -> Result<()> {
+        for i in 1..=KEYS {
+            let expected = Some(format!("value{}", i)).filter(|_| i <= intact);
+            assert_eq!(store.get(format!("key{}", i))?, expected, "key{}", i);
+        }
+        Ok(())
+    }
+
+    /// Checks that the store takes writes after recovering, and still has
+    /// them and the first `intact` keys when opened again.
+    fn assert_writable(&self, mut store: KvStore, intact: usize) -> Result<()> {
+        store.set("key6".to_owned(), "value6".to_owned())?;
+        drop(store);
+        let mut store = KvStore::open(self.path())?;
+        self.assert_keys(&mut store, intact)?;
+        assert_eq!(store.get("key6".to_owned())?, Some("value6".to_owned()));
+        Ok(())
+    }
+}
+
+// Opening a store whose last entry was cut anywhere short should drop just that
+// entry and leave the file ending at the one before.
+#[test]
+fn open_after_cut_tail() -> Result<()> {
+    for cut in 1..ENTRY_LEN {
+        let fixture = Fixture::new()?;
+        fixture.corrupt(Damage::CutTail(cut))?;
+
+        let mut store = KvStore::open(fixture.path())?;
+        fixture.assert_keys(&mut store, KEYS - 1)?;
+        assert_eq!(
+            fixture.data_len()?,
+            HEADER_LEN + (KEYS as u64 - 1) * ENTRY_LEN
+        );
+        fixture.assert_writable(store, KEYS - 1)?;
+    }
+
+    Ok(())
+}
+
+// `repair` should drop the same bytes `open` would for a cut tail, and
+// nothing the second time.
+#[test]
+fn repair_after_cut_tail() -> Result<()> {
+    for cut in [1, ENTRY_LEN / 2, ENTRY_LEN - 1] {
+        let fixture = Fixture::new()?;
+        fixture.corrupt(Damage::CutTail(cut))?;
+
+        assert_eq!(KvStore::repair(fixture.path())?, ENTRY_LEN - cut);
+        assert_eq!(KvStore::repair(fixture.path())?, 0);
+        let store = KvStore::open(fixture.path())?;
+        fixture.assert_writable(store, KEYS - 1)?;
+    }
+
+    Ok(())
+}
+
+// A file cut inside its header should start over empty if what's left of the
+// header is intact.
+#[test]
+fn open_after_cut_header() -> Result<()> {
+    for len in 0..HEADER_LEN {
+        let fixture = Fixture::new()?;
+        fixture.corrupt(Damage::CutTail(fixture.data_len()? - len))?;
+
+        let mut store = KvStore::open(fixture.path())?;
+        fixture.assert_keys(&mut store, 0)?;
+        assert_eq!(fixture.data_len()?, HEADER_LEN);
+        fixture.assert_writable(store, 0)?;
+    }
+
+    Ok(())
+}
+
+// Less than an entry header of garbage after the last entry looks like a torn
+// write and should be dropped on open, while more of it is corruption that
+// only `repair` drops.
+#[test]
+fn open_after_appended_garbage() -> Result<()> {
+    let end = HEADER_LEN + KEYS as u64 * ENTRY_LEN;
+
+    let fixture = Fixture::new()?;
+    fixture.corrupt(Damage::Append(&[0xff; 20]))?;
+    let mut store = KvStore::open(fixture.path())?;
+    fixture.assert_keys(&mut store, KEYS)?;
+    assert_eq!(fixture.data_len()?, end);
+    fixture.assert_writable(store, KEYS)?;
+
+    let fixture = Fixture::new()?;
+    fixture.corrupt(Damage::Append(&[0xff; 64]))?;
+    match KvStore::open(fixture.path()) {
+        Err(e) if e.is_corruption() => {}
+        Err(e) => panic!("unexpected error {:?}", e),
+        Ok(_) => panic!("corruption not detected"),
+    }
+    assert_eq!(KvStore::repair(fixture.path())?, 64);
+    let store = KvStore::open(fixture.path())?;
+    assert_eq!(fixture.data_len()?, end);
+    fixture.assert_writable(store, KEYS)?;
+
+    Ok(())
+}
+
+// A damaged entry header in the middle of the log should fail the open rather
+// than lose the entries after it, and `repair` should keep the entries before it.
+#[test]
+fn open_after_corrupt_entry() -> Result<()> {
+    let damaged = 2;
+    let offset = HEADER_LEN + damaged as u64 * ENTRY_LEN;
+
+    let fixture = Fixture::new()?;
+    fixture.corrupt(Damage::FlipByte(offset))?;
+    match KvStore::open(fixture.path()) {
+        Err(KvsError::CorruptedEntry(at)) => assert_eq!(at, offset),
+        Err(e) if e.is_corruption() => {}
+        Err(e) => panic!("unexpected error {:?}", e),
+        Ok(_) => panic!("corruption not detected"),
+    }
+    let report = KvStore::verify(fixture.path())?;
+    assert_eq!(report.first_corruption, Some(offset));
+
+    assert_eq!(
+        KvStore::repair(fixture.path())?,
+        (KEYS - damaged) as u64 * ENTRY_LEN
+    );
+    let store = KvStore::open(fixture.path())?;
+    fixture.assert_writable(store, damaged)?;
+
+    Ok(())
+}
+
+// An entry whose value is no longer valid UTF-8 should still open, readable
+// through `get_lossy`, and one whose key isn't should be skipped.
+#[test]
+fn open_after_undecodable_entry() -> Result<()> {
+    let offset = HEADER_LEN + 2 * ENTRY_LEN;
+
+    let fixture = Fixture::new()?;
+    fixture.corrupt(Damage::FlipByte(offset + ENTRY_LEN - 1))?;
+    let mut store = KvStore::open(fixture.path())?;
+    match store.get("key3".to_owned()) {
+        Err(KvsError::StringDecode(_)) => {}
+        other => panic!("unexpected result {:?}", other),
+    }
+    assert_eq!(store.get_lossy("key3")?, Some("value\u{FFFD}".to_owned()));
+    assert_eq!(store.get("key5".to_owned())?, Some("value5".to_owned()));
+
+    let fixture = Fixture::new()?;
+    fixture.corrupt(Damage::FlipByte(offset + 33))?;
+    let mut store = KvStore::open(fixture.path())?;
+    assert_eq!(store.get("key3".to_owned())?, None);
+    assert_eq!(store.get("key5".to_owned())?, Some("value5".to_owned()));
+    store.set("key3".to_owned(), "value6".to_owned())?;
+    drop(store);
+    let mut store = KvStore::open(fixture.path())?;
+    assert_eq!(store.get("key3".to_owned())?, Some("value6".to_owned()));
+
+    Ok(())
+}
+
+// A batch whose entries didn't all make it to disk should be dropped as a
+// whole, by `open` and by `repair` alike, wherever it was cut.
+#[test]
+fn open_after_cut_batch() -> Result<()> {
+    let end = HEADER_LEN + KEYS as u64 * ENTRY_LEN;
+    let batch = |store: &mut KvStore| {
+        store.apply_batch(vec![
+            WriteOp::Put {
+                key: "key6".to_owned(),
+                val: "value6".to_owned(),
+            },
+            WriteOp::Delete {
+                key: "key1".to_owned(),
+            },
+        ])
+    };
+    // the marker, "key6" and the tombstone of "key1"
+    let batch_len = 33 + ENTRY_LEN + 37;
+
+    for cut in [1, 37, 38, 37 + ENTRY_LEN, batch_len - 1] {
+        for repair in [false, true] {
+            let fixture = Fixture::new()?;
+            let mut store = KvStore::open(fixture.path())?;
+            batch(&mut store)?;
+            drop(store);
+            assert_eq!(fixture.data_len()?, end + batch_len);
+            fixture.corrupt(Damage::CutTail(cut))?;
+
+            if repair {
+                assert_eq!(KvStore::repair(fixture.path())?, batch_len - cut);
+            }
+            let mut store = KvStore::open(fixture.path())?;
+            fixture.assert_keys(&mut store, KEYS)?;
+            assert_eq!(store.get("key6".to_owned())?, None);
+            assert_eq!(fixture.data_len()?, end);
+        }
+    }
+
+    Ok(())
+}
+
+// A torn last entry should be dropped on open even when it's over the size
+// limits the store is opened with.
+#[test]
+fn open_after_cut_large_entry() -> Result<()> {
+    let end = HEADER_LEN + KEYS as u64 * ENTRY_LEN;
+    let fixture = Fixture::new()?;
+    let mut store = KvStore::open(fixture.path())?;
+    store.set("key6".to_owned(), "v".repeat(200))?;
+    drop(store);
+    fixture.corrupt(Damage::CutTail(10))?;
+
+    let mut store = KvStore::builder()
+        .max_value_size(150)
+        .build(fixture.path())?;
+    fixture.assert_keys(&mut store, KEYS)?;
+    assert_eq!(store.get("key6".to_owned())?, None);
+    assert_eq!(fixture.data_len()?, end);
+
+    Ok(())
+}
+
+// A rename cut anywhere, between its two entries included, should leave the
+// value under exactly one of the keys: the old one unless all of it was kept.
+#[test]
+fn open_after_cut_rename() -> Result<()> {
+    let end = HEADER_LEN + KEYS as u64 * ENTRY_LEN;
+    // the marker, "key6" with the value of "key1" and the tombstone of "key1"
+    let rename_len = 33 + ENTRY_LEN + 37;
+
+    for cut in [0, 1, 37, 38, rename_len - 1] {
+        let fixture = Fixture::new()?;
+        let mut store = KvStore::open(fixture.path())?;
+        store.rename("key1", "key6")?;
+        drop(store);
+        assert_eq!(fixture.data_len()?, end + rename_len);
+        fixture.corrupt(Damage::CutTail(cut))?;
+
+        let mut store = KvStore::open(fixture.path())?;
+        let old = store.get("key1".to_owned())?;
+        let new = store.get("key6".to_owned())?;
+        match cut {
+            0 => assert_eq!((old, new), (None, Some("value1".to_owned()))),
+            _ => assert_eq!((old, new), (Some("value1".to_owned()), None)),
+        }
+    }
+
+    Ok(())
+}
+
+// A merge file left by a compaction that crashed should be thrown away while
+// the data file is there, and take its place when the crash came between
+// removing the data file and renaming the merge file.
+#[test]
+fn open_after_interrupted_compaction() -> Result<()> {
+    let fixture = Fixture::new()?;
+    std::fs::write(fixture.merge_path(), [0xff; 30])?;
+    let mut store = KvStore::open(fixture.path())?;
+    assert!(!fixture.merge_path().exists());
+    fixture.assert_keys(&mut store, KEYS)?;
+    fixture.assert_writable(store, KEYS)?;
+
+    let fixture = Fixture::new()?;
+    std::fs::rename(fixture.data_path(), fixture.merge_path())?;
+    let mut store = KvStore::open(fixture.path())?;
+    assert!(!fixture.merge_path().exists());
+    fixture.assert_keys(&mut store, KEYS)?;
+    fixture.assert_writable(store, KEYS)?;
+
+    Ok(())
+}
+
+// A blob merge file left by a compaction that crashed should go with the merge
+// file while the data file is there, and replace the blob file once the
+// merged data file pointing into it has taken the place of the data file.
+#[test]
+fn open_after_interrupted_blob_compaction() -> Result<()> {
+    let dir = TempDir::new().expect("unable to create temporary working directory");
+    let data_path = dir.path().join("miniDB.data");
+    let blob_path = dir.path().join("miniDB.blob");
+    let blob_merge_path = dir.path().join("miniDB.blob.merge");
+    let mut store = KvStore::builder().blob_threshold(10).build(dir.path())?;
+    store.set("key1".to_owned(), "a".repeat(100))?;
+    store.set("key1".to_owned(), "b".repeat(100))?;
+    drop(store);
+    let old_blobs = std::fs::read(&blob_path)?;
+    let mut store = KvStore::open(dir.path())?;
+    store.purge_tombstones()?;
+    drop(store);
+    assert_eq!(std::fs::read(&blob_path)?, "b".repeat(100).as_bytes());
+
+    // crashed before the merged data file was installed
+    std::fs::write(dir.path().join("miniDB.merge"), [0xff; 30])?;
+    std::fs::write(&blob_merge_path, [0xff; 30])?;
+    let mut store = KvStore::open(dir.path())?;
+    assert!(!blob_merge_path.exists());
+    assert_eq!(store.get("key1".to_owned())?, Some("b".repeat(100)));
+    drop(store);
+
+    // crashed after it, the old blobs still in place
+    std::fs::rename(&blob_path, &blob_merge_path)?;
+    std::fs::write(&blob_path, &old_blobs)?;
+    let mut store = KvStore::open(dir.path())?;
+    assert!(!blob_merge_path.exists());
+    assert_eq!(store.get("key1".to_owned())?, Some("b".repeat(100)));
+    drop(store);
+
+    // crashed between removing the data file and renaming the merge file
+    std::fs::rename(&data_path, dir.path().join("miniDB.merge"))?;
+    std::fs::rename(&blob_path, &blob_merge_path)?;
+    std::fs::write(&blob_path, &old_blobs)?;
+    let mut store = KvStore::open(dir.path())?;
+    assert!(!blob_merge_path.exists());
+    assert_eq!(store.get("key1".to_owned())?, Some("b".repeat(100)));
+
+    Ok(())
+}
+
+// Damage that `open` recovers from on its own should also leave nothing for a
+// later `repair` to do.
+#[test]
+fn repair_after_open() -> Result<()> {
+    for (damage, intact) in [
+        (Damage::CutTail(5), KEYS - 1),
+        (Damage::Append(&[0; 10]), KEYS),
+    ] {
+        let fixture = Fixture::new()?;
+        fixture.corrupt(damage)?;
+        drop(KvStore::open(fixture.path())?);
+        assert_eq!(KvStore::repair(fixture.path())?, 0);
+        let mut store = KvStore::open(fixture.path())?;
+        fixture.assert_keys(&mut store, intact)?;
+    }
+
+    Ok(())
+}
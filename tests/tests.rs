@@ -1,6 +1,10 @@
+// the CLI tests pass their arguments as `&[..]`
+#![allow(clippy::needless_borrows_for_generic_args)]
+
 extern crate assert_cmd;
 extern crate kvs;
 extern crate predicates;
+extern crate proptest;
 extern crate tempfile;
 extern crate walkdir;
 
@@ -13,7 +17,12 @@ use predicates::str::{contains, is_empty, PredicateStrExt};
 use tempfile::TempDir;
 use walkdir::WalkDir;
 
-use kvs::{KvStore, Result};
+use kvs::{
+    BitcaskStore, CancellationToken, CmdKind, CommitInterval, CompactionInfo, CompactionProgress,
+    DuplicatePolicy, EntryCodec, IndexHasher, IndexKind, KeyState, KvKey, KvStore, KvsError,
+    MemoryStorage, MetricsSnapshot, MockClock, Options, Page, ReadSource, Result,
+    SimplifiedBitcask, Storage, StoreStats, TypedKvStore, WriteOp,
+};
 
 // `kvs` with no args should exit with a non-zero code.
 #[test]
@@ -288,6 +297,7 @@ fn compaction() -> Result<()> {
             continue;
         }
         // Compaction triggered.
+        assert!(store.metrics().compaction_count > 0);
 
         drop(store);
         // reopen and check content.
@@ -301,3 +311,4676 @@ fn compaction() -> Result<()> {
 
     panic!("No compaction detected");
 }
+
+// Range queries should return keys in `[start, end)` in sorted order for every index kind.
+#[test]
+fn range_query() -> Result<()> {
+    for index_kind in [IndexKind::Hash, IndexKind::Ordered, IndexKind::Hashed] {
+        let temp_dir = TempDir::new().expect("unable to create temporary working directory");
+        let options = Options {
+            index_kind,
+            ..Options::default()
+        };
+        let mut store = KvStore::open_with_options(temp_dir.path(), options.clone())?;
+        for key in ["d", "a", "m", "c", "z", "b"] {
+            store.set(key.to_owned(), format!("value_{}", key))?;
+        }
+        store.remove("c".to_owned())?;
+
+        let expected = vec![
+            ("a".to_owned(), "value_a".to_owned()),
+            ("b".to_owned(), "value_b".to_owned()),
+            ("d".to_owned(), "value_d".to_owned()),
+        ];
+        assert_eq!(store.range("a".to_owned(), "m".to_owned())?, expected);
+        assert!(store.range("m".to_owned(), "a".to_owned())?.is_empty());
+
+        // Open from disk again and check the rebuilt index.
+        drop(store);
+        let mut store = KvStore::open_with_options(temp_dir.path(), options)?;
+        assert_eq!(store.range("a".to_owned(), "m".to_owned())?, expected);
+    }
+
+    Ok(())
+}
+
+// Clear should drop every key, both in memory and on disk, and leave the store usable.
+#[test]
+fn clear_store() -> Result<()> {
+    let temp_dir = TempDir::new().expect("unable to create temporary working directory");
+    let mut store = KvStore::open(temp_dir.path())?;
+    store.set("key1".to_owned(), "value1".to_owned())?;
+    store.set("key2".to_owned(), "value2".to_owned())?;
+
+    store.clear()?;
+    assert_eq!(store.get("key1".to_owned())?, None);
+    assert!(store.remove("key2".to_owned()).is_err());
+
+    store.set("key3".to_owned(), "value3".to_owned())?;
+    assert_eq!(store.get("key3".to_owned())?, Some("value3".to_owned()));
+
+    // Open from disk again and check persistent data.
+    drop(store);
+    let mut store = KvStore::open(temp_dir.path())?;
+    assert_eq!(store.get("key1".to_owned())?, None);
+    assert_eq!(store.get("key2".to_owned())?, None);
+    assert_eq!(store.get("key3".to_owned())?, Some("value3".to_owned()));
+
+    Ok(())
+}
+
+// Keys and values above the configured limits should be rejected without being written.
+#[test]
+fn reject_oversized_entries() -> Result<()> {
+    let temp_dir = TempDir::new().expect("unable to create temporary working directory");
+    let options = Options {
+        max_key_size: 4,
+        max_value_size: 8,
+        ..Options::default()
+    };
+    let mut store = KvStore::open_with_options(temp_dir.path(), options)?;
+
+    match store.set("key12".to_owned(), "value".to_owned()) {
+        Err(KvsError::KeyTooLarge { size: 5, limit: 4 }) => {}
+        other => panic!("unexpected result {:?}", other),
+    }
+    match store.set("key1".to_owned(), "value1234".to_owned()) {
+        Err(KvsError::ValueTooLarge { size: 9, limit: 8 }) => {}
+        other => panic!("unexpected result {:?}", other),
+    }
+    store.set("key1".to_owned(), "value123".to_owned())?;
+    assert_eq!(store.get("key1".to_owned())?, Some("value123".to_owned()));
+    assert!(matches!(
+        store.rename("key1", "key12"),
+        Err(KvsError::KeyTooLarge { size: 5, limit: 4 })
+    ));
+
+    // Nothing from the rejected writes should have been persisted.
+    drop(store);
+    let mut store = KvStore::open(temp_dir.path())?;
+    assert_eq!(store.get("key12".to_owned())?, None);
+    assert_eq!(store.get("key1".to_owned())?, Some("value123".to_owned()));
+
+    Ok(())
+}
+
+// Lowering the size limits should only affect new writes, entries already on
+// disk should still load, verify and survive a repair.
+#[test]
+fn lowered_size_limits() -> Result<()> {
+    let temp_dir = TempDir::new().expect("unable to create temporary working directory");
+    let mut store = KvStore::open(temp_dir.path())?;
+    store.set("a".to_owned(), "value".to_owned())?;
+    store.set("b".to_owned(), "v".repeat(200))?;
+    store.set("c".to_owned(), "value".to_owned())?;
+    drop(store);
+
+    let limited = || Options {
+        max_value_size: 150,
+        ..Options::default()
+    };
+    assert_eq!(KvStore::repair_with_options(temp_dir.path(), limited())?, 0);
+    let report = KvStore::verify_with_options(temp_dir.path(), limited())?;
+    assert_eq!(report.valid_entries, 3);
+    assert_eq!(report.first_corruption, None);
+    let mut store = KvStore::open_with_options(temp_dir.path(), limited())?;
+    assert_eq!(store.get("b".to_owned())?, Some("v".repeat(200)));
+    assert_eq!(store.get("c".to_owned())?, Some("value".to_owned()));
+    assert!(matches!(
+        store.set("d".to_owned(), "v".repeat(200)),
+        Err(KvsError::ValueTooLarge {
+            size: 200,
+            limit: 150
+        })
+    ));
+
+    Ok(())
+}
+
+// A length field pointing past the end of the file should be reported as corruption.
+#[test]
+fn corrupted_length() -> Result<()> {
+    let temp_dir = TempDir::new().expect("unable to create temporary working directory");
+    let mut store = KvStore::open(temp_dir.path())?;
+    store.set("key1".to_owned(), "value1".to_owned())?;
+    drop(store);
+
+    let data_path = temp_dir.path().join("miniDB.data");
+    let mut data = std::fs::read(&data_path)?;
+    // the first entry follows the 8 byte file header
+    data[8] = 0x7f;
+    std::fs::write(&data_path, data)?;
+
+    match KvStore::open(temp_dir.path()) {
+        Err(KvsError::CorruptedEntry(8)) => {}
+        Err(e) => panic!("unexpected error {:?}", e),
+        Ok(_) => panic!("corruption not detected"),
+    }
+
+    Ok(())
+}
+
+// A value that isn't valid UTF-8 should fail `get` but not `get_lossy`, and an
+// entry whose key isn't should be skipped on open rather than fail it.
+#[test]
+fn invalid_utf8_entries() -> Result<()> {
+    let temp_dir = TempDir::new().expect("unable to create temporary working directory");
+    let mut store = KvStore::builder()
+        .blob_threshold(100)
+        .build(temp_dir.path())?;
+    store.set("key1".to_owned(), "value1".to_owned())?;
+    store.set("key2".to_owned(), "value2".to_owned())?;
+    store.set("key3".to_owned(), "value3".to_owned())?;
+    store.set("key4".to_owned(), "v".repeat(200))?;
+    drop(store);
+
+    // the last byte of the value of key2, and of the key of key3
+    let data_path = temp_dir.path().join("miniDB.data");
+    let mut data = std::fs::read(&data_path)?;
+    data[8 + 2 * 43 - 1] = 0xff;
+    data[8 + 2 * 43 + 33 + 3] = 0xff;
+    std::fs::write(&data_path, data)?;
+    let blob_path = temp_dir.path().join("miniDB.blob");
+    let mut blobs = std::fs::read(&blob_path)?;
+    let last = blobs.len() - 1;
+    blobs[last] = 0xff;
+    std::fs::write(&blob_path, blobs)?;
+
+    let mut store = KvStore::open(temp_dir.path())?;
+    assert_eq!(store.get("key1".to_owned())?, Some("value1".to_owned()));
+    match store.get("key2".to_owned()) {
+        Err(KvsError::StringDecode(_)) => {}
+        other => panic!("unexpected result {:?}", other),
+    }
+    assert_eq!(store.get_lossy("key2")?, Some("value\u{FFFD}".to_owned()));
+    assert_eq!(store.get_lossy("key3")?, None);
+    assert_eq!(store.get_lossy("key4")?, Some("v".repeat(199) + "\u{FFFD}"));
+    assert_eq!(store.get_lossy("key1")?, Some("value1".to_owned()));
+
+    Ok(())
+}
+
+// Buffered writes should be readable right away and visible to other handles after `flush`.
+#[test]
+fn flush_buffered_writes() -> Result<()> {
+    let temp_dir = TempDir::new().expect("unable to create temporary working directory");
+    let mut store = KvStore::open(temp_dir.path())?;
+    store.set("key1".to_owned(), "value1".to_owned())?;
+    assert_eq!(store.get("key1".to_owned())?, Some("value1".to_owned()));
+
+    store.set("key2".to_owned(), "value2".to_owned())?;
+    store.flush()?;
+    let mut other = KvStore::open(temp_dir.path())?;
+    assert_eq!(other.get("key1".to_owned())?, Some("value1".to_owned()));
+    assert_eq!(other.get("key2".to_owned())?, Some("value2".to_owned()));
+
+    Ok(())
+}
+
+// The in-memory store should behave like the on-disk one.
+#[test]
+fn in_memory_store() -> Result<()> {
+    let mut store = KvStore::in_memory();
+    store.set("b".to_owned(), "value_b".to_owned())?;
+    store.set("a".to_owned(), "value_a".to_owned())?;
+    store.set("c".to_owned(), "value_c".to_owned())?;
+    assert_eq!(store.get("a".to_owned())?, Some("value_a".to_owned()));
+    assert_eq!(store.get("d".to_owned())?, None);
+
+    store.remove("c".to_owned())?;
+    assert!(matches!(
+        store.remove("c".to_owned()),
+        Err(KvsError::KeyNotFound)
+    ));
+    assert_eq!(
+        store.range("a".to_owned(), "z".to_owned())?,
+        vec![
+            ("a".to_owned(), "value_a".to_owned()),
+            ("b".to_owned(), "value_b".to_owned()),
+        ]
+    );
+
+    store.clear()?;
+    assert_eq!(store.get("a".to_owned())?, None);
+
+    Ok(())
+}
+
+// Reads and writes should go through a backend passed to `with_storage`.
+#[test]
+fn custom_storage() -> Result<()> {
+    struct Recording {
+        inner: MemoryStorage,
+        puts: std::sync::Arc<std::sync::atomic::AtomicUsize>,
+    }
+
+    impl Storage for Recording {
+        fn get(&mut self, key: String) -> Result<Option<String>> {
+            self.inner.get(key)
+        }
+
+        fn get_state(&mut self, key: String) -> Result<KeyState> {
+            self.inner.get_state(key)
+        }
+
+        fn get_with_source(&mut self, key: String) -> Result<(Option<String>, kvs::ReadSource)> {
+            self.inner.get_with_source(key)
+        }
+
+        fn get_versioned(&mut self, key: String) -> Result<Option<(String, u64)>> {
+            self.inner.get_versioned(key)
+        }
+
+        fn key_version(&mut self, key: &str) -> Result<u64> {
+            self.inner.key_version(key)
+        }
+
+        fn get_with_meta(&mut self, key: String) -> Result<Option<(String, u64)>> {
+            self.inner.get_with_meta(key)
+        }
+
+        fn get_into(&mut self, key: &str, buf: &mut Vec<u8>) -> Result<bool> {
+            self.inner.get_into(key, buf)
+        }
+
+        fn get_shared(&mut self, key: &str) -> Result<Option<std::sync::Arc<str>>> {
+            self.inner.get_shared(key)
+        }
+
+        fn get_many(&mut self, keys: &[String]) -> Result<Vec<Option<String>>> {
+            self.inner.get_many(keys)
+        }
+
+        fn put_reader(
+            &mut self,
+            key: String,
+            reader: &mut dyn std::io::Read,
+            len: usize,
+        ) -> Result<()> {
+            self.inner.put_reader(key, reader, len)
+        }
+
+        fn get_writer(&mut self, key: &str, writer: &mut dyn std::io::Write) -> Result<bool> {
+            self.inner.get_writer(key, writer)
+        }
+
+        fn put(&mut self, key: String, val: String) -> Result<()> {
+            self.puts.fetch_add(1, std::sync::atomic::Ordering::Relaxed);
+            self.inner.put(key, val)
+        }
+
+        fn put_and_locate(&mut self, key: String, val: String) -> Result<u64> {
+            self.inner.put_and_locate(key, val)
+        }
+
+        fn locate(&mut self, key: &str) -> Result<Option<(u64, usize)>> {
+            self.inner.locate(key)
+        }
+
+        fn get_at(&mut self, offset: u64) -> Result<Option<String>> {
+            self.inner.get_at(offset)
+        }
+
+        fn remove(&mut self, key: String) -> Result<()> {
+            self.inner.remove(key)
+        }
+
+        fn rename(&mut self, old: &str, new: &str) -> Result<()> {
+            self.inner.rename(old, new)
+        }
+
+        fn remove_batch(&mut self, keys: &[String]) -> Result<Vec<String>> {
+            self.inner.remove_batch(keys)
+        }
+
+        fn apply_batch(&mut self, ops: Vec<WriteOp>) -> Result<()> {
+            self.inner.apply_batch(ops)
+        }
+
+        fn range(&mut self, start: String, end: String) -> Result<Vec<(String, String)>> {
+            self.inner.range(start, end)
+        }
+
+        fn scan(&mut self) -> Result<Vec<(String, String)>> {
+            self.inner.scan()
+        }
+
+        fn scan_iter(&mut self) -> Result<kvs::ScanIter> {
+            self.inner.scan_iter()
+        }
+
+        fn iter_in_write_order(&mut self) -> Result<kvs::ScanIter> {
+            self.inner.iter_in_write_order()
+        }
+
+        fn scan_page(&mut self, cursor: Option<String>, limit: usize) -> Result<Page> {
+            self.inner.scan_page(cursor, limit)
+        }
+
+        fn first_key(&self) -> Result<Option<String>> {
+            self.inner.first_key()
+        }
+
+        fn last_key(&self) -> Result<Option<String>> {
+            self.inner.last_key()
+        }
+
+        fn clear(&mut self) -> Result<()> {
+            self.inner.clear()
+        }
+
+        fn flush(&mut self) -> Result<()> {
+            self.inner.flush()
+        }
+
+        fn sync(&mut self) -> Result<()> {
+            self.inner.sync()
+        }
+
+        fn flush_and_sync(&mut self) -> Result<()> {
+            self.inner.flush_and_sync()
+        }
+
+        fn snapshot(&mut self, dest: &std::path::Path) -> Result<()> {
+            self.inner.snapshot(dest)
+        }
+
+        fn snapshot_throttled(&mut self, dest: &std::path::Path, rate: u64) -> Result<()> {
+            self.inner.snapshot_throttled(dest, rate)
+        }
+
+        fn checkpoint(&mut self, dest: &std::path::Path) -> Result<()> {
+            self.inner.checkpoint(dest)
+        }
+
+        fn reopen(&mut self) -> Result<()> {
+            self.inner.reopen()
+        }
+
+        fn metrics(&self) -> MetricsSnapshot {
+            self.inner.metrics()
+        }
+
+        fn stats(&self) -> StoreStats {
+            self.inner.stats()
+        }
+
+        fn data_path(&self) -> Option<&std::path::Path> {
+            self.inner.data_path()
+        }
+
+        fn size_on_disk(&self) -> Result<u64> {
+            self.inner.size_on_disk()
+        }
+
+        fn compact_step(&mut self, budget: u64) -> Result<CompactionProgress> {
+            self.inner.compact_step(budget)
+        }
+
+        fn compaction_estimate(&mut self) -> Result<kvs::CompactionEstimate> {
+            self.inner.compaction_estimate()
+        }
+
+        fn purge_tombstones(&mut self) -> Result<u64> {
+            self.inner.purge_tombstones()
+        }
+
+        fn compact_if_needed(&mut self) -> Result<bool> {
+            self.inner.compact_if_needed()
+        }
+
+        fn compact_sorted(&mut self) -> Result<u64> {
+            self.inner.compact_sorted()
+        }
+
+        fn compact_keys(&mut self, keys: &[String]) -> Result<u64> {
+            self.inner.compact_keys(keys)
+        }
+
+        fn shrink_index(&mut self) -> (usize, usize) {
+            self.inner.shrink_index()
+        }
+
+        fn log_from(&mut self, offset: u64) -> Result<Vec<(u64, kvs::kv::storage::Entry)>> {
+            self.inner.log_from(offset)
+        }
+
+        fn get_history(&mut self, key: &str) -> Result<Vec<(u64, Option<String>)>> {
+            self.inner.get_history(key)
+        }
+    }
+
+    let puts = std::sync::Arc::new(std::sync::atomic::AtomicUsize::new(0));
+    let mut store = KvStore::with_storage(Box::new(Recording {
+        inner: MemoryStorage::new(),
+        puts: puts.clone(),
+    }));
+    store.set("key1".to_owned(), "value1".to_owned())?;
+    store.set("key2".to_owned(), "value2".to_owned())?;
+    assert_eq!(store.get("key1".to_owned())?, Some("value1".to_owned()));
+    assert_eq!(puts.load(std::sync::atomic::Ordering::Relaxed), 2);
+
+    Ok(())
+}
+
+// A corrupt data file should make the CLI print an error and exit with non-zero code instead of panicking.
+#[test]
+fn cli_corrupted_store() {
+    let temp_dir = TempDir::new().expect("unable to create temporary working directory");
+    drop(KvStore::open(temp_dir.path()).unwrap());
+    // key length of usize::MAX, empty value, PUT, timestamp 0, version 0
+    let mut data = std::fs::read(temp_dir.path().join("miniDB.data")).unwrap();
+    data.extend_from_slice(&[0xff; 8]);
+    data.extend_from_slice(&[0; 8]);
+    data.push(1);
+    data.extend_from_slice(&[0; 8]);
+    data.extend_from_slice(&[0; 8]);
+    std::fs::write(temp_dir.path().join("miniDB.data"), data).unwrap();
+
+    Command::cargo_bin("kvs")
+        .unwrap()
+        .args(["get", "key1"])
+        .current_dir(&temp_dir)
+        .assert()
+        .failure()
+        .stderr(eq("corrupted entry at offset 8").trim());
+}
+
+// Exporting and importing should carry over every live pair.
+#[test]
+fn export_import() -> Result<()> {
+    let temp_dir = TempDir::new().expect("unable to create temporary working directory");
+    let mut store = KvStore::open(temp_dir.path())?;
+    store.set("key1".to_owned(), "value1".to_owned())?;
+    store.set("key2".to_owned(), "value2".to_owned())?;
+    store.set("key3".to_owned(), String::new())?;
+    store.remove("key2".to_owned())?;
+
+    let mut dump = Vec::new();
+    assert_eq!(store.export(&mut dump)?, 2);
+
+    let mut other = KvStore::in_memory();
+    other.set("key2".to_owned(), "stale".to_owned())?;
+    assert_eq!(other.import(dump.as_slice())?, 2);
+    assert_eq!(other.get("key1".to_owned())?, Some("value1".to_owned()));
+    assert_eq!(other.get("key2".to_owned())?, Some("stale".to_owned()));
+    assert_eq!(other.get("key3".to_owned())?, Some(String::new()));
+
+    // A truncated dump is an error rather than a partial import.
+    dump.pop();
+    assert!(KvStore::in_memory().import(dump.as_slice()).is_err());
+
+    Ok(())
+}
+
+// `kvs export` and `kvs import --clear-first` should restore the exported pairs.
+#[test]
+fn cli_export_import() {
+    let temp_dir = TempDir::new().expect("unable to create temporary working directory");
+    let dump_path = temp_dir.path().join("dump.bin");
+    let kvs = |args: &[&str]| {
+        Command::cargo_bin("kvs")
+            .unwrap()
+            .args(args)
+            .current_dir(&temp_dir)
+            .assert()
+            .success()
+    };
+
+    kvs(&["set", "key1", "value1"]);
+    kvs(&["export", dump_path.to_str().unwrap()]);
+    kvs(&["set", "key2", "value2"]);
+    kvs(&["import", "--clear-first", dump_path.to_str().unwrap()]);
+
+    kvs(&["get", "key1"]).stdout(eq("value1").trim());
+    kvs(&["get", "key2"]).stdout(eq("Key not found").trim());
+}
+
+// A snapshot should hold the data at the time it was taken and open as a store.
+#[test]
+fn snapshot() -> Result<()> {
+    let temp_dir = TempDir::new().expect("unable to create temporary working directory");
+    let snapshot_dir = TempDir::new().expect("unable to create temporary working directory");
+    let mut store = KvStore::open(temp_dir.path())?;
+    store.set("key1".to_owned(), "value1".to_owned())?;
+    store.set("key2".to_owned(), "value2".to_owned())?;
+    store.snapshot(snapshot_dir.path())?;
+    store.set("key1".to_owned(), "value1_new".to_owned())?;
+    store.set("key3".to_owned(), "value3".to_owned())?;
+
+    let mut copy = KvStore::open(snapshot_dir.path())?;
+    assert_eq!(copy.get("key1".to_owned())?, Some("value1".to_owned()));
+    assert_eq!(copy.get("key2".to_owned())?, Some("value2".to_owned()));
+    assert_eq!(copy.get("key3".to_owned())?, None);
+    drop(copy);
+
+    let mut memory = KvStore::in_memory();
+    memory.set("key4".to_owned(), "value4".to_owned())?;
+    memory.snapshot(snapshot_dir.path())?;
+    let mut copy = KvStore::open(snapshot_dir.path())?;
+    assert_eq!(copy.get("key1".to_owned())?, None);
+    assert_eq!(copy.get("key4".to_owned())?, Some("value4".to_owned()));
+
+    Ok(())
+}
+
+// `get_state` should tell removed keys apart from keys that never existed.
+#[test]
+fn get_key_state() -> Result<()> {
+    let temp_dir = TempDir::new().expect("unable to create temporary working directory");
+    let mut store = KvStore::open(temp_dir.path())?;
+    store.set("key1".to_owned(), "value1".to_owned())?;
+    store.set("key2".to_owned(), "value2".to_owned())?;
+    store.remove("key2".to_owned())?;
+
+    let check = |store: &mut KvStore| -> Result<()> {
+        assert_eq!(
+            store.get_state("key1".to_owned())?,
+            KeyState::Present("value1".to_owned())
+        );
+        assert_eq!(store.get_state("key2".to_owned())?, KeyState::Tombstoned);
+        assert_eq!(store.get_state("key3".to_owned())?, KeyState::Absent);
+        Ok(())
+    };
+    check(&mut store)?;
+
+    // Tombstones are rebuilt from the log.
+    drop(store);
+    let mut store = KvStore::open(temp_dir.path())?;
+    check(&mut store)?;
+
+    store.set("key2".to_owned(), "value2".to_owned())?;
+    assert_eq!(
+        store.get_state("key2".to_owned())?,
+        KeyState::Present("value2".to_owned())
+    );
+
+    let mut memory = KvStore::in_memory();
+    memory.set("key1".to_owned(), "value1".to_owned())?;
+    memory.set("key2".to_owned(), "value2".to_owned())?;
+    memory.remove("key2".to_owned())?;
+    check(&mut memory)?;
+
+    Ok(())
+}
+
+// Metrics should count operations, hits and misses.
+#[test]
+fn metrics() -> Result<()> {
+    let temp_dir = TempDir::new().expect("unable to create temporary working directory");
+    let mut stores = vec![KvStore::open(temp_dir.path())?, KvStore::in_memory()];
+    for store in &mut stores {
+        store.set("key1".to_owned(), "value1".to_owned())?;
+        store.set("key2".to_owned(), "value2".to_owned())?;
+        store.get("key1".to_owned())?;
+        store.get("key3".to_owned())?;
+        store.remove("key2".to_owned())?;
+        assert!(store.remove("key2".to_owned()).is_err());
+        store.get_state("key2".to_owned())?;
+
+        assert_eq!(
+            store.metrics(),
+            MetricsSnapshot {
+                get_count: 3,
+                put_count: 2,
+                remove_count: 1,
+                hit_count: 1,
+                miss_count: 2,
+                compaction_count: 0,
+                cache_hit_count: 0,
+                cache_miss_count: 0,
+            }
+        );
+    }
+
+    Ok(())
+}
+
+// The compaction callback should see a start and a finish event that shrank the file.
+#[test]
+fn compaction_callback() -> Result<()> {
+    let temp_dir = TempDir::new().expect("unable to create temporary working directory");
+    let events = std::sync::Arc::new(std::sync::Mutex::new(Vec::new()));
+    let recorded = events.clone();
+    let options = Options {
+        on_compaction: Some(std::sync::Arc::new(move |info| {
+            recorded.lock().unwrap().push(info)
+        })),
+        ..Options::default()
+    };
+    let mut store = KvStore::open_with_options(temp_dir.path(), options)?;
+
+    let mut iter = 0;
+    while store.metrics().compaction_count == 0 {
+        for key_id in 0..100 {
+            store.set(format!("key{}", key_id), format!("{}", iter))?;
+        }
+        iter += 1;
+    }
+
+    let events = events.lock().unwrap();
+    assert_eq!(events.len(), 2);
+    let started_before = match events[0] {
+        CompactionInfo::Started {
+            bytes_before,
+            pending_bytes,
+        } => {
+            assert!(pending_bytes > 0);
+            bytes_before
+        }
+        other => panic!("unexpected event {:?}", other),
+    };
+    match events[1] {
+        CompactionInfo::Finished {
+            bytes_before,
+            bytes_after,
+            ..
+        } => {
+            assert_eq!(bytes_before, started_before);
+            assert!(bytes_after < bytes_before);
+        }
+        other => panic!("unexpected event {:?}", other),
+    }
+
+    Ok(())
+}
+
+// Removed values should count toward the compaction threshold like
+// overwritten ones do.
+#[test]
+fn remove_counts_toward_compaction() -> Result<()> {
+    let temp_dir = TempDir::new().expect("unable to create temporary working directory");
+    let events = std::sync::Arc::new(std::sync::Mutex::new(Vec::new()));
+    let recorded = events.clone();
+    let options = Options {
+        on_compaction: Some(std::sync::Arc::new(move |info| {
+            recorded.lock().unwrap().push(info)
+        })),
+        ..Options::default()
+    };
+    let mut store = KvStore::open_with_options(temp_dir.path(), options)?;
+
+    let value = "v".repeat(8 * 1024);
+    for key_id in 0..10 {
+        store.set(format!("key{}", key_id), value.clone())?;
+    }
+    for key_id in 0..10 {
+        store.remove(format!("key{}", key_id))?;
+    }
+    store.set("key10".to_owned(), "value".to_owned())?;
+
+    // the removes alone cross the threshold before the last few of them
+    let events = events.lock().unwrap();
+    match events.first() {
+        Some(CompactionInfo::Started { pending_bytes, .. }) => {
+            assert!(*pending_bytes >= 8 * value.len() as u64);
+        }
+        other => panic!("unexpected event {:?}", other),
+    }
+    drop(events);
+    assert_eq!(store.get("key10".to_owned())?, Some("value".to_owned()));
+    assert_eq!(store.get("key0".to_owned())?, None);
+
+    Ok(())
+}
+
+// A store that only ever removes should still compact once enough is dead.
+#[test]
+fn remove_triggers_compaction() -> Result<()> {
+    let temp_dir = TempDir::new().expect("unable to create temporary working directory");
+    let mut store = KvStore::open(temp_dir.path())?;
+
+    let value = "v".repeat(8 * 1024);
+    for key_id in 0..10 {
+        store.set(format!("key{}", key_id), value.clone())?;
+    }
+    store.flush()?;
+    let data_path = temp_dir.path().join("miniDB.data");
+    let size_before = std::fs::metadata(&data_path)?.len();
+    for key_id in 0..10 {
+        store.remove(format!("key{}", key_id))?;
+    }
+    store.flush()?;
+
+    assert_eq!(store.metrics().compaction_count, 1);
+    assert!(std::fs::metadata(&data_path)?.len() < size_before);
+    for key_id in 0..10 {
+        assert_eq!(store.get(format!("key{}", key_id))?, None);
+    }
+
+    Ok(())
+}
+
+// `reopen` should pick up writes and compactions made by another handle.
+#[test]
+fn reopen() -> Result<()> {
+    let temp_dir = TempDir::new().expect("unable to create temporary working directory");
+    let mut store = KvStore::open(temp_dir.path())?;
+    store.set("key1".to_owned(), "value1".to_owned())?;
+    store.set("key2".to_owned(), "value2".to_owned())?;
+    store.flush()?;
+
+    let mut other = KvStore::open(temp_dir.path())?;
+    other.remove("key1".to_owned())?;
+    while other.metrics().compaction_count == 0 {
+        other.set("key3".to_owned(), "v".repeat(1024))?;
+    }
+    drop(other);
+
+    store.reopen()?;
+    assert_eq!(store.get("key1".to_owned())?, None);
+    assert_eq!(store.get("key2".to_owned())?, Some("value2".to_owned()));
+    assert_eq!(store.get("key3".to_owned())?, Some("v".repeat(1024)));
+
+    store.set("key4".to_owned(), "value4".to_owned())?;
+    drop(store);
+    let mut store = KvStore::open(temp_dir.path())?;
+    assert_eq!(store.get("key2".to_owned())?, Some("value2".to_owned()));
+    assert_eq!(store.get("key4".to_owned())?, Some("value4".to_owned()));
+
+    Ok(())
+}
+
+// `open_file` should open a data file under any name, keeping its merge file
+// next to it.
+#[test]
+fn open_file() -> Result<()> {
+    let temp_dir = TempDir::new().expect("unable to create temporary working directory");
+    let backup_dir = TempDir::new().expect("unable to create temporary working directory");
+    let mut store = KvStore::open(temp_dir.path())?;
+    store.set("key1".to_owned(), "value1".to_owned())?;
+    store.set("key2".to_owned(), "value2".to_owned())?;
+    drop(store);
+
+    let backup_path = backup_dir.path().join("backup-1.bak");
+    std::fs::copy(temp_dir.path().join("miniDB.data"), &backup_path)?;
+    let mut backup = KvStore::open_file(&backup_path)?;
+    assert_eq!(backup.get("key1".to_owned())?, Some("value1".to_owned()));
+    assert_eq!(backup.get("key2".to_owned())?, Some("value2".to_owned()));
+
+    backup.remove("key1".to_owned())?;
+    while backup.metrics().compaction_count == 0 {
+        backup.set("key3".to_owned(), "v".repeat(1024))?;
+    }
+    drop(backup);
+    let mut names: Vec<_> = std::fs::read_dir(backup_dir.path())?
+        .map(|entry| entry.unwrap().file_name())
+        .collect();
+    names.sort();
+    assert_eq!(names, vec!["backup-1.bak", "backup-1.footer"]);
+
+    let mut backup = KvStore::open_file(&backup_path)?;
+    assert_eq!(backup.get("key1".to_owned())?, None);
+    assert_eq!(backup.get("key2".to_owned())?, Some("value2".to_owned()));
+    let mut store = KvStore::open(temp_dir.path())?;
+    assert_eq!(store.get("key1".to_owned())?, Some("value1".to_owned()));
+
+    Ok(())
+}
+
+// Namespaces should keep their keys apart within one store.
+#[test]
+fn namespaces() -> Result<()> {
+    let temp_dir = TempDir::new().expect("unable to create temporary working directory");
+    let mut store = KvStore::open(temp_dir.path())?;
+    store.set("key1".to_owned(), "plain".to_owned())?;
+    store
+        .namespace("users")
+        .set("key1".to_owned(), "alice".to_owned())?;
+    store
+        .namespace("users")
+        .set("key2".to_owned(), "bob".to_owned())?;
+    store
+        .namespace("user")
+        .set("key1".to_owned(), "other".to_owned())?;
+
+    let mut users = store.namespace("users");
+    assert_eq!(users.name(), "users");
+    assert_eq!(users.get("key1".to_owned())?, Some("alice".to_owned()));
+    assert_eq!(users.get("key3".to_owned())?, None);
+    assert_eq!(users.keys()?, vec!["key1".to_owned(), "key2".to_owned()]);
+    users.remove("key1".to_owned())?;
+    assert_eq!(users.scan()?, vec![("key2".to_owned(), "bob".to_owned())]);
+    assert!(matches!(
+        users.remove("key1".to_owned()),
+        Err(KvsError::KeyNotFound)
+    ));
+
+    assert_eq!(store.get("key1".to_owned())?, Some("plain".to_owned()));
+    assert_eq!(
+        store.namespace("user").scan()?,
+        vec![("key1".to_owned(), "other".to_owned())]
+    );
+    drop(store);
+
+    let mut store = KvStore::open(temp_dir.path())?;
+    assert_eq!(
+        store.namespace("users").get("key2".to_owned())?,
+        Some("bob".to_owned())
+    );
+
+    Ok(())
+}
+
+// A put that would push the data file past `max_data_size` should compact
+// first, and only fail if that doesn't free enough.
+#[test]
+fn max_data_size() -> Result<()> {
+    let temp_dir = TempDir::new().expect("unable to create temporary working directory");
+    let options = Options {
+        max_data_size: Some(4096),
+        ..Options::default()
+    };
+    let mut store = KvStore::open_with_options(temp_dir.path(), options)?;
+    let value = "v".repeat(1000);
+
+    // overwrites of the same key keep fitting thanks to compaction
+    for _ in 0..20 {
+        store.set("key0".to_owned(), value.clone())?;
+    }
+    assert!(store.metrics().compaction_count > 0);
+
+    for key_id in 1..3 {
+        store.set(format!("key{}", key_id), value.clone())?;
+    }
+    assert!(matches!(
+        store.set("key3".to_owned(), value.clone()),
+        Err(KvsError::StorageFull { limit: 4096 })
+    ));
+    assert_eq!(store.get("key3".to_owned())?, None);
+    assert!(std::fs::metadata(temp_dir.path().join("miniDB.data"))?.len() <= 4096);
+
+    // removing makes room again
+    store.remove("key1".to_owned())?;
+    store.set("key3".to_owned(), value.clone())?;
+    assert_eq!(store.get("key3".to_owned())?, Some(value));
+    assert_eq!(store.get("key1".to_owned())?, None);
+
+    Ok(())
+}
+
+// Values in the blob file should count toward `max_data_size` along with the
+// data file, compaction making room in both.
+#[test]
+fn max_data_size_blobs() -> Result<()> {
+    let temp_dir = TempDir::new().expect("unable to create temporary working directory");
+    let mut store = KvStore::builder()
+        .blob_threshold(100)
+        .max_data_size(4096)
+        .build(temp_dir.path())?;
+    let value = "v".repeat(1000);
+
+    for _ in 0..20 {
+        store.set("key0".to_owned(), value.clone())?;
+    }
+    assert!(store.metrics().compaction_count > 0);
+
+    for key_id in 1..3 {
+        store.set(format!("key{}", key_id), value.clone())?;
+    }
+    assert!(matches!(
+        store.set("key3".to_owned(), value.clone()),
+        Err(KvsError::StorageFull { limit: 4096 })
+    ));
+    assert!(matches!(
+        store.apply_batch(vec![WriteOp::Put {
+            key: "key3".to_owned(),
+            val: value.clone(),
+        }]),
+        Err(KvsError::StorageFull { limit: 4096 })
+    ));
+    assert_eq!(store.get("key3".to_owned())?, None);
+    assert!(store.size_on_disk()? <= 4096);
+
+    Ok(())
+}
+
+// `create` and `create_new` should tell opening an existing store apart from
+// creating a fresh one.
+#[test]
+fn create_options() -> Result<()> {
+    let temp_dir = TempDir::new().expect("unable to create temporary working directory");
+    let existing = Options {
+        create: false,
+        ..Options::default()
+    };
+    let fresh = Options {
+        create_new: true,
+        ..Options::default()
+    };
+
+    match KvStore::open_with_options(temp_dir.path(), existing.clone()) {
+        Err(KvsError::IO(e)) => assert_eq!(e.kind(), std::io::ErrorKind::NotFound),
+        other => panic!("unexpected result {:?}", other.map(|_| ())),
+    }
+    assert!(!temp_dir.path().join("miniDB.data").exists());
+
+    let mut store = KvStore::open_with_options(temp_dir.path(), fresh.clone())?;
+    store.set("key1".to_owned(), "value1".to_owned())?;
+    store.reopen()?;
+    assert_eq!(store.get("key1".to_owned())?, Some("value1".to_owned()));
+    drop(store);
+
+    match KvStore::open_with_options(temp_dir.path(), fresh) {
+        Err(KvsError::IO(e)) => assert_eq!(e.kind(), std::io::ErrorKind::AlreadyExists),
+        other => panic!("unexpected result {:?}", other.map(|_| ())),
+    }
+    let mut store = KvStore::open_with_options(temp_dir.path(), existing)?;
+    assert_eq!(store.get("key1".to_owned())?, Some("value1".to_owned()));
+
+    Ok(())
+}
+
+// Compacting a store with nothing left alive should leave a bare header.
+#[test]
+fn compaction_of_dead_store() -> Result<()> {
+    let temp_dir = TempDir::new().expect("unable to create temporary working directory");
+    let mut store = KvStore::open(temp_dir.path())?;
+    let value = "v".repeat(1024);
+    while store.metrics().compaction_count == 0 {
+        store.set("key1".to_owned(), value.clone())?;
+        store.remove("key1".to_owned())?;
+    }
+    store.flush()?;
+
+    let data_path = temp_dir.path().join("miniDB.data");
+    assert_eq!(std::fs::metadata(&data_path)?.len(), 8);
+    assert_eq!(store.get("key1".to_owned())?, None);
+    store.set("key2".to_owned(), "value2".to_owned())?;
+    drop(store);
+    let mut store = KvStore::open(temp_dir.path())?;
+    assert_eq!(store.get("key2".to_owned())?, Some("value2".to_owned()));
+
+    Ok(())
+}
+
+// Writes after a compaction should land at the end of the compacted file,
+// also once it has been truncated.
+#[test]
+fn write_after_compaction() -> Result<()> {
+    let temp_dir = TempDir::new().expect("unable to create temporary working directory");
+    let data_path = temp_dir.path().join("miniDB.data");
+    let mut store = KvStore::open(temp_dir.path())?;
+    while store.metrics().compaction_count == 0 {
+        store.set("key1".to_owned(), "v".repeat(1024))?;
+    }
+    store.set("key2".to_owned(), "value2".to_owned())?;
+    assert_eq!(store.get("key1".to_owned())?, Some("v".repeat(1024)));
+    assert_eq!(store.get("key2".to_owned())?, Some("value2".to_owned()));
+
+    store.clear()?;
+    store.set("key3".to_owned(), "value3".to_owned())?;
+    store.flush()?;
+    assert_eq!(std::fs::metadata(&data_path)?.len(), 8 + 33 + 4 + 6);
+    drop(store);
+
+    let mut store = KvStore::open(temp_dir.path())?;
+    assert_eq!(store.get("key1".to_owned())?, None);
+    assert_eq!(store.get("key3".to_owned())?, Some("value3".to_owned()));
+
+    Ok(())
+}
+
+// Failed writes and reads of the data file should surface as errors, and a
+// write that failed on flush should go through when flushing again.
+#[cfg(feature = "testing")]
+#[test]
+fn injected_faults() -> Result<()> {
+    use kvs::{FaultInjector, FaultKind};
+
+    let temp_dir = TempDir::new().expect("unable to create temporary working directory");
+    let faults = FaultInjector::new();
+    let options = Options {
+        faults: Some(faults.clone()),
+        ..Options::default()
+    };
+    let mut store = KvStore::open_with_options(temp_dir.path(), options.clone())?;
+    store.set("key1".to_owned(), "value1".to_owned())?;
+    let writes = faults.calls(FaultKind::Write);
+    faults.fail_nth(FaultKind::Write, 1);
+    assert!(matches!(store.flush(), Err(KvsError::IO(_))));
+    assert_eq!(faults.calls(FaultKind::Write), writes + 1);
+    store.flush()?;
+    drop(store);
+
+    let mut store = KvStore::open_with_options(temp_dir.path(), options)?;
+    assert_eq!(store.get("key1".to_owned())?, Some("value1".to_owned()));
+
+    // mapped reads don't go through the file
+    #[cfg(not(feature = "mmap"))]
+    {
+        faults.fail_nth(FaultKind::Read, 1);
+        assert!(matches!(store.get("key1".to_owned()), Err(KvsError::IO(_))));
+        assert_eq!(store.get("key1".to_owned())?, Some("value1".to_owned()));
+
+        faults.fail_nth(FaultKind::Seek, 2);
+        store.get("key1".to_owned())?;
+        assert!(matches!(store.get("key1".to_owned()), Err(KvsError::IO(_))));
+        assert_eq!(store.get("key1".to_owned())?, Some("value1".to_owned()));
+    }
+
+    Ok(())
+}
+
+// A key should be readable right after it is written, even when the reader
+// has buffered up to the old end of the file.
+#[test]
+fn read_after_write() -> Result<()> {
+    let temp_dir = TempDir::new().expect("unable to create temporary working directory");
+    let options = Options {
+        read_buffer_capacity: 1 << 16,
+        ..Options::default()
+    };
+    let mut store = KvStore::open_with_options(temp_dir.path(), options)?;
+    store.set("key0".to_owned(), "value0".to_owned())?;
+    for key_id in 1..100 {
+        assert_eq!(
+            store.get(format!("key{}", key_id - 1))?,
+            Some(format!("value{}", key_id - 1))
+        );
+        store.set(format!("key{}", key_id), format!("value{}", key_id))?;
+        assert_eq!(
+            store.get(format!("key{}", key_id))?,
+            Some(format!("value{}", key_id))
+        );
+    }
+
+    Ok(())
+}
+
+// Typed values should round-trip through JSON.
+#[test]
+fn typed_values() -> Result<()> {
+    #[derive(serde::Serialize, serde::Deserialize, Debug, PartialEq)]
+    struct User {
+        name: String,
+        age: u32,
+    }
+
+    let temp_dir = TempDir::new().expect("unable to create temporary working directory");
+    let mut store = KvStore::open(temp_dir.path())?;
+    let alice = User {
+        name: "alice".to_owned(),
+        age: 30,
+    };
+    store.put_typed("user1".to_owned(), &alice)?;
+    store.put_typed("count".to_owned(), &42u64)?;
+    store.put_typed("tags".to_owned(), &["a", "b"][..])?;
+
+    assert_eq!(store.get_typed::<User>("user1".to_owned())?, Some(alice));
+    assert_eq!(store.get_typed::<u64>("count".to_owned())?, Some(42));
+    assert_eq!(
+        store.get_typed::<Vec<String>>("tags".to_owned())?,
+        Some(vec!["a".to_owned(), "b".to_owned()])
+    );
+    assert_eq!(store.get("count".to_owned())?, Some("42".to_owned()));
+    assert_eq!(store.get_typed::<u64>("missing".to_owned())?, None);
+    assert!(matches!(
+        store.get_typed::<u64>("user1".to_owned()),
+        Err(KvsError::Serde(_))
+    ));
+
+    Ok(())
+}
+
+// The builder should open a store with the options it was given.
+#[test]
+fn builder() -> Result<()> {
+    let temp_dir = TempDir::new().expect("unable to create temporary working directory");
+    let builder = KvStore::builder()
+        .name("users")
+        .index_kind(IndexKind::Ordered)
+        .compaction_threshold(4096)
+        .cache_capacity(10);
+    assert_eq!(builder.options().name, "users");
+    assert_eq!(builder.options().compaction_threshold, 4096);
+    let mut store = builder.build(temp_dir.path())?;
+
+    let value = "v".repeat(1024);
+    for _ in 0..5 {
+        store.set("key1".to_owned(), value.clone())?;
+    }
+    assert_eq!(store.metrics().compaction_count, 1);
+    drop(store);
+    assert!(temp_dir.path().join("users.data").is_file());
+
+    let mut store = KvStore::builder()
+        .name("users")
+        .create(false)
+        .build(temp_dir.path())?;
+    assert_eq!(store.get("key1".to_owned())?, Some(value));
+    assert!(KvStore::builder()
+        .name("other")
+        .create(false)
+        .build(temp_dir.path())
+        .is_err());
+
+    Ok(())
+}
+
+// `get_shared` should hand out the cached value itself once a key is cached.
+#[test]
+fn get_shared() -> Result<()> {
+    let temp_dir = TempDir::new().expect("unable to create temporary working directory");
+    let mut store = KvStore::builder()
+        .cache_capacity(10)
+        .build(temp_dir.path())?;
+    store.set("key1".to_owned(), "v".repeat(1024))?;
+
+    let first = store.get_shared("key1")?.unwrap();
+    let second = store.get_shared("key1")?.unwrap();
+    assert!(std::sync::Arc::ptr_eq(&first, &second));
+    assert_eq!(&*first, "v".repeat(1024));
+    assert_eq!(store.get("key1".to_owned())?, Some("v".repeat(1024)));
+    assert_eq!(store.get_shared("key2")?, None);
+
+    // a write replaces the cached value, readers keep theirs
+    store.set("key1".to_owned(), "value1".to_owned())?;
+    assert_eq!(store.get_shared("key1")?.as_deref(), Some("value1"));
+    assert_eq!(&*first, "v".repeat(1024));
+
+    let mut store = KvStore::in_memory();
+    store.set("key1".to_owned(), "value1".to_owned())?;
+    assert_eq!(store.get_shared("key1")?.as_deref(), Some("value1"));
+
+    Ok(())
+}
+
+// `get_or_insert_with` should only compute and store a value for a missing key.
+#[test]
+fn get_or_insert_with() -> Result<()> {
+    let temp_dir = TempDir::new().expect("unable to create temporary working directory");
+    let mut store = KvStore::open(temp_dir.path())?;
+    store.set("key1".to_owned(), "value1".to_owned())?;
+
+    let mut calls = 0;
+    let val = store.get_or_insert_with("key1".to_owned(), || {
+        calls += 1;
+        "computed".to_owned()
+    })?;
+    assert_eq!(val, "value1");
+    let val = store.get_or_insert_with("key2".to_owned(), || {
+        calls += 1;
+        "computed".to_owned()
+    })?;
+    assert_eq!(val, "computed");
+    assert_eq!(calls, 1);
+
+    drop(store);
+    let mut store = KvStore::open(temp_dir.path())?;
+    assert_eq!(store.get("key2".to_owned())?, Some("computed".to_owned()));
+
+    Ok(())
+}
+
+// `remove_batch` should remove the keys that exist and report the others.
+#[test]
+fn remove_batch() -> Result<()> {
+    let temp_dir = TempDir::new().expect("unable to create temporary working directory");
+    let mut store = KvStore::open(temp_dir.path())?;
+    for key_id in 0..4 {
+        store.set(format!("key{}", key_id), format!("value{}", key_id))?;
+    }
+    let events = store.subscribe();
+
+    let keys: Vec<String> = ["key0", "key2", "missing", "key2"]
+        .iter()
+        .map(|key| key.to_string())
+        .collect();
+    let skipped = store.remove_batch(&keys)?;
+    assert_eq!(skipped, vec!["missing".to_owned(), "key2".to_owned()]);
+    assert_eq!(events.try_iter().count(), 2);
+    assert_eq!(store.get("key0".to_owned())?, None);
+    assert_eq!(store.get("key1".to_owned())?, Some("value1".to_owned()));
+    assert_eq!(store.get("key2".to_owned())?, None);
+    drop(store);
+
+    let mut store = KvStore::open(temp_dir.path())?;
+    assert_eq!(store.get("key0".to_owned())?, None);
+    assert_eq!(store.get("key3".to_owned())?, Some("value3".to_owned()));
+    assert_eq!(store.get_state("key2".to_owned())?, KeyState::Tombstoned);
+
+    let mut store = KvStore::in_memory();
+    store.set("key0".to_owned(), "value0".to_owned())?;
+    assert_eq!(store.remove_batch(&keys)?, keys[1..].to_vec());
+
+    Ok(())
+}
+
+// `swap` should exchange the values of two keys, across a reopen, and change
+// nothing when either is missing.
+#[test]
+fn swap() -> Result<()> {
+    let temp_dir = TempDir::new().expect("unable to create temporary working directory");
+    let mut store = KvStore::open(temp_dir.path())?;
+    store.set("blue".to_owned(), "v1".to_owned())?;
+    store.set("green".to_owned(), "v2".to_owned())?;
+    let events = store.subscribe();
+
+    store.swap("blue", "green")?;
+    assert_eq!(events.try_iter().count(), 2);
+    assert_eq!(store.get("blue".to_owned())?, Some("v2".to_owned()));
+    assert_eq!(store.get("green".to_owned())?, Some("v1".to_owned()));
+    store.swap("blue", "blue")?;
+    assert_eq!(store.get("blue".to_owned())?, Some("v2".to_owned()));
+
+    for (a, b) in [("blue", "missing"), ("missing", "green")] {
+        match store.swap(a, b) {
+            Err(KvsError::KeyNotFound) => {}
+            other => panic!("unexpected result {:?}", other),
+        }
+    }
+    assert_eq!(store.get("missing".to_owned())?, None);
+    drop(store);
+
+    let mut store = KvStore::open(temp_dir.path())?;
+    assert_eq!(store.get("blue".to_owned())?, Some("v2".to_owned()));
+    assert_eq!(store.get("green".to_owned())?, Some("v1".to_owned()));
+
+    let mut store = KvStore::in_memory();
+    store.set("a".to_owned(), "1".to_owned())?;
+    store.set("b".to_owned(), "2".to_owned())?;
+    store.swap("a", "b")?;
+    assert_eq!(store.get("a".to_owned())?, Some("2".to_owned()));
+
+    Ok(())
+}
+
+// `apply_batch` should apply every op in order or, when a delete finds no key,
+// none of them, and compaction should keep what it applied.
+#[test]
+fn apply_batch() -> Result<()> {
+    let put = |key: &str, val: &str| WriteOp::Put {
+        key: key.to_owned(),
+        val: val.to_owned(),
+    };
+    let delete = |key: &str| WriteOp::Delete {
+        key: key.to_owned(),
+    };
+    let temp_dir = TempDir::new().expect("unable to create temporary working directory");
+    let stores = vec![
+        KvStore::open(temp_dir.path())?,
+        KvStore::builder()
+            .separate_values()
+            .name("blobs")
+            .build(temp_dir.path())?,
+        KvStore::in_memory(),
+    ];
+    for mut store in stores {
+        store.set("key1".to_owned(), "value1".to_owned())?;
+        let events = store.subscribe();
+
+        let ops = vec![
+            put("key2", "value2"),
+            delete("key1"),
+            put("key3", "value3"),
+            delete("key3"),
+            put("key2", "value4"),
+        ];
+        store.apply_batch(ops)?;
+        assert_eq!(events.try_iter().count(), 5);
+        assert_eq!(store.get("key1".to_owned())?, None);
+        assert_eq!(store.get("key2".to_owned())?, Some("value4".to_owned()));
+        assert_eq!(store.get("key3".to_owned())?, None);
+        assert_eq!(
+            store.get_versioned("key2".to_owned())?.map(|(_, v)| v),
+            Some(2)
+        );
+
+        let ops = vec![put("key4", "value4"), delete("key1")];
+        match store.apply_batch(ops) {
+            Err(KvsError::KeyNotFound) => {}
+            other => panic!("unexpected result {:?}", other),
+        }
+        assert_eq!(events.try_iter().count(), 0);
+        assert_eq!(store.get("key4".to_owned())?, None);
+
+        store.compact_keys(&["key2".to_owned()])?;
+        store.compact_sorted()?;
+        assert_eq!(store.get("key2".to_owned())?, Some("value4".to_owned()));
+        assert_eq!(store.get("key1".to_owned())?, None);
+    }
+
+    let mut store = KvStore::open(temp_dir.path())?;
+    assert_eq!(store.get("key2".to_owned())?, Some("value4".to_owned()));
+    store.apply_batch(vec![put("key5", "value5")])?;
+    drop(store);
+    let mut store = KvStore::open(temp_dir.path())?;
+    assert_eq!(store.get("key5".to_owned())?, Some("value5".to_owned()));
+    assert_eq!(store.get("key2".to_owned())?, Some("value4".to_owned()));
+
+    Ok(())
+}
+
+// The reader should refill its buffer for the first read after a write, and
+// only then, and never hand back bytes of a file truncated since.
+#[test]
+fn reader_generation() -> Result<()> {
+    let temp_dir = TempDir::new().expect("unable to create temporary working directory");
+    let mut bitcask = SimplifiedBitcask::open(temp_dir.path().to_path_buf(), Options::default())?;
+    bitcask.put("key1".to_owned(), "value1".to_owned())?;
+    bitcask.locate("key1")?;
+    let generation = bitcask.reader_generation();
+    for _ in 0..3 {
+        assert_eq!(bitcask.get("key1".to_owned())?, Some("value1".to_owned()));
+        bitcask.locate("key1")?;
+    }
+    assert_eq!(bitcask.reader_generation(), generation);
+
+    bitcask.put("key2".to_owned(), "value2".to_owned())?;
+    assert_eq!(bitcask.locate("key2")?.map(|(_, size)| size), Some(43));
+    assert_eq!(bitcask.reader_generation(), generation + 1);
+    assert_eq!(bitcask.get("key2".to_owned())?, Some("value2".to_owned()));
+    assert_eq!(bitcask.reader_generation(), generation + 1);
+
+    for val in ["value3", "value4"] {
+        bitcask.clear()?;
+        bitcask.put("key1".to_owned(), val.to_owned())?;
+        assert_eq!(bitcask.get("key1".to_owned())?, Some(val.to_owned()));
+        assert_eq!(bitcask.get("key2".to_owned())?, None);
+    }
+
+    Ok(())
+}
+
+// Typed keys should round trip and keep their order in range queries.
+#[test]
+fn typed_store() -> Result<()> {
+    let temp_dir = TempDir::new().expect("unable to create temporary working directory");
+    let mut ids: TypedKvStore<u64, String> = TypedKvStore::new(KvStore::open(temp_dir.path())?);
+    ids.set(&256, &"b".to_owned())?;
+    ids.set(&1, &"a".to_owned())?;
+    assert_eq!(ids.get(&256)?, Some("b".to_owned()));
+    assert_eq!(ids.get(&2)?, None);
+    assert_eq!(
+        ids.scan()?,
+        vec![(1, "a".to_owned()), (256, "b".to_owned())]
+    );
+    ids.remove(&1)?;
+    assert!(matches!(ids.remove(&1), Err(KvsError::KeyNotFound)));
+    ids.store().set("plain".to_owned(), "\"c\"".to_owned())?;
+    assert!(matches!(ids.scan(), Err(KvsError::InvalidKey(_))));
+
+    let mut cells: TypedKvStore<(u32, u32), u64> = TypedKvStore::new(KvStore::in_memory());
+    for (row, col) in [(2, 0), (1, 300), (1, 2), (0, 7)] {
+        cells.set(&(row, col), &(row as u64 * 1000 + col as u64))?;
+    }
+    assert_eq!(
+        cells.range(&(1, 0), &(2, 0))?,
+        vec![((1, 2), 1002), ((1, 300), 1300)]
+    );
+
+    let mut signed: TypedKvStore<i64, i64> = TypedKvStore::new(KvStore::in_memory());
+    for n in [5, -1, 0, i64::MIN, -300, i64::MAX] {
+        signed.set(&n, &n)?;
+    }
+    let keys: Vec<i64> = signed.scan()?.into_iter().map(|(key, _)| key).collect();
+    assert_eq!(keys, vec![i64::MIN, -300, -1, 0, 5, i64::MAX]);
+    assert_eq!(
+        <(u8, String)>::from_key_bytes(&(7u8, "x".to_owned()).to_key_bytes()),
+        Some((7, "x".to_owned()))
+    );
+    Ok(())
+}
+
+// A store from `open_temp` should work like any other and take its directory
+// with it when dropped.
+#[test]
+fn open_temp() -> Result<()> {
+    let mut store = KvStore::open_temp()?;
+    store.set("key1".to_owned(), "value1".to_owned())?;
+    store.compact_sorted()?;
+    assert_eq!(store.get("key1".to_owned())?, Some("value1".to_owned()));
+    let dir = store.data_dir().unwrap().to_path_buf();
+    assert!(dir.join("miniDB.data").exists());
+    drop(store);
+    assert!(!dir.exists());
+
+    let store = KvStore::builder().name("temp").build_temp()?;
+    assert!(store.data_path().unwrap().ends_with("temp.data"));
+
+    Ok(())
+}
+
+// A store should tell where its data file is.
+#[test]
+fn data_path() -> Result<()> {
+    let temp_dir = TempDir::new().expect("unable to create temporary working directory");
+    let store = KvStore::open_named(temp_dir.path(), "users")?;
+    assert_eq!(
+        store.data_path(),
+        Some(temp_dir.path().join("users.data").as_path())
+    );
+    assert_eq!(store.data_dir(), Some(temp_dir.path()));
+
+    let backup_path = temp_dir.path().join("backup.bak");
+    std::fs::copy(store.data_path().unwrap(), &backup_path)?;
+    let backup = KvStore::open_file(&backup_path)?;
+    assert_eq!(backup.data_path(), Some(backup_path.as_path()));
+
+    assert_eq!(KvStore::in_memory().data_path(), None);
+
+    Ok(())
+}
+
+// Values streamed in with `put_reader` should stream back out of
+// `get_writer` unchanged, also from the blob file, and a bad stream should
+// leave nothing behind.
+#[test]
+fn streamed_values() -> Result<()> {
+    let temp_dir = TempDir::new().expect("unable to create temporary working directory");
+    let blob_dir = TempDir::new().expect("unable to create temporary working directory");
+    let blobs = Options {
+        blob_threshold: Some(1024),
+        ..Options::default()
+    };
+    // multibyte characters end up split across copy chunks
+    let value = "aé€😀".repeat(10_000);
+    for mut store in [
+        KvStore::open(temp_dir.path())?,
+        KvStore::open_with_options(blob_dir.path(), blobs)?,
+        KvStore::in_memory(),
+    ] {
+        store.put_reader("key1".to_owned(), &mut value.as_bytes(), value.len())?;
+        store.set("key2".to_owned(), "value2".to_owned())?;
+        let mut out = Vec::new();
+        assert!(store.get_writer("key1", &mut out)?);
+        assert_eq!(out, value.as_bytes());
+        assert_eq!(store.get("key1".to_owned())?, Some(value.clone()));
+        assert!(!store.get_writer("missing", &mut out)?);
+
+        let mut invalid = value.as_bytes().to_vec();
+        invalid[5000] = 0xff;
+        assert!(store
+            .put_reader("key3".to_owned(), &mut invalid.as_slice(), invalid.len())
+            .is_err());
+        assert!(store
+            .put_reader("key3".to_owned(), &mut value.as_bytes(), value.len() + 1)
+            .is_err());
+        assert_eq!(store.get("key3".to_owned())?, None);
+        assert_eq!(store.get("key2".to_owned())?, Some("value2".to_owned()));
+        store.set("key4".to_owned(), "value4".to_owned())?;
+    }
+
+    for dir in [&temp_dir, &blob_dir] {
+        let mut store = KvStore::open(dir.path())?;
+        assert_eq!(store.get("key1".to_owned())?, Some(value.clone()));
+        assert_eq!(store.get("key3".to_owned())?, None);
+        assert_eq!(store.get("key4".to_owned())?, Some("value4".to_owned()));
+    }
+
+    Ok(())
+}
+
+// With a compaction ratio, a large store should put off compacting until
+// that share of it is dead.
+#[test]
+fn compaction_ratio() -> Result<()> {
+    let temp_dir = TempDir::new().expect("unable to create temporary working directory");
+    let mut store = KvStore::builder()
+        .compaction_threshold(1024)
+        .compaction_ratio(0.4)
+        .build(temp_dir.path())?;
+    let value = "v".repeat(100);
+    for key_id in 0..100 {
+        store.set(format!("key{}", key_id), value.clone())?;
+    }
+
+    // past the threshold, but not yet 40% of the file
+    for key_id in 0..50 {
+        store.set(format!("key{}", key_id), value.clone())?;
+    }
+    assert_eq!(store.metrics().compaction_count, 0);
+    for key_id in 50..100 {
+        store.set(format!("key{}", key_id), value.clone())?;
+    }
+    assert_eq!(store.metrics().compaction_count, 1);
+    for key_id in 0..100 {
+        assert_eq!(store.get(format!("key{}", key_id))?, Some(value.clone()));
+    }
+
+    Ok(())
+}
+
+// An empty value should be stored as such, not mistaken for a removed key.
+#[test]
+fn empty_value() -> Result<()> {
+    let temp_dir = TempDir::new().expect("unable to create temporary working directory");
+    let mut store = KvStore::builder()
+        .compaction_threshold(1)
+        .build(temp_dir.path())?;
+    store.set("key1".to_owned(), "".to_owned())?;
+    assert_eq!(store.get("key1".to_owned())?, Some("".to_owned()));
+    assert_eq!(
+        store.get_state("key1".to_owned())?,
+        KeyState::Present("".to_owned())
+    );
+    assert_eq!(store.scan()?, vec![("key1".to_owned(), "".to_owned())]);
+
+    // the overwrite compacts the first entry away
+    store.set("key1".to_owned(), "".to_owned())?;
+    assert_eq!(store.metrics().compaction_count, 1);
+    assert_eq!(store.get("key1".to_owned())?, Some("".to_owned()));
+    drop(store);
+
+    let mut store = KvStore::open(temp_dir.path())?;
+    assert_eq!(store.get("key1".to_owned())?, Some("".to_owned()));
+    store.remove("key1".to_owned())?;
+    assert_eq!(store.get("key1".to_owned())?, None);
+
+    let mut store = KvStore::in_memory();
+    store.set("key1".to_owned(), "".to_owned())?;
+    assert_eq!(store.get("key1".to_owned())?, Some("".to_owned()));
+
+    Ok(())
+}
+
+// The hashed index should behave like the others through overwrites, removals,
+// both kinds of compaction and reopening.
+#[test]
+fn hashed_index() -> Result<()> {
+    let temp_dir = TempDir::new().expect("unable to create temporary working directory");
+    let open = |threshold| {
+        KvStore::builder()
+            .index_kind(IndexKind::Hashed)
+            .compaction_threshold(threshold)
+            .build(temp_dir.path())
+    };
+    let mut store = open(1 << 20)?;
+    for key_id in 0..100 {
+        store.set(format!("key{}", key_id), "old".to_owned())?;
+    }
+    for key_id in 0..50 {
+        store.set(format!("key{}", key_id), "new".to_owned())?;
+    }
+    for key_id in 90..100 {
+        store.remove(format!("key{}", key_id))?;
+    }
+    let expected = |key_id: usize| match key_id {
+        0..=49 => Some("new".to_owned()),
+        50..=89 => Some("old".to_owned()),
+        _ => None,
+    };
+    let check = |store: &mut KvStore| -> Result<()> {
+        for key_id in 0..100 {
+            assert_eq!(store.get(format!("key{}", key_id))?, expected(key_id));
+        }
+        assert_eq!(store.stats().key_count, 90);
+        assert_eq!(store.first_key()?, Some("key0".to_owned()));
+        assert_eq!(store.last_key()?, Some("key9".to_owned()));
+        Ok(())
+    };
+    check(&mut store)?;
+
+    while let CompactionProgress::Pending { .. } = store.compact_step(512)? {
+        store.set("key0".to_owned(), "new".to_owned())?;
+    }
+    check(&mut store)?;
+    drop(store);
+
+    let mut store = open(1)?;
+    check(&mut store)?;
+    store.set("key0".to_owned(), "new".to_owned())?;
+    assert_eq!(store.metrics().compaction_count, 1);
+    check(&mut store)?;
+    assert_eq!(store.scan()?.len(), 90);
+
+    Ok(())
+}
+
+// A scan iterator should keep returning the pairs of the moment it was created,
+// through later writes and compactions.
+#[test]
+fn scan_iter() -> Result<()> {
+    let temp_dir = TempDir::new().expect("unable to create temporary working directory");
+    let mut store = KvStore::builder()
+        .compaction_threshold(1)
+        .blob_threshold(16)
+        .build(temp_dir.path())?;
+    let large = "v".repeat(100);
+    store.set("key1".to_owned(), "value1".to_owned())?;
+    store.set("key2".to_owned(), large.clone())?;
+    store.set("key3".to_owned(), "value3".to_owned())?;
+
+    let mut pairs = store.scan_iter()?;
+    assert_eq!(
+        pairs.next().transpose()?,
+        Some(("key1".to_owned(), "value1".to_owned()))
+    );
+    store.set("key2".to_owned(), "value2".to_owned())?;
+    store.remove("key3".to_owned())?;
+    store.set("key4".to_owned(), "value4".to_owned())?;
+    assert!(store.metrics().compaction_count > 0);
+    assert_eq!(
+        pairs.collect::<Result<Vec<_>>>()?,
+        vec![
+            ("key2".to_owned(), large),
+            ("key3".to_owned(), "value3".to_owned()),
+        ]
+    );
+
+    let mut store = KvStore::in_memory();
+    store.set("key1".to_owned(), "value1".to_owned())?;
+    let pairs = store.scan_iter()?;
+    store.remove("key1".to_owned())?;
+    assert_eq!(pairs.count(), 1);
+
+    Ok(())
+}
+
+// A scan iterator should report a blob reference past the end of the blob
+// file as corruption rather than read or allocate for it.
+#[test]
+fn scan_iter_damaged_blob_ref() -> Result<()> {
+    let temp_dir = TempDir::new().expect("unable to create temporary working directory");
+    let builder = KvStore::builder().blob_threshold(16);
+    let mut store = builder.clone().build(temp_dir.path())?;
+    store.set("key1".to_owned(), "v".repeat(100))?;
+    drop(store);
+
+    let data_path = temp_dir.path().join("miniDB.data");
+    let data = std::fs::read(&data_path)?;
+    let at = data.windows(5).position(|w| w == b"0:100").unwrap();
+    let mut damaged = data;
+    damaged[at..at + 5].copy_from_slice(b"0:999");
+    std::fs::write(&data_path, damaged)?;
+
+    let mut store = builder.build(temp_dir.path())?;
+    match store.scan_iter()?.next() {
+        Some(Err(KvsError::CorruptedEntry(8))) => {}
+        other => panic!("unexpected result {:?}", other),
+    }
+
+    Ok(())
+}
+
+// A store read from a data file in memory should see the same data, and refuse
+// writes.
+#[test]
+fn open_reader() -> Result<()> {
+    let temp_dir = TempDir::new().expect("unable to create temporary working directory");
+    let mut store = KvStore::open(temp_dir.path())?;
+    store.set("key1".to_owned(), "value1".to_owned())?;
+    store.set("key2".to_owned(), "value2".to_owned())?;
+    store.set("key1".to_owned(), "value3".to_owned())?;
+    store.remove("key2".to_owned())?;
+    store.set("key4".to_owned(), "value4".to_owned())?;
+    drop(store);
+    let data = std::fs::read(temp_dir.path().join("miniDB.data"))?;
+
+    let mut store = KvStore::open_reader(std::io::Cursor::new(data.clone()))?;
+    assert_eq!(store.get("key1".to_owned())?, Some("value3".to_owned()));
+    assert_eq!(store.get_state("key2".to_owned())?, KeyState::Tombstoned);
+    assert_eq!(
+        store.scan()?,
+        vec![
+            ("key1".to_owned(), "value3".to_owned()),
+            ("key4".to_owned(), "value4".to_owned()),
+        ]
+    );
+    assert_eq!(store.stats().file_size, data.len() as u64);
+    assert!(matches!(
+        store.set("key5".to_owned(), "value5".to_owned()),
+        Err(KvsError::Unsupported)
+    ));
+    assert!(matches!(
+        store.remove("key1".to_owned()),
+        Err(KvsError::Unsupported)
+    ));
+
+    let mut torn = data.clone();
+    torn.truncate(data.len() - 1);
+    assert!(KvStore::open_reader(std::io::Cursor::new(torn)).is_err());
+    assert!(matches!(
+        KvStore::open_reader(std::io::Cursor::new(b"not a store".to_vec())),
+        Err(KvsError::IncompatibleFormat { .. })
+    ));
+
+    Ok(())
+}
+
+// A store with a statically known backend should behave like a boxed one.
+#[test]
+fn bitcask_store() -> Result<()> {
+    let temp_dir = TempDir::new().expect("unable to create temporary working directory");
+    let bitcask = SimplifiedBitcask::open(temp_dir.path().to_path_buf(), Options::default())?;
+    let mut store: BitcaskStore = KvStore::from_storage(bitcask);
+    store.set("key1".to_owned(), "value1".to_owned())?;
+    store.set("key2".to_owned(), "value2".to_owned())?;
+    store.remove("key2".to_owned())?;
+    store
+        .namespace("ns")
+        .set("key3".to_owned(), "value3".to_owned())?;
+    assert_eq!(store.get("key1".to_owned())?, Some("value1".to_owned()));
+    assert_eq!(store.namespace("ns").keys()?, vec!["key3".to_owned()]);
+    drop(store);
+
+    let mut store = KvStore::open(temp_dir.path())?;
+    assert_eq!(store.get("key1".to_owned())?, Some("value1".to_owned()));
+    assert_eq!(store.get("key2".to_owned())?, None);
+
+    Ok(())
+}
+
+// Entry lengths should be stored as 8 bytes whatever the host's usize width.
+#[test]
+fn entry_layout() -> Result<()> {
+    let mut data = b"mnDB".to_vec();
+    data.extend_from_slice(&3u32.to_be_bytes());
+    data.extend_from_slice(&3u64.to_be_bytes());
+    data.extend_from_slice(&5u64.to_be_bytes());
+    data.push(1);
+    data.extend_from_slice(&42u64.to_be_bytes());
+    data.extend_from_slice(&1u64.to_be_bytes());
+    data.extend_from_slice(b"keyvalue");
+
+    let mut store = KvStore::open_reader(std::io::Cursor::new(data.clone()))?;
+    assert_eq!(store.get("key".to_owned())?, Some("value".to_owned()));
+
+    let temp_dir = TempDir::new().expect("unable to create temporary working directory");
+    let mut store = KvStore::open(temp_dir.path())?;
+    store.set("key".to_owned(), "value".to_owned())?;
+    drop(store);
+    let written = std::fs::read(temp_dir.path().join("miniDB.data"))?;
+    assert_eq!(written.len(), data.len());
+    assert_eq!(written[..25], data[..25]);
+    assert_eq!(written[33..], data[33..]);
+
+    Ok(())
+}
+
+// Purging should drop every tombstone past its grace period and count them.
+#[test]
+fn purge_tombstones() -> Result<()> {
+    let temp_dir = TempDir::new().expect("unable to create temporary working directory");
+    let data_path = temp_dir.path().join("miniDB.data");
+    let mut store = KvStore::open(temp_dir.path())?;
+    for i in 0..10 {
+        store.set(format!("key{}", i), format!("value{}", i))?;
+    }
+    for i in 0..5 {
+        store.remove(format!("key{}", i))?;
+    }
+    store.flush()?;
+    let len_before = std::fs::metadata(&data_path)?.len();
+
+    assert_eq!(store.purge_tombstones()?, 5);
+    assert_eq!(store.get_state("key0".to_owned())?, KeyState::Absent);
+    assert_eq!(store.get("key5".to_owned())?, Some("value5".to_owned()));
+    assert!(std::fs::metadata(&data_path)?.len() < len_before);
+    assert_eq!(store.purge_tombstones()?, 0);
+    drop(store);
+
+    let mut store = KvStore::builder()
+        .tombstone_grace(std::time::Duration::from_secs(3600))
+        .build(temp_dir.path())?;
+    assert_eq!(store.scan()?.len(), 5);
+    store.remove("key5".to_owned())?;
+    assert_eq!(store.purge_tombstones()?, 0);
+    assert_eq!(store.get_state("key5".to_owned())?, KeyState::Tombstoned);
+
+    let mut store = KvStore::in_memory();
+    store.set("key1".to_owned(), "value1".to_owned())?;
+    store.remove("key1".to_owned())?;
+    assert_eq!(store.purge_tombstones()?, 1);
+    assert_eq!(store.get_state("key1".to_owned())?, KeyState::Absent);
+
+    Ok(())
+}
+
+// Each get should report whether it hit the cache, the data file, or nothing.
+#[test]
+fn get_with_source() -> Result<()> {
+    let temp_dir = TempDir::new().expect("unable to create temporary working directory");
+    let mut store = KvStore::builder()
+        .cache_capacity(1)
+        .build(temp_dir.path())?;
+    store.set("key1".to_owned(), "value1".to_owned())?;
+    store.set("key2".to_owned(), "value2".to_owned())?;
+
+    assert_eq!(
+        store.get_with_source("key1".to_owned())?,
+        (Some("value1".to_owned()), ReadSource::IndexHitDisk)
+    );
+    assert_eq!(
+        store.get_with_source("key1".to_owned())?,
+        (Some("value1".to_owned()), ReadSource::CacheHit)
+    );
+    assert_eq!(
+        store.get_with_source("key2".to_owned())?,
+        (Some("value2".to_owned()), ReadSource::IndexHitDisk)
+    );
+    assert_eq!(
+        store.get_with_source("key3".to_owned())?,
+        (None, ReadSource::Miss)
+    );
+
+    Ok(())
+}
+
+// Values within `inline_threshold` should be read from memory however they were
+// written, before and after reopening, and stop being so once overwritten by a
+// bigger value or removed.
+#[test]
+fn inline_threshold() -> Result<()> {
+    let temp_dir = TempDir::new().expect("unable to create temporary working directory");
+    let builder = KvStore::builder().inline_threshold(6);
+    let mut store = builder.clone().build(temp_dir.path())?;
+    store.set("key1".to_owned(), "value1".to_owned())?;
+    store.set("key2".to_owned(), "value22".to_owned())?;
+    store.put_reader("key3".to_owned(), &mut "value3".as_bytes(), 6)?;
+    store.apply_batch(vec![WriteOp::Put {
+        key: "key4".to_owned(),
+        val: "value4".to_owned(),
+    }])?;
+    store.rename("key4", "key5")?;
+
+    let sources = |store: &mut KvStore| -> Result<Vec<ReadSource>> {
+        (1..=5)
+            .map(|i| Ok(store.get_with_source(format!("key{}", i))?.1))
+            .collect()
+    };
+    let expected = vec![
+        ReadSource::InlineHit,
+        ReadSource::IndexHitDisk,
+        ReadSource::InlineHit,
+        ReadSource::Miss,
+        ReadSource::InlineHit,
+    ];
+    assert_eq!(sources(&mut store)?, expected);
+    assert_eq!(store.get("key3".to_owned())?, Some("value3".to_owned()));
+    assert_eq!(store.get("key5".to_owned())?, Some("value4".to_owned()));
+    store.compact_sorted()?;
+    assert_eq!(sources(&mut store)?, expected);
+    drop(store);
+
+    let mut store = builder.build(temp_dir.path())?;
+    assert_eq!(sources(&mut store)?, expected);
+    store.set("key1".to_owned(), "value11".to_owned())?;
+    store.remove("key3".to_owned())?;
+    assert_eq!(
+        store.get_with_source("key1".to_owned())?,
+        (Some("value11".to_owned()), ReadSource::IndexHitDisk)
+    );
+    assert_eq!(
+        store.get_with_source("key3".to_owned())?,
+        (None, ReadSource::Miss)
+    );
+
+    Ok(())
+}
+
+// Live pairs should come back in the order they were last written.
+#[test]
+fn iter_in_write_order() -> Result<()> {
+    let temp_dir = TempDir::new().expect("unable to create temporary working directory");
+    let mut store = KvStore::open(temp_dir.path())?;
+    store.set("key3".to_owned(), "value3".to_owned())?;
+    store.set("key1".to_owned(), "value1".to_owned())?;
+    store.set("key2".to_owned(), "value2".to_owned())?;
+    store.set("key3".to_owned(), "value4".to_owned())?;
+    store.remove("key1".to_owned())?;
+
+    let keys = |store: &mut KvStore| -> Result<Vec<String>> {
+        store
+            .iter_in_write_order()?
+            .map(|pair| pair.map(|(key, _)| key))
+            .collect()
+    };
+    assert_eq!(
+        keys(&mut store)?,
+        vec!["key2".to_owned(), "key3".to_owned()]
+    );
+    store.purge_tombstones()?;
+    assert_eq!(
+        keys(&mut store)?,
+        vec!["key2".to_owned(), "key3".to_owned()]
+    );
+    assert_eq!(
+        store.iter_in_write_order()?.last().transpose()?,
+        Some(("key3".to_owned(), "value4".to_owned()))
+    );
+
+    assert!(matches!(
+        KvStore::in_memory().iter_in_write_order(),
+        Err(KvsError::Unsupported)
+    ));
+
+    Ok(())
+}
+
+// Compaction should report its progress and stop cleanly once cancelled.
+#[test]
+fn compaction_progress_and_cancel() -> Result<()> {
+    let temp_dir = TempDir::new().expect("unable to create temporary working directory");
+    let data_path = temp_dir.path().join("miniDB.data");
+    let token = CancellationToken::new();
+    let events = std::sync::Arc::new(std::sync::Mutex::new(Vec::new()));
+    let recorded = events.clone();
+    let cancel = token.clone();
+    let mut store = KvStore::builder()
+        .compaction_threshold(u64::MAX)
+        .compaction_cancel(token.clone())
+        .on_compaction(std::sync::Arc::new(move |info| {
+            if let CompactionInfo::Progress { scanned, .. } = info {
+                if scanned > 2 << 20 {
+                    cancel.cancel();
+                }
+            }
+            recorded.lock().unwrap().push(info);
+        }))
+        .build(temp_dir.path())?;
+    let value = "v".repeat(8 * 1024);
+    for iter in 0..2 {
+        for key_id in 0..200 {
+            store.set(format!("key{}", key_id), format!("{}{}", value, iter))?;
+        }
+    }
+    store.flush()?;
+    let len_before = std::fs::metadata(&data_path)?.len();
+
+    assert!(matches!(
+        store.purge_tombstones(),
+        Err(KvsError::CompactionCancelled)
+    ));
+    assert!(token.is_cancelled());
+    assert!(!temp_dir.path().join("miniDB.merge").exists());
+    assert_eq!(std::fs::metadata(&data_path)?.len(), len_before);
+    assert_eq!(store.get("key0".to_owned())?, Some(format!("{}1", value)));
+
+    let events = events.lock().unwrap().clone();
+    assert!(matches!(events[0], CompactionInfo::Started { .. }));
+    let progress: Vec<(u64, u64)> = events[1..]
+        .iter()
+        .map(|info| match *info {
+            CompactionInfo::Progress { scanned, total } => (scanned, total),
+            other => panic!("unexpected event {:?}", other),
+        })
+        .collect();
+    assert_eq!(progress.len(), 2);
+    assert!(progress.windows(2).all(|w| w[0].0 < w[1].0));
+    assert!(progress.iter().all(|(_, total)| *total == len_before));
+
+    // later compactions don't start, writes that would trigger one still work
+    assert!(matches!(
+        store.purge_tombstones(),
+        Err(KvsError::CompactionCancelled)
+    ));
+    drop(store);
+    let mut store = KvStore::builder()
+        .compaction_threshold(0)
+        .compaction_cancel(token)
+        .build(temp_dir.path())?;
+    store.set("key0".to_owned(), "value".to_owned())?;
+    assert_eq!(store.get("key0".to_owned())?, Some("value".to_owned()));
+    assert_eq!(store.metrics().compaction_count, 0);
+
+    Ok(())
+}
+
+// Both entry codecs should read back what they wrote, the manual one in the
+// data file layout.
+#[test]
+fn entry_codecs() -> Result<()> {
+    use kvs::kv::storage::Entry;
+
+    let entries = vec![
+        Entry::new("key1".to_owned(), "value1".to_owned(), CmdKind::PUT),
+        Entry::new("key2".to_owned(), String::new(), CmdKind::DEL),
+        Entry::new(String::new(), "v".repeat(1000), CmdKind::PUT),
+    ];
+    for codec in [EntryCodec::Manual, EntryCodec::Bincode] {
+        let mut buf = Vec::new();
+        for e in &entries {
+            buf.extend_from_slice(&codec.encode(e)?);
+        }
+        let mut reader = buf.as_slice();
+        for e in &entries {
+            let decoded = codec.decode(&mut reader)?.expect("an entry");
+            assert_eq!(decoded.key(), e.key());
+            assert_eq!(decoded.value(), e.value());
+            assert_eq!(decoded.kind(), e.kind());
+            assert_eq!(decoded.timestamp(), e.timestamp());
+        }
+        assert!(codec.decode(&mut reader)?.is_none());
+
+        let torn = &buf[..buf.len() - 1];
+        let mut reader = torn;
+        let decoded: Result<Vec<_>> = (0..entries.len())
+            .map(|_| codec.decode(&mut reader))
+            .collect();
+        assert!(decoded.is_err());
+    }
+    assert_eq!(EntryCodec::Manual.encode(&entries[0])?, entries[0].encode());
+
+    Ok(())
+}
+
+// Every write should bump the key's version, and conditional puts should only
+// go through at the expected one.
+#[test]
+fn key_versions() -> Result<()> {
+    let temp_dir = TempDir::new().expect("unable to create temporary working directory");
+    let stores = vec![KvStore::open(temp_dir.path())?, KvStore::in_memory()];
+    for mut store in stores {
+        assert_eq!(store.get_versioned("key1".to_owned())?, None);
+        assert_eq!(
+            store.put_if_version("key1".to_owned(), "value1".to_owned(), 0)?,
+            1
+        );
+        store.set("key1".to_owned(), "value2".to_owned())?;
+        assert_eq!(
+            store.get_versioned("key1".to_owned())?,
+            Some(("value2".to_owned(), 2))
+        );
+
+        assert!(matches!(
+            store.put_if_version("key1".to_owned(), "value3".to_owned(), 1),
+            Err(KvsError::VersionMismatch {
+                expected: 1,
+                found: 2
+            })
+        ));
+        assert_eq!(store.get("key1".to_owned())?, Some("value2".to_owned()));
+        assert_eq!(
+            store.put_if_version("key1".to_owned(), "value3".to_owned(), 2)?,
+            3
+        );
+
+        // a removal counts as a write, and its tombstone carries the version on
+        store.remove("key1".to_owned())?;
+        assert!(matches!(
+            store.put_if_version("key1".to_owned(), "value4".to_owned(), 3),
+            Err(KvsError::VersionMismatch {
+                expected: 3,
+                found: 0
+            })
+        ));
+        assert_eq!(
+            store.put_if_version("key1".to_owned(), "value4".to_owned(), 0)?,
+            5
+        );
+    }
+
+    let mut store = KvStore::open(temp_dir.path())?;
+    assert_eq!(
+        store.get_versioned("key1".to_owned())?,
+        Some(("value4".to_owned(), 5))
+    );
+    store.purge_tombstones()?;
+    assert_eq!(
+        store.get_versioned("key1".to_owned())?,
+        Some(("value4".to_owned(), 5))
+    );
+
+    Ok(())
+}
+
+// `open_files` should work through handles opened by the caller, writing the
+// header of a new file and reading back what an earlier store wrote.
+#[test]
+fn open_files() -> Result<()> {
+    let temp_dir = TempDir::new().expect("unable to create temporary working directory");
+    let path = temp_dir.path().join("passed.data");
+    let open = || -> Result<(std::fs::File, std::fs::File)> {
+        let writer = std::fs::OpenOptions::new()
+            .append(true)
+            .create(true)
+            .open(&path)?;
+        Ok((std::fs::File::open(&path)?, writer))
+    };
+
+    let (reader, writer) = open()?;
+    let mut store = KvStore::open_files(&path, reader, writer)?;
+    store.set("key1".to_owned(), "value1".to_owned())?;
+    store.set("key2".to_owned(), "value2".to_owned())?;
+    store.remove("key2".to_owned())?;
+    drop(store);
+
+    let (reader, writer) = open()?;
+    let mut store = KvStore::open_files(&path, reader, writer)?;
+    assert_eq!(store.get("key1".to_owned())?, Some("value1".to_owned()));
+    assert_eq!(store.get("key2".to_owned())?, None);
+    store.set("key3".to_owned(), "value3".to_owned())?;
+    drop(store);
+
+    let mut store = KvStore::open_file(&path)?;
+    assert_eq!(store.get("key3".to_owned())?, Some("value3".to_owned()));
+
+    Ok(())
+}
+
+// `compact_sorted` should rewrite the live entries in key order, leaving
+// removed keys out.
+#[test]
+fn compact_sorted() -> Result<()> {
+    let temp_dir = TempDir::new().expect("unable to create temporary working directory");
+    let mut store = KvStore::open(temp_dir.path())?;
+    for i in [7, 3, 9, 1, 5, 8, 2] {
+        store.set(format!("key{}", i), format!("value{}", i))?;
+    }
+    store.set("key3".to_owned(), "value3b".to_owned())?;
+    store.remove("key8".to_owned())?;
+
+    assert_eq!(store.compact_sorted()?, 1);
+    let keys: Vec<String> = store
+        .iter_in_write_order()?
+        .map(|pair| pair.map(|(key, _)| key))
+        .collect::<Result<_>>()?;
+    assert_eq!(keys, ["key1", "key2", "key3", "key5", "key7", "key9"]);
+    assert_eq!(store.get("key3".to_owned())?, Some("value3b".to_owned()));
+    assert_eq!(store.get("key8".to_owned())?, None);
+    drop(store);
+
+    let mut store = KvStore::open(temp_dir.path())?;
+    assert_eq!(store.get("key9".to_owned())?, Some("value9".to_owned()));
+    assert_eq!(store.get("key8".to_owned())?, None);
+
+    Ok(())
+}
+
+// Compaction should keep the last `keep_versions` values of each live key,
+// oldest first, and drop every value of a removed key.
+#[test]
+fn keep_versions() -> Result<()> {
+    use kvs::kv::storage::SimplifiedBitcask;
+
+    fn values(path: &std::path::Path) -> Result<Vec<(String, String)>> {
+        let mut bitcask = SimplifiedBitcask::open(path.to_path_buf(), Options::default())?;
+        let entries = bitcask.iter_entries().collect::<Result<Vec<_>>>()?;
+        Ok(entries
+            .iter()
+            .map(|(_, e)| (e.key().to_owned(), e.value().to_owned()))
+            .collect())
+    }
+
+    let temp_dir = TempDir::new().expect("unable to create temporary working directory");
+    let options = Options {
+        keep_versions: 3,
+        ..Options::default()
+    };
+    let mut store = KvStore::open_with_options(temp_dir.path(), options.clone())?;
+    for i in 1..=5 {
+        store.set("key1".to_owned(), format!("value{}", i))?;
+    }
+    store.set("key2".to_owned(), "value1".to_owned())?;
+    store.set("key2".to_owned(), "value2".to_owned())?;
+    store.set("key3".to_owned(), "value1".to_owned())?;
+    store.remove("key3".to_owned())?;
+
+    while let CompactionProgress::Pending { .. } = store.compact_step(64)? {}
+    assert_eq!(store.get("key1".to_owned())?, Some("value5".to_owned()));
+    drop(store);
+    let kept = [
+        ("key1", "value3"),
+        ("key1", "value4"),
+        ("key1", "value5"),
+        ("key2", "value1"),
+        ("key2", "value2"),
+    ]
+    .map(|(key, val)| (key.to_owned(), val.to_owned()));
+    assert_eq!(values(temp_dir.path())?, kept);
+
+    let mut store = KvStore::open_with_options(temp_dir.path(), options)?;
+    store.set("key2".to_owned(), "value3".to_owned())?;
+    store.set("key2".to_owned(), "value4".to_owned())?;
+    store.compact_sorted()?;
+    assert_eq!(store.get("key2".to_owned())?, Some("value4".to_owned()));
+    drop(store);
+    let kept = [
+        ("key1", "value3"),
+        ("key1", "value4"),
+        ("key1", "value5"),
+        ("key2", "value2"),
+        ("key2", "value3"),
+        ("key2", "value4"),
+    ]
+    .map(|(key, val)| (key.to_owned(), val.to_owned()));
+    assert_eq!(values(temp_dir.path())?, kept);
+
+    let mut store = KvStore::open(temp_dir.path())?;
+    assert_eq!(store.get("key1".to_owned())?, Some("value5".to_owned()));
+    assert_eq!(store.get("key3".to_owned())?, None);
+
+    Ok(())
+}
+
+// `put_batch` should store the last value of a repeated key without writing
+// the others, or refuse the whole batch under `DuplicatePolicy::Error`.
+#[test]
+fn put_batch_duplicates() -> Result<()> {
+    let temp_dir = TempDir::new().expect("unable to create temporary working directory");
+    let mut store = KvStore::open(temp_dir.path())?;
+    let pairs = |pairs: &[(&str, &str)]| -> Vec<(String, String)> {
+        pairs
+            .iter()
+            .map(|(k, v)| (k.to_string(), v.to_string()))
+            .collect()
+    };
+
+    let events = store.subscribe();
+    store.put_batch(
+        pairs(&[("a", "1"), ("b", "2"), ("a", "3"), ("c", "4"), ("a", "5")]),
+        DuplicatePolicy::LastWins,
+    )?;
+    assert_eq!(store.get("a".to_owned())?, Some("5".to_owned()));
+    assert_eq!(store.get("b".to_owned())?, Some("2".to_owned()));
+    assert_eq!(store.stats().key_count, 3);
+    assert_eq!(store.stats().pending_bytes, 0);
+    let keys: Vec<String> = events.try_iter().map(|event| event.key).collect();
+    assert_eq!(keys, ["b", "c", "a"]);
+
+    match store.put_batch(
+        pairs(&[("d", "1"), ("e", "2"), ("d", "3")]),
+        DuplicatePolicy::Error,
+    ) {
+        Err(KvsError::DuplicateKey(key)) => assert_eq!(key, "d"),
+        other => panic!("expected a duplicate key error, got {:?}", other),
+    }
+    assert_eq!(store.get("d".to_owned())?, None);
+    assert_eq!(store.get("e".to_owned())?, None);
+
+    store.put_batch(pairs(&[("d", "1"), ("e", "2")]), DuplicatePolicy::Error)?;
+    assert_eq!(store.get("e".to_owned())?, Some("2".to_owned()));
+
+    Ok(())
+}
+
+// `shrink_index` should give back index capacity after most keys are removed,
+// keeping the rest readable.
+#[test]
+fn shrink_index() -> Result<()> {
+    let temp_dir = TempDir::new().expect("unable to create temporary working directory");
+    let mut stores = vec![KvStore::open(temp_dir.path())?, KvStore::in_memory()];
+    for store in &mut stores {
+        for i in 0..2000 {
+            store.set(format!("key{}", i), "v".to_owned())?;
+        }
+        for i in 10..2000 {
+            store.remove(format!("key{}", i))?;
+        }
+        let (before, after) = store.shrink_index();
+        assert!(before >= 2000);
+        assert!(after >= 10 && after < before);
+        assert_eq!(store.get("key3".to_owned())?, Some("v".to_owned()));
+        assert_eq!(store.get("key30".to_owned())?, None);
+    }
+
+    Ok(())
+}
+
+// `compact_keys` should only drop what the given keys left behind, keeping the
+// overwritten entries of other keys.
+#[test]
+fn compact_keys() -> Result<()> {
+    let temp_dir = TempDir::new().expect("unable to create temporary working directory");
+    let mut store = KvStore::open(temp_dir.path())?;
+    store.set("cold".to_owned(), "v0".to_owned())?;
+    store.set("gone".to_owned(), "v0".to_owned())?;
+    for i in 1..=5 {
+        store.set("hot".to_owned(), format!("v{}", i))?;
+    }
+    store.set("cold".to_owned(), "v6".to_owned())?;
+    store.remove("gone".to_owned())?;
+    assert_eq!(store.stats().pending_bytes, 4 * 38 + 39 + 39);
+
+    let keys = ["hot".to_owned(), "gone".to_owned()];
+    assert_eq!(store.compact_keys(&keys)?, 4 * 38 + 39 + 37);
+    assert_eq!(store.stats().pending_bytes, 39);
+    assert_eq!(store.stats().file_size, 8 + 2 * 39 + 38);
+    assert_eq!(store.get("hot".to_owned())?, Some("v5".to_owned()));
+    assert_eq!(store.get("cold".to_owned())?, Some("v6".to_owned()));
+    assert_eq!(store.get_state("gone".to_owned())?, KeyState::Absent);
+    drop(store);
+
+    let mut store = KvStore::open(temp_dir.path())?;
+    assert_eq!(store.get("hot".to_owned())?, Some("v5".to_owned()));
+    assert_eq!(store.get("cold".to_owned())?, Some("v6".to_owned()));
+    assert_eq!(store.get("gone".to_owned())?, None);
+    assert_eq!(store.compaction_estimate()?.dead_bytes, 39);
+
+    Ok(())
+}
+
+// Opening should name the path when a directory sits where the data file
+// belongs, rather than fail with whatever the OS reports.
+#[test]
+fn data_path_not_a_file() -> Result<()> {
+    let temp_dir = TempDir::new().expect("unable to create temporary working directory");
+    let data_path = temp_dir.path().join("miniDB.data");
+    std::fs::create_dir(&data_path)?;
+    match KvStore::open(temp_dir.path()) {
+        Err(KvsError::NotAFile(path)) => assert_eq!(path, data_path),
+        Err(e) => panic!("expected NotAFile, got {:?}", e),
+        Ok(_) => panic!("opened a directory as the data file"),
+    }
+    assert!(matches!(
+        KvStore::open_file(&data_path),
+        Err(KvsError::NotAFile(_))
+    ));
+    assert!(matches!(
+        KvStore::verify(temp_dir.path()),
+        Err(KvsError::NotAFile(_))
+    ));
+
+    Ok(())
+}
+
+// With `auto_compaction` off, writes should leave dead bytes alone however
+// many pile up, until a compaction is asked for.
+#[test]
+fn auto_compaction_off() -> Result<()> {
+    let temp_dir = TempDir::new().expect("unable to create temporary working directory");
+    let mut store = KvStore::builder()
+        .auto_compaction(false)
+        .compaction_threshold(1024)
+        .build(temp_dir.path())?;
+    for i in 0..100 {
+        store.set("key".to_owned(), format!("value{}", i))?;
+    }
+    store.remove("key".to_owned())?;
+    assert_eq!(store.metrics().compaction_count, 0);
+    assert!(store.stats().pending_bytes > 1024);
+
+    store.purge_tombstones()?;
+    assert_eq!(store.metrics().compaction_count, 1);
+    assert_eq!(store.stats().pending_bytes, 0);
+
+    Ok(())
+}
+
+// With a commit interval, every write should reach the data file right away
+// and keep doing so after a compaction swapped the file.
+#[test]
+fn commit_interval() -> Result<()> {
+    let temp_dir = TempDir::new().expect("unable to create temporary working directory");
+    let mut store = KvStore::builder()
+        .commit_interval(CommitInterval {
+            every: std::time::Duration::from_millis(5),
+            max_bytes: 1024,
+        })
+        .compaction_threshold(1024)
+        .build(temp_dir.path())?;
+    let data_path = store.data_path().unwrap().to_path_buf();
+    store.set("key1".to_owned(), "value1".to_owned())?;
+    assert_eq!(std::fs::metadata(&data_path)?.len(), 8 + 43);
+
+    for i in 0..100 {
+        store.set("key2".to_owned(), format!("value{}", i % 10))?;
+    }
+    assert!(store.metrics().compaction_count > 0);
+    store.set("key3".to_owned(), "value3".to_owned())?;
+    let len = std::fs::metadata(&data_path)?.len();
+    assert_eq!(len, store.size_on_disk()?);
+    std::thread::sleep(std::time::Duration::from_millis(20));
+    drop(store);
+
+    let mut store = KvStore::open(temp_dir.path())?;
+    assert_eq!(store.get("key1".to_owned())?, Some("value1".to_owned()));
+    assert_eq!(store.get("key3".to_owned())?, Some("value3".to_owned()));
+    Ok(())
+}
+
+// `compact_if_needed` should compact only once the threshold is crossed, with
+// writes leaving it alone.
+#[test]
+fn compact_if_needed() -> Result<()> {
+    let temp_dir = TempDir::new().expect("unable to create temporary working directory");
+    let mut store = KvStore::builder()
+        .auto_compaction(false)
+        .compaction_threshold(1024)
+        .build(temp_dir.path())?;
+    store.set("key".to_owned(), "value".to_owned())?;
+    store.set("key".to_owned(), "value".to_owned())?;
+    assert!(!store.compact_if_needed()?);
+
+    while store.stats().pending_bytes < 1024 {
+        store.set("key".to_owned(), "value".to_owned())?;
+    }
+    assert_eq!(store.metrics().compaction_count, 0);
+    assert!(store.compact_if_needed()?);
+    assert_eq!(store.metrics().compaction_count, 1);
+    assert!(!store.compact_if_needed()?);
+    assert_eq!(store.get("key".to_owned())?, Some("value".to_owned()));
+    assert!(!KvStore::in_memory().compact_if_needed()?);
+
+    Ok(())
+}
+
+// Under `compaction_idle` writes should leave compaction to `compact_if_needed`
+// once the store has been quiet, unless the ceiling is crossed.
+#[test]
+fn compaction_idle() -> Result<()> {
+    use std::time::Duration;
+
+    let temp_dir = TempDir::new().expect("unable to create temporary working directory");
+    let clock = MockClock::new(1_000_000);
+    let mut store = KvStore::builder()
+        .compaction_threshold(100)
+        .compaction_idle(Duration::from_millis(100))
+        .compaction_ceiling(1000)
+        .clock(clock.clone())
+        .build(temp_dir.path())?;
+    while store.stats().pending_bytes < 200 {
+        store.set("key".to_owned(), "value".to_owned())?;
+    }
+    assert!(!store.compact_if_needed()?);
+    assert_eq!(store.metrics().compaction_count, 0);
+
+    clock.advance(Duration::from_millis(99));
+    assert!(!store.compact_if_needed()?);
+    clock.advance(Duration::from_millis(1));
+    assert!(store.compact_if_needed()?);
+    assert_eq!(store.metrics().compaction_count, 1);
+    assert_eq!(store.stats().pending_bytes, 0);
+
+    while store.metrics().compaction_count == 1 {
+        assert!(store.stats().pending_bytes < 1000);
+        store.set("key".to_owned(), "value".to_owned())?;
+    }
+    assert_eq!(store.get("key".to_owned())?, Some("value".to_owned()));
+
+    Ok(())
+}
+
+// `preallocate` should reserve blocks for the data file without changing its
+// length, also for the file compaction puts in its place.
+#[cfg(target_os = "linux")]
+#[test]
+fn preallocate() -> Result<()> {
+    use std::os::unix::fs::MetadataExt;
+
+    let temp_dir = TempDir::new().expect("unable to create temporary working directory");
+    let data_path = temp_dir.path().join("miniDB.data");
+    let mut store = KvStore::builder()
+        .preallocate(1 << 20)
+        .build(temp_dir.path())?;
+    store.set("key1".to_owned(), "value1".to_owned())?;
+    store.flush()?;
+    let metadata = std::fs::metadata(&data_path)?;
+    assert_eq!(metadata.len(), 8 + 43);
+    assert!(metadata.blocks() * 512 >= 1 << 20);
+
+    store.set("key1".to_owned(), "value2".to_owned())?;
+    store.purge_tombstones()?;
+    let metadata = std::fs::metadata(&data_path)?;
+    assert_eq!(metadata.len(), 8 + 43);
+    assert!(metadata.blocks() * 512 >= 1 << 20);
+    drop(store);
+
+    let mut store = KvStore::open(temp_dir.path())?;
+    assert_eq!(store.get("key1".to_owned())?, Some("value2".to_owned()));
+
+    Ok(())
+}
+
+// After `flush_and_sync` everything written should be in the data file,
+// including what compaction moved into a new one.
+#[test]
+fn flush_and_sync() -> Result<()> {
+    let temp_dir = TempDir::new().expect("unable to create temporary working directory");
+    let mut store = KvStore::open(temp_dir.path())?;
+    store.set("key1".to_owned(), "value1".to_owned())?;
+    store.set("key1".to_owned(), "value2".to_owned())?;
+    store.purge_tombstones()?;
+    store.set("key2".to_owned(), "value3".to_owned())?;
+    store.flush_and_sync()?;
+
+    let mut other = KvStore::open(temp_dir.path())?;
+    assert_eq!(other.get("key1".to_owned())?, Some("value2".to_owned()));
+    assert_eq!(other.get("key2".to_owned())?, Some("value3".to_owned()));
+    KvStore::in_memory().flush_and_sync()?;
+
+    Ok(())
+}
+
+// `latency_report` should count every get, put and remove, with percentiles
+// in order and bounded by the slowest call.
+#[cfg(feature = "latency")]
+#[test]
+fn latency_report() -> Result<()> {
+    let temp_dir = TempDir::new().expect("unable to create temporary working directory");
+    let mut store = KvStore::open(temp_dir.path())?;
+    assert_eq!(store.latency_report().get.count, 0);
+    for i in 0..100 {
+        store.set(format!("key{}", i), "value".to_owned())?;
+        store.get(format!("key{}", i))?;
+    }
+    store.get("absent".to_owned())?;
+    store.remove("key1".to_owned())?;
+    assert!(store.remove("key1".to_owned()).is_err());
+
+    let report = store.latency_report();
+    assert_eq!(report.put.count, 100);
+    assert_eq!(report.get.count, 101);
+    assert_eq!(report.remove.count, 2);
+    for stats in [report.get, report.put, report.remove] {
+        assert!(stats.p50 <= stats.p90 && stats.p90 <= stats.p99);
+        assert!(stats.p99 <= stats.p999 && stats.p999 <= stats.max);
+        assert!(stats.max > std::time::Duration::ZERO);
+    }
+
+    Ok(())
+}
+
+// With `separate_values` the data file should only grow by the keys and value
+// references, whatever the size of the values.
+#[test]
+fn separate_values() -> Result<()> {
+    let temp_dir = TempDir::new().expect("unable to create temporary working directory");
+    let data_path = temp_dir.path().join("miniDB.data");
+    let mut store = KvStore::builder()
+        .separate_values()
+        .build(temp_dir.path())?;
+    store.set("key1".to_owned(), "v".to_owned())?;
+    store.flush()?;
+    let entry_len = std::fs::metadata(&data_path)?.len();
+    store.set("key2".to_owned(), "v".repeat(100_000))?;
+    store.flush()?;
+    assert!(std::fs::metadata(&data_path)?.len() < 2 * entry_len + 16);
+    assert!(std::fs::metadata(temp_dir.path().join("miniDB.blob"))?.len() > 100_000);
+    drop(store);
+
+    let mut store = KvStore::builder()
+        .separate_values()
+        .build(temp_dir.path())?;
+    assert_eq!(store.get("key1".to_owned())?, Some("v".to_owned()));
+    assert_eq!(store.get("key2".to_owned())?, Some("v".repeat(100_000)));
+
+    Ok(())
+}
+
+// A merge file left by an interrupted compaction should be deleted while the
+// data file is there, and renamed in its place when it isn't.
+#[test]
+fn stale_merge_file() -> Result<()> {
+    let temp_dir = TempDir::new().expect("unable to create temporary working directory");
+    let data_path = temp_dir.path().join("miniDB.data");
+    let merge_path = temp_dir.path().join("miniDB.merge");
+    let mut store = KvStore::open(temp_dir.path())?;
+    store.set("key1".to_owned(), "value1".to_owned())?;
+    drop(store);
+
+    std::fs::write(&merge_path, b"half a compaction")?;
+    let mut store = KvStore::open(temp_dir.path())?;
+    assert!(!merge_path.exists());
+    assert_eq!(store.get("key1".to_owned())?, Some("value1".to_owned()));
+    drop(store);
+
+    std::fs::rename(&data_path, &merge_path)?;
+    let mut store = KvStore::open(temp_dir.path())?;
+    assert!(!merge_path.exists());
+    assert_eq!(store.get("key1".to_owned())?, Some("value1".to_owned()));
+
+    Ok(())
+}
+
+// `size_on_disk` should count the data and blob files, buffered writes
+// included, and estimate the same entries for a store in memory.
+#[test]
+fn size_on_disk() -> Result<()> {
+    let temp_dir = TempDir::new().expect("unable to create temporary working directory");
+    let mut store = KvStore::builder()
+        .blob_threshold(100)
+        .build(temp_dir.path())?;
+    assert_eq!(store.size_on_disk()?, 8);
+    store.set("key1".to_owned(), "value1".to_owned())?;
+    assert_eq!(store.size_on_disk()?, 8 + 43);
+    store.set("key2".to_owned(), "v".repeat(1000))?;
+    store.flush()?;
+    let data_len = std::fs::metadata(temp_dir.path().join("miniDB.data"))?.len();
+    let blob_len = std::fs::metadata(temp_dir.path().join("miniDB.blob"))?.len();
+    assert_eq!(store.size_on_disk()?, data_len + blob_len);
+
+    let mut memory = KvStore::in_memory();
+    memory.set("key1".to_owned(), "value1".to_owned())?;
+    assert_eq!(memory.size_on_disk()?, 8 + 43);
+
+    Ok(())
+}
+
+// `snapshot_throttled` should take about as long as its rate allows and copy
+// the same store as `snapshot`.
+#[test]
+fn snapshot_throttled() -> Result<()> {
+    let temp_dir = TempDir::new().expect("unable to create temporary working directory");
+    let dest_dir = TempDir::new().expect("unable to create temporary working directory");
+    let mut store = KvStore::open(temp_dir.path())?;
+    for i in 0..40 {
+        store.set(format!("key{}", i), "v".repeat(1000))?;
+    }
+
+    let start = std::time::Instant::now();
+    store.snapshot_throttled(dest_dir.path(), 200_000)?;
+    assert!(start.elapsed() >= std::time::Duration::from_millis(150));
+    let mut copy = KvStore::open(dest_dir.path())?;
+    assert_eq!(copy.scan()?, store.scan()?);
+
+    Ok(())
+}
+
+// A store that never syncs should still write a checkpoint that opens as a
+// copy of it, leaving nothing half written in the destination.
+#[test]
+fn checkpoint() -> Result<()> {
+    let temp_dir = TempDir::new().expect("unable to create temporary working directory");
+    let dest_dir = TempDir::new().expect("unable to create temporary working directory");
+    let mut store = KvStore::builder()
+        .no_sync(true)
+        .commit_interval(CommitInterval {
+            every: std::time::Duration::from_millis(10),
+            max_bytes: 1,
+        })
+        .blob_threshold(100)
+        .build(temp_dir.path())?;
+    store.set("key1".to_owned(), "value1".to_owned())?;
+    store.set("key2".to_owned(), "v".repeat(1000))?;
+    store.sync()?;
+    store.flush_and_sync()?;
+
+    store.checkpoint(dest_dir.path())?;
+    store.set("key3".to_owned(), "value3".to_owned())?;
+    let mut files: Vec<_> = std::fs::read_dir(dest_dir.path())?
+        .map(|entry| entry.map(|entry| entry.file_name()))
+        .collect::<std::io::Result<_>>()?;
+    files.sort();
+    assert_eq!(files, ["miniDB.blob", "miniDB.data"]);
+    let mut copy = KvStore::open(dest_dir.path())?;
+    assert_eq!(copy.get("key2".to_owned())?, Some("v".repeat(1000)));
+    assert_eq!(copy.get("key3".to_owned())?, None);
+    drop(copy);
+
+    let mut store = KvStore::in_memory();
+    store.set("key1".to_owned(), "value1".to_owned())?;
+    store.checkpoint(dest_dir.path())?;
+    let mut copy = KvStore::open(dest_dir.path())?;
+    assert_eq!(copy.scan()?, store.scan()?);
+
+    Ok(())
+}
+
+// `rename` should move a value to its new key, blobs included, and survive a
+// reopen, failing on a missing key without writing anything.
+#[test]
+fn rename() -> Result<()> {
+    let temp_dir = TempDir::new().expect("unable to create temporary working directory");
+    let mut store = KvStore::builder()
+        .blob_threshold(100)
+        .build(temp_dir.path())?;
+    store.set("old".to_owned(), "value1".to_owned())?;
+    store.set("taken".to_owned(), "value2".to_owned())?;
+    store.set("big".to_owned(), "v".repeat(1000))?;
+
+    let events = store.subscribe();
+    store.rename("old", "taken")?;
+    store.rename("big", "moved")?;
+    store.rename("moved", "moved")?;
+    assert!(matches!(
+        store.rename("old", "other"),
+        Err(KvsError::KeyNotFound)
+    ));
+    let kinds: Vec<(String, CmdKind)> = events.try_iter().map(|e| (e.key, e.kind)).collect();
+    assert_eq!(
+        kinds,
+        [
+            ("taken".to_owned(), CmdKind::PUT),
+            ("old".to_owned(), CmdKind::DEL),
+            ("moved".to_owned(), CmdKind::PUT),
+            ("big".to_owned(), CmdKind::DEL),
+        ]
+    );
+    drop(store);
+
+    let mut stores = vec![KvStore::open(temp_dir.path())?, KvStore::in_memory()];
+    stores[1].set("taken".to_owned(), "value1".to_owned())?;
+    stores[1].set("big".to_owned(), "v".repeat(1000))?;
+    stores[1].rename("big", "moved")?;
+    for store in &mut stores {
+        assert_eq!(store.get("old".to_owned())?, None);
+        assert_eq!(store.get("taken".to_owned())?, Some("value1".to_owned()));
+        assert_eq!(store.get("big".to_owned())?, None);
+        assert_eq!(store.get("moved".to_owned())?, Some("v".repeat(1000)));
+    }
+
+    Ok(())
+}
+
+// A custom `index_hasher` should be used by both hash indexes, and keys should
+// stay apart even when it sends them all to the same hash.
+#[test]
+fn index_hasher() -> Result<()> {
+    use std::hash::{BuildHasher, Hasher};
+    use std::sync::atomic::{AtomicUsize, Ordering};
+    use std::sync::Arc;
+
+    /// Hashes every key to 0, counting the keys hashed.
+    #[derive(Clone, Default)]
+    struct Colliding(Arc<AtomicUsize>);
+
+    struct Zero;
+
+    impl Hasher for Zero {
+        fn finish(&self) -> u64 {
+            0
+        }
+
+        fn write(&mut self, _bytes: &[u8]) {}
+    }
+
+    impl BuildHasher for Colliding {
+        type Hasher = Zero;
+
+        fn build_hasher(&self) -> Zero {
+            self.0.fetch_add(1, Ordering::SeqCst);
+            Zero
+        }
+    }
+
+    for kind in [IndexKind::Hash, IndexKind::Hashed] {
+        let temp_dir = TempDir::new().expect("unable to create temporary working directory");
+        let hasher = Colliding::default();
+        let build = || {
+            KvStore::builder()
+                .index_kind(kind)
+                .index_hasher(IndexHasher::new(hasher.clone()))
+                .build(temp_dir.path())
+        };
+        let mut store = build()?;
+        for i in 0..20 {
+            store.set(format!("key{}", i), format!("value{}", i))?;
+        }
+        store.remove("key3".to_owned())?;
+        store.set("key4".to_owned(), "value4b".to_owned())?;
+        drop(store);
+
+        let mut store = build()?;
+        assert_eq!(store.get("key3".to_owned())?, None);
+        assert_eq!(store.get("key4".to_owned())?, Some("value4b".to_owned()));
+        assert_eq!(store.get("key19".to_owned())?, Some("value19".to_owned()));
+        assert_eq!(store.stats().key_count, 19);
+        assert!(hasher.0.load(Ordering::SeqCst) > 0);
+    }
+
+    Ok(())
+}
+
+// A read interrupted by a signal should be retried, not fail the import.
+#[test]
+fn import_interrupted() -> Result<()> {
+    struct Interrupting<'a> {
+        inner: &'a [u8],
+        interrupt: bool,
+    }
+
+    impl std::io::Read for Interrupting<'_> {
+        fn read(&mut self, buf: &mut [u8]) -> std::io::Result<usize> {
+            self.interrupt = !self.interrupt;
+            if self.interrupt {
+                return Err(std::io::ErrorKind::Interrupted.into());
+            }
+            std::io::Read::read(&mut self.inner, buf)
+        }
+    }
+
+    let mut store = KvStore::in_memory();
+    store.set("key1".to_owned(), "value1".to_owned())?;
+    store.set("key2".to_owned(), "value2".to_owned())?;
+    let mut dump = Vec::new();
+    store.export(&mut dump)?;
+
+    let mut other = KvStore::in_memory();
+    let reader = Interrupting {
+        inner: &dump,
+        interrupt: false,
+    };
+    assert_eq!(other.import(reader)?, 2);
+    assert_eq!(other.get("key2".to_owned())?, Some("value2".to_owned()));
+    Ok(())
+}
+
+// Stores with different names should live side by side in one directory.
+#[test]
+fn named_stores() -> Result<()> {
+    let temp_dir = TempDir::new().expect("unable to create temporary working directory");
+    let mut users = KvStore::open_named(temp_dir.path(), "users")?;
+    let mut sessions = KvStore::open_named(temp_dir.path(), "sessions")?;
+    users.set("key1".to_owned(), "alice".to_owned())?;
+    sessions.set("key1".to_owned(), "token".to_owned())?;
+    drop(users);
+    drop(sessions);
+
+    assert!(temp_dir.path().join("users.data").is_file());
+    assert!(temp_dir.path().join("sessions.data").is_file());
+    let mut users = KvStore::open_named(temp_dir.path(), "users")?;
+    assert_eq!(users.get("key1".to_owned())?, Some("alice".to_owned()));
+    let mut sessions = KvStore::open_named(temp_dir.path(), "sessions")?;
+    assert_eq!(sessions.get("key1".to_owned())?, Some("token".to_owned()));
+    let mut default = KvStore::open(temp_dir.path())?;
+    assert_eq!(default.get("key1".to_owned())?, None);
+
+    Ok(())
+}
+
+// `repair` should drop a partially written trailing entry so the store opens again.
+#[test]
+fn repair_truncated_tail() -> Result<()> {
+    let temp_dir = TempDir::new().expect("unable to create temporary working directory");
+    let mut store = KvStore::open(temp_dir.path())?;
+    store.set("key1".to_owned(), "value1".to_owned())?;
+    store.set("key2".to_owned(), "value2".to_owned())?;
+    drop(store);
+
+    let data_path = temp_dir.path().join("miniDB.data");
+    let mut data = std::fs::read(&data_path)?;
+    let entry_len = (data.len() - 8) / 2;
+    data.truncate(data.len() - 3);
+    std::fs::write(&data_path, &data)?;
+
+    assert_eq!(KvStore::repair(temp_dir.path())?, entry_len as u64 - 3);
+    assert_eq!(KvStore::repair(temp_dir.path())?, 0);
+    let mut store = KvStore::open(temp_dir.path())?;
+    assert_eq!(store.get("key1".to_owned())?, Some("value1".to_owned()));
+    assert_eq!(store.get("key2".to_owned())?, None);
+
+    Ok(())
+}
+
+// `kvs repair` should report the dropped bytes and leave an openable store.
+#[test]
+fn cli_repair() {
+    let temp_dir = TempDir::new().expect("unable to create temporary working directory");
+    std::fs::write(temp_dir.path().join("miniDB.data"), [0; 5]).unwrap();
+
+    Command::cargo_bin("kvs")
+        .unwrap()
+        .args(["repair"])
+        .current_dir(&temp_dir)
+        .assert()
+        .success()
+        .stdout(eq("Dropped 5 bytes").trim());
+    Command::cargo_bin("kvs")
+        .unwrap()
+        .args(["get", "key1"])
+        .current_dir(&temp_dir)
+        .assert()
+        .success()
+        .stdout(eq("Key not found").trim());
+}
+
+// `verify` should report where the first damaged entry starts without
+// touching the file.
+#[test]
+fn verify() -> Result<()> {
+    let temp_dir = TempDir::new().expect("unable to create temporary working directory");
+    let mut store = KvStore::open(temp_dir.path())?;
+    for key_id in 0..3 {
+        store.set(format!("key{}", key_id), format!("value{}", key_id))?;
+    }
+    drop(store);
+
+    let data_path = temp_dir.path().join("miniDB.data");
+    let data = std::fs::read(&data_path)?;
+    let report = KvStore::verify(temp_dir.path())?;
+    assert_eq!(report.valid_entries, 3);
+    assert_eq!(report.first_corruption, None);
+    assert_eq!(report.bytes_scanned, data.len() as u64);
+
+    // an invalid UTF-8 byte in the key of the second entry
+    let entry_len = (data.len() - 8) / 3;
+    let mut damaged = data;
+    damaged[8 + entry_len + 33] = 0xff;
+    std::fs::write(&data_path, &damaged)?;
+    let report = KvStore::verify(temp_dir.path())?;
+    assert_eq!(report.valid_entries, 1);
+    assert_eq!(report.first_corruption, Some(8 + entry_len as u64));
+    assert_eq!(report.bytes_scanned, 8 + entry_len as u64);
+    assert_eq!(std::fs::read(&data_path)?, damaged);
+
+    Ok(())
+}
+
+// `kvs verify` should fail on a damaged data file.
+#[test]
+fn cli_verify() {
+    let temp_dir = TempDir::new().expect("unable to create temporary working directory");
+    Command::cargo_bin("kvs")
+        .unwrap()
+        .args(["set", "key1", "value1"])
+        .current_dir(&temp_dir)
+        .assert()
+        .success();
+    Command::cargo_bin("kvs")
+        .unwrap()
+        .args(["verify"])
+        .current_dir(&temp_dir)
+        .assert()
+        .success()
+        .stdout(eq("1 valid entries, 51 bytes scanned").trim());
+
+    let data_path = temp_dir.path().join("miniDB.data");
+    let mut data = std::fs::read(&data_path).unwrap();
+    data.truncate(data.len() - 1);
+    std::fs::write(&data_path, data).unwrap();
+    Command::cargo_bin("kvs")
+        .unwrap()
+        .args(["verify"])
+        .current_dir(&temp_dir)
+        .assert()
+        .failure()
+        .stdout(contains("Corrupted entry at offset 8"));
+}
+
+// `kvs list` should print the live keys, optionally filtered and with values.
+#[test]
+fn cli_list() {
+    let temp_dir = TempDir::new().expect("unable to create temporary working directory");
+    for (key, val) in [("user2", "b"), ("user1", "a"), ("item1", "c")] {
+        Command::cargo_bin("kvs")
+            .unwrap()
+            .args(["set", key, val])
+            .current_dir(&temp_dir)
+            .assert()
+            .success();
+    }
+    Command::cargo_bin("kvs")
+        .unwrap()
+        .args(["rm", "item1"])
+        .current_dir(&temp_dir)
+        .assert()
+        .success();
+
+    Command::cargo_bin("kvs")
+        .unwrap()
+        .args(["list"])
+        .current_dir(&temp_dir)
+        .assert()
+        .success()
+        .stdout(eq("user1\nuser2\n"));
+    Command::cargo_bin("kvs")
+        .unwrap()
+        .args(["keys", "--prefix", "user2", "--values"])
+        .current_dir(&temp_dir)
+        .assert()
+        .success()
+        .stdout(eq("user2=b\n"));
+}
+
+// `kvs set <KEY> -` should store stdin as it is, and `kvs get --raw` should
+// print it back byte for byte.
+#[test]
+fn cli_stdin_value() {
+    let temp_dir = TempDir::new().expect("unable to create temporary working directory");
+    let val = "line1\n  line2\t\n\n";
+    Command::cargo_bin("kvs")
+        .unwrap()
+        .args(["set", "key1", "-"])
+        .current_dir(&temp_dir)
+        .with_stdin()
+        .buffer(val)
+        .assert()
+        .success()
+        .stdout(is_empty());
+    Command::cargo_bin("kvs")
+        .unwrap()
+        .args(["get", "key1", "--raw"])
+        .current_dir(&temp_dir)
+        .assert()
+        .success()
+        .stdout(eq(val));
+    Command::cargo_bin("kvs")
+        .unwrap()
+        .args(["get", "key2", "--raw"])
+        .current_dir(&temp_dir)
+        .assert()
+        .failure()
+        .stdout(is_empty())
+        .stderr(contains("Key not found"));
+}
+
+// `--path` and `MINIDB_PATH` should point the CLI at a store outside the
+// current directory, the flag taking precedence.
+#[test]
+fn cli_path() -> Result<()> {
+    let temp_dir = TempDir::new().expect("unable to create temporary working directory");
+    let store_dir = TempDir::new().expect("unable to create temporary working directory");
+    let other_dir = TempDir::new().expect("unable to create temporary working directory");
+    Command::cargo_bin("kvs")
+        .unwrap()
+        .args(["set", "key1", "value1", "--path"])
+        .arg(store_dir.path())
+        .current_dir(&temp_dir)
+        .assert()
+        .success();
+    Command::cargo_bin("kvs")
+        .unwrap()
+        .arg("--dir")
+        .arg(store_dir.path())
+        .args(["get", "key1"])
+        .current_dir(&temp_dir)
+        .assert()
+        .success()
+        .stdout(eq("value1").trim());
+    Command::cargo_bin("kvs")
+        .unwrap()
+        .args(["get", "key1"])
+        .env("MINIDB_PATH", store_dir.path())
+        .current_dir(&temp_dir)
+        .assert()
+        .success()
+        .stdout(eq("value1").trim());
+    Command::cargo_bin("kvs")
+        .unwrap()
+        .args(["get", "key1", "--path"])
+        .arg(other_dir.path())
+        .env("MINIDB_PATH", store_dir.path())
+        .current_dir(&temp_dir)
+        .assert()
+        .success()
+        .stdout(eq("Key not found").trim());
+
+    assert!(!temp_dir.path().join("miniDB.data").exists());
+    let mut store = KvStore::open(store_dir.path())?;
+    assert_eq!(store.get("key1".to_owned())?, Some("value1".to_owned()));
+
+    Ok(())
+}
+
+// `stats` should track live keys, the file size and the bytes waiting for compaction.
+#[test]
+fn stats() -> Result<()> {
+    let temp_dir = TempDir::new().expect("unable to create temporary working directory");
+    let mut store = KvStore::open(temp_dir.path())?;
+    assert_eq!(
+        store.stats(),
+        StoreStats {
+            key_count: 0,
+            file_size: 8,
+            pending_bytes: 0,
+            clean_shutdown: false,
+            index_from_checkpoint: false,
+        }
+    );
+
+    store.set("key1".to_owned(), "value1".to_owned())?;
+    store.set("key2".to_owned(), "value2".to_owned())?;
+    store.set("key1".to_owned(), "value3".to_owned())?;
+    let stats = store.stats();
+    assert_eq!(stats.key_count, 2);
+    assert_eq!(stats.file_size, 8 + 3 * 43);
+    assert_eq!(stats.pending_bytes, 43);
+
+    let mut store = KvStore::in_memory();
+    store.set("key1".to_owned(), "value1".to_owned())?;
+    assert_eq!(store.stats().key_count, 1);
+
+    Ok(())
+}
+
+// `kvs stats` should print the store's stats. The overwrite happened in another
+// process, so only the estimate sees it.
+#[test]
+fn cli_stats() {
+    let temp_dir = TempDir::new().expect("unable to create temporary working directory");
+    for val in ["value1", "value2"] {
+        Command::cargo_bin("kvs")
+            .unwrap()
+            .args(["set", "key1", val])
+            .current_dir(&temp_dir)
+            .assert()
+            .success();
+    }
+    Command::cargo_bin("kvs")
+        .unwrap()
+        .args(["stats"])
+        .current_dir(&temp_dir)
+        .assert()
+        .success()
+        .stdout(eq(
+            "keys: 1\nfile size: 94 bytes\npending compaction: 0 bytes\nreclaimable: 43 bytes\n",
+        ));
+}
+
+// Opening a store whose last entry was cut short should drop that entry and keep going.
+#[test]
+fn open_with_torn_tail() -> Result<()> {
+    let temp_dir = TempDir::new().expect("unable to create temporary working directory");
+    let mut store = KvStore::open(temp_dir.path())?;
+    store.set("key1".to_owned(), "value1".to_owned())?;
+    store.set("key2".to_owned(), "value2".to_owned())?;
+    drop(store);
+
+    let data_path = temp_dir.path().join("miniDB.data");
+    let data = std::fs::read(&data_path)?;
+    let entry_len = (data.len() - 8) / 2;
+    for cut in [3, entry_len - 5] {
+        std::fs::write(&data_path, &data[..data.len() - cut])?;
+
+        let mut store = KvStore::open(temp_dir.path())?;
+        assert_eq!(store.get("key1".to_owned())?, Some("value1".to_owned()));
+        assert_eq!(store.get("key2".to_owned())?, None);
+        assert_eq!(std::fs::metadata(&data_path)?.len(), 8 + entry_len as u64);
+
+        // New writes land right after the last complete entry.
+        store.set("key3".to_owned(), "value3".to_owned())?;
+        drop(store);
+        let mut store = KvStore::open(temp_dir.path())?;
+        assert_eq!(store.get("key3".to_owned())?, Some("value3".to_owned()));
+    }
+
+    Ok(())
+}
+
+// A store closed cleanly should be recognized as such by its footer, which
+// shouldn't vouch for a file changed since.
+#[test]
+fn clean_shutdown_footer() -> Result<()> {
+    let temp_dir = TempDir::new().expect("unable to create temporary working directory");
+    let data_path = temp_dir.path().join("miniDB.data");
+    let footer_path = temp_dir.path().join("miniDB.footer");
+    let mut store = KvStore::open(temp_dir.path())?;
+    assert!(!store.stats().clean_shutdown);
+    store.set("key1".to_owned(), "value1".to_owned())?;
+    drop(store);
+    assert_eq!(std::fs::metadata(&footer_path)?.len(), 20);
+
+    let store = KvStore::open(temp_dir.path())?;
+    assert!(store.stats().clean_shutdown);
+    assert!(!footer_path.exists());
+    drop(store);
+
+    // a torn write appended after the close
+    let mut data = std::fs::read(&data_path)?;
+    std::fs::write(&data_path, [data.as_slice(), &[0; 5]].concat())?;
+    let mut store = KvStore::open(temp_dir.path())?;
+    assert!(!store.stats().clean_shutdown);
+    assert_eq!(store.get("key1".to_owned())?, Some("value1".to_owned()));
+    drop(store);
+
+    // the same length with different bytes
+    let last = data.len() - 1;
+    data[last] = b'2';
+    std::fs::write(&data_path, &data)?;
+    let mut store = KvStore::open(temp_dir.path())?;
+    assert!(!store.stats().clean_shutdown);
+    assert_eq!(store.get("key1".to_owned())?, Some("value2".to_owned()));
+
+    Ok(())
+}
+
+// A store closed cleanly should load its index from the checkpoint, and fall
+// back to the data file when the checkpoint doesn't match it.
+#[test]
+fn index_checkpoint() -> Result<()> {
+    for kind in [IndexKind::Hash, IndexKind::Ordered, IndexKind::Hashed] {
+        let temp_dir = TempDir::new().expect("unable to create temporary working directory");
+        let data_path = temp_dir.path().join("miniDB.data");
+        let checkpoint_path = temp_dir.path().join("miniDB.index");
+        let builder = KvStore::builder().index_kind(kind).index_checkpoint(true);
+        let mut store = builder.clone().build(temp_dir.path())?;
+        store.set("key1".to_owned(), "value1".to_owned())?;
+        store.set("key2".to_owned(), "value2".to_owned())?;
+        store.remove("key2".to_owned())?;
+        store.set("key3".to_owned(), "value3".to_owned())?;
+        store.set("key3".to_owned(), "value4".to_owned())?;
+        assert!(!store.stats().index_from_checkpoint);
+        drop(store);
+        assert!(checkpoint_path.exists());
+        let stale = std::fs::read(&checkpoint_path)?;
+
+        let mut store = builder.clone().build(temp_dir.path())?;
+        assert!(store.stats().index_from_checkpoint);
+        assert!(!checkpoint_path.exists());
+        assert_eq!(store.get("key1".to_owned())?, Some("value1".to_owned()));
+        assert_eq!(store.get_state("key2".to_owned())?, KeyState::Tombstoned);
+        assert_eq!(store.get("key3".to_owned())?, Some("value4".to_owned()));
+        store.set("key1".to_owned(), "value5".to_owned())?;
+        drop(store);
+
+        // a checkpoint of the file as it was before the last close
+        std::fs::write(&checkpoint_path, &stale)?;
+        let mut store = builder.clone().build(temp_dir.path())?;
+        assert!(store.stats().clean_shutdown);
+        assert!(!store.stats().index_from_checkpoint);
+        assert_eq!(store.get("key1".to_owned())?, Some("value5".to_owned()));
+        drop(store);
+
+        // a file appended to after the close
+        let data = std::fs::read(&data_path)?;
+        std::fs::write(&data_path, [data.as_slice(), &[0; 5]].concat())?;
+        let mut store = builder.build(temp_dir.path())?;
+        assert!(!store.stats().index_from_checkpoint);
+        assert!(!checkpoint_path.exists());
+        assert_eq!(store.get("key1".to_owned())?, Some("value5".to_owned()));
+        assert_eq!(store.get("key3".to_owned())?, Some("value4".to_owned()));
+    }
+    Ok(())
+}
+
+// A store that failed to load shouldn't leave a footer vouching for its data
+// file when dropped.
+#[test]
+fn no_footer_after_failed_open() -> Result<()> {
+    let temp_dir = TempDir::new().expect("unable to create temporary working directory");
+    let data_path = temp_dir.path().join("miniDB.data");
+    let footer_path = temp_dir.path().join("miniDB.footer");
+    let mut store = KvStore::open(temp_dir.path())?;
+    for i in 1..=3 {
+        store.set(format!("key{}", i), format!("value{}", i))?;
+    }
+    drop(store);
+
+    // the kind byte of the entry of key2
+    let kind_offset = 8 + 43 + 16;
+    let mut data = std::fs::read(&data_path)?;
+    let kind = data[kind_offset];
+    data[kind_offset] = 0x7f;
+    std::fs::write(&data_path, &data)?;
+    assert!(KvStore::open(temp_dir.path()).is_err());
+    assert!(!footer_path.exists());
+
+    data[kind_offset] = kind;
+    std::fs::write(&data_path, &data)?;
+    let mut store = KvStore::open(temp_dir.path())?;
+    assert!(!store.stats().clean_shutdown);
+    assert_eq!(store.get("key3".to_owned())?, Some("value3".to_owned()));
+
+    Ok(())
+}
+
+// A store whose load failed part way shouldn't leave a checkpoint of the part
+// it loaded, which the next open would trust.
+#[test]
+fn no_checkpoint_after_failed_open() -> Result<()> {
+    let temp_dir = TempDir::new().expect("unable to create temporary working directory");
+    let data_path = temp_dir.path().join("miniDB.data");
+    let checkpoint_path = temp_dir.path().join("miniDB.index");
+    let builder = KvStore::builder().index_checkpoint(true);
+    let mut store = builder.clone().build(temp_dir.path())?;
+    for key in ["a", "b", "c"] {
+        store.set(key.to_owned(), "v".repeat(200))?;
+    }
+    drop(store);
+
+    // the kind byte of the entry of b
+    let kind_offset = 8 + (33 + 1 + 200) + 16;
+    let mut data = std::fs::read(&data_path)?;
+    let kind = data[kind_offset];
+    data[kind_offset] = 0x7f;
+    std::fs::write(&data_path, &data)?;
+    assert!(builder.clone().build(temp_dir.path()).is_err());
+    assert!(!checkpoint_path.exists());
+
+    data[kind_offset] = kind;
+    std::fs::write(&data_path, &data)?;
+    let mut store = builder.build(temp_dir.path())?;
+    assert!(!store.stats().index_from_checkpoint);
+    for key in ["a", "b", "c"] {
+        assert_eq!(store.get(key.to_owned())?, Some("v".repeat(200)));
+    }
+
+    Ok(())
+}
+
+// A header cut short should count as a torn entry even when the bytes that made
+// it describe an entry with an empty key and value.
+#[test]
+fn open_with_torn_header() -> Result<()> {
+    let temp_dir = TempDir::new().expect("unable to create temporary working directory");
+    let mut store = KvStore::open(temp_dir.path())?;
+    store.set("".to_owned(), "".to_owned())?;
+    drop(store);
+
+    let data_path = temp_dir.path().join("miniDB.data");
+    let mut data = std::fs::read(&data_path)?;
+    let entry_len = data.len() - 8;
+    let torn = data[8..8 + entry_len - 5].to_vec();
+    data.extend_from_slice(&torn);
+    std::fs::write(&data_path, &data)?;
+
+    let mut store = KvStore::open(temp_dir.path())?;
+    assert_eq!(store.get("".to_owned())?, Some("".to_owned()));
+    assert_eq!(std::fs::metadata(&data_path)?.len(), 8 + entry_len as u64);
+    store.set("key1".to_owned(), "value1".to_owned())?;
+    drop(store);
+    let mut store = KvStore::open(temp_dir.path())?;
+    assert_eq!(store.get("key1".to_owned())?, Some("value1".to_owned()));
+
+    Ok(())
+}
+
+// The async wrapper should run operations on the blocking pool and see the same data.
+#[cfg(feature = "async")]
+#[test]
+fn async_store() -> Result<()> {
+    let temp_dir = TempDir::new().expect("unable to create temporary working directory");
+    let runtime = tokio::runtime::Builder::new_current_thread()
+        .build()
+        .expect("unable to create runtime");
+
+    runtime.block_on(async {
+        let store = kvs::AsyncKvStore::open(temp_dir.path()).await?;
+        store.set("key1".to_owned(), "value1".to_owned()).await?;
+        store.set("key2".to_owned(), "value2".to_owned()).await?;
+        assert_eq!(
+            store.get("key1".to_owned()).await?,
+            Some("value1".to_owned())
+        );
+        store.remove("key2".to_owned()).await?;
+        assert!(matches!(
+            store.remove("key2".to_owned()).await,
+            Err(KvsError::KeyNotFound)
+        ));
+        store.flush().await
+    })?;
+
+    let mut store = KvStore::open(temp_dir.path())?;
+    assert_eq!(store.get("key1".to_owned())?, Some("value1".to_owned()));
+    assert_eq!(store.get("key2".to_owned())?, None);
+
+    Ok(())
+}
+
+// Incremental compaction should shrink the file while writes keep coming in between steps.
+#[test]
+fn incremental_compaction() -> Result<()> {
+    let temp_dir = TempDir::new().expect("unable to create temporary working directory");
+    let data_path = temp_dir.path().join("miniDB.data");
+    let mut store = KvStore::open(temp_dir.path())?;
+    for iter in 0..10 {
+        for key_id in 0..100 {
+            store.set(format!("key{}", key_id), format!("{}", iter))?;
+        }
+    }
+    store.flush()?;
+    let size_before = std::fs::metadata(&data_path)?.len();
+
+    let mut steps = 0;
+    while let CompactionProgress::Pending { scanned, total } = store.compact_step(1024)? {
+        assert!(scanned < total);
+        // overwrite, remove and add keys while the compaction is half done
+        store.set(format!("key{}", steps), "updated".to_owned())?;
+        store.remove(format!("key{}", 99 - steps))?;
+        store.set(format!("new{}", steps), "fresh".to_owned())?;
+        steps += 1;
+    }
+    assert!(steps > 1);
+    assert!(!temp_dir.path().join("miniDB.merge").exists());
+    assert!(std::fs::metadata(&data_path)?.len() < size_before);
+
+    let check = |store: &mut KvStore| -> Result<()> {
+        for key_id in 0..100 {
+            let expected = if key_id < steps {
+                Some("updated".to_owned())
+            } else if key_id >= 100 - steps {
+                None
+            } else {
+                Some("9".to_owned())
+            };
+            assert_eq!(store.get(format!("key{}", key_id))?, expected);
+        }
+        for step in 0..steps {
+            assert_eq!(store.get(format!("new{}", step))?, Some("fresh".to_owned()));
+        }
+        Ok(())
+    };
+    check(&mut store)?;
+    assert_eq!(store.get_state(format!("key{}", 99))?, KeyState::Tombstoned);
+    drop(store);
+    let mut store = KvStore::open(temp_dir.path())?;
+    check(&mut store)?;
+
+    Ok(())
+}
+
+// `compare_and_swap` should only write when the current value matches.
+#[test]
+fn compare_and_swap() -> Result<()> {
+    let temp_dir = TempDir::new().expect("unable to create temporary working directory");
+    let mut store = KvStore::open(temp_dir.path())?;
+
+    assert!(store.compare_and_swap("lock".to_owned(), None, "owner1".to_owned())?);
+    assert!(!store.compare_and_swap("lock".to_owned(), None, "owner2".to_owned())?);
+    assert!(!store.compare_and_swap(
+        "lock".to_owned(),
+        Some("owner2".to_owned()),
+        "owner3".to_owned()
+    )?);
+    assert_eq!(store.get("lock".to_owned())?, Some("owner1".to_owned()));
+
+    assert!(store.compare_and_swap(
+        "lock".to_owned(),
+        Some("owner1".to_owned()),
+        "owner2".to_owned()
+    )?);
+    assert_eq!(store.get("lock".to_owned())?, Some("owner2".to_owned()));
+
+    Ok(())
+}
+
+// `incr` should treat a missing key as 0 and refuse values that aren't integers.
+#[test]
+fn incr() -> Result<()> {
+    let temp_dir = TempDir::new().expect("unable to create temporary working directory");
+    let mut store = KvStore::open(temp_dir.path())?;
+
+    assert_eq!(store.incr("counter".to_owned(), 5)?, 5);
+    assert_eq!(store.incr("counter".to_owned(), -7)?, -2);
+    assert_eq!(store.get("counter".to_owned())?, Some("-2".to_owned()));
+
+    store.set("name".to_owned(), "miniDB".to_owned())?;
+    assert!(matches!(
+        store.incr("name".to_owned(), 1),
+        Err(KvsError::NotAnInteger)
+    ));
+    store.set("big".to_owned(), i64::MAX.to_string())?;
+    assert!(matches!(
+        store.incr("big".to_owned(), 1),
+        Err(KvsError::IntegerOverflow)
+    ));
+    assert_eq!(store.get("big".to_owned())?, Some(i64::MAX.to_string()));
+
+    Ok(())
+}
+
+// `append` should create missing keys and extend existing values.
+#[test]
+fn append() -> Result<()> {
+    let temp_dir = TempDir::new().expect("unable to create temporary working directory");
+    let mut store = KvStore::open(temp_dir.path())?;
+
+    assert_eq!(store.append("log".to_owned(), "start;")?, 6);
+    assert_eq!(store.append("log".to_owned(), "stop;")?, 11);
+    assert_eq!(store.get("log".to_owned())?, Some("start;stop;".to_owned()));
+
+    Ok(())
+}
+
+// The raw log iterator should yield every write in order, tombstones included.
+#[test]
+fn iter_log_entries() -> Result<()> {
+    use kvs::kv::storage::{CmdKind, SimplifiedBitcask};
+
+    let temp_dir = TempDir::new().expect("unable to create temporary working directory");
+    let mut store = KvStore::open(temp_dir.path())?;
+    store.set("key1".to_owned(), "value1".to_owned())?;
+    store.set("key2".to_owned(), "value2".to_owned())?;
+    store.set("key1".to_owned(), "value3".to_owned())?;
+    store.remove("key2".to_owned())?;
+    drop(store);
+
+    let mut bitcask = SimplifiedBitcask::open(temp_dir.path().to_path_buf(), Options::default())?;
+    let entries = bitcask.iter_entries().collect::<Result<Vec<_>>>()?;
+    let writes: Vec<(&str, &str, &CmdKind)> = entries
+        .iter()
+        .map(|(_, e)| (e.key(), e.value(), e.kind()))
+        .collect();
+    assert_eq!(
+        writes,
+        vec![
+            ("key1", "value1", &CmdKind::PUT),
+            ("key2", "value2", &CmdKind::PUT),
+            ("key1", "value3", &CmdKind::PUT),
+            ("key2", "", &CmdKind::DEL),
+        ]
+    );
+    // entries start after the 8 byte file header
+    let mut expected_offset = 8;
+    for (offset, e) in &entries {
+        assert_eq!(*offset, expected_offset);
+        expected_offset += e.size() as u64;
+    }
+
+    Ok(())
+}
+
+// Tools outside the crate should be able to summarize a log from the entries
+// and kinds exported at the top level.
+#[test]
+fn summarize_log_entries() -> Result<()> {
+    use kvs::Entry;
+    use std::collections::HashMap;
+
+    let temp_dir = TempDir::new().expect("unable to create temporary working directory");
+    let mut store = KvStore::builder()
+        .blob_threshold(100)
+        .build(temp_dir.path())?;
+    store.set("key1".to_owned(), "value1".to_owned())?;
+    store.set("key2".to_owned(), "v".repeat(200))?;
+    store.remove("key1".to_owned())?;
+    drop(store);
+
+    let mut bitcask = SimplifiedBitcask::open(temp_dir.path().to_path_buf(), Options::default())?;
+    let entries: Vec<(u64, Entry)> = bitcask.iter_entries().collect::<Result<_>>()?;
+    let mut by_kind: HashMap<CmdKind, (usize, usize)> = HashMap::new();
+    for (_, e) in &entries {
+        let (count, bytes) = by_kind.entry(*e.kind()).or_default();
+        *count += 1;
+        *bytes += e.size();
+    }
+    assert_eq!(by_kind[&CmdKind::PUT], (1, 43));
+    assert_eq!(by_kind[&CmdKind::DEL], (1, 37));
+    assert_eq!(by_kind[&CmdKind::BLOB].0, 1);
+    let file_len = std::fs::metadata(temp_dir.path().join("miniDB.data"))?.len();
+    let total: usize = by_kind.values().map(|(_, bytes)| bytes).sum();
+    assert_eq!(8 + total as u64, file_len);
+    // the blob entry only holds a reference to the value
+    assert_ne!(entries[1].1.value(), "v".repeat(200));
+    assert_eq!(entries[2].1.value(), "");
+
+    Ok(())
+}
+
+// `get_history` should list every value of a key left in the log, blobs and
+// removals included, until compaction drops them.
+#[test]
+fn get_history() -> Result<()> {
+    let temp_dir = TempDir::new().expect("unable to create temporary working directory");
+    let mut store = KvStore::builder()
+        .blob_threshold(100)
+        .build(temp_dir.path())?;
+    store.set("key1".to_owned(), "value1".to_owned())?;
+    store.set("key2".to_owned(), "value2".to_owned())?;
+    store.set("key1".to_owned(), "v".repeat(200))?;
+    store.remove("key1".to_owned())?;
+    store.set("key1".to_owned(), "value3".to_owned())?;
+
+    let history = store.get_history("key1")?;
+    let offsets: Vec<u64> = history.iter().map(|(offset, _)| *offset).collect();
+    let values: Vec<Option<String>> = history.into_iter().map(|(_, val)| val).collect();
+    assert_eq!(
+        values,
+        [
+            Some("value1".to_owned()),
+            Some("v".repeat(200)),
+            None,
+            Some("value3".to_owned()),
+        ]
+    );
+    assert_eq!(offsets[..2], [8, 8 + 2 * 43]);
+    assert_eq!(
+        store.locate("key1")?.map(|(offset, _)| offset),
+        offsets.last().copied()
+    );
+    assert_eq!(store.get_history("key3")?, []);
+
+    store.purge_tombstones()?;
+    assert_eq!(
+        store.get_history("key1")?,
+        [(8 + 43, Some("value3".to_owned()))]
+    );
+    assert_eq!(KvStore::in_memory().get_history("key1")?, []);
+
+    Ok(())
+}
+
+// Subscribers should see puts and removes in order, and a dropped receiver shouldn't break writes.
+#[test]
+fn subscribe_changes() -> Result<()> {
+    use kvs::{ChangeEvent, CmdKind};
+
+    let mut store = KvStore::in_memory();
+    let events = store.subscribe();
+    let dropped = store.subscribe();
+    drop(dropped);
+
+    store.set("key1".to_owned(), "value1".to_owned())?;
+    store.append("key1".to_owned(), "!")?;
+    store.remove("key1".to_owned())?;
+    assert!(store.remove("key1".to_owned()).is_err());
+
+    let received: Vec<ChangeEvent> = events.try_iter().collect();
+    assert_eq!(
+        received,
+        vec![
+            ChangeEvent {
+                key: "key1".to_owned(),
+                kind: CmdKind::PUT,
+                value: "value1".to_owned(),
+            },
+            ChangeEvent {
+                key: "key1".to_owned(),
+                kind: CmdKind::PUT,
+                value: "value1!".to_owned(),
+            },
+            ChangeEvent {
+                key: "key1".to_owned(),
+                kind: CmdKind::DEL,
+                value: String::new(),
+            },
+        ]
+    );
+
+    // A subscriber that never reads must not block writes.
+    for i in 0..2000 {
+        store.set(format!("key{}", i), "value".to_owned())?;
+    }
+    assert_eq!(events.try_iter().count(), 1024);
+
+    Ok(())
+}
+
+// A replica should catch up with the primary and tolerate the same range being shipped twice.
+#[test]
+fn replicate_log() -> Result<()> {
+    use kvs::Replica;
+
+    let primary_dir = TempDir::new().expect("unable to create temporary working directory");
+    let replica_dir = TempDir::new().expect("unable to create temporary working directory");
+    let mut primary = KvStore::open(primary_dir.path())?;
+    let mut replica = Replica::new(KvStore::open(replica_dir.path())?, 0);
+
+    primary.set("key1".to_owned(), "value1".to_owned())?;
+    primary.set("key2".to_owned(), "value2".to_owned())?;
+    let mut shipped = Vec::new();
+    let next = primary.ship_log(replica.offset(), &mut shipped)?;
+    assert_eq!(replica.apply(&mut shipped.as_slice())?, 2);
+    assert_eq!(replica.offset(), next);
+
+    primary.set("key1".to_owned(), "value3".to_owned())?;
+    primary.remove("key2".to_owned())?;
+    // The replica lost the ack and asks again from the start.
+    let mut shipped = Vec::new();
+    primary.ship_log(0, &mut shipped)?;
+    assert_eq!(replica.apply(&mut shipped.as_slice())?, 2);
+
+    assert_eq!(
+        replica.store().get("key1".to_owned())?,
+        Some("value3".to_owned())
+    );
+    assert_eq!(replica.store().get("key2".to_owned())?, None);
+
+    Ok(())
+}
+
+// Stores should work the same with tiny and large buffers.
+#[test]
+fn buffer_capacities() -> Result<()> {
+    for capacity in [1, 256 * 1024] {
+        let temp_dir = TempDir::new().expect("unable to create temporary working directory");
+        let options = Options {
+            read_buffer_capacity: capacity,
+            write_buffer_capacity: capacity,
+            ..Options::default()
+        };
+        let mut store = KvStore::open_with_options(temp_dir.path(), options.clone())?;
+        for key_id in 0..100 {
+            store.set(format!("key{}", key_id), format!("value{}", key_id))?;
+        }
+        drop(store);
+
+        let mut store = KvStore::open_with_options(temp_dir.path(), options)?;
+        for key_id in 0..100 {
+            assert_eq!(
+                store.get(format!("key{}", key_id))?,
+                Some(format!("value{}", key_id))
+            );
+        }
+    }
+
+    Ok(())
+}
+
+// With a bloom filter, lookups should still find every present key and miss absent ones.
+#[test]
+fn bloom_filter() -> Result<()> {
+    use kvs::BloomOptions;
+
+    let temp_dir = TempDir::new().expect("unable to create temporary working directory");
+    let options = Options {
+        bloom_filter: Some(BloomOptions {
+            expected_keys: 1000,
+            false_positive_rate: 0.01,
+        }),
+        ..Options::default()
+    };
+    let mut store = KvStore::open_with_options(temp_dir.path(), options.clone())?;
+    for key_id in 0..500 {
+        store.set(format!("key{}", key_id), format!("value{}", key_id))?;
+    }
+    store.remove("key0".to_owned())?;
+    drop(store);
+
+    let mut store = KvStore::open_with_options(temp_dir.path(), options)?;
+    assert_eq!(store.get("key0".to_owned())?, None);
+    for key_id in 1..500 {
+        assert_eq!(
+            store.get(format!("key{}", key_id))?,
+            Some(format!("value{}", key_id))
+        );
+    }
+    for key_id in 0..500 {
+        assert_eq!(store.get(format!("absent{}", key_id))?, None);
+    }
+
+    Ok(())
+}
+
+// A sharded store should spread keys over its shard files and write from several threads at once.
+#[test]
+fn sharded_store() -> Result<()> {
+    use kvs::ShardedKvStore;
+
+    let temp_dir = TempDir::new().expect("unable to create temporary working directory");
+    let store = ShardedKvStore::open(temp_dir.path(), 4)?;
+    std::thread::scope(|scope| {
+        for thread_id in 0..4 {
+            let store = &store;
+            scope.spawn(move || {
+                for key_id in 0..100 {
+                    store
+                        .set(
+                            format!("key{}_{}", thread_id, key_id),
+                            format!("value{}", key_id),
+                        )
+                        .unwrap();
+                }
+            });
+        }
+    });
+    store.remove("key0_0".to_owned())?;
+    drop(store);
+
+    for i in 0..4 {
+        let shard_path = temp_dir.path().join(format!("miniDB.{}.data", i));
+        assert!(std::fs::metadata(shard_path)?.len() > 0);
+    }
+    let store = ShardedKvStore::open(temp_dir.path(), 4)?;
+    assert_eq!(store.get("key0_0".to_owned())?, None);
+    for thread_id in 0..4 {
+        for key_id in 1..100 {
+            assert_eq!(
+                store.get(format!("key{}_{}", thread_id, key_id))?,
+                Some(format!("value{}", key_id))
+            );
+        }
+    }
+
+    Ok(())
+}
+
+// Cached values should be served without going stale after overwrites and removes.
+#[test]
+fn value_cache() -> Result<()> {
+    let temp_dir = TempDir::new().expect("unable to create temporary working directory");
+    let options = Options {
+        cache_capacity: 2,
+        ..Options::default()
+    };
+    let mut store = KvStore::open_with_options(temp_dir.path(), options)?;
+    store.set("key1".to_owned(), "value1".to_owned())?;
+    store.set("key2".to_owned(), "value2".to_owned())?;
+    store.set("key3".to_owned(), "value3".to_owned())?;
+
+    assert_eq!(store.get("key1".to_owned())?, Some("value1".to_owned()));
+    assert_eq!(store.get("key1".to_owned())?, Some("value1".to_owned()));
+    assert_eq!(store.metrics().cache_hit_count, 1);
+    assert_eq!(store.metrics().cache_miss_count, 1);
+
+    store.set("key1".to_owned(), "value1_new".to_owned())?;
+    assert_eq!(store.get("key1".to_owned())?, Some("value1_new".to_owned()));
+    store.remove("key1".to_owned())?;
+    assert_eq!(store.get("key1".to_owned())?, None);
+
+    // key2 and key3 fill the cache, then key2 is the least recently used.
+    store.get("key2".to_owned())?;
+    store.get("key3".to_owned())?;
+    store.get("key3".to_owned())?;
+    store.set("key4".to_owned(), "value4".to_owned())?;
+    store.get("key4".to_owned())?;
+    let before = store.metrics();
+    assert_eq!(store.get("key3".to_owned())?, Some("value3".to_owned()));
+    assert_eq!(store.get("key2".to_owned())?, Some("value2".to_owned()));
+    let after = store.metrics();
+    assert_eq!(after.cache_hit_count - before.cache_hit_count, 1);
+    assert_eq!(after.cache_miss_count - before.cache_miss_count, 1);
+
+    Ok(())
+}
+
+// `get_many` should return values in the order of the requested keys.
+#[test]
+fn get_put_many() -> Result<()> {
+    let temp_dir = TempDir::new().expect("unable to create temporary working directory");
+    let mut stores = vec![KvStore::open(temp_dir.path())?, KvStore::in_memory()];
+    for store in &mut stores {
+        store.put_many(
+            (0..10)
+                .map(|i| (format!("key{}", i), format!("value{}", i)))
+                .collect(),
+        )?;
+        let keys: Vec<String> = ["key7", "absent", "key2", "key9", "key2"]
+            .iter()
+            .map(|k| k.to_string())
+            .collect();
+        assert_eq!(
+            store.get_many(&keys)?,
+            vec![
+                Some("value7".to_owned()),
+                None,
+                Some("value2".to_owned()),
+                Some("value9".to_owned()),
+                Some("value2".to_owned()),
+            ]
+        );
+    }
+
+    Ok(())
+}
+
+// Loading a large file in parallel should give the same result as replaying it in order.
+#[cfg(feature = "rayon")]
+#[test]
+fn parallel_load_index() -> Result<()> {
+    let temp_dir = TempDir::new().expect("unable to create temporary working directory");
+    let mut store = KvStore::open(temp_dir.path())?;
+    // stay below the compaction threshold so the history is spread over the whole file
+    for key_id in 0..40000 {
+        store.set(format!("key{}", key_id), format!("value{}", key_id))?;
+    }
+    for key_id in 0..500 {
+        store.set(format!("key{}", key_id * 2), "updated".to_owned())?;
+        store.remove(format!("key{}", key_id * 2 + 1))?;
+    }
+    store.set("key1".to_owned(), "back".to_owned())?;
+    drop(store);
+    assert!(std::fs::metadata(temp_dir.path().join("miniDB.data"))?.len() > 1 << 20);
+
+    let mut store = KvStore::open(temp_dir.path())?;
+    assert_eq!(store.get("key1".to_owned())?, Some("back".to_owned()));
+    for key_id in 1..500 {
+        assert_eq!(
+            store.get(format!("key{}", key_id * 2))?,
+            Some("updated".to_owned())
+        );
+        assert_eq!(
+            store.get_state(format!("key{}", key_id * 2 + 1))?,
+            KeyState::Tombstoned
+        );
+    }
+    for key_id in 1000..40000 {
+        assert_eq!(
+            store.get(format!("key{}", key_id))?,
+            Some(format!("value{}", key_id))
+        );
+    }
+
+    Ok(())
+}
+
+// Concurrent writers through group commit should all get their writes applied and synced.
+#[test]
+fn group_commit() -> Result<()> {
+    use kvs::{GroupCommitKvStore, GroupCommitOptions};
+
+    let temp_dir = TempDir::new().expect("unable to create temporary working directory");
+    let store = GroupCommitKvStore::open(
+        temp_dir.path(),
+        GroupCommitOptions {
+            max_batch: 8,
+            max_wait: std::time::Duration::from_millis(5),
+        },
+    )?;
+    std::thread::scope(|scope| {
+        for thread_id in 0..8 {
+            let store = &store;
+            scope.spawn(move || {
+                for key_id in 0..20 {
+                    store
+                        .set(format!("key{}_{}", thread_id, key_id), "value".to_owned())
+                        .unwrap();
+                }
+                store.remove(format!("key{}_0", thread_id)).unwrap();
+                assert!(matches!(
+                    store.remove(format!("key{}_0", thread_id)),
+                    Err(KvsError::KeyNotFound)
+                ));
+            });
+        }
+    });
+    assert_eq!(store.get("key3_5".to_owned())?, Some("value".to_owned()));
+    drop(store);
+
+    let mut store = KvStore::open(temp_dir.path())?;
+    for thread_id in 0..8 {
+        assert_eq!(store.get(format!("key{}_0", thread_id))?, None);
+        for key_id in 1..20 {
+            assert_eq!(
+                store.get(format!("key{}_{}", thread_id, key_id))?,
+                Some("value".to_owned())
+            );
+        }
+    }
+
+    Ok(())
+}
+
+// Offsets from `put_and_locate` should read back through `get_at`.
+#[test]
+fn put_and_get_at() -> Result<()> {
+    let temp_dir = TempDir::new().expect("unable to create temporary working directory");
+    let mut store = KvStore::open(temp_dir.path())?;
+    let first = store.put_and_locate("key1".to_owned(), "value1".to_owned())?;
+    let second = store.put_and_locate("key1".to_owned(), "value2".to_owned())?;
+    assert!(second > first);
+
+    assert_eq!(store.get_at(first)?, Some("value1".to_owned()));
+    assert_eq!(store.get_at(second)?, Some("value2".to_owned()));
+    store.remove("key1".to_owned())?;
+    assert_eq!(store.get_at(second)?, Some("value2".to_owned()));
+    // The tombstone follows the second entry.
+    assert_eq!(store.get_at(second + (second - first))?, None);
+    assert_eq!(store.get_at(1 << 30)?, None);
+    assert!(matches!(
+        store.get_at(first + 3),
+        Err(KvsError::InvalidOffset(11))
+    ));
+
+    assert!(matches!(
+        KvStore::in_memory().get_at(0),
+        Err(KvsError::Unsupported)
+    ));
+
+    Ok(())
+}
+
+// `locate` should give the offset and size of the live entry of a key.
+#[test]
+fn locate() -> Result<()> {
+    let temp_dir = TempDir::new().expect("unable to create temporary working directory");
+    let mut store = KvStore::open(temp_dir.path())?;
+    store.set("key1".to_owned(), "value1".to_owned())?;
+    assert_eq!(store.locate("key1")?, Some((8, 43)));
+    let offset = store.put_and_locate("key1".to_owned(), "value10".to_owned())?;
+    assert_eq!(store.locate("key1")?, Some((offset, 44)));
+    assert_eq!(store.get_at(offset)?, Some("value10".to_owned()));
+    assert_eq!(store.locate("key2")?, None);
+    store.remove("key1".to_owned())?;
+    assert_eq!(store.locate("key1")?, None);
+
+    assert!(matches!(
+        KvStore::in_memory().locate("key1"),
+        Err(KvsError::Unsupported)
+    ));
+
+    Ok(())
+}
+
+// Values over `blob_threshold` should live in the blob file and survive
+// compaction, reopening, snapshots and replication.
+#[test]
+fn blob_values() -> Result<()> {
+    use kvs::Replica;
+
+    let temp_dir = TempDir::new().expect("unable to create temporary working directory");
+    let options = Options {
+        blob_threshold: Some(1024),
+        ..Options::default()
+    };
+    let big = "x".repeat(4096);
+    let mut store = KvStore::open_with_options(temp_dir.path(), options.clone())?;
+    store.set("big".to_owned(), big.clone())?;
+    store.set("small".to_owned(), "value".to_owned())?;
+    store.flush()?;
+    let data_len = std::fs::metadata(temp_dir.path().join("miniDB.data"))?.len();
+    let blob_len = std::fs::metadata(temp_dir.path().join("miniDB.blob"))?.len();
+    assert!(data_len < 1024);
+    assert_eq!(blob_len, 4096);
+    assert_eq!(store.get("big".to_owned())?, Some(big.clone()));
+
+    // Compaction copies the blob along with its reference.
+    for iter in 0..4000 {
+        store.set("small".to_owned(), format!("value{}", iter))?;
+    }
+    assert!(store.metrics().compaction_count > 0);
+    assert_eq!(store.get("big".to_owned())?, Some(big.clone()));
+
+    let snapshot_dir = TempDir::new().expect("unable to create temporary working directory");
+    store.snapshot(snapshot_dir.path())?;
+    let mut shipped = Vec::new();
+    store.ship_log(0, &mut shipped)?;
+    drop(store);
+
+    // The blob is still found without a threshold set.
+    let mut store = KvStore::open(temp_dir.path())?;
+    assert_eq!(store.get("big".to_owned())?, Some(big.clone()));
+    let mut copy = KvStore::open(snapshot_dir.path())?;
+    assert_eq!(copy.get("big".to_owned())?, Some(big.clone()));
+
+    let replica_dir = TempDir::new().expect("unable to create temporary working directory");
+    let mut replica = Replica::new(KvStore::open(replica_dir.path())?, 0);
+    replica.apply(&mut shipped.as_slice())?;
+    assert_eq!(replica.store().get("big".to_owned())?, Some(big));
+
+    Ok(())
+}
+
+// Compaction should leave overwritten blobs behind, keeping the blob file to
+// the live values, in either order and across a reopen, while a scan started
+// before it still reads the old blobs.
+#[test]
+fn compact_blobs() -> Result<()> {
+    let temp_dir = TempDir::new().expect("unable to create temporary working directory");
+    let mut store = KvStore::builder()
+        .blob_threshold(100)
+        .auto_compaction(false)
+        .build(temp_dir.path())?;
+    let blob_path = temp_dir.path().join("miniDB.blob");
+    store.set("key1".to_owned(), "a".repeat(1000))?;
+    for i in 0..100 {
+        store.set("key2".to_owned(), format!("{:01000}", i))?;
+    }
+    store.set("key3".to_owned(), "value3".to_owned())?;
+    assert_eq!(std::fs::metadata(&blob_path)?.len(), 101 * 1000);
+
+    let mut scan = store.scan_iter()?;
+    store.purge_tombstones()?;
+    assert_eq!(std::fs::metadata(&blob_path)?.len(), 2 * 1000);
+    assert_eq!(store.get("key1".to_owned())?, Some("a".repeat(1000)));
+    assert_eq!(store.get("key2".to_owned())?, Some(format!("{:01000}", 99)));
+    let first = scan.next().transpose()?;
+    assert_eq!(first, Some(("key1".to_owned(), "a".repeat(1000))));
+
+    for i in 0..10 {
+        store.set("key1".to_owned(), format!("{:01000}", i))?;
+    }
+    store.compact_sorted()?;
+    assert_eq!(std::fs::metadata(&blob_path)?.len(), 2 * 1000);
+    drop(store);
+
+    let mut store = KvStore::open(temp_dir.path())?;
+    assert_eq!(store.get("key1".to_owned())?, Some(format!("{:01000}", 9)));
+    assert_eq!(store.get("key2".to_owned())?, Some(format!("{:01000}", 99)));
+    assert_eq!(store.get("key3".to_owned())?, Some("value3".to_owned()));
+    assert!(!temp_dir.path().join("miniDB.blob.merge").exists());
+
+    Ok(())
+}
+
+// `retain` should remove exactly the keys rejected by the predicate.
+#[test]
+fn retain() -> Result<()> {
+    let temp_dir = TempDir::new().expect("unable to create temporary working directory");
+    let mut store = KvStore::open(temp_dir.path())?;
+    for i in 0..10 {
+        store.set(format!("tmp{}", i), format!("{}", i))?;
+        store.set(format!("key{}", i), format!("{}", i))?;
+    }
+    let events = store.subscribe();
+
+    let removed = store.retain(|key, val| !key.starts_with("tmp") && val != "3")?;
+    assert_eq!(removed, 11);
+    assert_eq!(events.try_iter().count(), 11);
+    assert_eq!(store.get("tmp0".to_owned())?, None);
+    assert_eq!(store.get("key3".to_owned())?, None);
+    assert_eq!(store.get("key4".to_owned())?, Some("4".to_owned()));
+    assert_eq!(store.scan()?.len(), 9);
+    drop(store);
+
+    let mut store = KvStore::open(temp_dir.path())?;
+    assert_eq!(store.scan()?.len(), 9);
+    assert_eq!(store.retain(|_, _| true)?, 0);
+
+    Ok(())
+}
+
+// `first_key` and `last_key` should agree across index kinds.
+#[test]
+fn first_last_key() -> Result<()> {
+    for index_kind in [IndexKind::Hash, IndexKind::Ordered, IndexKind::Hashed] {
+        let temp_dir = TempDir::new().expect("unable to create temporary working directory");
+        let options = Options {
+            index_kind,
+            ..Options::default()
+        };
+        let mut store = KvStore::open_with_options(temp_dir.path(), options)?;
+        assert_eq!(store.first_key()?, None);
+        assert_eq!(store.last_key()?, None);
+
+        for key in ["m", "c", "x", "a", "q"] {
+            store.set(key.to_owned(), "value".to_owned())?;
+        }
+        store.remove("a".to_owned())?;
+        assert_eq!(store.first_key()?, Some("c".to_owned()));
+        assert_eq!(store.last_key()?, Some("x".to_owned()));
+    }
+
+    Ok(())
+}
+
+// Paging through a store should visit every key once, in order.
+#[test]
+fn scan_pages() -> Result<()> {
+    let hash_dir = TempDir::new().expect("unable to create temporary working directory");
+    let ordered_dir = TempDir::new().expect("unable to create temporary working directory");
+    let options = Options {
+        index_kind: IndexKind::Ordered,
+        ..Options::default()
+    };
+    let stores = vec![
+        KvStore::open(hash_dir.path())?,
+        KvStore::open_with_options(ordered_dir.path(), options)?,
+        KvStore::in_memory(),
+    ];
+    for mut store in stores {
+        for i in 0..10 {
+            store.set(format!("key{}", i), format!("value{}", i))?;
+        }
+
+        let mut seen = Vec::new();
+        let mut cursor = None;
+        loop {
+            let (page, next) = store.scan_page(cursor, 3)?;
+            assert!(page.len() <= 3);
+            seen.extend(page);
+            if next.is_none() {
+                break;
+            }
+            cursor = next;
+        }
+        assert_eq!(seen, store.scan()?);
+
+        let (page, next) = store.scan_page(Some("key7".to_owned()), 2)?;
+        assert_eq!(page.len(), 2);
+        assert_eq!(next, None);
+        let (page, next) = store.scan_page(None, 0)?;
+        assert_eq!(page, vec![("key0".to_owned(), "value0".to_owned())]);
+        assert_eq!(next, Some("key0".to_owned()));
+        let (page, next) = store.scan_page(Some("key7".to_owned()), 0)?;
+        assert_eq!(page, vec![("key8".to_owned(), "value8".to_owned())]);
+        assert_eq!(next, Some("key8".to_owned()));
+        let (page, next) = store.scan_page(next, 0)?;
+        assert_eq!(page, vec![("key9".to_owned(), "value9".to_owned())]);
+        assert_eq!(next, None);
+    }
+
+    Ok(())
+}
+
+// Data files without the expected format header should be rejected on open.
+#[test]
+fn incompatible_format() -> Result<()> {
+    let temp_dir = TempDir::new().expect("unable to create temporary working directory");
+    let mut store = KvStore::open(temp_dir.path())?;
+    store.set("key1".to_owned(), "value1".to_owned())?;
+    drop(store);
+
+    let data_path = temp_dir.path().join("miniDB.data");
+    let mut data = std::fs::read(&data_path)?;
+    data[7] = 42;
+    std::fs::write(&data_path, &data)?;
+    assert!(matches!(
+        KvStore::open(temp_dir.path()),
+        Err(KvsError::IncompatibleFormat {
+            found: 42,
+            expected: 3
+        })
+    ));
+
+    // A file from before the header existed.
+    std::fs::write(&data_path, &data[8..])?;
+    assert!(matches!(
+        KvStore::open(temp_dir.path()),
+        Err(KvsError::IncompatibleFormat { found: 0, .. })
+    ));
+
+    // A header cut short right after creating the file is written again.
+    std::fs::write(&data_path, &data[..3])?;
+    let mut store = KvStore::open(temp_dir.path())?;
+    assert_eq!(store.get("key1".to_owned())?, None);
+    store.set("key1".to_owned(), "value1".to_owned())?;
+    drop(store);
+    let mut store = KvStore::open(temp_dir.path())?;
+    assert_eq!(store.get("key1".to_owned())?, Some("value1".to_owned()));
+
+    Ok(())
+}
+
+/// Encodes an entry the way format versions 0 and 1 did, without a timestamp.
+fn legacy_entry(key: &str, val: &str, kind: u8) -> Vec<u8> {
+    let mut buf = Vec::new();
+    buf.extend_from_slice(&key.len().to_be_bytes());
+    buf.extend_from_slice(&val.len().to_be_bytes());
+    buf.push(kind);
+    buf.extend_from_slice(key.as_bytes());
+    buf.extend_from_slice(val.as_bytes());
+    buf
+}
+
+// Data files from before the format header and before timestamps should open
+// again after `migrate`.
+#[test]
+fn migrate_legacy_file() -> Result<()> {
+    let mut entries = Vec::new();
+    entries.extend(legacy_entry("key1", "value1", 1));
+    entries.extend(legacy_entry("key2", "value2", 1));
+    entries.extend(legacy_entry("key1", "value3", 1));
+    entries.extend(legacy_entry("key3", "value4", 1));
+    entries.extend(legacy_entry("key3", "12345", 2));
+    let mut with_header = b"mnDB\0\0\0\x01".to_vec();
+    with_header.extend_from_slice(&entries);
+
+    for data in [entries.clone(), with_header] {
+        let temp_dir = TempDir::new().expect("unable to create temporary working directory");
+        let data_path = temp_dir.path().join("miniDB.data");
+        std::fs::write(&data_path, &data)?;
+        assert!(matches!(
+            KvStore::open(temp_dir.path()),
+            Err(KvsError::IncompatibleFormat { .. })
+        ));
+
+        assert!(KvStore::migrate(temp_dir.path())?);
+        assert!(!KvStore::migrate(temp_dir.path())?);
+        let mut store = KvStore::open(temp_dir.path())?;
+        assert_eq!(
+            store.get_with_meta("key1".to_owned())?,
+            Some(("value3".to_owned(), 0))
+        );
+        assert_eq!(store.get("key2".to_owned())?, Some("value2".to_owned()));
+        assert_eq!(store.get("key3".to_owned())?, None);
+    }
+
+    Ok(())
+}
+
+// A legacy entry whose lengths overflow when added should fail `migrate`,
+// leaving the data file as it was.
+#[test]
+fn migrate_overflowing_lengths() -> Result<()> {
+    let temp_dir = TempDir::new().expect("unable to create temporary working directory");
+    let data_path = temp_dir.path().join("miniDB.data");
+    let mut data = b"mnDB\0\0\0\x01".to_vec();
+    data.extend(legacy_entry("key1", "value1", 1));
+    let damaged = data.len() as u64;
+    data.extend_from_slice(&u64::MAX.to_be_bytes());
+    data.extend_from_slice(&1u64.to_be_bytes());
+    data.push(1);
+    data.extend_from_slice(b"key2value2");
+    std::fs::write(&data_path, &data)?;
+
+    match KvStore::migrate(temp_dir.path()) {
+        Err(KvsError::CorruptedEntry(at)) => assert_eq!(at, damaged),
+        other => panic!("unexpected result {:?}", other),
+    }
+    assert_eq!(std::fs::read(&data_path)?, data);
+
+    Ok(())
+}
+
+// Data files from before keys had versions should get them numbered by
+// `migrate`.
+#[test]
+fn migrate_v2_file() -> Result<()> {
+    let mut data = b"mnDB\0\0\0\x02".to_vec();
+    for (key, val, kind) in [
+        ("key1", "value1", 1),
+        ("key1", "value2", 1),
+        ("key2", "", 2),
+    ] {
+        data.extend_from_slice(&(key.len() as u64).to_be_bytes());
+        data.extend_from_slice(&(val.len() as u64).to_be_bytes());
+        data.push(kind);
+        data.extend_from_slice(&42u64.to_be_bytes());
+        data.extend_from_slice(key.as_bytes());
+        data.extend_from_slice(val.as_bytes());
+    }
+    let temp_dir = TempDir::new().expect("unable to create temporary working directory");
+    std::fs::write(temp_dir.path().join("miniDB.data"), &data)?;
+    assert!(matches!(
+        KvStore::open(temp_dir.path()),
+        Err(KvsError::IncompatibleFormat { found: 2, .. })
+    ));
+
+    assert!(KvStore::migrate(temp_dir.path())?);
+    let mut store = KvStore::open(temp_dir.path())?;
+    assert_eq!(
+        store.get_versioned("key1".to_owned())?,
+        Some(("value2".to_owned(), 2))
+    );
+    assert_eq!(
+        store.get_with_meta("key1".to_owned())?,
+        Some(("value2".to_owned(), 42))
+    );
+    assert_eq!(store.get("key2".to_owned())?, None);
+
+    Ok(())
+}
+
+// `kvs migrate` should upgrade the store in the current directory.
+#[test]
+fn cli_migrate() {
+    let temp_dir = TempDir::new().expect("unable to create temporary working directory");
+    std::fs::write(
+        temp_dir.path().join("miniDB.data"),
+        legacy_entry("key1", "value1", 1),
+    )
+    .unwrap();
+
+    Command::cargo_bin("kvs")
+        .unwrap()
+        .args(["migrate"])
+        .current_dir(&temp_dir)
+        .assert()
+        .success()
+        .stdout(eq("Migrated").trim());
+    Command::cargo_bin("kvs")
+        .unwrap()
+        .args(["migrate"])
+        .current_dir(&temp_dir)
+        .assert()
+        .success()
+        .stdout(eq("Already up to date").trim());
+    Command::cargo_bin("kvs")
+        .unwrap()
+        .args(["get", "key1"])
+        .current_dir(&temp_dir)
+        .assert()
+        .success()
+        .stdout(eq("value1").trim());
+}
+
+// Dropping a torn entry on open should be reported through the `log` facade.
+#[test]
+fn log_torn_tail() -> Result<()> {
+    use std::sync::Mutex;
+
+    struct Capture(Mutex<Vec<(log::Level, String)>>);
+
+    impl log::Log for Capture {
+        fn enabled(&self, _: &log::Metadata) -> bool {
+            true
+        }
+
+        fn log(&self, record: &log::Record) {
+            let mut records = self.0.lock().unwrap();
+            records.push((record.level(), record.args().to_string()));
+        }
+
+        fn flush(&self) {}
+    }
+
+    static CAPTURE: Capture = Capture(Mutex::new(Vec::new()));
+    log::set_logger(&CAPTURE).expect("no other test installs a logger");
+    log::set_max_level(log::LevelFilter::Trace);
+
+    let temp_dir = TempDir::new().expect("unable to create temporary working directory");
+    let mut store = KvStore::open(temp_dir.path())?;
+    store.set("key1".to_owned(), "value1".to_owned())?;
+    drop(store);
+    let data_path = temp_dir.path().join("miniDB.data");
+    let data = std::fs::read(&data_path)?;
+    std::fs::write(&data_path, &data[..data.len() - 3])?;
+    KvStore::open(temp_dir.path())?;
+
+    let path = data_path.display().to_string();
+    let records = CAPTURE.0.lock().unwrap();
+    let mine: Vec<&(log::Level, String)> = records
+        .iter()
+        .filter(|(_, msg)| msg.contains(&path))
+        .collect();
+    assert!(mine
+        .iter()
+        .any(|(level, msg)| *level == log::Level::Warn && msg.contains("torn entry at offset 8")));
+    assert!(mine.iter().any(|(level, _)| *level == log::Level::Debug));
+
+    Ok(())
+}
+
+// `compaction_estimate` should match what a compaction actually frees.
+#[test]
+fn compaction_estimate() -> Result<()> {
+    let temp_dir = TempDir::new().expect("unable to create temporary working directory");
+    let data_path = temp_dir.path().join("miniDB.data");
+    let mut store = KvStore::open(temp_dir.path())?;
+    for iter in 0..3 {
+        for key_id in 0..10 {
+            store.set(format!("key{}", key_id), format!("value{}", iter))?;
+        }
+    }
+    store.remove("key0".to_owned())?;
+
+    let estimate = store.compaction_estimate()?;
+    store.flush()?;
+    let size_before = std::fs::metadata(&data_path)?.len();
+    assert_eq!(size_before, 8 + estimate.live_bytes + estimate.dead_bytes);
+    assert!(estimate.dead_bytes > estimate.live_bytes);
+
+    while let CompactionProgress::Pending { .. } = store.compact_step(1 << 20)? {}
+    let size_after = std::fs::metadata(&data_path)?.len();
+    assert_eq!(size_before - size_after, estimate.reclaimable_bytes);
+    let estimate = store.compaction_estimate()?;
+    assert_eq!(estimate.dead_bytes, 0);
+    assert_eq!(estimate.reclaimable_bytes, 0);
+
+    Ok(())
+}
+
+// Compaction should keep tombstones younger than `tombstone_grace` and drop older ones.
+#[test]
+fn tombstone_grace() -> Result<()> {
+    use std::time::Duration;
+
+    let temp_dir = TempDir::new().expect("unable to create temporary working directory");
+    let clock = MockClock::new(1_000_000);
+    let options = Options {
+        tombstone_grace: Duration::from_secs(1),
+        clock: Some(std::sync::Arc::new(clock.clone())),
+        ..Options::default()
+    };
+    let mut store = KvStore::open_with_options(temp_dir.path(), options.clone())?;
+    store.set("key1".to_owned(), "value1".to_owned())?;
+    store.set("key2".to_owned(), "value2".to_owned())?;
+    store.remove("key1".to_owned())?;
+    store.remove("key2".to_owned())?;
+    store.set("key2".to_owned(), "value3".to_owned())?;
+
+    while let CompactionProgress::Pending { .. } = store.compact_step(16)? {}
+    assert_eq!(store.get_state("key1".to_owned())?, KeyState::Tombstoned);
+    assert_eq!(
+        store.get_state("key2".to_owned())?,
+        KeyState::Present("value3".to_owned())
+    );
+    assert!(store.compaction_estimate()?.reclaimable_bytes == 0);
+
+    // Compaction triggered by writes keeps it as well, across a reopen.
+    for iter in 0..4000 {
+        store.set("key3".to_owned(), format!("value{}", iter))?;
+    }
+    assert!(store.metrics().compaction_count > 1);
+    drop(store);
+    let mut store = KvStore::open_with_options(temp_dir.path(), options)?;
+    assert_eq!(store.get_state("key1".to_owned())?, KeyState::Tombstoned);
+
+    clock.advance(Duration::from_secs(1));
+    assert!(store.compaction_estimate()?.reclaimable_bytes > 0);
+    while let CompactionProgress::Pending { .. } = store.compact_step(16)? {}
+    assert_eq!(store.get_state("key1".to_owned())?, KeyState::Absent);
+
+    Ok(())
+}
+
+// Writes should be stamped with the time of the clock the store was given,
+// whatever the backend.
+#[test]
+fn mock_clock() -> Result<()> {
+    use std::time::Duration;
+
+    let temp_dir = TempDir::new().expect("unable to create temporary working directory");
+    let clock = MockClock::new(1_000_000);
+    let stores = vec![
+        KvStore::builder()
+            .clock(clock.clone())
+            .build(temp_dir.path())?,
+        KvStore::with_storage(Box::new(MemoryStorage::with_clock(std::sync::Arc::new(
+            clock.clone(),
+        )))),
+    ];
+    for mut store in stores {
+        clock.set(1_000_000);
+        store.set("key1".to_owned(), "value1".to_owned())?;
+        clock.advance(Duration::from_secs(5));
+        store.set("key2".to_owned(), "value2".to_owned())?;
+        assert_eq!(
+            store.get_with_meta("key1".to_owned())?,
+            Some(("value1".to_owned(), 1_000_000))
+        );
+        assert_eq!(
+            store.get_with_meta("key2".to_owned())?,
+            Some(("value2".to_owned(), 1_005_000))
+        );
+    }
+
+    let mut store = KvStore::open(temp_dir.path())?;
+    let (_, timestamp) = store.get_with_meta("key1".to_owned())?.unwrap();
+    assert_eq!(timestamp, 1_000_000);
+    store.set("key1".to_owned(), "value3".to_owned())?;
+    let (_, timestamp) = store.get_with_meta("key1".to_owned())?.unwrap();
+    assert!(timestamp > 1_005_000);
+
+    Ok(())
+}
+
+// Every write should record when it happened.
+#[test]
+fn entry_timestamps() -> Result<()> {
+    use std::time::{SystemTime, UNIX_EPOCH};
+
+    let now = || {
+        SystemTime::now()
+            .duration_since(UNIX_EPOCH)
+            .unwrap()
+            .as_millis() as u64
+    };
+    let temp_dir = TempDir::new().expect("unable to create temporary working directory");
+    let mut stores = vec![KvStore::open(temp_dir.path())?, KvStore::in_memory()];
+    for store in &mut stores {
+        let before = now();
+        store.set("key1".to_owned(), "value1".to_owned())?;
+        let (val, written_at) = store.get_with_meta("key1".to_owned())?.unwrap();
+        assert_eq!(val, "value1");
+        assert!(before <= written_at && written_at <= now());
+        assert_eq!(store.get_with_meta("key2".to_owned())?, None);
+    }
+    drop(stores);
+
+    // The timestamp is stored, not taken when reading.
+    let mut store = KvStore::open(temp_dir.path())?;
+    let (_, first) = store.get_with_meta("key1".to_owned())?.unwrap();
+    std::thread::sleep(std::time::Duration::from_millis(5));
+    let (_, again) = store.get_with_meta("key1".to_owned())?.unwrap();
+    assert_eq!(first, again);
+
+    Ok(())
+}
+
+// An encoded entry should start with a header describing it.
+#[test]
+fn entry_header() -> Result<()> {
+    use kvs::kv::storage::{Entry, EntryHeader};
+    use kvs::CmdKind;
+
+    let e = Entry::new("key1".to_owned(), "value1".to_owned(), CmdKind::PUT);
+    let buf = e.encode();
+    let head = EntryHeader::decode(buf[..33].try_into().unwrap())?;
+    assert_eq!(head.key_len, 4);
+    assert_eq!(head.value_len, 6);
+    assert_eq!(head.kind, CmdKind::PUT);
+    assert_eq!(head.timestamp, e.timestamp());
+    assert_eq!(head.entry_size(), buf.len());
+
+    let decoded = Entry::from_header(head, "key1".to_owned(), "value1".to_owned());
+    assert_eq!(decoded.encode(), buf);
+
+    Ok(())
+}
+
+// `get_into` should fill a reused buffer with the same bytes `get` returns.
+#[test]
+fn get_into_buffer() -> Result<()> {
+    let temp_dir = TempDir::new().expect("unable to create temporary working directory");
+    let blob_dir = TempDir::new().expect("unable to create temporary working directory");
+    let cached = Options {
+        cache_capacity: 10,
+        ..Options::default()
+    };
+    let blobs = Options {
+        blob_threshold: Some(8),
+        ..Options::default()
+    };
+    let stores = vec![
+        KvStore::open_with_options(temp_dir.path(), cached)?,
+        KvStore::open_with_options(blob_dir.path(), blobs)?,
+        KvStore::in_memory(),
+    ];
+    for mut store in stores {
+        store.set("key1".to_owned(), "value1".to_owned())?;
+        store.set("key2".to_owned(), "a longer value".to_owned())?;
+
+        let mut buf = Vec::new();
+        for _ in 0..2 {
+            assert!(store.get_into("key2", &mut buf)?);
+            assert_eq!(buf, b"a longer value");
+            assert!(store.get_into("key1", &mut buf)?);
+            assert_eq!(buf, b"value1");
+            store.get("key1".to_owned())?;
+        }
+        assert!(!store.get_into("key3", &mut buf)?);
+        assert_eq!(buf, b"value1");
+    }
+
+    Ok(())
+}
+
+proptest::proptest! {
+    #![proptest_config(proptest::prelude::ProptestConfig::with_cases(64))]
+
+    // Whatever goes in through `set` should come back out of `get`, before
+    // and after reopening, including empty and multibyte keys and values.
+    #[test]
+    fn prop_set_get_round_trip(
+        pairs in proptest::collection::vec((".{0,16}", ".{0,64}"), 1..16)
+    ) {
+        let temp_dir = TempDir::new().expect("unable to create temporary working directory");
+        let mut store = KvStore::open(temp_dir.path()).unwrap();
+        let mut expected = std::collections::HashMap::new();
+        for (key, value) in pairs {
+            store.set(key.clone(), value.clone()).unwrap();
+            expected.insert(key, value);
+        }
+        for (key, value) in expected.iter() {
+            proptest::prop_assert_eq!(store.get(key.clone()).unwrap(), Some(value.clone()));
+        }
+
+        drop(store);
+        let mut store = KvStore::open(temp_dir.path()).unwrap();
+        for (key, value) in expected {
+            proptest::prop_assert_eq!(store.get(key).unwrap(), Some(value));
+        }
+    }
+
+    // Decoding the header of an encoded entry and reading the key and value
+    // after it should rebuild the same entry.
+    #[test]
+    fn prop_entry_round_trip(key in ".{0,32}", value in ".{0,256}", del in proptest::bool::ANY) {
+        use kvs::kv::storage::{Entry, EntryHeader};
+        use kvs::CmdKind;
+
+        let kind = if del { CmdKind::DEL } else { CmdKind::PUT };
+        let e = Entry::new(key.clone(), value.clone(), kind);
+        let buf = e.encode();
+        let head = EntryHeader::decode(buf[..33].try_into().unwrap()).unwrap();
+        proptest::prop_assert_eq!(head.key_len, key.len());
+        proptest::prop_assert_eq!(head.value_len, value.len());
+        proptest::prop_assert_eq!(head.entry_size(), buf.len());
+
+        let body = &buf[33..];
+        let decoded_key = String::from_utf8(body[..head.key_len].to_vec()).unwrap();
+        let decoded_value = String::from_utf8(body[head.key_len..].to_vec()).unwrap();
+        proptest::prop_assert_eq!(&decoded_key, &key);
+        proptest::prop_assert_eq!(&decoded_value, &value);
+
+        let decoded = Entry::from_header(head, decoded_key, decoded_value);
+        proptest::prop_assert_eq!(decoded.kind(), e.kind());
+        proptest::prop_assert_eq!(decoded.timestamp(), e.timestamp());
+        proptest::prop_assert_eq!(decoded.encode(), buf);
+    }
+}